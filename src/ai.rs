@@ -1,7 +1,48 @@
 use anyhow::{bail, Result};
+use async_trait::async_trait;
 use bytes::Bytes;
+use once_cell::sync::Lazy;
+use rusqlite::params;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tokio::task;
+
+use crate::cache;
+use crate::claude::Claude;
+use crate::storage;
+
+// the model used when a room hasn't picked one, unless AI_CHAT_MODEL says otherwise
+pub const CHAT_MODEL: &str = "gpt-4o";
+const SEARCH_MODEL: &str = "gpt-4o-search-preview";
+const IMAGE_MODEL: &str = "dall-e-3";
+
+const CHAT_URL: &str = "https://api.openai.com/v1/chat/completions";
+const IMAGE_URL: &str = "https://api.openai.com/v1/images/generations";
+
+// the most we'll store of any one prompt or completion
+const DEFAULT_LOG_LENGTH: usize = 2000;
+
+// what's asked of the model for a photo's caption
+const DESCRIBE_PROMPT: &str = "Write a short, one-line caption for this family photo, like \
+\"Charlie at the beach\". Only use a name if you're told who's who. Answer with just the caption.";
+
+// how many requests go out to the models at once, unless AI_MAX_CONCURRENT says otherwise
+const DEFAULT_MAX_CONCURRENT: usize = 2;
+
+// Everything that talks to a model takes a turn from here first, so a burst of prompts waits in
+// line (first come, first served) instead of tripping the rate limits.
+static TURNS: Lazy<Semaphore> = Lazy::new(|| {
+    let max = env::var("AI_MAX_CONCURRENT")
+        .map(|max| max.parse().expect("AI_MAX_CONCURRENT is not an integer"))
+        .unwrap_or(DEFAULT_MAX_CONCURRENT);
+
+    Semaphore::new(max)
+});
+
+// how many are waiting on a turn
+static WAITING: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Serialize)]
 struct ImageBody<'a> {
@@ -22,16 +63,47 @@ struct ImageResponse {
     data: Vec<Data>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: String,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn system(content: &str) -> Message {
+        Message {
+            role: "system".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn user(content: &str) -> Message {
+        Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn assistant(content: &str) -> Message {
+        Message {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct MessageList {
     model: String,
     messages: Vec<Message>,
+    n: usize,
+}
+
+#[derive(Serialize)]
+struct StreamBody<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
 }
 
 #[derive(Deserialize)]
@@ -39,76 +111,598 @@ struct Choice {
     message: Message,
 }
 
+// one piece of a streamed answer
+#[derive(Deserialize)]
+struct StreamChunk {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
 }
 
-pub async fn chat(prompt: &str) -> Result<String> {
-    let client = reqwest::Client::new();
+#[derive(Serialize)]
+struct SearchBody<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    web_search_options: serde_json::Value,
+}
 
-    let auth = env::var("OPENAI_KEY").expect("OPENAI_KEY environmental variable not set");
+#[derive(Deserialize)]
+struct SearchResponse {
+    choices: Vec<SearchChoice>,
+}
 
-    let body = MessageList {
-        model: "gpt-4o".to_string(),
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: prompt.to_string(),
-        }],
-    };
+#[derive(Deserialize)]
+struct SearchChoice {
+    message: SearchMessage,
+}
+
+#[derive(Deserialize)]
+struct SearchMessage {
+    content: String,
+    #[serde(default)]
+    annotations: Vec<Annotation>,
+}
+
+#[derive(Deserialize)]
+struct Annotation {
+    url_citation: Option<Source>,
+}
 
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
+// a page the web search turned up
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct Source {
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+}
+
+// How many requests are waiting ahead of a new one, or None if there's a turn free and it would go
+// right away.
+pub fn ahead() -> Option<usize> {
+    if TURNS.available_permits() > 0 {
+        None
+    } else {
+        Some(WAITING.load(Ordering::SeqCst))
+    }
+}
+
+pub async fn take_turn() -> Result<SemaphorePermit<'static>> {
+    WAITING.fetch_add(1, Ordering::SeqCst);
+    let turn = TURNS.acquire().await;
+    WAITING.fetch_sub(1, Ordering::SeqCst);
+
+    Ok(turn?)
+}
+
+// Somewhere chat completions come from. Each backend takes its own turn before it goes out, and
+// the answers are logged by whoever asked for them.
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // `n` different completions of the same conversation
+    async fn chat(&self, messages: &[Message], model: &str, n: usize) -> Result<Vec<String>>;
+
+    // Everything so far goes out on `progress` each time there's more, and the whole answer is
+    // returned at the end.
+    async fn chat_streaming(
+        &self,
+        messages: &[Message],
+        model: &str,
+        progress: watch::Sender<String>,
+    ) -> Result<String>;
+
+    // The first answer to a conversation with a photo (a JPEG) attached to its last message. The
+    // detail is how closely to look, for backends that care: "low", "high", or "auto".
+    async fn look(
+        &self,
+        messages: &[Message],
+        model: &str,
+        jpeg: &[u8],
+        detail: &str,
+    ) -> Result<String>;
+}
+
+// the model for a room that hasn't picked one
+pub fn default_model() -> String {
+    env::var("AI_CHAT_MODEL").unwrap_or(CHAT_MODEL.to_string())
+}
+
+// Claude models go to Anthropic, and everything else to Open AI, so a room picks its backend along
+// with its model (and the default follows AI_CHAT_MODEL).
+fn backend(model: &str) -> &'static dyn ChatBackend {
+    if model.starts_with("claude") {
+        &Claude
+    } else {
+        &OpenAi
+    }
+}
+
+// the model is whatever the room picked, or the usual one
+pub async fn chat_with_context(messages: &[Message], model: Option<&str>) -> Result<String> {
+    let mut choices = chat_choices(messages, 1, model).await?;
+    Ok(choices.remove(0))
+}
+
+// asks for `n` different completions of the same conversation
+pub async fn chat_choices(
+    messages: &[Message],
+    n: usize,
+    model: Option<&str>,
+) -> Result<Vec<String>> {
+    let model = model.map(str::to_string).unwrap_or_else(default_model);
+    let completions = backend(&model).chat(messages, &model, n).await?;
+
+    if let Some(prompt) = messages.last() {
+        log_exchange(&model, &prompt.content, &completions.join("\n\n"));
+    }
+
+    Ok(completions)
+}
+
+// Like chat_with_context, but the answer comes in a bit at a time as the model writes it. Everything
+// so far goes out on `progress` each time there's more, and the whole answer is returned at the end.
+pub async fn chat_streaming(
+    messages: &[Message],
+    model: Option<&str>,
+    progress: watch::Sender<String>,
+) -> Result<String> {
+    let model = model.map(str::to_string).unwrap_or_else(default_model);
+    let answer = backend(&model)
+        .chat_streaming(messages, &model, progress)
+        .await?;
+
+    if let Some(prompt) = messages.last() {
+        log_exchange(&model, &prompt.content, &answer);
+    }
+
+    Ok(answer)
+}
+
+// Reads a streamed answer a line at a time, with `delta` pulling the text (if there is any) out of
+// each one. Everything so far goes out on `progress` each time there's more.
+pub async fn read_stream(
+    mut response: reqwest::Response,
+    progress: watch::Sender<String>,
+    delta: fn(&str) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut buffer: Vec<u8> = vec![];
+    let mut answer = String::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+
+        // events come a line at a time, and a chunk can end partway through one
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+
+            if let Some(delta) = delta(&String::from_utf8_lossy(&line))? {
+                answer.push_str(&delta);
+
+                // nobody may be watching anymore, which is no reason to stop
+                let _ = progress.send(answer.clone());
+            }
+        }
+    }
+
+    Ok(answer)
+}
+
+struct OpenAi;
+
+#[async_trait]
+impl ChatBackend for OpenAi {
+    fn name(&self) -> &'static str {
+        "Open AI"
+    }
+
+    async fn chat(&self, messages: &[Message], model: &str, n: usize) -> Result<Vec<String>> {
+        let body = MessageList {
+            model: model.to_string(),
+            messages: messages.to_vec(),
+            n,
+        };
+
+        let _turn = take_turn().await?;
+
+        let body = post(CHAT_URL, &body).await?.json::<ChatResponse>().await?;
+
+        let completions: Vec<String> = body
+            .choices
+            .into_iter()
+            .map(|choice| choice.message.content)
+            .collect();
+
+        if completions.is_empty() {
+            bail!("no choices from Open AI");
+        }
+
+        Ok(completions)
+    }
+
+    async fn chat_streaming(
+        &self,
+        messages: &[Message],
+        model: &str,
+        progress: watch::Sender<String>,
+    ) -> Result<String> {
+        let body = StreamBody {
+            model,
+            messages,
+            stream: true,
+        };
+
+        let _turn = take_turn().await?;
+
+        let response = post(CHAT_URL, &body).await?;
+
+        let answer = read_stream(response, progress, stream_delta).await?;
+
+        if answer.is_empty() {
+            bail!("no answer from Open AI");
+        }
+
+        Ok(answer)
+    }
+
+    async fn look(
+        &self,
+        messages: &[Message],
+        model: &str,
+        jpeg: &[u8],
+        detail: &str,
+    ) -> Result<String> {
+        let (last, earlier) = match messages.split_last() {
+            Some(split) => split,
+            None => bail!("nothing to ask about the photo"),
+        };
+
+        let mut messages = earlier
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        messages.push(with_photo(&last.content, jpeg, detail));
+
+        let body = serde_json::json!({
+            "model": model,
+            "messages": messages,
+        });
+
+        let _turn = take_turn().await?;
+
+        let body = post(CHAT_URL, &body).await?.json::<ChatResponse>().await?;
+
+        match body.choices.into_iter().next() {
+            Some(choice) => Ok(choice.message.content),
+            None => bail!("no choices from Open AI"),
+        }
+    }
+}
+
+// sends a request to Open AI, failing on anything but a success
+async fn post<T: Serialize + ?Sized>(url: &str, body: &T) -> Result<reqwest::Response> {
+    let auth = env::var("OPENAI_KEY").expect("OPENAI_KEY environmental variable not set");
+
+    let response = reqwest::Client::new()
+        .post(url)
         .header("Authorization", format!("Bearer {}", auth))
         .header("Content-Type", "application/json")
-        .json(&body)
+        .json(body)
         .send()
         .await?;
 
     if !response.status().is_success() {
         bail!(
             "unexpected response status from Open AI: {}",
-            response.status(),
+            response.status()
         );
     }
 
-    let body = response.json::<ChatResponse>().await?;
+    Ok(response)
+}
+
+// the text in one line of a streamed answer, if there's any
+fn stream_delta(line: &str) -> Result<Option<String>> {
+    let data = match line.trim().strip_prefix("data:") {
+        Some(data) => data.trim(),
+        None => return Ok(None),
+    };
+
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+
+    let chunk: StreamChunk = serde_json::from_str(data)?;
 
-    Ok(body.choices.first().unwrap().message.content.clone())
+    Ok(chunk
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.delta.content)
+        .filter(|content| !content.is_empty()))
 }
 
-pub async fn generate_image(prompt: &str) -> Result<Bytes> {
-    let client = reqwest::Client::new();
+// Answers with a web search behind it, returning the answer and every page the search found.
+pub async fn search(messages: &[Message]) -> Result<(String, Vec<Source>)> {
+    let body = SearchBody {
+        model: SEARCH_MODEL,
+        messages,
+        web_search_options: serde_json::json!({}),
+    };
 
-    let auth = env::var("OPENAI_KEY").expect("OPENAI_KEY environmental variable not set");
+    let _turn = take_turn().await?;
+
+    let body = post(CHAT_URL, &body)
+        .await?
+        .json::<SearchResponse>()
+        .await?;
+
+    let message = match body.choices.into_iter().next() {
+        Some(choice) => choice.message,
+        None => bail!("no choices from Open AI"),
+    };
+
+    let mut sources: Vec<Source> = vec![];
+
+    for source in message
+        .annotations
+        .into_iter()
+        .filter_map(|a| a.url_citation)
+    {
+        if !sources.iter().any(|s| s.url == source.url) {
+            sources.push(source);
+        }
+    }
+
+    if let Some(prompt) = messages.last() {
+        log_exchange(SEARCH_MODEL, &prompt.content, &message.content);
+    }
+
+    Ok((message.content, sources))
+}
+
+// A one-line caption for a photo (a JPEG), from the chat model looking at it. Anything in context
+// (like who's in the family) goes along with the prompt, so it can put names to faces.
+pub async fn describe_photo(jpeg: &[u8], context: Option<&str>) -> Result<String> {
+    let prompt = match context {
+        Some(context) => format!("{} {}", context, DESCRIBE_PROMPT),
+        None => DESCRIBE_PROMPT.to_string(),
+    };
+
+    let model = default_model();
+    let backend = backend(&model);
+
+    // a low detail look is plenty for a caption, and costs a fraction of a full one
+    let messages = [Message::user(&prompt)];
+    let caption = one_line(&backend.look(&messages, &model, jpeg, "low").await?);
+
+    if caption.is_empty() {
+        bail!("no caption from {}", backend.name());
+    }
+
+    log_exchange(&model, &prompt, &caption);
+
+    Ok(caption)
+}
+
+// Answers a question about a photo (a JPEG), with the conversation so far in front of it. The
+// photo goes along with the last message.
+pub async fn ask_about_photo(
+    messages: &[Message],
+    jpeg: &[u8],
+    model: Option<&str>,
+) -> Result<String> {
+    let model = model.map(str::to_string).unwrap_or_else(default_model);
 
+    let question = match messages.last() {
+        Some(question) => question,
+        None => bail!("nothing to ask about the photo"),
+    };
+
+    let answer = backend(&model).look(messages, &model, jpeg, "auto").await?;
+
+    log_exchange(&model, &question.content, &answer);
+
+    Ok(answer)
+}
+
+// a message from the user with a photo attached, the way the vision models take it
+fn with_photo(text: &str, jpeg: &[u8], detail: &str) -> serde_json::Value {
+    serde_json::json!({
+        "role": "user",
+        "content": [
+            { "type": "text", "text": text },
+            {
+                "type": "image_url",
+                "image_url": {
+                    "url": format!("data:image/jpeg;base64,{}", base64::encode(jpeg)),
+                    "detail": detail,
+                },
+            },
+        ],
+    })
+}
+
+// the first line of an answer, without the quotes models like to put around a caption
+fn one_line(content: &str) -> String {
+    content
+        .lines()
+        .map(|line| line.trim().trim_matches('"').trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub async fn generate_image(prompt: &str) -> Result<Bytes> {
     let body = ImageBody {
         prompt,
         n: 1,
         size: "1024x1024",
-        model: "dall-e-3",
+        model: IMAGE_MODEL,
         quality: "hd",
     };
 
-    let response = client
-        .post("https://api.openai.com/v1/images/generations")
-        .header("Authorization", format!("Bearer {}", auth))
-        .header("Content-Type", "application/json")
-        .json(&body)
-        .send()
+    let _turn = take_turn().await?;
+
+    let body = post(IMAGE_URL, &body)
+        .await?
+        .json::<ImageResponse>()
         .await?;
+    let url = &body.data.first().unwrap().url;
 
-    if !response.status().is_success() {
-        bail!(
-            "unexpected response status from Open AI: {}",
-            response.status(),
+    log_exchange(IMAGE_MODEL, prompt, url);
+
+    cache::get_url(url).await
+}
+
+// Logging is controlled by AI_LOG: "full" stores everything, "redacted" masks Matrix IDs and any
+// names listed (comma separated) in AI_LOG_REDACT, and anything else turns logging off.
+fn log_exchange(model: &str, prompt: &str, completion: &str) {
+    let redact = match env::var("AI_LOG").as_deref() {
+        Ok("full") => false,
+        Ok("redacted") => true,
+        _ => return,
+    };
+
+    let max_length = env::var("AI_LOG_MAX_LENGTH")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_LOG_LENGTH);
+
+    let names: Vec<String> = env::var("AI_LOG_REDACT")
+        .unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().to_lowercase())
+        .filter(|n| !n.is_empty())
+        .collect();
+
+    let clean = |text: &str| {
+        let text = if redact {
+            redact_names(text, &names)
+        } else {
+            text.to_string()
+        };
+
+        text.chars().take(max_length).collect::<String>()
+    };
+
+    let model = model.to_string();
+    let prompt = clean(prompt);
+    let completion = clean(completion);
+
+    // the database is written in the background, so logging never holds up an answer
+    task::spawn_blocking(move || {
+        if let Err(e) = insert_log(&model, &prompt, &completion) {
+            println!("could not log AI request: {}", e);
+        }
+    });
+}
+
+fn insert_log(model: &str, prompt: &str, completion: &str) -> Result<()> {
+    let conn = storage::open_shared()?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS ai_log (
+            id INTEGER PRIMARY KEY,
+            date TEXT NOT NULL,
+            model TEXT NOT NULL,
+            prompt TEXT NOT NULL,
+            completion TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        INSERT INTO ai_log
+            (date, model, prompt, completion)
+        VALUES
+            (?1, ?2, ?3, ?4)",
+        params![chrono::Utc::now().to_rfc3339(), model, prompt, completion],
+    )?;
+
+    Ok(())
+}
+
+// masks Matrix IDs, and any of the names (which are lowercase) as whole words
+fn redact_names(text: &str, names: &[String]) -> String {
+    // Matrix IDs first
+    let mut redacted: Vec<String> = text
+        .split(' ')
+        .map(|word| {
+            if word.starts_with('@') && word.contains(':') {
+                "@redacted".to_string()
+            } else {
+                word.to_string()
+            }
+        })
+        .collect();
+
+    // then the names, swapping out only the name itself, so the punctuation around it (and the
+    // case of everything else) stays as it was
+    for word in redacted.iter_mut() {
+        let bare = word
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_string();
+
+        if !bare.is_empty() && names.contains(&bare.to_lowercase()) {
+            *word = word.replacen(&bare, "[redacted]", 1);
+        }
+    }
+
+    redacted.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_names() {
+        let names = vec!["charlie".to_string(), "gwen".to_string()];
+
+        assert_eq!(
+            redact_names(
+                "Ask @phil:kulak.us if Charlie, or CHARLIE's Mom, can come.",
+                &names
+            ),
+            "Ask @redacted if [redacted], or CHARLIE's Mom, can come."
         );
+        assert_eq!(
+            redact_names("Gwen! Don't tell Charlotte.", &names),
+            "[redacted]! Don't tell Charlotte."
+        );
+        assert_eq!(redact_names("", &names), "");
     }
 
-    let body = response.json::<ImageResponse>().await?;
-    let url = &body.data.first().unwrap().url;
+    #[test]
+    fn reads_stream_deltas() {
+        let line = r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#;
+        assert_eq!(stream_delta(line).unwrap(), Some("Hel".to_string()));
 
-    let image = client.get(url).send().await?.bytes().await?;
+        // the first chunk only has the role, and the last one nothing at all
+        let role = r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":""}}]}"#;
+        assert_eq!(stream_delta(role).unwrap(), None);
+        let last = r#"data: {"choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#;
+        assert_eq!(stream_delta(last).unwrap(), None);
 
-    Ok(image)
+        assert_eq!(stream_delta("data: [DONE]").unwrap(), None);
+        assert_eq!(stream_delta("").unwrap(), None);
+        assert!(stream_delta("data: {not json").is_err());
+    }
 }
@@ -0,0 +1,378 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+
+use crate::google_photos;
+use crate::immich;
+
+// somewhere originals are kept, since the emails only get shrunk copies
+#[async_trait]
+pub trait Archive: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // The album is only for archives that have them; anything else keeps everything together. The
+    // description is one written by the vision model, if there is one.
+    async fn save(
+        &self,
+        photo: &Bytes,
+        mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        album: Option<&str>,
+    ) -> Result<()>;
+}
+
+// Every archive named in PHOTO_ARCHIVE (a comma separated list of "google", "immich", "dropbox",
+// "s3", and "webdav"). Without it, that's Google Photos if it's set up, or the DROPBOX directory.
+// This is worth calling once at startup, so a typo shows up then rather than with the first photo.
+pub fn configured() -> Result<Vec<Box<dyn Archive>>> {
+    let names: Vec<String> = match env::var("PHOTO_ARCHIVE") {
+        Ok(archives) => archives
+            .split(',')
+            .map(|a| a.trim().to_lowercase())
+            .filter(|a| !a.is_empty())
+            .collect(),
+        Err(_) if google_photos::enabled() => vec!["google".to_string()],
+        Err(_) => vec!["dropbox".to_string()],
+    };
+
+    names
+        .iter()
+        .map(|name| -> Result<Box<dyn Archive>> {
+            Ok(match name.as_str() {
+                "google" if google_photos::enabled() => Box::new(GooglePhotos),
+                "google" => bail!("PHOTO_ARCHIVE has google, but Google Photos isn't set up"),
+                "immich" if immich::enabled() => Box::new(Immich),
+                "immich" => {
+                    bail!("PHOTO_ARCHIVE has immich, but IMMICH_URL or IMMICH_API_KEY isn't set")
+                }
+                "dropbox" => Box::new(Local::new(&dropbox())),
+                "s3" => Box::new(S3::new()),
+                "webdav" => Box::new(WebDav::new()),
+                _ => bail!("unknown archive in PHOTO_ARCHIVE: {}", name),
+            })
+        })
+        .collect()
+}
+
+// counts up with every file, so two with the same name in the same second still differ
+static STAMPS: AtomicU64 = AtomicU64::new(0);
+
+fn dropbox() -> String {
+    env::var("DROPBOX").expect("DROPBOX environmental variable not set")
+}
+
+// Everything archived to the DROPBOX directory, newest first. That's the one archive there's
+// reading back from, for whatever shows the photos again.
+pub fn local_files() -> Result<Vec<PathBuf>> {
+    files_in(&dropbox())
+}
+
+// everything archived to a directory, newest first
+pub fn files_in(dir: &str) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        // dot files are whatever syncs the directory keeping notes, and sidecars are ours
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if meta.is_file() && !name.starts_with('.') && !name.ends_with(SIDECAR) {
+            files.push((meta.modified()?, entry.path()));
+        }
+    }
+
+    files.sort_by(|a, b| b.cmp(a));
+
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+// what's added to a file's name for the JSON kept beside it
+const SIDECAR: &str = ".json";
+
+// Archives that only keep files get a sidecar with the caption and description next to each one
+// that has a description; the caption alone is already in the file's name.
+fn sidecar(caption: Option<&str>, description: Option<&str>) -> Option<Vec<u8>> {
+    description.map(|description| {
+        serde_json::json!({
+            "caption": caption,
+            "description": description,
+        })
+        .to_string()
+        .into_bytes()
+    })
+}
+
+// Archives with a description of their own get the caption and the model's description together.
+fn described(caption: Option<&str>, description: Option<&str>) -> Option<String> {
+    match (caption, description) {
+        (Some(caption), Some(description)) => Some(format!("{} ({})", caption, description)),
+        (caption, description) => caption.or(description).map(|d| d.to_string()),
+    }
+}
+
+// file names get the time (and a count) in front, so nothing is ever overwritten
+fn stamped(file_name: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let count = STAMPS.fetch_add(1, Ordering::Relaxed);
+
+    format!("{}-{}-{}", now, count, file_name)
+}
+
+struct GooglePhotos;
+
+#[async_trait]
+impl Archive for GooglePhotos {
+    fn name(&self) -> &'static str {
+        "google"
+    }
+
+    async fn save(
+        &self,
+        photo: &Bytes,
+        mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        album: Option<&str>,
+    ) -> Result<()> {
+        let caption = described(caption, description);
+        google_photos::upload(photo, mime_type, file_name, caption.as_deref(), album).await
+    }
+}
+
+struct Immich;
+
+#[async_trait]
+impl Archive for Immich {
+    fn name(&self) -> &'static str {
+        "immich"
+    }
+
+    async fn save(
+        &self,
+        photo: &Bytes,
+        mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        album: Option<&str>,
+    ) -> Result<()> {
+        let caption = described(caption, description);
+        immich::upload(photo, mime_type, file_name, caption.as_deref(), album).await
+    }
+}
+
+// a directory on the bot's own machine, like DROPBOX
+pub struct Local {
+    dir: String,
+}
+
+impl Local {
+    pub fn new(dir: &str) -> Local {
+        Local {
+            dir: dir.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Archive for Local {
+    fn name(&self) -> &'static str {
+        "dropbox"
+    }
+
+    async fn save(
+        &self,
+        photo: &Bytes,
+        _mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        _album: Option<&str>,
+    ) -> Result<()> {
+        let path = format!("{}/{}", self.dir, stamped(file_name));
+
+        // and just in case, this won't write over anything that's there already
+        let create = |path: &str| OpenOptions::new().write(true).create_new(true).open(path);
+
+        create(&path)?.write_all(photo)?;
+
+        if let Some(sidecar) = sidecar(caption, description) {
+            create(&format!("{}{}", path, SIDECAR))?.write_all(&sidecar)?;
+        }
+
+        Ok(())
+    }
+}
+
+// S3, or anything that speaks it, from S3_BUCKET, S3_REGION, S3_ACCESS_KEY, and S3_SECRET_KEY;
+// S3_ENDPOINT points somewhere other than AWS, and S3_PREFIX puts everything in a "folder"
+struct S3 {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl S3 {
+    fn new() -> S3 {
+        let name = env::var("S3_BUCKET").expect("S3_BUCKET environmental variable not set");
+        let region = env::var("S3_REGION").expect("S3_REGION environmental variable not set");
+
+        let access_key =
+            env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY environmental variable not set");
+        let secret_key =
+            env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY environmental variable not set");
+
+        let credentials = Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)
+            .expect("S3_ACCESS_KEY and S3_SECRET_KEY are not valid credentials");
+
+        // other providers mostly want the bucket in the path, rather than the host name
+        let bucket = match env::var("S3_ENDPOINT") {
+            Ok(endpoint) => Bucket::new(&name, Region::Custom { region, endpoint }, credentials)
+                .map(|bucket| bucket.with_path_style()),
+            Err(_) => Bucket::new(
+                &name,
+                region.parse().expect("S3_REGION is not a region"),
+                credentials,
+            ),
+        }
+        .expect("could not set up the S3 bucket");
+
+        S3 {
+            bucket,
+            prefix: env::var("S3_PREFIX").unwrap_or_default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Archive for S3 {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn save(
+        &self,
+        photo: &Bytes,
+        mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        _album: Option<&str>,
+    ) -> Result<()> {
+        let path = match self.prefix.trim_matches('/') {
+            "" => stamped(file_name),
+            prefix => format!("{}/{}", prefix, stamped(file_name)),
+        };
+
+        let mut uploads = vec![(path.clone(), photo.to_vec(), mime_type)];
+
+        if let Some(sidecar) = sidecar(caption, description) {
+            uploads.push((format!("{}{}", path, SIDECAR), sidecar, "application/json"));
+        }
+
+        for (path, data, mime_type) in uploads {
+            let response = self
+                .bucket
+                .put_object_with_content_type(&path, &data, mime_type)
+                .await?;
+
+            if response.status_code() >= 300 {
+                bail!(
+                    "unexpected response status from S3: {}",
+                    response.status_code()
+                );
+            }
+
+            println!("uploaded {} to S3", path);
+        }
+
+        Ok(())
+    }
+}
+
+// a WebDAV directory (like one in Nextcloud) from WEBDAV_URL, logging in with WEBDAV_USERNAME and
+// WEBDAV_PASSWORD
+struct WebDav {
+    url: String,
+    username: String,
+    password: String,
+}
+
+impl WebDav {
+    fn new() -> WebDav {
+        WebDav {
+            url: env::var("WEBDAV_URL").expect("WEBDAV_URL environmental variable not set"),
+            username: env::var("WEBDAV_USERNAME")
+                .expect("WEBDAV_USERNAME environmental variable not set"),
+            password: env::var("WEBDAV_PASSWORD")
+                .expect("WEBDAV_PASSWORD environmental variable not set"),
+        }
+    }
+}
+
+#[async_trait]
+impl Archive for WebDav {
+    fn name(&self) -> &'static str {
+        "webdav"
+    }
+
+    async fn save(
+        &self,
+        photo: &Bytes,
+        mime_type: &str,
+        file_name: &str,
+        caption: Option<&str>,
+        description: Option<&str>,
+        _album: Option<&str>,
+    ) -> Result<()> {
+        let url = format!("{}/{}", self.url.trim_end_matches('/'), stamped(file_name));
+
+        let mut uploads = vec![(url.clone(), photo.clone(), mime_type)];
+
+        if let Some(sidecar) = sidecar(caption, description) {
+            uploads.push((
+                format!("{}{}", url, SIDECAR),
+                Bytes::from(sidecar),
+                "application/json",
+            ));
+        }
+
+        for (url, data, mime_type) in uploads {
+            let response = reqwest::Client::new()
+                .put(&url)
+                .basic_auth(&self.username, Some(&self.password))
+                .header("Content-Type", mime_type)
+                .body(data)
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                bail!(
+                    "unexpected response status from WebDAV: {}",
+                    response.status()
+                );
+            }
+
+            println!("uploaded {} to WebDAV", url);
+        }
+
+        Ok(())
+    }
+}
@@ -1,17 +1,399 @@
-use bytes::Buf;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use chrono::{DateTime, Utc};
 use matrix_sdk::room::{Joined, Room};
-use matrix_sdk::ruma::events::room::message::MessageEventContent;
-use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::room::member::{MemberEventContent, MembershipState};
+use matrix_sdk::ruma::events::room::message::{MessageEventContent, Relation};
+use matrix_sdk::ruma::events::{SyncMessageEvent, SyncStateEvent};
+use matrix_sdk::ruma::{EventId, MxcUri, RoomId, UserId};
 use matrix_sdk::{Client, SyncSettings};
 use mime;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::watch;
 
 use crate::ai;
+use crate::i18n;
+use crate::image;
 use crate::matrix;
+use crate::matrix::Media;
+use crate::scheduler;
+use crate::scheduler::Recurrence;
+use crate::storage;
+
+// how many days a room's conversation is remembered, unless AI_CONTEXT_DAYS says otherwise
+const DEFAULT_CONTEXT_DAYS: i64 = 7;
+
+// how many messages (prompts and responses) we remember per room
+const MAX_CONTEXT: usize = 20;
+
+// how much of each message to show when inspecting the context
+const PREVIEW_LENGTH: usize = 80;
+
+// the reactions used to pick between options, which also caps how many we'll ask for
+const OPTION_KEYS: &[&str] = &["1️⃣", "2️⃣", "3️⃣", "4️⃣", "5️⃣"];
+
+// the most a voice room gets read back to it
+const MAX_SPOKEN_SENTENCES: usize = 2;
+
+const VOICE_PROMPT: &str =
+    "Your answers are read aloud by a voice assistant. Answer in one or two short sentences of \
+    plain speech, with no markdown, lists, links, or emoji.";
+
+const CITATION_PROMPT: &str =
+    "Cite what you found on the web with a numbered marker, like [1], right after each fact, and \
+    end with a list of your sources, one per line, like \"[1] https://example.com/page\". Only \
+    cite pages from your search results.";
+
+const KID_SAFE_PROMPT: &str =
+    "You are talking with children. Keep everything age appropriate, and \
+    gently steer away from anything that isn't.";
+
+// the personas a room can pick from during setup, and what each adds to the system prompt
+const PERSONAS: &[(&str, Option<&str>)] = &[
+    ("Just Sherman", None),
+    (
+        "A patient teacher",
+        Some(
+            "You are a patient teacher. Explain things step by step, and check that they make \
+            sense before moving on.",
+        ),
+    ),
+    (
+        "A storyteller",
+        Some("You are a playful storyteller. Answer with imagination and a little humor."),
+    ),
+    (
+        "Straight to the point",
+        Some("Answer as briefly as you can, with no small talk."),
+    ),
+];
+
+// The models offered during setup, unless AI_MODELS (comma separated) says otherwise. Any Claude
+// model (like "claude-sonnet-4-5") can go in the list too, which makes it easy to try both.
+const DEFAULT_MODELS: &str = "gpt-4o,gpt-4o-mini";
+
+// how many of a room's latest photos we hang on to, for replies asking about them
+const PHOTOS_KEPT: usize = 5;
+
+// photos are shrunk to this (on the long side) before Sherman looks at them
+const PHOTO_SIZE: u32 = 1024;
+
+// the least time between edits of an answer that's still coming in
+const EDIT_INTERVAL: Duration = Duration::from_secs(1);
+
+// what Sherman's asked about a photo that comes with no question
+const PHOTO_QUESTION: &str = "What's in this photo?";
+
+type Context = Arc<Mutex<HashMap<RoomId, RoomContext>>>;
+
+#[derive(Default, Clone)]
+struct RoomContext {
+    messages: Vec<Remembered>,
+    settings: Settings,
+    settings_loaded: bool,
+    setup: Option<Setup>,
+    modifier: Option<Modifier>,
+    // from the room's space; the room can ask for it in its settings too
+    kid_safe: bool,
+    language: Option<String>,
+    options: Option<Options>,
+    // rooms bridged to a voice assistant, either from a space or set in the room itself
+    voice_space: bool,
+    voice: Option<bool>,
+    // the latest photos posted, oldest first, as JPEGs by event ID
+    photos: Vec<(String, Bytes)>,
+}
+
+// Something said in a room, kept across restarts. The token count is a rough guess (about four
+// characters each), but it's plenty to see how much a room's context costs.
+#[derive(Clone)]
+struct Remembered {
+    // None if it couldn't be saved, so it'll be gone after a restart
+    id: Option<i64>,
+    message: ai::Message,
+    created: DateTime<Utc>,
+    tokens: usize,
+}
+
+impl Remembered {
+    fn new(message: ai::Message) -> Remembered {
+        Remembered {
+            id: None,
+            tokens: estimate_tokens(&message.content),
+            message,
+            created: Utc::now(),
+        }
+    }
+}
+
+fn estimate_tokens(content: &str) -> usize {
+    content.chars().count().div_ceil(4)
+}
+
+fn context_retention() -> chrono::Duration {
+    let days = env::var("AI_CONTEXT_DAYS")
+        .map(|days| days.parse().expect("AI_CONTEXT_DAYS is not an integer"))
+        .unwrap_or(DEFAULT_CONTEXT_DAYS);
+
+    chrono::Duration::days(days)
+}
+
+// alternatives we've offered, waiting on someone to react with their pick
+#[derive(Clone)]
+struct Options {
+    event_id: String,
+    prompt: String,
+    choices: Vec<String>,
+}
+
+// what a room picked during setup, kept across restarts
+#[derive(Default, Clone, Debug, PartialEq)]
+struct Settings {
+    persona: Option<String>,
+    // a room's own system prompt, set by a parent, in place of the persona's
+    custom_persona: Option<String>,
+    model: Option<String>,
+    // None leaves it up to the room: everything in private rooms and "AI Chat", otherwise only
+    // when someone says "Sherman"
+    always_on: Option<bool>,
+    kid_safe: bool,
+}
+
+// the setup question we're waiting on an answer to, by number or by reaction
+#[derive(Clone)]
+struct Setup {
+    event_id: String,
+    step: Step,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Step {
+    Persona,
+    Model,
+    Answering,
+    KidSafe,
+}
+
+impl Step {
+    fn question(&self) -> &'static str {
+        match self {
+            Step::Persona => "Who should I be in this room?",
+            Step::Model => "Which model should I use?",
+            Step::Answering => "When should I answer?",
+            Step::KidSafe => "Are there kids in this room?",
+        }
+    }
+
+    fn choices(&self) -> Vec<String> {
+        match self {
+            Step::Persona => PERSONAS.iter().map(|(name, _)| name.to_string()).collect(),
+            Step::Model => models(),
+            Step::Answering => vec![
+                "Whenever anyone says anything".to_string(),
+                "Only when someone says \"Sherman\"".to_string(),
+            ],
+            Step::KidSafe => vec![
+                "Yes, keep it kid-safe".to_string(),
+                "No, just grown-ups".to_string(),
+            ],
+        }
+    }
+
+    // there's nothing to ask about the model when there's only one
+    fn next(&self) -> Option<Step> {
+        match self {
+            Step::Persona if models().len() > 1 => Some(Step::Model),
+            Step::Persona | Step::Model => Some(Step::Answering),
+            Step::Answering => Some(Step::KidSafe),
+            Step::KidSafe => None,
+        }
+    }
+
+    fn apply(&self, settings: &mut Settings, index: usize) {
+        match self {
+            Step::Persona => {
+                settings.persona = Some(PERSONAS[index].0.to_string());
+                settings.custom_persona = None;
+            }
+            Step::Model => settings.model = models().get(index).cloned(),
+            Step::Answering => settings.always_on = Some(index == 0),
+            Step::KidSafe => settings.kid_safe = index == 0,
+        }
+    }
+}
+
+// only as many as there are reactions to pick them with
+fn models() -> Vec<String> {
+    env::var("AI_MODELS")
+        .unwrap_or(DEFAULT_MODELS.to_string())
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .take(OPTION_KEYS.len())
+        .collect()
+}
+
+// Something to act on for a while, like "talk like a pirate". It's saved, so it outlasts a restart,
+// but not its time.
+#[derive(Clone)]
+struct Modifier {
+    prompt: String,
+    expires: DateTime<Utc>,
+}
+
+impl RoomContext {
+    fn voice(&self) -> bool {
+        self.voice.unwrap_or(self.voice_space)
+    }
+
+    // the conversation so far, leaving out anything older than we keep
+    fn history(&self) -> Vec<ai::Message> {
+        let cutoff = Utc::now() - context_retention();
+
+        self.messages
+            .iter()
+            .filter(|remembered| remembered.created > cutoff)
+            .map(|remembered| remembered.message.clone())
+            .collect()
+    }
+
+    fn forget_expired(&mut self) {
+        let cutoff = Utc::now() - context_retention();
+        self.messages
+            .retain(|remembered| remembered.created > cutoff);
+    }
+
+    // the system prompt is layered: deployment-wide base, then the room's language, then
+    // kid-safety if the room's space asks for it, then the room's persona, then whatever temporary
+    // modifier is still active, and last, keeping it speakable in voice rooms
+    fn system_prompt(&self) -> Option<ai::Message> {
+        let mut layers = vec![];
+
+        if let Ok(base) = env::var("AI_BASE_PROMPT") {
+            layers.push(base);
+        }
+
+        if let Some(language) = &self.language {
+            layers.push(format!("Always respond in {}.", language));
+        }
+
+        if self.kid_safe || self.settings.kid_safe {
+            layers.push(env::var("AI_KID_SAFE_PROMPT").unwrap_or(KID_SAFE_PROMPT.to_string()));
+        }
+
+        let persona = PERSONAS
+            .iter()
+            .find(|(name, _)| self.settings.persona.as_deref() == Some(*name))
+            .and_then(|(_, prompt)| *prompt);
+
+        if let Some(persona) = self.settings.custom_persona.as_deref().or(persona) {
+            layers.push(persona.to_string());
+        }
+
+        if let Some(modifier) = &self.modifier {
+            if modifier.expires > Utc::now() {
+                layers.push(modifier.prompt.clone());
+            }
+        }
+
+        if self.voice() {
+            layers.push(VOICE_PROMPT.to_string());
+        }
+
+        if layers.is_empty() {
+            None
+        } else {
+            Some(ai::Message::system(&layers.join("\n\n")))
+        }
+    }
+}
 
 pub async fn main() -> anyhow::Result<()> {
     let client = matrix::create_client("aibot").await?;
 
-    client.register_event_handler(on_room_message).await;
+    // pick up every room's conversation where it left off
+    let rooms = load_context().unwrap_or_else(|e| {
+        println!("Could not load context: {}", e);
+        HashMap::new()
+    });
+
+    let context: Context = Arc::new(Mutex::new(rooms));
+
+    client
+        .register_event_handler({
+            let context = context.clone();
+
+            move |event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client| {
+                let context = context.clone();
+
+                async move {
+                    on_room_message(event, room, client, context).await;
+                }
+            }
+        })
+        .await;
+
+    client
+        .register_event_handler({
+            let context = context.clone();
+
+            move |event: SyncMessageEvent<ReactionEventContent>, room: Room, client: Client| {
+                let context = context.clone();
+
+                async move {
+                    on_reaction(event, room, client, context).await;
+                }
+            }
+        })
+        .await;
+
+    client
+        .register_event_handler({
+            let context = context.clone();
+
+            move |event: SyncStateEvent<MemberEventContent>, room: Room, client: Client| {
+                let context = context.clone();
+
+                async move {
+                    on_member(event, room, client, context).await;
+                }
+            }
+        })
+        .await;
+
+    // modifiers that have run out are cleared, and their rooms told
+    scheduler::spawn("modifiers", scheduler::next_minute, {
+        let client = client.clone();
+        let context = context.clone();
+
+        move || {
+            let client = client.clone();
+            let context = context.clone();
+
+            async move { expire_modifiers(&client, &context).await }
+        }
+    });
+
+    // run any prompts scheduled for this hour
+    scheduler::spawn("scheduled prompts", scheduler::next_hour, {
+        let client = client.clone();
+        let context = context.clone();
+
+        move || {
+            let client = client.clone();
+            let context = context.clone();
+
+            async move {
+                run_scheduled_prompts(&client, &context).await;
+                Ok(())
+            }
+        }
+    });
 
     let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
     client.sync(settings).await;
@@ -19,31 +401,320 @@ pub async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client) {
-    if let Some((joined, _, message)) = matrix::get_text_message(event, room, client).await {
-        handle_message(joined, &message).await;
+// a room we've just joined gets asked how it wants Sherman, unless it's been set up before
+async fn on_member(
+    event: SyncStateEvent<MemberEventContent>,
+    room: Room,
+    client: Client,
+    context: Context,
+) {
+    let joined = match room {
+        Room::Joined(joined) => joined,
+        _ => return,
+    };
+
+    if client.user_id().await.map(|id| id.to_string()) != Some(event.state_key.clone()) {
+        return;
+    }
+
+    // name and avatar changes come through as joins too
+    let was_joined = event
+        .prev_content
+        .map(|prev| prev.membership == MembershipState::Join)
+        .unwrap_or(false);
+
+    if event.content.membership != MembershipState::Join || was_joined {
+        return;
+    }
+
+    if !matrix::active_in(&client, "ai", joined.room_id()).await {
+        return;
+    }
+
+    match load_settings(joined.room_id()) {
+        Ok(None) => ask(&joined, &context, Step::Persona).await,
+        Ok(Some(_)) => {}
+        Err(e) => println!("Could not load settings for {}: {}", joined.room_id(), e),
+    }
+}
+
+async fn on_room_message(
+    event: SyncMessageEvent<MessageEventContent>,
+    room: Room,
+    client: Client,
+    context: Context,
+) {
+    if !matrix::routes_to(&client, "ai", room.room_id(), &event).await {
+        return;
+    }
+
+    let event_id = event.event_id.to_string();
+
+    let reply_to = match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.to_string()),
+        _ => None,
+    };
+
+    if let Some((joined, _, uri, info)) =
+        matrix::get_image_message(event.clone(), room.clone(), client.clone()).await
+    {
+        load_room(&client, &joined, &context).await;
+
+        let mime_type = info.and_then(|info| info.mimetype);
+
+        if let Err(e) = on_photo(&joined, &context, event_id, &uri, mime_type).await {
+            println!("Could not look at photo in {}: {}", joined.room_id(), e);
+        }
+
+        return;
+    }
+
+    if let Some((joined, sender, message)) =
+        matrix::get_text_message(event, room, client.clone()).await
+    {
+        load_room(&client, &joined, &context).await;
+
+        // a reply to a photo asks about that photo
+        let photo = reply_to.and_then(|id| kept_photo(&context, joined.room_id(), &id));
+
+        if let Some(photo) = photo {
+            let message = matrix::strip_reply_fallback(&message);
+            let prompt = matrix::find_command(vec!["sherman,", "sherman"], message);
+
+            if prompt.is_some() || answers_everything(&joined, &context).await {
+                respond_to_photo(&joined, &context, &photo, prompt.unwrap_or(message)).await;
+                return;
+            }
+        }
+
+        handle_message(joined, sender, &message, &context).await;
+    }
+}
+
+// picks up what the room's space says about it, and its settings the first time we need them
+async fn load_room(client: &Client, joined: &Joined, context: &Context) {
+    let features = matrix::room_features(client, joined.room_id()).await;
+
+    let mut all = context.lock().unwrap();
+    let room = all.entry(joined.room_id().clone()).or_default();
+    room.kid_safe = features.contains("kid-safe");
+    room.voice_space = features.contains("voice");
+
+    if !room.settings_loaded {
+        match load_settings(joined.room_id()) {
+            Ok(settings) => {
+                room.settings = settings.unwrap_or_default();
+                room.settings_loaded = true;
+            }
+            Err(e) => println!("Could not load settings for {}: {}", joined.room_id(), e),
+        }
+    }
+}
+
+// Every photo is kept for a while, in case someone replies to ask about it. Rooms where Sherman
+// answers everything get told what's in it right away.
+async fn on_photo(
+    joined: &Joined,
+    context: &Context,
+    event_id: String,
+    uri: &MxcUri,
+    mime_type: Option<String>,
+) -> anyhow::Result<()> {
+    let photo = matrix::Homeserver::default().download(uri).await?;
+
+    let mime_type = match mime_type {
+        Some(mime_type) => mime_type,
+        None => match image::sniff_mime_type(&photo) {
+            Some(mime_type) => mime_type.to_string(),
+            None => anyhow::bail!("not a photo we can read"),
+        },
+    };
+
+    let jpeg = tokio::task::spawn_blocking(move || {
+        let output = image::Output {
+            max_size: Some((PHOTO_SIZE, PHOTO_SIZE)),
+            quality: None,
+        };
+
+        image::render(&photo, &mime_type, false, output)
+    })
+    .await??;
+
+    keep_photo(context, joined.room_id(), event_id, jpeg.clone());
+
+    if answers_everything(joined, context).await {
+        respond_to_photo(joined, context, &jpeg, PHOTO_QUESTION).await;
+    }
+
+    Ok(())
+}
+
+fn keep_photo(context: &Context, room_id: &RoomId, event_id: String, jpeg: Bytes) {
+    let mut all = context.lock().unwrap();
+    let photos = &mut all.entry(room_id.clone()).or_default().photos;
+    photos.push((event_id, jpeg));
+
+    if photos.len() > PHOTOS_KEPT {
+        let excess = photos.len() - PHOTOS_KEPT;
+        photos.drain(..excess);
+    }
+}
+
+fn kept_photo(context: &Context, room_id: &RoomId, event_id: &str) -> Option<Bytes> {
+    context
+        .lock()
+        .unwrap()
+        .get(room_id)?
+        .photos
+        .iter()
+        .find(|(id, _)| id == event_id)
+        .map(|(_, jpeg)| jpeg.clone())
+}
+
+// everything in private rooms and "AI Chat", unless the room has said otherwise
+async fn answers_everything(joined: &Joined, context: &Context) -> bool {
+    let always_on = context
+        .lock()
+        .unwrap()
+        .get(joined.room_id())
+        .and_then(|room| room.settings.always_on);
+
+    match always_on {
+        Some(always_on) => always_on,
+        None => {
+            joined.members_no_sync().await.unwrap().len() <= 2
+                || joined.display_name().await.unwrap_or("".to_string()) == "AI Chat"
+        }
     }
 }
 
-async fn handle_message(joined: Joined, message: &str) {
+async fn handle_message(joined: Joined, sender: UserId, message: &str, context: &Context) {
     let private_room = joined.members_no_sync().await.unwrap().len() <= 2;
 
+    let setting_up = context
+        .lock()
+        .unwrap()
+        .get(joined.room_id())
+        .map(|room| room.setup.is_some())
+        .unwrap_or_default();
+
+    // a number on its own answers the setup question
+    if let (true, Ok(number)) = (setting_up, message.trim().parse::<usize>()) {
+        if number > 0 {
+            answer(&joined, context, number - 1).await;
+            return;
+        }
+    }
+
+    if matrix::get_command("setup", message) == Some("") {
+        ask(&joined, context, Step::Persona).await;
+        return;
+    }
+
+    if matrix::is_admin(&sender) {
+        if matrix::get_command("context show", message).is_some() {
+            show_context(&joined, context).await;
+            return;
+        }
+
+        if let Some(command) = matrix::get_command("context drop", message) {
+            drop_context(&joined, context, command).await;
+            return;
+        }
+
+        if let Some(prompt) = matrix::find_command(
+            vec!["sherman, set persona", "sherman set persona", "set persona"],
+            message,
+        ) {
+            set_persona(&joined, context, Some(prompt)).await;
+            return;
+        }
+
+        if matrix::find_command(
+            vec![
+                "sherman, reset persona",
+                "sherman reset persona",
+                "reset persona",
+            ],
+            message,
+        ) == Some("")
+        {
+            set_persona(&joined, context, None).await;
+            return;
+        }
+
+        let result = if let Some(command) = matrix::get_command("unschedule", message) {
+            Some(on_unschedule_message(&joined, command).await)
+        } else if let Some(command) = matrix::get_command("schedule", message) {
+            Some(on_schedule_message(&joined, command).await)
+        } else {
+            None
+        };
+
+        if let Some(result) = result {
+            if let Err(e) = result {
+                matrix::send(&joined, matrix::text_plain(&e.to_string()))
+                    .await
+                    .unwrap();
+            }
+
+            return;
+        }
+    }
+
+    if matrix::get_command("schedules", message) == Some("") {
+        if let Err(e) = on_schedules_message(&joined).await {
+            println!("Could not list schedules for {}: {}", joined.room_id(), e);
+        }
+
+        return;
+    }
+
+    // a single word, so we don't catch a conversation about languages
+    if let Some(language) = matrix::get_command("language", message) {
+        if !language.is_empty() && !language.contains(' ') {
+            set_language(&joined, context, language).await;
+            return;
+        }
+    }
+
+    if let Some(setting) = matrix::get_command("voice", message) {
+        match setting.to_lowercase().as_str() {
+            "on" => {
+                set_voice(&joined, context, true).await;
+                return;
+            }
+            "off" => {
+                set_voice(&joined, context, false).await;
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    let language = room_language(context, joined.room_id());
+
     if let Some(prompt) = matrix::find_command(
         vec!["show me", "sherman, show me", "sherman show me"],
         message,
     ) {
-        joined
-            .send(matrix::text_plain("Let's see..."), None)
-            .await
-            .unwrap();
+        matrix::send(
+            &joined,
+            matrix::text_plain(&i18n::translate("Let's see...", language.as_deref())),
+        )
+        .await
+        .unwrap();
+
+        announce_wait(&joined, language.as_deref()).await;
 
         let image = match ai::generate_image(prompt).await {
             Ok(image) => image,
             Err(e) => {
                 println!("Error creating image: {}", e);
 
-                joined
-                    .send(matrix::text_plain("Oh no! I couldn't do it. :("), None)
+                let message = i18n::translate("Oh no! I couldn't do it. :(", language.as_deref());
+
+                matrix::send_sticker(&joined, "sad", &message)
                     .await
                     .unwrap();
 
@@ -56,51 +727,1839 @@ async fn handle_message(joined: Joined, message: &str) {
             .await
             .unwrap();
     } else if let Some(prompt) = matrix::find_command(vec!["sherman,", "sherman"], message) {
-        let response = match ai::chat(prompt).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                println!("Error with chat: {}", e);
+        respond_or_modify(&joined, context, prompt).await;
+    } else if answers_everything(&joined, context).await {
+        // we won't get involved if the conversation is about us
+        if !private_room && message.to_lowercase().contains("sherman") {
+            return;
+        }
 
-                joined
-                    .send(matrix::text_plain("I have no words. :("), None)
-                    .await
-                    .unwrap();
+        respond_or_modify(&joined, context, message).await;
+    }
+}
 
-                return;
-            }
-        };
+async fn respond_or_modify(joined: &Joined, context: &Context, prompt: &str) {
+    if let Some(command) = matrix::get_command("for the next", prompt) {
+        set_modifier(joined, context, command).await;
+    } else if let Some((count, prompt)) = parse_options(prompt) {
+        respond_with_options(joined, context, prompt, count).await;
+    } else if let Some(question) = matrix::get_command("search", prompt) {
+        search(joined, context, question).await;
+    } else {
+        respond(joined, context, prompt).await;
+    }
+}
 
-        joined
-            .send(matrix::text_plain(&response), None)
+async fn set_modifier(joined: &Joined, context: &Context, command: &str) {
+    // far enough out to not be a time at all is as good as no time
+    let parsed = parse_modifier(command).and_then(|(duration, prompt)| {
+        let expires = Utc::now().checked_add_signed(chrono::Duration::from_std(duration).ok()?)?;
+        Some((expires, prompt))
+    });
+
+    let (expires, prompt) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            matrix::send(
+                joined,
+                matrix::text_plain("Usage: for the next [number] [minutes/hours] [prompt]"),
+            )
             .await
             .unwrap();
-
-        return;
-    } else if joined.display_name().await.unwrap_or("".to_string()) == "AI Chat" || private_room {
-        // we won't get involved if the conversation is about us
-        if !private_room && message.to_lowercase().contains("sherman") {
             return;
         }
+    };
 
-        let response = match ai::chat(message).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                println!("Error with chat: {}", e);
+    let modifier = Modifier {
+        prompt: prompt.to_string(),
+        expires,
+    };
 
-                joined
-                    .send(matrix::text_plain("I have no words. :("), None)
-                    .await
-                    .unwrap();
+    // it still works until a restart if it can't be saved
+    if let Err(e) = save_modifier(joined.room_id(), &modifier) {
+        println!("Could not save modifier! {}", e);
+    }
 
-                return;
-            }
+    context
+        .lock()
+        .unwrap()
+        .entry(joined.room_id().clone())
+        .or_default()
+        .modifier = Some(modifier);
+
+    let language = room_language(context, joined.room_id());
+
+    matrix::send_sticker(
+        joined,
+        "ok",
+        &i18n::translate("You got it!", language.as_deref()),
+    )
+    .await
+    .unwrap();
+}
+
+// Clears modifiers once they've run out, and lets their rooms know. A newer modifier replaces the
+// old one's row, so only the latest in a room is ever cleared.
+async fn expire_modifiers(client: &Client, context: &Context) -> anyhow::Result<()> {
+    let now = Utc::now();
+
+    for room_id in take_expired_modifiers(&now)? {
+        let room_id = match RoomId::try_from(room_id.as_str()) {
+            Ok(room_id) => room_id,
+            Err(_) => continue,
         };
 
-        joined
-            .send(matrix::text_plain(&response), None)
-            .await
-            .unwrap();
+        if let Some(room) = context.lock().unwrap().get_mut(&room_id) {
+            if matches!(&room.modifier, Some(modifier) if modifier.expires <= now) {
+                room.modifier = None;
+            }
+        }
 
-        return;
+        if let Some(joined) = client.get_joined_room(&room_id) {
+            let language = room_language(context, &room_id);
+            let message = i18n::translate("Okay, back to normal.", language.as_deref());
+
+            if let Err(e) = matrix::send(&joined, matrix::text_plain(&message)).await {
+                println!("Could not say a modifier is over! {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// parses "hour act like a pirate" or "30 minutes talk like a robot"
+fn parse_modifier(command: &str) -> Option<(Duration, &str)> {
+    let mut parts = command.splitn(2, ' ');
+    let first = parts.next()?;
+
+    let (count, rest) = match first.parse::<u64>() {
+        Ok(n) => (n, parts.next()?),
+        Err(_) => (1, command),
+    };
+
+    let mut parts = rest.splitn(2, ' ');
+    let unit = parts.next()?.to_lowercase();
+    let prompt = parts.next()?.trim_start_matches([',', ':']).trim();
+
+    let seconds = if unit.starts_with("minute") {
+        60
+    } else if unit.starts_with("hour") {
+        60 * 60
+    } else if unit.starts_with("day") {
+        60 * 60 * 24
+    } else {
+        return None;
+    };
+
+    if prompt.is_empty() || count == 0 {
+        return None;
+    }
+
+    Some((Duration::from_secs(count * seconds), prompt))
+}
+
+async fn respond(joined: &Joined, context: &Context, prompt: &str) {
+    let room_id = joined.room_id().clone();
+    let prompt = ai::Message::user(prompt);
+
+    let room = context
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut messages: Vec<ai::Message> = room.system_prompt().into_iter().collect();
+    messages.extend(room.history());
+    messages.push(prompt.clone());
+
+    announce_wait(joined, room.language.as_deref()).await;
+
+    let model = room.settings.model.as_deref();
+
+    // a voice assistant would read out every edit, so voice rooms get the answer all at once
+    let streamed = !room.voice();
+
+    let response = if streamed {
+        stream_response(joined, &messages, model).await
+    } else {
+        ai::chat_with_context(&messages, model).await
+    };
+
+    let response = match response {
+        Ok(resp) => resp,
+        Err(e) => {
+            println!("Error with chat: {}", e);
+
+            let message = i18n::translate("I have no words. :(", room.language.as_deref());
+
+            matrix::send(joined, matrix::text_plain(&message))
+                .await
+                .unwrap();
+
+            return;
+        }
+    };
+
+    // the prompt asks nicely, but this makes sure
+    let response = if room.voice() {
+        speakable(&response)
+    } else {
+        response
+    };
+
+    // only remember the exchange once we have both halves of it
+    remember(context, room_id, prompt, &response);
+
+    if !streamed {
+        matrix::send(joined, matrix::text_plain(&response))
+            .await
+            .unwrap();
+    }
+}
+
+// Shows an answer as it's written: the first of it goes out as soon as there is any, and that
+// message is edited (every EDIT_INTERVAL at most) as more comes in, until it's all there.
+async fn stream_response(
+    joined: &Joined,
+    messages: &[ai::Message],
+    model: Option<&str>,
+) -> anyhow::Result<String> {
+    let (progress, mut written) = watch::channel(String::new());
+
+    let editor = tokio::spawn({
+        let joined = joined.clone();
+
+        async move {
+            let mut event_id: Option<EventId> = None;
+            let mut shown = String::new();
+
+            // this ends once the answer is done and nothing more is coming
+            while written.changed().await.is_ok() {
+                shown = written.borrow_and_update().clone();
+
+                let result = match &event_id {
+                    Some(event_id) => matrix::edit(&joined, event_id, &shown).await,
+                    None => matrix::send(&joined, matrix::text_plain(&shown))
+                        .await
+                        .map(|response| event_id = Some(response.event_id)),
+                };
+
+                if let Err(e) = result {
+                    println!("Could not show answer so far: {}", e);
+                }
+
+                tokio::time::sleep(EDIT_INTERVAL).await;
+            }
+
+            (event_id, shown)
+        }
+    });
+
+    let response = ai::chat_streaming(messages, model, progress).await;
+    let (event_id, shown) = editor.await?;
+    let response = response?;
+
+    if shown != response {
+        // an answer too long for one message can't be an edit, so it goes out again in full
+        let edited = match &event_id {
+            Some(event_id) => matrix::edit(joined, event_id, &response).await.is_ok(),
+            None => false,
+        };
+
+        if !edited {
+            matrix::send(joined, matrix::text_plain(&response)).await?;
+        }
+    }
+
+    Ok(response)
+}
+
+// Like respond, but with a photo for the model to look at. Only the question is remembered, since
+// the photo won't be around for the next one.
+async fn respond_to_photo(joined: &Joined, context: &Context, jpeg: &[u8], question: &str) {
+    let room_id = joined.room_id().clone();
+    let prompt = ai::Message::user(question);
+
+    let room = context
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut messages: Vec<ai::Message> = room.system_prompt().into_iter().collect();
+    messages.extend(room.history());
+    messages.push(prompt.clone());
+
+    announce_wait(joined, room.language.as_deref()).await;
+
+    let model = room.settings.model.as_deref();
+
+    let response = match ai::ask_about_photo(&messages, jpeg, model).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            println!("Error asking about photo: {}", e);
+
+            let message = i18n::translate("I have no words. :(", room.language.as_deref());
+
+            matrix::send(joined, matrix::text_plain(&message))
+                .await
+                .unwrap();
+
+            return;
+        }
+    };
+
+    let response = if room.voice() {
+        speakable(&response)
+    } else {
+        response
+    };
+
+    remember(context, room_id, prompt, &response);
+
+    matrix::send(joined, matrix::text_plain(&response))
+        .await
+        .unwrap();
+}
+
+// Answers from a web search, with footnotes for the pages it cites. Citations that don't point at
+// something the search actually found are dropped.
+async fn search(joined: &Joined, context: &Context, prompt: &str) {
+    let room_id = joined.room_id().clone();
+    let prompt = ai::Message::user(prompt);
+
+    let room = context
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut messages: Vec<ai::Message> = room.system_prompt().into_iter().collect();
+    messages.push(ai::Message::system(CITATION_PROMPT));
+    messages.extend(room.history());
+    messages.push(prompt.clone());
+
+    announce_wait(joined, room.language.as_deref()).await;
+
+    let (response, sources) = match ai::search(&messages).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            println!("Error with search: {}", e);
+
+            let message = i18n::translate("I have no words. :(", room.language.as_deref());
+
+            matrix::send(joined, matrix::text_plain(&message))
+                .await
+                .unwrap();
+
+            return;
+        }
+    };
+
+    // footnotes don't mean much read aloud
+    if room.voice() {
+        let response = speakable(&cite(&response, &[]).0);
+        remember(context, room_id, prompt, &response);
+
+        matrix::send(joined, matrix::text_plain(&response))
+            .await
+            .unwrap();
+
+        return;
+    }
+
+    let (body, footnotes) = cite(&response, &sources);
+
+    let mut plain = body.clone();
+    let mut html = matrix::escape_html(&body).replace('\n', "<br>");
+
+    for (index, _) in footnotes.iter().enumerate() {
+        let marker = format!("[{}]", index + 1);
+        html = html.replace(&marker, &format!("<sup>{}</sup>", marker));
+    }
+
+    if !footnotes.is_empty() {
+        plain.push('\n');
+        html.push_str("<ol>");
+
+        for (index, source) in footnotes.iter().enumerate() {
+            let title = source_title(source);
+
+            plain.push_str(&format!("\n[{}] {}: {}", index + 1, title, source.url));
+            html.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>",
+                matrix::escape_html(&source.url),
+                matrix::escape_html(&title)
+            ));
+        }
+
+        html.push_str("</ol>");
+    }
+
+    remember(context, room_id, prompt, &plain);
+
+    matrix::send(joined, matrix::text_html(&plain, &html))
+        .await
+        .unwrap();
+}
+
+// Takes the model's list of sources off the end of a response, and renumbers its markers to match
+// the ones that point at a page the search found, in the order they're cited. Any other markers
+// are dropped. Returns the response and the sources, in footnote order.
+fn cite(response: &str, sources: &[ai::Source]) -> (String, Vec<ai::Source>) {
+    let mut listed: HashMap<String, ai::Source> = HashMap::new();
+    let mut lines = vec![];
+
+    for line in response.lines() {
+        let trimmed = line.trim().trim_start_matches(['-', '*', ' ']);
+
+        let heading = trimmed
+            .trim_matches(|c: char| c == '*' || c == '#' || c == ':' || c == ' ')
+            .to_lowercase();
+
+        if heading == "sources" || heading == "references" {
+            continue;
+        }
+
+        match source_line(trimmed) {
+            Some((number, url)) => {
+                let found = sources
+                    .iter()
+                    .find(|source| normalize_url(&source.url) == normalize_url(url));
+
+                if let Some(found) = found {
+                    listed.insert(number.to_string(), found.clone());
+                }
+            }
+            None => lines.push(line),
+        }
+    }
+
+    let body = lines.join("\n");
+    let mut cited: Vec<ai::Source> = vec![];
+    let mut text = String::new();
+    let mut rest = body.as_str();
+
+    while let Some(start) = rest.find('[') {
+        let marker = rest[start + 1..]
+            .find(']')
+            .map(|end| &rest[start + 1..start + 1 + end])
+            .filter(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+
+        let number = match marker {
+            // a link's text isn't a citation
+            Some(number) if !rest[start + number.len() + 2..].starts_with('(') => number,
+            _ => {
+                text.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+
+        text.push_str(&rest[..start]);
+        rest = &rest[start + number.len() + 2..];
+
+        match listed.get(number) {
+            Some(source) => {
+                let index = match cited.iter().position(|s| s == source) {
+                    Some(index) => index,
+                    None => {
+                        cited.push(source.clone());
+                        cited.len() - 1
+                    }
+                };
+
+                text.push_str(&format!("[{}]", index + 1));
+            }
+            None if text.ends_with(' ') => {
+                text.pop();
+            }
+            None => {}
+        }
+    }
+
+    text.push_str(rest);
+
+    (text.trim().to_string(), cited)
+}
+
+// a line like "[1] https://example.com", or "[2] [A page](https://example.com)"
+fn source_line(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let (number, rest) = rest.split_once(']')?;
+
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let url = &rest[rest.find("http")?..];
+    let end = url
+        .find(|c: char| c.is_whitespace() || c == ')' || c == '>')
+        .unwrap_or(url.len());
+
+    Some((number, &url[..end]))
+}
+
+// close enough to tell whether two links go to the same page, whatever tracking is tacked on
+fn normalize_url(url: &str) -> String {
+    let url = url.split('#').next().unwrap_or(url).to_lowercase();
+
+    let (page, query) = match url.split_once('?') {
+        Some((page, query)) => (page.to_string(), query.to_string()),
+        None => (url, String::new()),
+    };
+
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("utm_"))
+        .collect();
+
+    let page = page.trim_end_matches('/');
+
+    if query.is_empty() {
+        page.to_string()
+    } else {
+        format!("{}?{}", page, query.join("&"))
+    }
+}
+
+// the page's title, or just where it is, if the search didn't give one
+fn source_title(source: &ai::Source) -> String {
+    if !source.title.trim().is_empty() {
+        return source.title.trim().to_string();
+    }
+
+    source
+        .url
+        .split("://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or(&source.url)
+        .to_string()
+}
+
+// lets the room know when there are other prompts in line ahead of theirs
+async fn announce_wait(joined: &Joined, language: Option<&str>) {
+    let message = match ai::ahead() {
+        None => return,
+        Some(0) => i18n::translate("Thinking... you're next.", language),
+        Some(count) => i18n::format(
+            "Thinking... {count} ahead of you.",
+            language,
+            &[("count", count.to_string().as_str())],
+        ),
+    };
+
+    matrix::send(joined, matrix::text_plain(&message))
+        .await
+        .unwrap();
+}
+
+fn remember(context: &Context, room_id: RoomId, prompt: ai::Message, response: &str) {
+    let mut exchange = [
+        Remembered::new(prompt),
+        Remembered::new(ai::Message::assistant(response)),
+    ];
+
+    // it's still worth remembering until a restart if it can't be saved
+    if let Err(e) = save_context(&room_id, &mut exchange) {
+        println!("Could not save context for {}: {}", room_id, e);
+    }
+
+    let mut all = context.lock().unwrap();
+    let room = all.entry(room_id).or_default();
+    room.forget_expired();
+
+    let messages = &mut room.messages;
+    messages.extend(exchange);
+
+    if messages.len() > MAX_CONTEXT {
+        let excess = messages.len() - MAX_CONTEXT;
+        messages.drain(..excess);
+    }
+}
+
+// parses "give me 3 options for a team name"
+fn parse_options(prompt: &str) -> Option<(usize, &str)> {
+    let command = matrix::get_command("give me", prompt)?;
+    let mut parts = command.splitn(3, ' ');
+
+    let count = parts.next()?.parse::<usize>().ok()?;
+
+    if !parts.next()?.to_lowercase().starts_with("option") {
+        return None;
+    }
+
+    let rest = parts.next()?;
+    let rest = matrix::find_command(vec!["for", "on", "of"], rest).unwrap_or(rest);
+
+    if count < 2 || rest.is_empty() {
+        return None;
+    }
+
+    Some((count.min(OPTION_KEYS.len()), rest))
+}
+
+// asks for several completions at once and lists them; nothing goes into the context until
+// someone picks one
+async fn respond_with_options(joined: &Joined, context: &Context, prompt: &str, count: usize) {
+    let room = context
+        .lock()
+        .unwrap()
+        .get(joined.room_id())
+        .cloned()
+        .unwrap_or_default();
+
+    let mut messages: Vec<ai::Message> = room.system_prompt().into_iter().collect();
+    messages.extend(room.history());
+    messages.push(ai::Message::user(prompt));
+
+    announce_wait(joined, room.language.as_deref()).await;
+
+    let choices = match ai::chat_choices(&messages, count, room.settings.model.as_deref()).await {
+        Ok(choices) => choices,
+        Err(e) => {
+            println!("Error with chat: {}", e);
+
+            let message = i18n::translate("I have no words. :(", room.language.as_deref());
+
+            matrix::send(joined, matrix::text_plain(&message))
+                .await
+                .unwrap();
+
+            return;
+        }
+    };
+
+    let text: Vec<String> = choices
+        .iter()
+        .enumerate()
+        .map(|(i, choice)| format!("{}. {}", i + 1, choice))
+        .collect();
+
+    let html: Vec<String> = choices
+        .iter()
+        .map(|choice| {
+            format!(
+                "<li>{}</li>",
+                matrix::escape_html(choice).replace('\n', "<br>")
+            )
+        })
+        .collect();
+
+    let response = matrix::send(
+        joined,
+        matrix::text_html(
+            &text.join("\n\n"),
+            &format!("<ol>\n{}\n</ol>", html.join("\n")),
+        ),
+    )
+    .await
+    .unwrap();
+
+    let event_id = response.event_id.to_string();
+
+    context
+        .lock()
+        .unwrap()
+        .entry(joined.room_id().clone())
+        .or_default()
+        .options = Some(Options {
+        event_id: event_id.clone(),
+        prompt: prompt.to_string(),
+        choices: choices.clone(),
+    });
+
+    // seed the reactions so picking is a single tap
+    for key in OPTION_KEYS.iter().take(choices.len()) {
+        if let Err(e) = matrix::react(joined, &event_id, key).await {
+            println!("Could not react to options: {}", e);
+        }
+    }
+}
+
+async fn on_reaction(
+    event: SyncMessageEvent<ReactionEventContent>,
+    room: Room,
+    client: Client,
+    context: Context,
+) {
+    let joined = match room {
+        Room::Joined(joined) => joined,
+        _ => return,
+    };
+
+    // ignore the reactions we seeded ourselves
+    if client.user_id().await.as_ref() == Some(&event.sender) {
+        return;
+    }
+
+    let relation = &event.content.relates_to;
+
+    let index = match OPTION_KEYS.iter().position(|key| *key == relation.emoji) {
+        Some(index) => index,
+        None => return,
+    };
+
+    let setup = context
+        .lock()
+        .unwrap()
+        .get(joined.room_id())
+        .and_then(|room| room.setup.clone());
+
+    if let Some(setup) = setup {
+        if setup.event_id == relation.event_id.as_str() {
+            answer(&joined, &context, index).await;
+            return;
+        }
+    }
+
+    let picked = {
+        let mut all = context.lock().unwrap();
+        let room = all.entry(joined.room_id().clone()).or_default();
+
+        match &room.options {
+            Some(options)
+                if options.event_id == relation.event_id.as_str()
+                    && index < options.choices.len() =>
+            {
+                let options = room.options.take().unwrap();
+                Some((options.prompt, options.choices[index].clone()))
+            }
+            _ => None,
+        }
+    };
+
+    let (prompt, choice) = match picked {
+        Some(picked) => picked,
+        None => return,
+    };
+
+    remember(
+        &context,
+        joined.room_id().clone(),
+        ai::Message::user(&prompt),
+        &choice,
+    );
+
+    matrix::send(
+        &joined,
+        matrix::text_plain(&format!("Going with number {}.", index + 1)),
+    )
+    .await
+    .unwrap();
+}
+
+// Asks one of the setup questions, with numbers to reply with and reactions to tap. Each answer is
+// saved as it comes, so a setup someone wanders off from keeps what was picked.
+async fn ask(joined: &Joined, context: &Context, step: Step) {
+    let choices = step.choices();
+
+    let text: Vec<String> = choices
+        .iter()
+        .enumerate()
+        .map(|(i, choice)| format!("{}. {}", i + 1, choice))
+        .collect();
+
+    let html: Vec<String> = choices
+        .iter()
+        .map(|choice| format!("<li>{}</li>", matrix::escape_html(choice)))
+        .collect();
+
+    let hint = "Reply with a number, or react.";
+
+    let response = matrix::send(
+        joined,
+        matrix::text_html(
+            &format!("{}\n{}\n{}", step.question(), text.join("\n"), hint),
+            &format!(
+                "{}\n<ol>\n{}\n</ol>\n{}",
+                step.question(),
+                html.join("\n"),
+                hint
+            ),
+        ),
+    )
+    .await;
+
+    let event_id = match response {
+        Ok(response) => response.event_id.to_string(),
+        Err(e) => {
+            println!("Could not ask a setup question: {}", e);
+            return;
+        }
+    };
+
+    context
+        .lock()
+        .unwrap()
+        .entry(joined.room_id().clone())
+        .or_default()
+        .setup = Some(Setup {
+        event_id: event_id.clone(),
+        step,
+    });
+
+    for key in OPTION_KEYS.iter().take(choices.len()) {
+        if let Err(e) = matrix::react(joined, &event_id, key).await {
+            println!("Could not react to a setup question: {}", e);
+        }
+    }
+}
+
+async fn answer(joined: &Joined, context: &Context, index: usize) {
+    let answered = {
+        let mut all = context.lock().unwrap();
+        let room = all.entry(joined.room_id().clone()).or_default();
+
+        match &room.setup {
+            Some(setup) if index < setup.step.choices().len() => {
+                let step = room.setup.take().unwrap().step;
+                step.apply(&mut room.settings, index);
+                Some((step, room.settings.clone()))
+            }
+            _ => None,
+        }
+    };
+
+    let (step, settings) = match answered {
+        Some(answered) => answered,
+        None => return,
+    };
+
+    if let Err(e) = save_settings(joined.room_id(), &settings) {
+        println!("Could not save settings for {}: {}", joined.room_id(), e);
+    }
+
+    if let Some(next) = step.next() {
+        ask(joined, context, next).await;
+        return;
+    }
+
+    matrix::send(joined, matrix::text_plain(&summary(&settings)))
+        .await
+        .unwrap();
+}
+
+fn summary(settings: &Settings) -> String {
+    let persona = match settings.persona.as_deref() {
+        _ if settings.custom_persona.is_some() => "playing the part I was given".to_string(),
+        Some(persona) if persona != PERSONAS[0].0 => persona.to_lowercase(),
+        _ => "just me".to_string(),
+    };
+
+    let answering = match settings.always_on {
+        Some(false) => "only when someone says \"Sherman\"",
+        _ => "whenever anyone says anything",
+    };
+
+    let kid_safe = match settings.kid_safe {
+        true => ", and I'll keep it kid-safe",
+        false => "",
+    };
+
+    format!(
+        "All set! Here I'm {}, using {}, and I'll answer {}{}. Say \"setup\" to change any of it.",
+        persona,
+        settings.model.clone().unwrap_or_else(ai::default_model),
+        answering,
+        kid_safe
+    )
+}
+
+fn load_settings(room_id: &RoomId) -> anyhow::Result<Option<Settings>> {
+    let conn = open_db()?;
+
+    let settings = conn
+        .query_row(
+            "
+            SELECT persona, model, always_on, kid_safe, custom_persona
+            FROM room_settings
+            WHERE room_id = ?1",
+            params![room_id.as_str()],
+            |row| {
+                Ok(Settings {
+                    persona: row.get(0)?,
+                    custom_persona: row.get(4)?,
+                    model: row.get(1)?,
+                    always_on: row.get(2)?,
+                    kid_safe: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(settings)
+}
+
+fn save_settings(room_id: &RoomId, settings: &Settings) -> anyhow::Result<()> {
+    let conn = open_db()?;
+
+    conn.execute(
+        "
+        INSERT OR REPLACE INTO room_settings
+            (room_id, persona, model, always_on, kid_safe, custom_persona)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            room_id.as_str(),
+            settings.persona,
+            settings.model,
+            settings.always_on,
+            settings.kid_safe,
+            settings.custom_persona
+        ],
+    )?;
+
+    Ok(())
+}
+
+// Every room's conversation, as far back as we keep. Anything older is cleared out first.
+fn load_context() -> anyhow::Result<HashMap<RoomId, RoomContext>> {
+    let conn = open_db()?;
+    let cutoff = (Utc::now() - context_retention()).to_rfc3339();
+
+    conn.execute(
+        "DELETE FROM context_messages WHERE created <= ?1",
+        params![cutoff],
+    )?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT id, room_id, role, content, created, tokens
+        FROM context_messages
+        ORDER BY id",
+    )?;
+
+    let rows = stmt.query_map([], |row| {
+        let room_id: String = row.get(1)?;
+        let created: String = row.get(4)?;
+
+        // we wrote it, so it'll parse, but there's no reason to fall over if it doesn't
+        let created = DateTime::parse_from_rfc3339(&created)
+            .map(|created| created.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+
+        let remembered = Remembered {
+            id: Some(row.get(0)?),
+            message: ai::Message {
+                role: row.get(2)?,
+                content: row.get(3)?,
+            },
+            created,
+            tokens: row.get(5)?,
+        };
+
+        Ok((room_id, remembered))
+    })?;
+
+    let mut rooms: HashMap<RoomId, RoomContext> = HashMap::new();
+
+    for row in rows {
+        let (room_id, remembered) = row?;
+
+        if let Ok(room_id) = RoomId::try_from(room_id.as_str()) {
+            rooms.entry(room_id).or_default().messages.push(remembered);
+        }
+    }
+
+    // and whatever modifiers haven't run out yet (the ones that have are cleared on schedule)
+    let mut stmt = conn.prepare("SELECT room_id, prompt, expires FROM modifiers")?;
+
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (room_id, prompt, expires) = row?;
+
+        if let (Ok(room_id), Ok(expires)) = (
+            RoomId::try_from(room_id.as_str()),
+            DateTime::parse_from_rfc3339(&expires),
+        ) {
+            rooms.entry(room_id).or_default().modifier = Some(Modifier {
+                prompt,
+                expires: expires.with_timezone(&Utc),
+            });
+        }
+    }
+
+    Ok(rooms)
+}
+
+fn save_modifier(room_id: &RoomId, modifier: &Modifier) -> anyhow::Result<()> {
+    let conn = open_db()?;
+
+    conn.execute(
+        "INSERT OR REPLACE INTO modifiers (room_id, prompt, expires) VALUES (?1, ?2, ?3)",
+        params![
+            room_id.as_str(),
+            modifier.prompt,
+            modifier.expires.to_rfc3339()
+        ],
+    )?;
+
+    Ok(())
+}
+
+// takes every modifier that's run out, returning their rooms
+fn take_expired_modifiers(now: &DateTime<Utc>) -> anyhow::Result<Vec<String>> {
+    let conn = open_db()?;
+    let now = now.to_rfc3339();
+
+    let room_ids = conn
+        .prepare("SELECT room_id FROM modifiers WHERE expires <= ?1")?
+        .query_map(params![now], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    conn.execute("DELETE FROM modifiers WHERE expires <= ?1", params![now])?;
+
+    Ok(room_ids)
+}
+
+// saves what was just said in a room, filling in the IDs, and trims what's kept of it to match
+fn save_context(room_id: &RoomId, messages: &mut [Remembered]) -> anyhow::Result<()> {
+    let conn = open_db()?;
+
+    for remembered in messages.iter_mut() {
+        conn.execute(
+            "
+            INSERT INTO context_messages
+                (room_id, role, content, created, tokens)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id.as_str(),
+                remembered.message.role,
+                remembered.message.content,
+                remembered.created.to_rfc3339(),
+                remembered.tokens
+            ],
+        )?;
+
+        remembered.id = Some(conn.last_insert_rowid());
+    }
+
+    conn.execute(
+        "
+        DELETE FROM context_messages
+        WHERE room_id = ?1 AND id NOT IN (
+            SELECT id FROM context_messages WHERE room_id = ?1 ORDER BY id DESC LIMIT ?2
+        )",
+        params![room_id.as_str(), MAX_CONTEXT],
+    )?;
+
+    let cutoff = (Utc::now() - context_retention()).to_rfc3339();
+
+    conn.execute(
+        "DELETE FROM context_messages WHERE created <= ?1",
+        params![cutoff],
+    )?;
+
+    Ok(())
+}
+
+fn forget(ids: &[i64]) -> anyhow::Result<()> {
+    let conn = open_db()?;
+
+    for id in ids {
+        conn.execute("DELETE FROM context_messages WHERE id = ?1", params![id])?;
+    }
+
+    Ok(())
+}
+
+fn open_db() -> anyhow::Result<Connection> {
+    let conn = storage::open("aibot")?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS room_settings (
+            room_id TEXT PRIMARY KEY,
+            persona TEXT,
+            model TEXT,
+            always_on INTEGER,
+            kid_safe INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // rooms could only pick from the personas, until parents could write their own
+    let has_custom_persona: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('room_settings') WHERE name = 'custom_persona'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if has_custom_persona == 0 {
+        conn.execute(
+            "ALTER TABLE room_settings ADD COLUMN custom_persona TEXT",
+            [],
+        )?;
+    }
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS scheduled_prompts (
+            id INTEGER PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            schedule TEXT NOT NULL,
+            prompt TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // a temporary modifier for each room that has one
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS modifiers (
+            room_id TEXT PRIMARY KEY,
+            prompt TEXT NOT NULL,
+            expires TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // the conversation in each room, oldest first
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS context_messages (
+            id INTEGER PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            role TEXT NOT NULL,
+            content TEXT NOT NULL,
+            created TEXT NOT NULL,
+            tokens INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(conn)
+}
+
+async fn set_voice(joined: &Joined, context: &Context, on: bool) {
+    context
+        .lock()
+        .unwrap()
+        .entry(joined.room_id().clone())
+        .or_default()
+        .voice = Some(on);
+
+    let response = if on {
+        "Okay, I'll keep it short and speakable in this room."
+    } else {
+        "Okay, I'll answer normally in this room."
+    };
+
+    matrix::send(joined, matrix::text_plain(response))
+        .await
+        .unwrap();
+}
+
+// Makes a response fit to be read aloud, whatever the model did with the prompt: no markdown, all
+// on one line, and no more than MAX_SPOKEN_SENTENCES sentences.
+fn speakable(response: &str) -> String {
+    let mut lines = vec![];
+
+    for line in response.lines() {
+        let line = line.trim();
+
+        // code fences go, but whatever was in them stays
+        if line.starts_with("```") {
+            continue;
+        }
+
+        // headings, quotes, and list markers
+        let line = line.trim_start_matches(['#', '>']).trim_start();
+        let line = line
+            .strip_prefix("- ")
+            .or_else(|| line.strip_prefix("* "))
+            .unwrap_or(line);
+        let line = match line.split_once(". ") {
+            Some((number, item)) if number.chars().all(|c| c.is_ascii_digit()) => item,
+            _ => line,
+        };
+
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+
+    let mut text = strip_links(&lines.join(" "));
+
+    for marker in ["**", "__", "*", "_", "`"] {
+        text = strip_paired(&text, marker);
+    }
+
+    let text = strip_emoji(&text);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    first_sentences(&text, MAX_SPOKEN_SENTENCES)
+}
+
+// Takes out emphasis (or inline code) marked with the given marker, but only where it comes in
+// pairs around something, so a lone asterisk in some math, or the underscores in a snake_case name,
+// are left alone.
+fn strip_paired(text: &str, marker: &str) -> String {
+    let underscore = marker.starts_with('_');
+    let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    let before = |i: usize| text[..i].chars().next_back();
+    let after = |i: usize| text[i + marker.len()..].chars().next();
+
+    let opens =
+        |i: usize| after(i).is_some_and(|c| !c.is_whitespace()) && !(underscore && word(before(i)));
+    let closes =
+        |i: usize| before(i).is_some_and(|c| !c.is_whitespace()) && !(underscore && word(after(i)));
+
+    let mut pairs = vec![];
+    let mut open = None;
+
+    for (i, _) in text.match_indices(marker) {
+        match open {
+            Some(start) if i > start + marker.len() && closes(i) => {
+                pairs.push((start, i));
+                open = None;
+            }
+            _ if opens(i) => open = Some(i),
+            _ => (),
+        }
+    }
+
+    let mut stripped = String::new();
+    let mut rest = 0;
+
+    for (start, end) in pairs {
+        stripped.push_str(&text[rest..start]);
+        stripped.push_str(&text[start + marker.len()..end]);
+        rest = end + marker.len();
+    }
+
+    stripped.push_str(&text[rest..]);
+    stripped
+}
+
+// Emoji only get read out by name, if at all. Punctuation that came after one goes back up against
+// the word before it.
+fn strip_emoji(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut removed = false;
+
+    for c in text.chars() {
+        if is_emoji(c) {
+            removed = true;
+            continue;
+        }
+
+        if removed && c.is_ascii_punctuation() {
+            stripped.truncate(stripped.trim_end().len());
+        }
+
+        removed = removed && c.is_whitespace();
+        stripped.push(c);
+    }
+
+    stripped
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        // pictographs, emoticons, transport, flags, and the rest of the supplemental symbols
+        0x1F000..=0x1FAFF
+            // weather, zodiac, dingbats, and the like
+            | 0x2600..=0x27BF
+            // stars and arrows that get drawn as emoji
+            | 0x2B00..=0x2BFF
+            // what glues emoji together or picks their style
+            | 0x200D | 0xFE0E | 0xFE0F | 0x20E3
+            // subdivision flag tags
+            | 0xE0020..=0xE007F
+    )
+}
+
+// turns [text](url) into just the text
+fn strip_links(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('[') {
+        let after = &rest[start + 1..];
+
+        let link = after.find("](").and_then(|middle| {
+            after[middle + 2..]
+                .find(')')
+                .map(|end| (middle, middle + 2 + end))
+        });
+
+        match link {
+            Some((middle, end)) => {
+                stripped.push_str(&rest[..start]);
+                stripped.push_str(&after[..middle]);
+                rest = &after[end + 1..];
+            }
+            None => {
+                stripped.push_str(&rest[..=start]);
+                rest = after;
+            }
+        }
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+fn first_sentences(text: &str, count: usize) -> String {
+    let mut found = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let ends = matches!(c, '.' | '!' | '?')
+            && chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+
+        if ends {
+            found += 1;
+
+            if found == count {
+                return text[..i + c.len_utf8()].to_string();
+            }
+        }
+    }
+
+    text.to_string()
+}
+
+async fn set_language(joined: &Joined, context: &Context, language: &str) {
+    let language = language.to_lowercase();
+
+    // english is what we'd do anyway
+    let (language, response) = match language.as_str() {
+        "english" | "off" | "reset" => (None, "Okay, back to English.".to_string()),
+        _ => {
+            let mut name = language.chars();
+            let name = match name.next() {
+                Some(first) => first.to_uppercase().collect::<String>() + name.as_str(),
+                None => return,
+            };
+
+            let response = format!("Okay, I'll speak {} in this room.", name);
+            (Some(name), response)
+        }
+    };
+
+    context
+        .lock()
+        .unwrap()
+        .entry(joined.room_id().clone())
+        .or_default()
+        .language = language;
+
+    matrix::send(joined, matrix::text_plain(&response))
+        .await
+        .unwrap();
+}
+
+// a prompt that's run on a schedule, with the answer posted to the room it was set up in
+struct ScheduledPrompt {
+    id: i64,
+    room_id: String,
+    schedule: Option<Recurrence>,
+    prompt: String,
+}
+
+// parses "every sunday at 5pm: suggest three dinner ideas for the week"
+fn parse_scheduled_prompt(command: &str) -> Option<(Recurrence, &str)> {
+    // with the space, so a time like "5:30" doesn't split it
+    let (schedule, prompt) = command.split_once(": ")?;
+    let prompt = prompt.trim();
+
+    if prompt.is_empty() {
+        return None;
+    }
+
+    Some((Recurrence::parse(schedule)?, prompt))
+}
+
+async fn on_schedule_message(joined: &Joined, command: &str) -> anyhow::Result<()> {
+    let (schedule, prompt) = match parse_scheduled_prompt(command) {
+        Some(parsed) => parsed,
+        None => anyhow::bail!("Usage: schedule [schedule]: [prompt]"),
+    };
+
+    open_db()?.execute(
+        "INSERT INTO scheduled_prompts (room_id, schedule, prompt) VALUES (?1, ?2, ?3)",
+        params![joined.room_id().as_str(), schedule.to_string(), prompt],
+    )?;
+
+    let message = format!("Okay, I'll answer that here {}.", schedule);
+    matrix::send(joined, matrix::text_plain(&message)).await?;
+
+    Ok(())
+}
+
+async fn on_unschedule_message(joined: &Joined, command: &str) -> anyhow::Result<()> {
+    let id = match command.trim_start_matches('#').parse::<i64>() {
+        Ok(id) => id,
+        Err(_) => anyhow::bail!("Usage: unschedule [number]"),
+    };
+
+    let deleted = open_db()?.execute(
+        "DELETE FROM scheduled_prompts WHERE id = ?1 AND room_id = ?2",
+        params![id, joined.room_id().as_str()],
+    )?;
+
+    if deleted == 0 {
+        anyhow::bail!("There's no scheduled prompt {} in this room.", id);
+    }
+
+    matrix::send(
+        joined,
+        matrix::text_plain(&format!("Unscheduled prompt {}.", id)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn on_schedules_message(joined: &Joined) -> anyhow::Result<()> {
+    let prompts: Vec<ScheduledPrompt> = scheduled_prompts()?
+        .into_iter()
+        .filter(|p| p.room_id == joined.room_id().as_str())
+        .collect();
+
+    let message = if prompts.is_empty() {
+        "There's nothing scheduled in this room.".to_string()
+    } else {
+        prompts
+            .iter()
+            .map(|p| {
+                let schedule = p
+                    .schedule
+                    .map(|s| s.to_string())
+                    .unwrap_or("never".to_string());
+
+                format!("{}. {}: {}", p.id, schedule, preview(&p.prompt))
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+
+    matrix::send(joined, matrix::text_plain(&message)).await?;
+
+    Ok(())
+}
+
+// Answers every prompt that's due this hour, just as if someone in the room had asked.
+async fn run_scheduled_prompts(client: &Client, context: &Context) {
+    let now = scheduler::now();
+
+    let prompts = match scheduled_prompts() {
+        Ok(prompts) => prompts,
+        Err(e) => {
+            println!("Could not load scheduled prompts! {}", e);
+            return;
+        }
+    };
+
+    for prompt in prompts
+        .iter()
+        .filter(|p| p.schedule.map(|s| s.matches(now)).unwrap_or(false))
+    {
+        let room = RoomId::try_from(prompt.room_id.as_str())
+            .ok()
+            .and_then(|id| client.get_joined_room(&id));
+
+        let joined = match room {
+            Some(joined) => joined,
+            None => {
+                println!("not in the room for scheduled prompt {}", prompt.id);
+                continue;
+            }
+        };
+
+        println!("running scheduled prompt {}", prompt.id);
+
+        load_room(client, &joined, context).await;
+        respond(&joined, context, &prompt.prompt).await;
+    }
+}
+
+fn scheduled_prompts() -> anyhow::Result<Vec<ScheduledPrompt>> {
+    let conn = open_db()?;
+    let mut stmt = conn.prepare("SELECT * FROM scheduled_prompts ORDER BY id")?;
+
+    let prompts = stmt
+        .query_map([], |row| {
+            let schedule: String = row.get("schedule")?;
+
+            Ok(ScheduledPrompt {
+                id: row.get("id")?,
+                room_id: row.get("room_id")?,
+                schedule: Recurrence::parse(&schedule),
+                prompt: row.get("prompt")?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(prompts)
+}
+
+// Gives the room a system prompt of its own, in place of whatever persona it picked in setup, or
+// takes it away again.
+async fn set_persona(joined: &Joined, context: &Context, prompt: Option<&str>) {
+    let prompt = prompt.map(str::trim);
+
+    if prompt == Some("") {
+        matrix::send(
+            joined,
+            matrix::text_plain("Usage: sherman, set persona [prompt]"),
+        )
+        .await
+        .unwrap();
+        return;
+    }
+
+    let settings = {
+        let mut all = context.lock().unwrap();
+        let room = all.entry(joined.room_id().clone()).or_default();
+        room.settings.custom_persona = prompt.map(|p| p.to_string());
+        room.settings.clone()
+    };
+
+    if let Err(e) = save_settings(joined.room_id(), &settings) {
+        println!("Could not save settings for {}: {}", joined.room_id(), e);
+    }
+
+    let response = match prompt {
+        Some(_) => "Got it. That's who I'll be in this room.",
+        None => "Okay, back to how I was set up.",
+    };
+
+    matrix::send(joined, matrix::text_plain(response))
+        .await
+        .unwrap();
+}
+
+fn room_language(context: &Context, room_id: &RoomId) -> Option<String> {
+    context
+        .lock()
+        .unwrap()
+        .get(room_id)
+        .and_then(|room| room.language.clone())
+}
+
+async fn show_context(joined: &Joined, context: &Context) {
+    let messages = context
+        .lock()
+        .unwrap()
+        .get_mut(joined.room_id())
+        .map(|room| {
+            room.forget_expired();
+            room.messages.clone()
+        })
+        .unwrap_or_default();
+
+    if messages.is_empty() {
+        matrix::send(
+            joined,
+            matrix::text_plain("There's no context for this room."),
+        )
+        .await
+        .unwrap();
+        return;
+    }
+
+    let mut lines: Vec<String> = messages
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            format!(
+                "{}. {}: {}",
+                i + 1,
+                m.message.role,
+                preview(&m.message.content)
+            )
+        })
+        .collect();
+
+    let tokens: usize = messages.iter().map(|m| m.tokens).sum();
+    lines.push(format!("About {} tokens in all.", tokens));
+
+    matrix::send(joined, matrix::text_plain(&lines.join("\n")))
+        .await
+        .unwrap();
+}
+
+async fn drop_context(joined: &Joined, context: &Context, command: &str) {
+    let indexes: Result<Vec<usize>, _> = command
+        .split([' ', ','])
+        .filter(|w| !w.trim().is_empty())
+        .map(|w| w.trim().parse::<usize>())
+        .collect();
+
+    let mut indexes = match indexes {
+        Ok(indexes) if !indexes.is_empty() => indexes,
+        _ => {
+            matrix::send(
+                joined,
+                matrix::text_plain("Usage: context drop [number]..."),
+            )
+            .await
+            .unwrap();
+            return;
+        }
+    };
+
+    // remove from the back so earlier removals don't shift later ones
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes.reverse();
+
+    let (response, dropped) = {
+        let mut all = context.lock().unwrap();
+        let room = all.entry(joined.room_id().clone()).or_default();
+        room.forget_expired();
+
+        let messages = &mut room.messages;
+        let mut dropped = vec![];
+
+        let response = if let Some(bad) = indexes.iter().find(|&&i| i == 0 || i > messages.len()) {
+            format!("There's no entry {} in the context.", bad)
+        } else {
+            for i in &indexes {
+                dropped.extend(messages.remove(i - 1).id);
+            }
+
+            let label = if indexes.len() == 1 {
+                "entry"
+            } else {
+                "entries"
+            };
+
+            format!("Dropped {} {} from the context.", indexes.len(), label)
+        };
+
+        (response, dropped)
+    };
+
+    if let Err(e) = forget(&dropped) {
+        println!("Could not drop context for {}: {}", joined.room_id(), e);
+    }
+
+    matrix::send(joined, matrix::text_plain(&response))
+        .await
+        .unwrap();
+}
+
+fn preview(content: &str) -> String {
+    let content = content.replace('\n', " ");
+
+    if content.chars().count() > PREVIEW_LENGTH {
+        let truncated: String = content.chars().take(PREVIEW_LENGTH).collect();
+        format!("{}...", truncated)
+    } else {
+        content
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scheduled_prompts() {
+        let (schedule, prompt) =
+            parse_scheduled_prompt("every sunday at 5pm: suggest three dinner ideas").unwrap();
+        assert_eq!(schedule.to_string(), "every sunday at 5pm");
+        assert_eq!(prompt, "suggest three dinner ideas");
+
+        // only the first colon with a space after it splits them
+        let (_, prompt) = parse_scheduled_prompt("weekdays at 7am: a quote: anything").unwrap();
+        assert_eq!(prompt, "a quote: anything");
+
+        assert!(parse_scheduled_prompt("every sunday at 5pm").is_none());
+        assert!(parse_scheduled_prompt("someday: nothing").is_none());
+        assert!(parse_scheduled_prompt("weekdays: ").is_none());
+    }
+
+    #[test]
+    fn forgets_old_context() {
+        let mut old = Remembered::new(ai::Message::user("what's for dinner?"));
+        old.created = Utc::now() - context_retention() - chrono::Duration::minutes(1);
+
+        let mut room = RoomContext {
+            messages: vec![old, Remembered::new(ai::Message::user("and dessert?"))],
+            ..Default::default()
+        };
+
+        let history = room.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "and dessert?");
+
+        room.forget_expired();
+        assert_eq!(room.messages.len(), 1);
+        assert_eq!(room.messages[0].tokens, 3);
+    }
+
+    #[test]
+    fn custom_personas_replace_picked_ones() {
+        let mut room = RoomContext::default();
+        room.settings.persona = Some("A storyteller".to_string());
+        room.settings.custom_persona = Some("You are a pirate.".to_string());
+
+        let prompt = room.system_prompt().unwrap().content;
+        assert!(prompt.contains("You are a pirate."));
+        assert!(!prompt.contains("storyteller"));
+
+        // picking one during setup again takes the custom one away
+        Step::Persona.apply(&mut room.settings, 2);
+        assert_eq!(room.settings.custom_persona, None);
+        assert!(room
+            .system_prompt()
+            .unwrap()
+            .content
+            .contains("storyteller"));
+    }
+
+    #[test]
+    fn walks_through_setup() {
+        let mut settings = Settings::default();
+        let mut steps = vec![];
+        let mut step = Some(Step::Persona);
+
+        while let Some(current) = step {
+            current.apply(&mut settings, 1);
+            steps.push(current);
+            step = current.next();
+        }
+
+        assert_eq!(
+            steps,
+            [Step::Persona, Step::Model, Step::Answering, Step::KidSafe]
+        );
+        assert_eq!(
+            settings,
+            Settings {
+                persona: Some("A patient teacher".to_string()),
+                custom_persona: None,
+                model: Some("gpt-4o-mini".to_string()),
+                always_on: Some(false),
+                kid_safe: false,
+            }
+        );
+    }
+
+    #[test]
+    fn speakable_strips_markdown() {
+        assert_eq!(
+            speakable("## Sure!\n\n- **Bold** and *italic* and `code`.\n- Another."),
+            "Sure! Bold and italic and code."
+        );
+        assert_eq!(
+            speakable("1. Preheat the __oven__.\n2. _Wait_.\n3. Eat."),
+            "Preheat the oven. Wait."
+        );
+        assert_eq!(speakable("```\nls -la\n```"), "ls -la");
+        assert_eq!(speakable("> quoted"), "quoted");
+    }
+
+    #[test]
+    fn speakable_keeps_what_isnt_emphasis() {
+        assert_eq!(
+            speakable("Set the my_var_name option."),
+            "Set the my_var_name option."
+        );
+        assert_eq!(speakable("It's 2 * 3, so 6."), "It's 2 * 3, so 6.");
+        assert_eq!(
+            speakable("One *unmatched marker."),
+            "One *unmatched marker."
+        );
+    }
+
+    #[test]
+    fn speakable_drops_emoji() {
+        assert_eq!(
+            speakable("Good night! 🌙✨ Sleep well 👨‍👩‍👧."),
+            "Good night! Sleep well."
+        );
+        assert_eq!(speakable("Nice ❤️ work ⭐"), "Nice work");
+    }
+
+    #[test]
+    fn speakable_is_short() {
+        assert_eq!(speakable("First. Second! Third? Fourth."), "First. Second!");
+    }
+
+    #[test]
+    fn strips_links() {
+        assert_eq!(
+            strip_links("See [the docs](https://example.com) or [this]."),
+            "See the docs or [this]."
+        );
+        assert_eq!(strip_links("[a](x) and [b](y)"), "a and b");
+        assert_eq!(strip_links("no links"), "no links");
+    }
+
+    fn source(url: &str) -> ai::Source {
+        ai::Source {
+            url: url.to_string(),
+            title: String::new(),
+        }
+    }
+
+    #[test]
+    fn cites_sources_from_the_search() {
+        let sources = vec![
+            source("https://a.com/page?utm_source=openai"),
+            source("https://b.com/"),
+        ];
+
+        let (body, cited) = cite(
+            "Cats sleep a lot [2]. They purr [1]. Dogs bark [3].\n\n\
+            **Sources:**\n[1] https://a.com/page\n[2] [B](https://b.com)\n\
+            [3] https://made-up.com",
+            &sources,
+        );
+
+        assert_eq!(body, "Cats sleep a lot [1]. They purr [2]. Dogs bark.");
+        assert_eq!(cited, vec![sources[1].clone(), sources[0].clone()]);
+    }
+
+    #[test]
+    fn cites_nothing_without_sources() {
+        let (body, cited) = cite("It's [sunny](https://x.com) [1].\n[1] https://x.com", &[]);
+
+        assert_eq!(body, "It's [sunny](https://x.com).");
+        assert!(cited.is_empty());
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        assert_eq!(
+            normalize_url("https://A.com/page/?utm_source=openai#top"),
+            "https://a.com/page"
+        );
+        assert_eq!(
+            normalize_url("https://a.com/?q=1&utm_medium=x"),
+            "https://a.com?q=1"
+        );
+    }
+
+    #[test]
+    fn finds_first_sentences() {
+        assert_eq!(first_sentences("One. Two. Three.", 2), "One. Two.");
+        assert_eq!(
+            first_sentences("It costs $1.50 today. Okay?", 1),
+            "It costs $1.50 today."
+        );
+        assert_eq!(first_sentences("No ending", 1), "No ending");
+        assert_eq!(first_sentences("Wow!", 2), "Wow!");
     }
 }
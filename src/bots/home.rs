@@ -1,16 +1,130 @@
-use matrix_sdk::room::Room;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::bail;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use matrix_sdk::room::{Joined, Room};
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
 use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
 use matrix_sdk::{Client, SyncSettings};
-use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task;
 
+use crate::config;
+use crate::image;
+use crate::listener;
 use crate::matrix;
+use crate::scheduler;
+use crate::scheduler::{Days, Recurrence};
+use crate::storage;
 use crate::webhook;
 
+const BROADCAST_COMMANDS: &[&str] = &["bc", "broadcast", "say"];
+
+// how often the sensors are checked for alerts, unless ALERT_POLL_MINUTES says otherwise
+const DEFAULT_ALERT_POLL_MINUTES: i64 = 5;
+
+// how many calls "webhook history" shows
+const WEBHOOK_HISTORY: usize = 20;
+
+// how many days "power" shows, unless it's told
+const DEFAULT_POWER_DAYS: i64 = 7;
+const MAX_POWER_DAYS: i64 = 31;
+
+// when the weekly energy report goes out, unless ENERGY_REPORT_SCHEDULE says otherwise
+const DEFAULT_ENERGY_REPORT_SCHEDULE: &str = "every monday at 8am";
+
+// how often a finished appliance nags until someone's taken care of it, unless
+// APPLIANCE_NAG_MINUTES says otherwise
+const DEFAULT_APPLIANCE_NAG_MINUTES: i64 = 30;
+
+// the reaction that says a finished appliance has been taken care of
+const DONE_KEY: &str = "✅";
+
+// where replies over the intercom go: wherever the last broadcast came from
+static LAST_BROADCAST_ROOM: Mutex<Option<RoomId>> = Mutex::new(None);
+
 pub async fn main() -> anyhow::Result<()> {
+    let nag_minutes = nag_minutes()?;
     let client = matrix::create_client("homebot").await?;
 
     client.register_event_handler(on_room_message).await;
+    client.register_event_handler(on_reaction).await;
+
+    let app = Router::new()
+        .route("/intercom", post(on_intercom))
+        .route("/appliance", post(on_appliance))
+        .route("/metrics", get(on_metrics))
+        .layer(Extension(client.clone()));
+
+    listener::serve(app)?;
+
+    // in test mode, webhook calls show up here instead of going off around the house
+    if let Ok(room_id) = env::var("WEBHOOK_ADMIN_ROOM") {
+        tokio::spawn(echo_webhooks(client.clone(), room_id));
+    }
+
+    // run any routines scheduled for this hour
+    scheduler::spawn("routines", scheduler::next_hour, {
+        let client = client.clone();
+
+        move || {
+            let client = client.clone();
+
+            async move {
+                run_scheduled_routines(&client).await;
+                Ok(())
+            }
+        }
+    });
+
+    // how much electricity the house went through last week, if there's somewhere to say so
+    if env::var("ENERGY_REPORT_ROOM").is_ok() {
+        let schedule = env::var("ENERGY_REPORT_SCHEDULE")
+            .unwrap_or(DEFAULT_ENERGY_REPORT_SCHEDULE.to_string());
+
+        scheduler::spawn("energy report", scheduler::every(&schedule)?, {
+            let client = client.clone();
+
+            move || {
+                let client = client.clone();
+
+                async move { send_energy_report(&client).await }
+            }
+        });
+    }
+
+    // check the sensors every so often for anything over (or under) an alert's threshold
+    scheduler::spawn("alerts", alert_poll, {
+        let client = client.clone();
+
+        move || {
+            let client = client.clone();
+
+            async move { check_alerts(&client).await }
+        }
+    });
+
+    // nag about any appliance that's been sitting finished
+    scheduler::spawn("appliances", appliance_poll, {
+        let client = client.clone();
+
+        move || {
+            let client = client.clone();
+
+            async move { nag_appliances(&client, nag_minutes).await }
+        }
+    });
 
     let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
     client.sync(settings).await;
@@ -19,8 +133,14 @@ pub async fn main() -> anyhow::Result<()> {
 }
 
 async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client) {
-    if let Some((joined, _, message)) = matrix::get_text_message(event, room, client).await {
-        handle_message(&message).await;
+    if !matrix::routes_to(&client, "home", room.room_id(), &event).await {
+        return;
+    }
+
+    if let Some((joined, sender, message)) =
+        matrix::get_text_message(event, room, client.clone()).await
+    {
+        handle_message(&client, &joined, &sender, &message).await;
 
         if message.to_lowercase().starts_with("in ") {
             let parts: Vec<&str> = message.split(' ').collect();
@@ -43,13 +163,12 @@ async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Roo
                 || unit.contains("month")
                 || unit.contains("year")
             {
-                joined
-                    .send(
-                        matrix::text_plain("Sorry, only minutes are supported right now"),
-                        None,
-                    )
-                    .await
-                    .unwrap();
+                matrix::send(
+                    &joined,
+                    matrix::text_plain("Sorry, only minutes are supported right now"),
+                )
+                .await
+                .unwrap();
                 return;
             }
 
@@ -65,22 +184,1358 @@ async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Roo
                 format!("See you in {} minutes!", minutes)
             };
 
-            joined
-                .send(matrix::text_plain(&response), None)
+            matrix::send(&joined, matrix::text_plain(&response))
                 .await
                 .unwrap();
             tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
-            handle_message(&command.join(" ")).await;
+            handle_message(&client, &joined, &sender, &command.join(" ")).await;
+        }
+    }
+}
+
+async fn handle_message(client: &Client, joined: &Joined, sender: &UserId, message: &str) {
+    if matrix::find_command(BROADCAST_COMMANDS.to_vec(), message).is_some() {
+        *LAST_BROADCAST_ROOM.lock().unwrap() = Some(joined.room_id().clone());
+    }
+
+    let result = if matrix::get_command("alerts", message).is_some() {
+        on_alerts_message(joined).await
+    } else if let Some(command) = matrix::get_command("alert", message) {
+        on_alert_message(joined, sender, command).await
+    } else if matrix::get_command("webhook history", message).is_some() {
+        on_webhook_history_message(joined, sender).await
+    } else if let Some(name) = matrix::get_command("webhook test", message) {
+        on_webhook_test_message(joined, sender, name).await
+    } else if let Some(command) = matrix::get_command("storage clean", message) {
+        on_storage_clean_message(joined, sender, command).await
+    } else if matrix::get_command("storage", message).is_some() {
+        on_storage_message(joined, sender).await
+    } else if matrix::get_command("routines", message).is_some() {
+        on_routines_message(joined).await
+    } else if let Some(command) = matrix::get_command("routine", message) {
+        on_routine_message(joined, sender, command).await
+    } else if let Some(command) = matrix::get_command("power", message) {
+        on_power_message(client, joined, command).await
+    } else {
+        run_command(message).await.map(|_| ())
+    };
+
+    if let Err(e) = result {
+        matrix::send(joined, matrix::text_plain(&e.to_string()))
+            .await
+            .unwrap();
+    }
+}
+
+// runs one of the basic home commands, returning whether the message was one
+async fn run_command(message: &str) -> anyhow::Result<bool> {
+    if let Some(command) = matrix::find_command(BROADCAST_COMMANDS.to_vec(), message) {
+        webhook::broadcast(command).await?;
+    } else if let Some(command) = matrix::find_command(vec!["n", "notify"], message) {
+        webhook::notify(command).await?;
+    } else if let Some(command) = matrix::get_command("trigger", message) {
+        let mut parts = command.splitn(2, ' ');
+        let name = parts.next().unwrap_or_default();
+        webhook::trigger(name, parts.next().unwrap_or_default()).await?;
+    } else {
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+// Speech-to-text from a Home Assistant voice satellite, something like
+// {"from": "kitchen", "text": "be right down"}.
+#[derive(Deserialize)]
+struct Intercom {
+    from: String,
+    text: String,
+}
+
+async fn on_intercom(
+    Extension(client): Extension<Client>,
+    headers: HeaderMap,
+    Json(intercom): Json<Intercom>,
+) -> StatusCode {
+    if !listener::authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    // fall back to INTERCOM_ROOM if nobody has broadcast anything since we started
+    let room_id = LAST_BROADCAST_ROOM.lock().unwrap().clone().or_else(|| {
+        env::var("INTERCOM_ROOM")
+            .ok()
+            .and_then(|id| RoomId::try_from(id.as_str()).ok())
+    });
+
+    let room = match room_id.and_then(|id| client.get_joined_room(&id)) {
+        Some(room) => room,
+        None => {
+            println!("no room for intercom reply from {}", intercom.from);
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    };
+
+    let mut from = intercom.from.chars();
+    let from = match from.next() {
+        Some(f) => f.to_uppercase().collect::<String>() + from.as_str(),
+        None => "Someone".to_string(),
+    };
+
+    let message = format!("{} says: {}", from, intercom.text.trim());
+
+    match matrix::send(&room, matrix::text_plain(&message)).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => {
+            println!("Could not post intercom reply! {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn on_metrics(headers: HeaderMap) -> (StatusCode, String) {
+    if !listener::authorized(&headers) {
+        return (StatusCode::UNAUTHORIZED, String::new());
+    }
+
+    match webhook::metrics() {
+        Ok(metrics) => (StatusCode::OK, metrics),
+        Err(e) => {
+            println!("Could not read webhook metrics! {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        }
+    }
+}
+
+// An appliance that's finished a cycle, from Home Assistant, like {"appliance": "washer"}. The
+// message defaults to something like "Washer finished".
+#[derive(Deserialize)]
+struct Finished {
+    appliance: String,
+    message: Option<String>,
+}
+
+// a finished cycle we've posted, waiting on someone to react that it's been dealt with
+struct Cycle {
+    event_id: String,
+    room_id: String,
+    appliance: String,
+    message: String,
+}
+
+impl Cycle {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Cycle> {
+        Ok(Cycle {
+            event_id: row.get("event_id")?,
+            room_id: row.get("room_id")?,
+            appliance: row.get("appliance")?,
+            message: row.get("message")?,
+        })
+    }
+}
+
+async fn on_appliance(
+    Extension(client): Extension<Client>,
+    headers: HeaderMap,
+    Json(finished): Json<Finished>,
+) -> StatusCode {
+    if !listener::authorized(&headers) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let room = env::var("APPLIANCE_ROOM")
+        .ok()
+        .and_then(|id| RoomId::try_from(id.as_str()).ok())
+        .and_then(|id| client.get_joined_room(&id));
+
+    let room = match room {
+        Some(room) => room,
+        None => {
+            println!("no room to say the {} finished", finished.appliance);
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+    };
+
+    match announce_appliance(&client, &room, &finished).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => {
+            println!(
+                "Could not post that the {} finished! {}",
+                finished.appliance, e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+// Posts that an appliance is done, pinned until someone reacts to say they've taken care of it.
+// A new cycle replaces the last one, if nobody got to it.
+async fn announce_appliance(
+    client: &Client,
+    room: &Joined,
+    finished: &Finished,
+) -> anyhow::Result<()> {
+    let appliance = finished.appliance.to_lowercase();
+
+    let message = match &finished.message {
+        Some(message) => message.clone(),
+        None => format!("{} finished", name_case(&appliance)),
+    };
+
+    for replaced in finish_cycles("appliance", &appliance)? {
+        unpin_cycle(client, &replaced).await;
+    }
+
+    let event_id = matrix::send(room, matrix::text_plain(&message))
+        .await?
+        .event_id;
+
+    open_db()?.execute(
+        "
+        INSERT INTO appliance_cycles
+            (event_id, room_id, appliance, message, nagged)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5)",
+        params![
+            event_id.as_str(),
+            room.room_id().as_str(),
+            appliance,
+            message,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+
+    matrix::react(room, event_id.as_str(), DONE_KEY).await?;
+
+    // we may not be allowed to pin anything here, which is no reason not to nag
+    if let Err(e) = matrix::pin(room, &event_id).await {
+        println!("Could not pin that the {} finished: {}", appliance, e);
+    }
+
+    Ok(())
+}
+
+async fn on_reaction(event: SyncMessageEvent<ReactionEventContent>, room: Room, client: Client) {
+    let joined = match room {
+        Room::Joined(joined) => joined,
+        _ => return,
+    };
+
+    // ignore the reaction we seeded ourselves
+    if client.user_id().await.as_ref() == Some(&event.sender) {
+        return;
+    }
+
+    let relation = &event.content.relates_to;
+
+    // some clients tack a variation selector onto the emoji
+    if relation.emoji.trim_end_matches('\u{fe0f}') != DONE_KEY {
+        return;
+    }
+
+    if let Err(e) = on_done(&client, &joined, relation.event_id.as_str()).await {
+        println!("Could not finish appliance cycle: {}", e);
+    }
+}
+
+// someone's emptied the washer (or whatever it was), so we can stop nagging and unpin it
+async fn on_done(client: &Client, joined: &Joined, event_id: &str) -> anyhow::Result<()> {
+    let cycle = match finish_cycles("event_id", event_id)?.pop() {
+        Some(cycle) => cycle,
+        None => return Ok(()),
+    };
+
+    unpin_cycle(client, &cycle).await;
+
+    matrix::send_thread_reply(joined, &cycle.event_id, "Thanks!").await?;
+    webhook::clear_appliance(&cycle.appliance).await?;
+
+    Ok(())
+}
+
+// marks every open cycle matching the column as done, returning them
+fn finish_cycles(column: &str, value: &str) -> anyhow::Result<Vec<Cycle>> {
+    let conn = open_db()?;
+
+    let cycles = conn
+        .prepare(&format!(
+            "SELECT * FROM appliance_cycles WHERE done = 0 AND {} = ?1",
+            column
+        ))?
+        .query_map(params![value], Cycle::from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for cycle in &cycles {
+        conn.execute(
+            "UPDATE appliance_cycles SET done = 1 WHERE event_id = ?1",
+            params![cycle.event_id],
+        )?;
+    }
+
+    Ok(cycles)
+}
+
+async fn unpin_cycle(client: &Client, cycle: &Cycle) {
+    let room = RoomId::try_from(cycle.room_id.as_str())
+        .ok()
+        .and_then(|id| client.get_joined_room(&id));
+
+    let event_id = match EventId::try_from(cycle.event_id.as_str()) {
+        Ok(event_id) => event_id,
+        Err(_) => return,
+    };
+
+    if let Some(room) = room {
+        if let Err(e) = matrix::unpin(&room, &event_id).await {
+            println!(
+                "Could not unpin that the {} finished: {}",
+                cycle.appliance, e
+            );
+        }
+    }
+}
+
+fn appliance_poll(now: DateTime<Tz>) -> DateTime<Tz> {
+    now + chrono::Duration::minutes(1)
+}
+
+// read once at startup, so a bad value stops us there rather than in every nag
+fn nag_minutes() -> anyhow::Result<i64> {
+    match env::var("APPLIANCE_NAG_MINUTES") {
+        Ok(minutes) => match minutes.parse() {
+            Ok(minutes) if minutes > 0 => Ok(minutes),
+            _ => bail!("APPLIANCE_NAG_MINUTES is not a positive integer"),
+        },
+        Err(_) => Ok(DEFAULT_APPLIANCE_NAG_MINUTES),
+    }
+}
+
+// Reminds the room about every appliance that's been waiting on someone for a while (`minutes`,
+// since the last reminder), in a thread off the first message.
+async fn nag_appliances(client: &Client, minutes: i64) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let since = (now - chrono::Duration::minutes(minutes)).to_rfc3339();
+
+    let conn = open_db()?;
+
+    let due = conn
+        .prepare("SELECT * FROM appliance_cycles WHERE done = 0 AND nagged <= ?1")?
+        .query_map(params![since], Cycle::from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for cycle in due {
+        conn.execute(
+            "UPDATE appliance_cycles SET nagged = ?1 WHERE event_id = ?2",
+            params![now.to_rfc3339(), cycle.event_id],
+        )?;
+
+        let room = RoomId::try_from(cycle.room_id.as_str())
+            .ok()
+            .and_then(|id| client.get_joined_room(&id));
+
+        if let Some(room) = room {
+            let message = format!(
+                "Still waiting: {}. React {} once it's done.",
+                cycle.message, DONE_KEY
+            );
+            matrix::send_thread_reply(&room, &cycle.event_id, &message).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// the last few webhook calls, so a flaky automation is easy to spot
+async fn on_webhook_history_message(joined: &Joined, sender: &UserId) -> anyhow::Result<()> {
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to see the webhook history.");
+    }
+
+    let calls = webhook::history(WEBHOOK_HISTORY)?;
+
+    if calls.is_empty() {
+        matrix::send(
+            joined,
+            matrix::text_plain("No webhooks have been called yet."),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = calls
+        .iter()
+        .map(|call| {
+            let date = chrono::DateTime::parse_from_rfc3339(&call.date)
+                .map(|date| {
+                    date.with_timezone(&scheduler::timezone())
+                        .format("%b %-d %-I:%M%P")
+                        .to_string()
+                })
+                .unwrap_or_else(|_| call.date.clone());
+
+            format!(
+                "{}: {} {} ({}ms)",
+                date,
+                call.name,
+                call.status,
+                call.latency.as_millis()
+            )
+        })
+        .collect();
+
+    matrix::send(joined, matrix::text_plain(&lines.join("\n"))).await?;
+
+    Ok(())
+}
+
+// a dry run of a webhook, to check it's set up without anyone hearing about it
+async fn on_webhook_test_message(
+    joined: &Joined,
+    sender: &UserId,
+    name: &str,
+) -> anyhow::Result<()> {
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to test webhooks.");
+    }
+
+    if name.is_empty() {
+        bail!("Which webhook?");
+    }
+
+    let echo = webhook::test(name)?;
+    matrix::send(joined, matrix::text_plain(&echo)).await?;
+
+    Ok(())
+}
+
+// Posts every webhook call that was skipped for WEBHOOK_TEST_MODE to WEBHOOK_ADMIN_ROOM. Calls
+// made by bots running in some other process never get here, but they're still in the history.
+async fn echo_webhooks(client: Client, room_id: String) {
+    let room_id = RoomId::try_from(room_id.as_str()).expect("WEBHOOK_ADMIN_ROOM is not a room ID");
+    let mut echoes = webhook::echoes();
+
+    loop {
+        let echo = match echoes.recv().await {
+            Ok(echo) => echo,
+            Err(RecvError::Lagged(missed)) => format!("(missed {} webhook calls)", missed),
+            Err(RecvError::Closed) => return,
+        };
+
+        let room = match client.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                println!("not in the webhook admin room, {}", room_id);
+                continue;
+            }
+        };
+
+        if let Err(e) = matrix::send(&room, matrix::text_plain(&echo)).await {
+            println!("Could not echo a webhook call! {}", e);
         }
     }
 }
 
-async fn handle_message(message: &str) {
-    if let Some(command) = matrix::find_command(vec!["bc", "broadcast", "say"], message) {
-        webhook::broadcast(command).await.unwrap()
+// how much each bot has on disk, and where
+async fn on_storage_message(joined: &Joined, sender: &UserId) -> anyhow::Result<()> {
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to see the storage.");
+    }
+
+    let mut lines = vec![format!("Everything's under {}.", storage::root().display())];
+
+    for (bot_name, areas) in storage::usage() {
+        let total: u64 = areas.iter().map(|(_, bytes)| bytes).sum();
+        let areas: Vec<String> = areas
+            .iter()
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(area, bytes)| format!("{} {}", area, storage::pretty_size(*bytes)))
+            .collect();
+
+        lines.push(format!(
+            "{}: {} ({})",
+            bot_name,
+            storage::pretty_size(total),
+            areas.join(", ")
+        ));
     }
 
-    if let Some(command) = matrix::find_command(vec!["n", "notify"], message) {
-        webhook::notify(command).await.unwrap()
+    matrix::send(joined, matrix::text_plain(&lines.join("\n"))).await?;
+
+    Ok(())
+}
+
+// clears out the media directories of one bot, or all of them
+async fn on_storage_clean_message(
+    joined: &Joined,
+    sender: &UserId,
+    command: &str,
+) -> anyhow::Result<()> {
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to clean up the storage.");
+    }
+
+    let bot_name = match command.trim().to_lowercase() {
+        name if name.is_empty() => None,
+        name if name.ends_with("bot") => Some(name),
+        name => Some(format!("{}bot", name)),
+    };
+
+    let freed = task::spawn_blocking(move || storage::clean(bot_name.as_deref())).await??;
+
+    let response = match freed {
+        0 => "There was nothing to clean up.".to_string(),
+        freed => format!("Cleaned up {}.", storage::pretty_size(freed)),
+    };
+
+    matrix::send(joined, matrix::text_plain(&response)).await?;
+
+    Ok(())
+}
+
+// A routine is a named list of commands, separated by semicolons. They can come from ROUTINES (a
+// JSON map of name to commands), or be defined by an admin in chat, and can run on a schedule.
+struct Routine {
+    name: String,
+    steps: String,
+    schedule: Option<Recurrence>,
+    room_id: Option<String>,
+}
+
+async fn on_routines_message(joined: &Joined) -> anyhow::Result<()> {
+    let routines = all_routines()?;
+
+    if routines.is_empty() {
+        matrix::send(joined, matrix::text_plain("There are no routines yet.")).await?;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = routines
+        .iter()
+        .map(|r| match r.schedule {
+            Some(schedule) => format!("{} ({}): {}", r.name, schedule, r.steps),
+            None => format!("{}: {}", r.name, r.steps),
+        })
+        .collect();
+
+    matrix::send(joined, matrix::text_plain(&lines.join("\n"))).await?;
+
+    Ok(())
+}
+
+async fn on_routine_message(joined: &Joined, sender: &UserId, command: &str) -> anyhow::Result<()> {
+    let usage = "Usage: routine [name], routine [name] = [command]; [command], \
+        routine [name] [schedule], or routine delete [name].";
+
+    // define (or redefine) a routine
+    if let Some((name, steps)) = command.split_once('=') {
+        require_admin(sender)?;
+
+        let name = name.trim().to_lowercase();
+        let steps = steps.trim();
+
+        if name.is_empty() || name.contains(' ') || steps.is_empty() {
+            bail!(usage);
+        }
+
+        save_routine(&name, steps, joined.room_id())?;
+
+        matrix::send(
+            joined,
+            matrix::text_plain(&format!("Saved the {} routine.", name)),
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    let args: Vec<String> = command
+        .split_whitespace()
+        .map(|a| a.to_lowercase())
+        .collect();
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["delete", name] => {
+            require_admin(sender)?;
+            delete_routine(name)?;
+
+            matrix::send(
+                joined,
+                matrix::text_plain(&format!("Deleted the {} routine.", name)),
+            )
+            .await?;
+        }
+        [name, ..] if args.len() > 1 => {
+            require_admin(sender)?;
+
+            let schedule = parse_schedule(&args[1..].join(" "))?;
+            schedule_routine(name, schedule)?;
+
+            let response = match schedule {
+                Some(schedule) => format!("The {} routine will run {}.", name, schedule),
+                None => format!("The {} routine won't run on its own anymore.", name),
+            };
+
+            matrix::send(joined, matrix::text_plain(&response)).await?;
+        }
+        [name] => {
+            let routine = match get_routine(name)? {
+                Some(routine) => routine,
+                None => bail!("I don't know the {} routine.", name),
+            };
+
+            run_routine(&routine).await?;
+
+            matrix::send(
+                joined,
+                matrix::text_plain(&format!("Ran the {} routine.", name)),
+            )
+            .await?;
+        }
+        _ => bail!(usage),
+    }
+
+    Ok(())
+}
+
+async fn run_routine(routine: &Routine) -> anyhow::Result<()> {
+    println!("running the {} routine", routine.name);
+
+    for step in routine.steps.split(';').map(|s| s.trim()) {
+        if step.is_empty() {
+            continue;
+        }
+
+        if !run_command(step).await? {
+            bail!("I don't know how to \"{}\".", step);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_scheduled_routines(client: &Client) {
+    let now = scheduler::now();
+
+    let routines = match all_routines() {
+        Ok(routines) => routines,
+        Err(e) => {
+            println!("Could not load routines! {}", e);
+            return;
+        }
+    };
+
+    for routine in routines
+        .iter()
+        .filter(|r| r.schedule.map(|s| s.matches(now)).unwrap_or(false))
+    {
+        if let Err(e) = run_routine(routine).await {
+            println!("Could not run the {} routine! {}", routine.name, e);
+
+            // let the room the routine was defined in know
+            let room = routine
+                .room_id
+                .as_deref()
+                .and_then(|id| RoomId::try_from(id).ok())
+                .and_then(|id| client.get_joined_room(&id));
+
+            if let Some(room) = room {
+                let message = format!("The {} routine failed: {}", routine.name, e);
+                matrix::send(&room, matrix::text_plain(&message)).await.ok();
+            }
+        }
+    }
+}
+
+fn require_admin(sender: &UserId) -> anyhow::Result<()> {
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to change routines.");
+    }
+
+    Ok(())
+}
+
+// "at 7am" (every day), "weekdays at 7am", "every other friday", or "off"
+fn parse_schedule(schedule: &str) -> anyhow::Result<Option<Recurrence>> {
+    let schedule = schedule.strip_prefix("at ").unwrap_or(schedule);
+
+    if schedule == "off" || schedule == "never" {
+        return Ok(None);
+    }
+
+    Ok(Some(schedule.parse()?))
+}
+
+fn open_db() -> anyhow::Result<Connection> {
+    let conn = storage::open("homebot")?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS routines (
+            name TEXT PRIMARY KEY,
+            steps TEXT NOT NULL,
+            hour INTEGER,
+            room_id TEXT
+        )",
+        [],
+    )?;
+
+    // the hour column only ever meant every day at that hour; schedules can say more
+    let has_schedule: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('routines') WHERE name = 'schedule'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if has_schedule == 0 {
+        conn.execute("ALTER TABLE routines ADD COLUMN schedule TEXT", [])?;
+    }
+
+    // An alert's threshold is for one side of one sensor, so a second "over" for the same sensor
+    // moves the threshold instead of adding another. Firing is whether it's past it right now,
+    // so it only speaks up on the way over, and on the way back.
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS alerts (
+            entity TEXT NOT NULL,
+            above INTEGER NOT NULL,
+            threshold REAL NOT NULL,
+            notify INTEGER NOT NULL,
+            room_id TEXT NOT NULL,
+            firing INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (entity, above)
+        )",
+        [],
+    )?;
+
+    // an appliance's finished cycle, nagged about every so often until someone reacts to it
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS appliance_cycles (
+            event_id TEXT PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            appliance TEXT NOT NULL,
+            message TEXT NOT NULL,
+            nagged TEXT NOT NULL,
+            done INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    Ok(conn)
+}
+
+fn configured_routines() -> HashMap<String, String> {
+    config::load("ROUTINES")
+}
+
+fn all_routines() -> anyhow::Result<Vec<Routine>> {
+    let conn = open_db()?;
+    let mut stmt = conn.prepare("SELECT * FROM routines ORDER BY name")?;
+
+    let mut routines: Vec<Routine> = stmt
+        .query_map([], |row| {
+            let schedule: Option<String> = row.get("schedule")?;
+            let hour: Option<u32> = row.get("hour")?;
+
+            Ok(Routine {
+                name: row.get("name")?,
+                steps: row.get("steps")?,
+                schedule: match (schedule, hour) {
+                    (Some(schedule), _) => Recurrence::parse(&schedule),
+                    (None, Some(hour)) => Some(Recurrence {
+                        days: Days::Every,
+                        hour,
+                    }),
+                    (None, None) => None,
+                },
+                room_id: row.get("room_id")?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    // saved routines override configured ones with the same name
+    for (name, steps) in configured_routines() {
+        if !routines.iter().any(|r| r.name == name) {
+            routines.push(Routine {
+                name,
+                steps,
+                schedule: None,
+                room_id: None,
+            });
+        }
+    }
+
+    routines.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(routines)
+}
+
+fn get_routine(name: &str) -> anyhow::Result<Option<Routine>> {
+    Ok(all_routines()?.into_iter().find(|r| r.name == name))
+}
+
+fn save_routine(name: &str, steps: &str, room_id: &RoomId) -> anyhow::Result<()> {
+    open_db()?.execute(
+        "
+        INSERT INTO routines
+            (name, steps, room_id)
+        VALUES
+            (?1, ?2, ?3)
+        ON CONFLICT(name) DO UPDATE SET steps=?2, room_id=?3",
+        params![name, steps, room_id.as_str()],
+    )?;
+
+    Ok(())
+}
+
+fn schedule_routine(name: &str, schedule: Option<Recurrence>) -> anyhow::Result<()> {
+    let conn = open_db()?;
+
+    let saved: Option<String> = conn
+        .query_row(
+            "SELECT name FROM routines WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    // configured routines need to be saved before they can be scheduled
+    if saved.is_none() {
+        match configured_routines().get(name) {
+            Some(steps) => {
+                conn.execute(
+                    "INSERT INTO routines (name, steps) VALUES (?1, ?2)",
+                    params![name, steps],
+                )?;
+            }
+            None => bail!("I don't know the {} routine.", name),
+        }
+    }
+
+    conn.execute(
+        "UPDATE routines SET schedule = ?2, hour = NULL WHERE name = ?1",
+        params![name, schedule.map(|s| s.to_string())],
+    )?;
+
+    Ok(())
+}
+
+fn delete_routine(name: &str) -> anyhow::Result<()> {
+    let deleted = open_db()?.execute("DELETE FROM routines WHERE name = ?1", params![name])?;
+
+    if deleted == 0 {
+        bail!("I can only delete routines that were saved in chat.");
+    }
+
+    Ok(())
+}
+
+// "Alert if basement humidity > 60%", and the sensor it's watching. The sensor can be named the
+// way Home Assistant does (sensor.basement_humidity) or the way people do (basement humidity).
+struct Alert {
+    entity: String,
+    above: bool,
+    threshold: f64,
+    notify: bool,
+    room_id: String,
+    firing: bool,
+}
+
+impl Alert {
+    fn name(&self) -> String {
+        sensor_name(&self.entity)
+    }
+
+    fn past(&self, value: f64) -> bool {
+        if self.above {
+            value > self.threshold
+        } else {
+            value < self.threshold
+        }
+    }
+
+    fn side(&self) -> &str {
+        if self.above {
+            "over"
+        } else {
+            "under"
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} {} {}{}",
+            self.name(),
+            self.side(),
+            self.threshold,
+            if self.notify {
+                ", with a notification"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+async fn on_alerts_message(joined: &Joined) -> anyhow::Result<()> {
+    let alerts = all_alerts()?;
+
+    let response = if alerts.is_empty() {
+        "There are no alerts yet.".to_string()
+    } else {
+        alerts
+            .iter()
+            .map(|a| a.describe())
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+
+    matrix::send(joined, matrix::text_plain(&response)).await?;
+
+    Ok(())
+}
+
+async fn on_alert_message(joined: &Joined, sender: &UserId, command: &str) -> anyhow::Result<()> {
+    let usage = "Usage: alert if [sensor] > [number], alert if [sensor] < [number] \
+        (add \"and notify\" to send a notification too), or alert delete [sensor].";
+
+    if !matrix::is_admin(sender) {
+        bail!("You are not allowed to change alerts.");
+    }
+
+    let lower = command.to_lowercase();
+
+    if let Some(sensor) = lower.strip_prefix("delete ") {
+        let entity = entity_id(sensor);
+        let deleted =
+            open_db()?.execute("DELETE FROM alerts WHERE entity = ?1", params![entity])?;
+
+        let response = if deleted == 0 {
+            format!("There aren't any alerts for {}.", sensor_name(&entity))
+        } else {
+            format!("Deleted the alerts for {}.", sensor_name(&entity))
+        };
+
+        matrix::send(joined, matrix::text_plain(&response)).await?;
+
+        return Ok(());
+    }
+
+    let rule = match lower.strip_prefix("if ") {
+        Some(rule) => rule,
+        None => bail!(usage),
+    };
+
+    let (rule, notify) = match rule.strip_suffix(" and notify") {
+        Some(rule) => (rule, true),
+        None => (rule, false),
+    };
+
+    let (sensor, threshold, above) = match (rule.split_once('>'), rule.split_once('<')) {
+        (Some((sensor, threshold)), None) => (sensor, threshold, true),
+        (None, Some((sensor, threshold))) => (sensor, threshold, false),
+        _ => bail!(usage),
+    };
+
+    let threshold: f64 = match threshold.trim().trim_end_matches('%').trim().parse() {
+        Ok(threshold) => threshold,
+        Err(_) => bail!(usage),
+    };
+
+    if sensor.trim().is_empty() {
+        bail!(usage);
+    }
+
+    let alert = Alert {
+        entity: entity_id(sensor),
+        above,
+        threshold,
+        notify,
+        room_id: joined.room_id().to_string(),
+        firing: false,
+    };
+
+    // make sure there's something there to watch before saving it
+    let now = webhook::state(&alert.entity).await?;
+    save_alert(&alert)?;
+
+    matrix::send(
+        joined,
+        matrix::text_plain(&format!(
+            "I'll let you know when {} goes {} {}. It's {} now.",
+            alert.name(),
+            alert.side(),
+            alert.threshold,
+            now
+        )),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn alert_poll(now: DateTime<Tz>) -> DateTime<Tz> {
+    let minutes = env::var("ALERT_POLL_MINUTES")
+        .map(|m| m.parse().expect("ALERT_POLL_MINUTES is not an integer"))
+        .unwrap_or(DEFAULT_ALERT_POLL_MINUTES);
+
+    now + chrono::Duration::minutes(minutes)
+}
+
+// Reads every sensor with an alert on it, and tells the alert's room about anything that's gone
+// past its threshold, or come back from it, since last time.
+async fn check_alerts(client: &Client) -> anyhow::Result<()> {
+    let alerts = all_alerts()?;
+    let mut values: HashMap<String, Option<f64>> = HashMap::new();
+
+    for alert in alerts {
+        if !values.contains_key(&alert.entity) {
+            let value = match webhook::state(&alert.entity).await {
+                // anything that isn't a number, like "unavailable", can't be past anything
+                Ok(state) => state.parse().ok(),
+                Err(e) => {
+                    println!("Could not read {}! {}", alert.entity, e);
+                    None
+                }
+            };
+
+            values.insert(alert.entity.clone(), value);
+        }
+
+        let value = match values[&alert.entity] {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let past = alert.past(value);
+
+        if past == alert.firing {
+            continue;
+        }
+
+        let message = if past {
+            format!(
+                "Heads up: {} is {} ({} {}).",
+                alert.name(),
+                value,
+                alert.side(),
+                alert.threshold
+            )
+        } else {
+            format!("{} is back to {}.", name_case(&alert.name()), value)
+        };
+
+        open_db()?.execute(
+            "UPDATE alerts SET firing = ?3 WHERE entity = ?1 AND above = ?2",
+            params![alert.entity, alert.above, past],
+        )?;
+
+        let room = RoomId::try_from(alert.room_id.as_str())
+            .ok()
+            .and_then(|id| client.get_joined_room(&id));
+
+        if let Some(room) = room {
+            matrix::send(&room, matrix::text_plain(&message)).await?;
+        }
+
+        if past && alert.notify {
+            webhook::notify(&message).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn all_alerts() -> anyhow::Result<Vec<Alert>> {
+    let conn = open_db()?;
+    let mut stmt = conn.prepare("SELECT * FROM alerts ORDER BY entity, above")?;
+
+    let alerts = stmt
+        .query_map([], |row| {
+            Ok(Alert {
+                entity: row.get("entity")?,
+                above: row.get("above")?,
+                threshold: row.get("threshold")?,
+                notify: row.get("notify")?,
+                room_id: row.get("room_id")?,
+                firing: row.get("firing")?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(alerts)
+}
+
+fn save_alert(alert: &Alert) -> anyhow::Result<()> {
+    open_db()?.execute(
+        "
+        INSERT INTO alerts
+            (entity, above, threshold, notify, room_id)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(entity, above) DO UPDATE SET threshold=?3, notify=?4, room_id=?5, firing=0",
+        params![
+            alert.entity,
+            alert.above,
+            alert.threshold,
+            alert.notify,
+            alert.room_id
+        ],
+    )?;
+
+    Ok(())
+}
+
+// "basement humidity" is sensor.basement_humidity; anything with a dot is already an entity
+fn entity_id(sensor: &str) -> String {
+    let sensor = sensor.trim().to_lowercase();
+
+    if sensor.contains('.') {
+        sensor
+    } else {
+        format!(
+            "sensor.{}",
+            sensor.split_whitespace().collect::<Vec<&str>>().join("_")
+        )
+    }
+}
+
+fn sensor_name(entity: &str) -> String {
+    entity
+        .strip_prefix("sensor.")
+        .unwrap_or(entity)
+        .replace('_', " ")
+}
+
+fn name_case(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    }
+}
+
+// Electricity use comes from ENERGY_SENSOR, a meter in Home Assistant that counts up in kWh (like
+// the energy dashboard uses), and each day is checked against ENERGY_BUDGET_KWH, if it's set.
+// With ENERGY_CHART=true, a bar chart goes along with the table.
+fn energy_sensor() -> anyhow::Result<String> {
+    match env::var("ENERGY_SENSOR") {
+        Ok(sensor) => Ok(entity_id(&sensor)),
+        Err(_) => bail!("There's no energy sensor set up."),
+    }
+}
+
+fn energy_budget() -> Option<f64> {
+    env::var("ENERGY_BUDGET_KWH")
+        .ok()
+        .map(|budget| budget.parse().expect("ENERGY_BUDGET_KWH is not a number"))
+}
+
+async fn on_power_message(client: &Client, joined: &Joined, command: &str) -> anyhow::Result<()> {
+    let days = match command {
+        "" => DEFAULT_POWER_DAYS,
+        days => match days.trim_end_matches(" days").parse::<i64>() {
+            Ok(days) if (1..=MAX_POWER_DAYS).contains(&days) => days,
+            _ => bail!(
+                "Usage: power, or power [number] days (up to {}).",
+                MAX_POWER_DAYS
+            ),
+        },
+    };
+
+    // today so far, and the days before it
+    let today = scheduler::now().naive_local().date();
+    let first = today - chrono::Duration::days(days - 1);
+
+    post_energy(client, joined, first, today).await
+}
+
+// the seven days before the report goes out
+async fn send_energy_report(client: &Client) -> anyhow::Result<()> {
+    let room_id =
+        env::var("ENERGY_REPORT_ROOM").expect("ENERGY_REPORT_ROOM environmental variable not set");
+
+    let room = match RoomId::try_from(room_id.as_str())
+        .ok()
+        .and_then(|id| client.get_joined_room(&id))
+    {
+        Some(room) => room,
+        None => bail!("not in the energy report room, {}", room_id),
+    };
+
+    let today = scheduler::now().naive_local().date();
+
+    post_energy(
+        client,
+        &room,
+        today - chrono::Duration::days(7),
+        today - chrono::Duration::days(1),
+    )
+    .await
+}
+
+async fn post_energy(
+    client: &Client,
+    joined: &Joined,
+    first: NaiveDate,
+    last: NaiveDate,
+) -> anyhow::Result<()> {
+    let sensor = energy_sensor()?;
+    let tz = scheduler::timezone();
+
+    let days: Vec<NaiveDate> = (0..=(last - first).num_days())
+        .map(|i| first + chrono::Duration::days(i))
+        .collect();
+
+    // midday always exists, even on the days the clocks change
+    let midnight = |day: NaiveDate| {
+        scheduler::at_hour(tz.from_local_datetime(&day.and_hms(12, 0, 0)).unwrap(), 0)
+    };
+    let start = midnight(first).with_timezone(&Utc);
+    let end = midnight(last + chrono::Duration::days(1))
+        .with_timezone(&Utc)
+        .min(Utc::now());
+
+    let readings: Vec<(DateTime<Tz>, f64)> = webhook::state_history(&sensor, start, end)
+        .await?
+        .into_iter()
+        // "unavailable" and the like come and go, and aren't readings
+        .filter_map(|(date, state)| Some((date.with_timezone(&tz), state.parse().ok()?)))
+        .collect();
+
+    let usage = daily_usage(&readings, &days);
+    let budget = energy_budget();
+    let (plain, html) = energy_table(&days, &usage, budget);
+
+    matrix::send(joined, matrix::text_html(&plain, &html)).await?;
+
+    if env::var("ENERGY_CHART")
+        .map(|chart| chart == "true")
+        .unwrap_or(false)
+    {
+        let (jpeg, width, height) =
+            task::spawn_blocking(move || image::bar_chart(&usage, budget)).await??;
+        matrix::send_image(client, joined, "Electricity use", &jpeg, width, height).await?;
+    }
+
+    Ok(())
+}
+
+// How much the meter went up each day, counting each rise on the day of the reading it rose to. A
+// meter that goes back down was reset, and is counting up from zero again.
+fn daily_usage(readings: &[(DateTime<Tz>, f64)], days: &[NaiveDate]) -> Vec<f64> {
+    let mut usage = vec![0.0; days.len()];
+
+    for pair in readings.windows(2) {
+        let ((_, before), (date, after)) = (pair[0], pair[1]);
+        let used = if after >= before {
+            after - before
+        } else {
+            after
+        };
+
+        if let Some(i) = days
+            .iter()
+            .position(|day| *day == date.naive_local().date())
+        {
+            usage[i] += used;
+        }
+    }
+
+    usage
+}
+
+fn energy_table(days: &[NaiveDate], usage: &[f64], budget: Option<f64>) -> (String, String) {
+    let over = |kwh: f64| budget.map(|budget| kwh > budget).unwrap_or(false);
+
+    let mut plain = vec![];
+    let mut rows = vec![];
+
+    for (day, kwh) in days.iter().zip(usage) {
+        let flag = if over(*kwh) { " (over budget)" } else { "" };
+        let date = day.format("%a %b %-d");
+
+        plain.push(format!("{}: {:.1} kWh{}", date, kwh, flag));
+        rows.push(format!(
+            "<tr><td>{}</td><td>{:.1} kWh</td><td>{}</td></tr>",
+            date,
+            kwh,
+            if over(*kwh) { "⚠️ over budget" } else { "" }
+        ));
+    }
+
+    let total: f64 = usage.iter().sum();
+    let mut summary = format!("{:.1} kWh in all", total);
+
+    if let Some(budget) = budget {
+        let days_over = usage.iter().filter(|kwh| over(**kwh)).count();
+        summary.push_str(&format!(
+            ", over the {:.1} kWh budget on {} of {} days",
+            budget,
+            days_over,
+            usage.len()
+        ));
+    }
+
+    summary.push('.');
+
+    (
+        format!("{}\n{}", plain.join("\n"), summary),
+        format!("<table>\n{}\n</table>\n<p>{}</p>", rows.join("\n"), summary),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Weekday;
+    use chrono_tz::US::Pacific;
+
+    #[test]
+    fn parses_routine_schedules() {
+        let at = |days, hour| Some(Recurrence { days, hour });
+
+        for (schedule, parsed) in [
+            ("at 7am", at(Days::Every, 7)),
+            ("at midnight", at(Days::Every, 0)),
+            ("at noon", at(Days::Every, 12)),
+            ("weekdays at 12am", at(Days::Weekdays, 0)),
+            ("every other friday", at(Days::EveryOther(Weekday::Fri), 9)),
+            ("off", None),
+            ("never", None),
+        ] {
+            assert_eq!(parse_schedule(schedule).unwrap(), parsed, "{:?}", schedule);
+        }
+
+        for schedule in [
+            "",
+            "at",
+            "at 24",
+            "at 13pm",
+            "sometimes",
+            "the 30th of the month",
+        ] {
+            assert!(parse_schedule(schedule).is_err(), "{:?}", schedule);
+        }
+    }
+
+    #[test]
+    fn adds_up_daily_usage() {
+        let at = |day: u32, hour: u32| Pacific.ymd(2024, 5, day).and_hms(hour, 0, 0);
+        let days = [
+            NaiveDate::from_ymd(2024, 5, 14),
+            NaiveDate::from_ymd(2024, 5, 15),
+        ];
+
+        // the meter resets to zero partway through the second day
+        let readings = [
+            (at(14, 0), 100.0),
+            (at(14, 12), 110.0),
+            (at(14, 23), 115.0),
+            (at(15, 8), 2.0),
+            (at(15, 20), 9.0),
+        ];
+
+        assert_eq!(daily_usage(&readings, &days), vec![15.0, 9.0]);
     }
 }
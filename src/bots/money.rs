@@ -1,18 +1,20 @@
+use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use anyhow;
-use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
-use chrono_tz::US::Pacific;
-use futures::executor;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use lettre::message::MultiPart;
 use matrix_sdk::room::{Joined, Room};
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
 use matrix_sdk::ruma::events::SyncMessageEvent;
 use matrix_sdk::ruma::{RoomId, UserId};
 use matrix_sdk::{Client, SyncSettings};
-use rusqlite::{params, Connection};
-use rust_decimal::prelude::ToPrimitive;
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
 use rusty_money::iso::Currency;
 use rusty_money::{iso, Money};
 use string_builder::Builder;
@@ -20,14 +22,27 @@ use tokio::task;
 
 use matrix::text_plain;
 
+use crate::commands;
+use crate::config;
+use crate::image;
+use crate::mail;
+use crate::mail::Mailer;
 use crate::matrix;
 use crate::matrix::text_html;
+use crate::pdf;
+use crate::scheduler;
+use crate::scheduler::{Days, Recurrence};
+use crate::storage;
+use crate::ynab;
 
 const MAIN_ROOM: &str = "!hMPITSQBLFEleSJmVm:kulak.us";
 
+// where fees go, and where the allowance comes from
+const BANK: &str = "@phil:kulak.us";
+
 pub async fn main() -> anyhow::Result<()> {
     let client = matrix::create_client("moneybot").await?;
-    let bot = Arc::new(Mutex::new(Bot::new()?));
+    let bot: SharedBot = Arc::new(Bot::new().await?);
 
     client
         .register_event_handler({
@@ -36,37 +51,163 @@ pub async fn main() -> anyhow::Result<()> {
             move |event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client| {
                 let bot = bot.clone();
 
-                task::spawn_blocking(move || {
-                    executor::block_on(bot.lock().unwrap().on_room_message(event, room, client))
+                async move {
+                    bot.on_room_message(event, room, client)
+                        .await
                         .expect("could not run message handler");
-                })
+                }
+            }
+        })
+        .await;
+
+    // Redactions are kept, so a send taken back while we were down still doesn't go through, and
+    // one taken back after it went through is sent back.
+    client
+        .register_event_handler({
+            let bot = bot.clone();
+
+            move |event: matrix::Redaction, room: Room| {
+                let bot = bot.clone();
+
+                async move {
+                    if let Err(e) = bot.on_redaction(event, room).await {
+                        println!("Could not handle redaction! {}", e);
+                    }
+                }
+            }
+        })
+        .await;
+
+    // parents sign off on big withdrawals by reacting to the request
+    client
+        .register_event_handler({
+            let bot = bot.clone();
+
+            move |event: SyncMessageEvent<ReactionEventContent>, _: Room, client: Client| {
+                let bot = bot.clone();
+
+                async move {
+                    if let Err(e) = bot.on_reaction(event, client).await {
+                        println!("Could not handle reaction! {}", e);
+                    }
+                }
             }
         })
         .await;
 
-    // manage weekly allowance
+    // withdrawals waiting on parents are expired or nudged within a minute of when they're due
     task::spawn({
         let client = client.clone();
         let bot = bot.clone();
 
         async move {
             loop {
-                if let Err(e) = manage_allowance(&client, &bot).await {
-                    println!("Could not send allowance! {}", e);
+                if let Err(e) = check_approvals(&client, &bot).await {
+                    println!("Could not check on withdrawals! {}", e);
                 }
+
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            }
+        }
+    });
+
+    // manage weekly allowance, checking every hour since everyone's Friday morning is different
+    scheduler::spawn("allowance", scheduler::next_hour, {
+        let client = client.clone();
+        let bot = bot.clone();
+
+        move || {
+            let client = client.clone();
+            let bot = bot.clone();
+
+            async move { send_allowance(&client, &bot).await }
+        }
+    });
+
+    // run the rules, before the statements go out
+    scheduler::spawn("weekly rules", scheduler::every("every sunday at 8am")?, {
+        let client = client.clone();
+        let bot = bot.clone();
+
+        move || {
+            let client = client.clone();
+            let bot = bot.clone();
+
+            async move { apply_rules(&client, &bot, Period::Weekly).await }
+        }
+    });
+
+    scheduler::spawn(
+        "monthly rules",
+        scheduler::every("first of the month at 8am")?,
+        {
+            let client = client.clone();
+            let bot = bot.clone();
+
+            move || {
+                let client = client.clone();
+                let bot = bot.clone();
+
+                async move { apply_rules(&client, &bot, Period::Monthly).await }
             }
+        },
+    );
+
+    // and the weekly money minute, for any parent who wants it
+    let schedule =
+        env::var("MONEY_MINUTE_SCHEDULE").unwrap_or(DEFAULT_MONEY_MINUTE_SCHEDULE.to_string());
+
+    scheduler::spawn("money minute", scheduler::every(&schedule)?, {
+        let client = client.clone();
+        let bot = bot.clone();
+
+        move || {
+            let client = client.clone();
+            let bot = bot.clone();
+
+            async move { send_money_minutes(&client, &bot).await }
         }
     });
 
+    // and monthly statements
+    scheduler::spawn(
+        "statements",
+        scheduler::every("first of the month at 9am")?,
+        {
+            let bot = bot.clone();
+
+            move || {
+                let bot = bot.clone();
+
+                async move { send_statements(&bot).await }
+            }
+        },
+    );
+
     let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
     client.sync(settings).await;
 
     Ok(())
 }
 
-async fn manage_allowance(client: &Client, bot: &Arc<Mutex<Bot>>) -> anyhow::Result<()> {
-    let now = Pacific.timestamp_millis(chrono::Utc::now().timestamp_millis());
+// everyone who gets an allowance, paid what CHASE and CHARLIE say (in cents)
+const ALLOWANCE_USERS: &[&str] = &["@chase:kulak.us", "@charlie:kulak.us"];
+
+// when the allowance goes out, from ALLOWANCE_SCHEDULE (like "every other friday at 9am")
+fn payday() -> Recurrence {
+    env::var("ALLOWANCE_SCHEDULE")
+        .map(|schedule| {
+            schedule
+                .parse()
+                .expect("ALLOWANCE_SCHEDULE is not a schedule")
+        })
+        .unwrap_or(Recurrence {
+            days: Days::Weekly(Weekday::Fri),
+            hour: 9,
+        })
+}
 
+async fn send_allowance(client: &Client, bot: &SharedBot) -> anyhow::Result<()> {
     let chase: i64 = env::var("CHASE")
         .expect("CHASE environmental variable not set")
         .parse()
@@ -77,421 +218,3256 @@ async fn manage_allowance(client: &Client, bot: &Arc<Mutex<Bot>>) -> anyhow::Res
         .parse()
         .expect("not an integer");
 
-    let morning = now
-        .with_hour(9)
-        .unwrap()
-        .with_minute(0)
-        .unwrap()
-        .with_second(0)
-        .unwrap();
+    let room_id = RoomId::try_from(MAIN_ROOM)?;
+    let payday = payday();
 
-    let next_friday =
-        morning + Duration::days(5 - morning.weekday().number_from_monday().to_i64().unwrap());
+    // the percentage of the allowance held back when chores aren't done
+    let hold: i64 = env::var("CHORE_HOLD")
+        .map(|hold| hold.parse().expect("not an integer"))
+        .unwrap_or(50);
 
-    let next_friday = if next_friday < now {
-        next_friday + Duration::days(7)
-    } else {
-        next_friday
-    };
+    let mut sent = vec![];
+    let mut held = vec![];
+
+    for (&user_id, amount) in ALLOWANCE_USERS.iter().zip([chase, charlie]) {
+        let user = UserId::try_from(user_id)?;
+        let now = scheduler::now_in(bot.get_timezone(&user).await?);
 
-    let duration = next_friday.signed_duration_since(now);
-    println!("allowance due in {:?} minutes", duration.num_minutes());
+        // allowance goes out on payday, wherever they are
+        if !payday.matches(now) {
+            continue;
+        }
 
-    tokio::time::sleep(duration.to_std().unwrap()).await;
+        // keyed by day, so a restart can't pay out twice
+        let event_id = format!("allowance:{}:{}", now.format("%Y-%m-%d"), user_id);
 
-    let room_id = RoomId::try_from(MAIN_ROOM)?;
+        // unless it's been skipped, or paused for a while, which gets said instead (once)
+        if let Some(skip) = bot.allowance_skip(&user, now.naive_local().date()).await? {
+            if bot.first_notice(&format!("skip:{}", event_id)).await? {
+                held.push(skip.describe(&user));
+            }
 
-    {
-        let bot = bot.lock().unwrap();
-        bot.send(
-            "@phil:kulak.us",
-            "@chase:kulak.us",
-            chase,
-            Some("allowance"),
-        )?;
-        bot.send(
-            "@phil:kulak.us",
-            "@charlie:kulak.us",
-            charlie,
-            Some("allowance"),
-        )?;
-    }
+            continue;
+        }
+        let last_payday = payday
+            .previous(scheduler::top_of_hour(now))
+            .ok_or_else(|| anyhow::anyhow!("There was no last payday."))?;
+        let undone = bot.undone_chores(&user, &last_payday).await?;
 
-    client
-        .room_send(
-            &room_id,
-            text_plain(
-                format!(
-                    "Sent {} to Chase and {} to Charlie.",
-                    Money::from_minor(chase, iso::USD),
-                    Money::from_minor(charlie, iso::USD)
+        let withheld = if undone.is_empty() {
+            0
+        } else {
+            amount * hold / 100
+        };
+
+        if amount > withheld
+            && !bot
+                .send(
+                    BANK,
+                    user_id,
+                    amount - withheld,
+                    Some("allowance"),
+                    &event_id,
                 )
-                .as_str(),
-            ),
-            None,
-        )
-        .await?;
+                .await?
+        {
+            continue;
+        }
+
+        if amount > withheld {
+            sent.push(format!(
+                "{} to {}",
+                Money::from_minor(amount - withheld, iso::USD),
+                pretty_account(&user)
+            ));
+        }
+
+        // nothing might have gone out to keep this from being said twice, so it's kept track of
+        if withheld > 0 && bot.first_notice(&format!("hold:{}", event_id)).await? {
+            held.push(format!(
+                "Held back {} from {} because these chores didn't get done: {}.",
+                Money::from_minor(withheld, iso::USD),
+                pretty_account(&user),
+                undone.join(", ")
+            ));
+        }
+    }
+
+    if sent.is_empty() && held.is_empty() {
+        return Ok(());
+    }
 
-    // sleep for a tad just to make sure we cycle over
-    tokio::time::sleep(Duration::minutes(1).to_std().unwrap()).await;
+    let mut lines = vec![];
+
+    if !sent.is_empty() {
+        lines.push(format!("Sent {}.", sent.join(" and ")));
+    }
+
+    lines.extend(held);
+
+    matrix::send_to(client, &room_id, text_plain(&lines.join("\n"))).await?;
 
     Ok(())
 }
 
-#[derive(Clone)]
-struct Transaction {
-    sender: Option<String>,
-    receiver: String,
-    amount: i64,
-    date: String,
-    memo: Option<String>,
-}
+// when the money minute goes out, unless MONEY_MINUTE_SCHEDULE says otherwise
+const DEFAULT_MONEY_MINUTE_SCHEDULE: &str = "every sunday at 6pm";
 
-#[derive(Clone)]
-struct BalanceTransaction<'a> {
-    balance: Money<'a, Currency>,
-    user: Option<UserId>,
-    amount: Money<'a, Currency>,
-    date: DateTime<chrono_tz::Tz>,
-    memo: Option<String>,
-}
+// how far back the money minute looks
+const MONEY_MINUTE_DAYS: i64 = 7;
 
-struct Bot {
-    conn: Connection,
-}
+// DMs the week's money minute to every parent who's asked for it
+async fn send_money_minutes(client: &Client, bot: &SharedBot) -> anyhow::Result<()> {
+    let subscribers = bot.money_minute_subscribers().await?;
 
-impl Bot {
-    fn new() -> anyhow::Result<Bot> {
-        let mut db_file = dirs::config_dir().expect("no config directory found");
-        db_file.push("moneybot");
-        db_file.push("database");
+    if subscribers.is_empty() {
+        return Ok(());
+    }
 
-        let db_created = !db_file.exists();
+    let summary = bot.money_minute().await?;
 
-        let bot = Bot {
-            conn: Connection::open(db_file)?,
+    // one parent we can't reach shouldn't cost the rest theirs
+    for user_id in subscribers {
+        let sent = match matrix::direct_room(client, &user_id).await {
+            Ok(room) => matrix::send(&room, text_plain(&summary)).await,
+            Err(e) => Err(e),
         };
 
-        if db_created {
-            bot.init()?;
+        if let Err(e) = sent {
+            println!("Could not send the money minute to {}! {}", user_id, e);
         }
+    }
 
-        Ok(bot)
+    Ok(())
+}
+
+// emails last month's statement to everyone listed (as a JSON map of Matrix ID to email address)
+// in the STATEMENTS environmental variable
+async fn send_statements(bot: &SharedBot) -> anyhow::Result<()> {
+    let recipients: HashMap<String, String> = config::load("STATEMENTS");
+
+    if recipients.is_empty() {
+        println!("no statement recipients configured");
+        return Ok(());
     }
 
-    fn init(self: &Bot) -> anyhow::Result<()> {
-        self.conn.execute(
-            "
-            CREATE TABLE transactions (
-                id INTEGER PRIMARY KEY,
-                sender TEXT,
-                receiver TEXT NOT NULL,
-                amount INTEGER NOT NULL,
-                date TEXT NOT NULL,
-                memo TEXT
-            )",
-            [],
-        )?;
+    let end = scheduler::at_hour(scheduler::now().with_day(1).unwrap(), 0);
+    let start = scheduler::add_months(end, -1);
+    let mailer = mail::Smtp::configured();
 
-        self.conn.execute(
-            "CREATE INDEX transaction_senders ON transactions (sender)",
-            [],
-        )?;
-        self.conn.execute(
-            "CREATE INDEX transaction_receivers ON transactions (receiver)",
-            [],
-        )?;
+    // one bad address (or account) shouldn't keep everyone else's statement from going out
+    for (user_id, address) in recipients {
+        if let Err(e) = send_statement(bot, &mailer, &user_id, &address, start, end).await {
+            println!("Could not send statement to {}! {}", address, e);
+        }
+    }
 
-        self.conn.execute(
-            "
-            CREATE TABLE users (
-                user_id TEXT PRIMARY KEY,
-                min_balance INTEGER NOT NULL
-            )",
-            [],
-        )?;
+    Ok(())
+}
 
-        let now = chrono::Utc::now().to_rfc3339();
+async fn send_statement(
+    bot: &SharedBot,
+    mailer: &mail::Smtp,
+    user_id: &str,
+    address: &str,
+    start: DateTime<Tz>,
+    end: DateTime<Tz>,
+) -> anyhow::Result<()> {
+    let user_id = UserId::try_from(user_id)?;
+    let (plain, html) = bot.statement(&user_id, start, end).await?;
 
-        // the two seed transactions
-        self.insert(&Transaction {
-            sender: None,
-            receiver: "@gwen:kulak.us".to_string(),
-            amount: 100_000,
-            date: now.to_string(),
-            memo: Some("seed value".to_string()),
-        })?;
+    let email = mail::build(
+        mailer.from(),
+        address,
+        &format!("Statement for {}", start.format("%B %Y")),
+        MultiPart::alternative_plain_html(plain, html),
+    )?;
 
-        self.insert(&Transaction {
-            sender: None,
-            receiver: "@phil:kulak.us".to_string(),
-            amount: 100_000,
-            date: now,
-            memo: Some("seed value".to_string()),
-        })?;
+    mailer.send_raw(address, &email.formatted()).await?;
 
-        println!("initialized new database");
+    println!("Sent statement to {}", address);
 
-        Ok(())
-    }
+    Ok(())
+}
 
-    pub fn send(
-        self: &Bot,
-        from: &str,
-        to: &str,
-        amount: i64,
-        memo: Option<&str>,
-    ) -> anyhow::Result<()> {
-        self.insert(&Transaction {
-            sender: Some(from.to_string()),
-            receiver: to.to_string(),
-            amount,
-            date: chrono::Utc::now().to_rfc3339(),
-            memo: memo.map(|s| s.to_string()),
-        })?;
+// withdrawals nobody signed off on in time are called off, and the rest get a nudge now and then
+async fn check_approvals(client: &Client, bot: &SharedBot) -> anyhow::Result<()> {
+    for pending in bot.expire_approvals().await? {
+        let message = format!(
+            "Not enough parents signed off in time, so I called this off: {}",
+            pending.describe("Take")
+        );
 
-        Ok(())
+        announce(client, &pending, &message).await?;
     }
 
-    fn insert(self: &Bot, t: &Transaction) -> anyhow::Result<()> {
-        self.conn.execute(
-            "
-            INSERT INTO transactions
-                (sender, receiver, amount, date, memo)
-            VALUES
-                (?1, ?2, ?3, ?4, ?5)",
-            params![t.sender, t.receiver, t.amount, t.date, t.memo],
-        )?;
+    for pending in bot.nudge_approvals().await? {
+        let room = client.get_joined_room(&RoomId::try_from(pending.request_room_id.as_str())?);
 
-        Ok(())
-    }
+        if let (Some(room), Some(request_id)) = (room, &pending.request_id) {
+            let nudge = format!(
+                "Still waiting on {} more {} for this.",
+                pending.missing(),
+                APPROVE_KEY
+            );
 
-    fn get_balance(self: &Bot, user_id: &UserId) -> anyhow::Result<Money<Currency>> {
-        let mut stmt = self.conn.prepare(
-            "
-                SELECT COALESCE(SUM(amount), 0)
-                FROM transactions
-                WHERE sender = ?1
-            ",
-        )?;
+            matrix::send_thread_reply(&room, request_id, &nudge).await?;
+        }
+    }
 
-        let sent: i64 = stmt.query_row(params![user_id.as_str()], |row| row.get(0))?;
+    Ok(())
+}
 
-        let mut stmt = self.conn.prepare(
-            "
-                SELECT COALESCE(SUM(amount), 0)
-                FROM transactions
-                WHERE receiver = ?1
-            ",
-        )?;
+// tells the parents, and whoever asked, if that was somewhere else
+async fn announce(client: &Client, pending: &PendingApproval, message: &str) -> anyhow::Result<()> {
+    let mut rooms = vec![pending.request_room_id.as_str()];
 
-        let received: i64 = stmt.query_row(params![user_id.as_str()], |row| row.get(0))?;
+    if pending.send.room_id != pending.request_room_id {
+        rooms.push(pending.send.room_id.as_str());
+    }
 
-        Ok(Money::from_minor(received - sent, iso::USD))
+    for room_id in rooms {
+        matrix::send_to(client, &RoomId::try_from(room_id)?, text_plain(message)).await?;
     }
 
-    fn get_min_balance(self: &Bot, user_id: &UserId) -> rusqlite::Result<Money<Currency>> {
-        let mut stmt = self.conn.prepare(
-            "
-                SELECT COALESCE(SUM(min_balance), 0)
-                FROM users
-                WHERE user_id = ?1
-            ",
-        )?;
+    Ok(())
+}
 
-        let min: i64 = stmt.query_row(params![user_id.as_str()], |row| row.get(0))?;
-        Ok(Money::from_minor(min, iso::USD))
+async fn apply_rules(client: &Client, bot: &SharedBot, period: Period) -> anyhow::Result<()> {
+    let results = bot.apply_rules(period).await?;
+
+    if results.is_empty() {
+        return Ok(());
     }
 
-    fn get_ledger(self: &Bot, user_id: &UserId) -> anyhow::Result<Vec<Transaction>> {
-        let mut stmt = self.conn.prepare(
-            "
-                SELECT *
-                FROM transactions
-                WHERE receiver = ?1 OR sender = ?1
-                ORDER BY date DESC LIMIT 5
-            ",
-        )?;
+    let room_id = RoomId::try_from(MAIN_ROOM)?;
 
-        let res = stmt.query_map(params![user_id.as_str()], |row| {
-            Ok(Transaction {
-                sender: row.get("sender")?,
-                receiver: row.get("receiver")?,
-                amount: row.get("amount")?,
-                date: row.get("date")?,
-                memo: row.get("memo")?,
-            })
-        })?;
+    matrix::send_to(client, &room_id, text_plain(&results.join("\n"))).await?;
 
-        Ok(res.into_iter().map(|row| row.unwrap()).collect())
-    }
+    Ok(())
+}
 
-    fn set_min_balance(self: &Bot, user_id: &UserId, min_balance: i64) -> anyhow::Result<()> {
-        self.conn.execute(
-            "
-            INSERT INTO users
-                (user_id, min_balance)
-            VALUES
-                (?1, ?2)
-            ON CONFLICT(user_id) DO UPDATE SET min_balance=?2",
-            params![user_id.as_str(), min_balance],
-        )?;
+// The queries below run on the database thread, and are shared by jobs that need more than one of
+// them at once.
 
-        Ok(())
+// returns the new row's ID, or None if the event has already been recorded
+fn insert(conn: &mut Connection, t: &Transaction) -> anyhow::Result<Option<i64>> {
+    let inserted = conn.execute(
+        "
+        INSERT INTO transactions
+            (sender, receiver, amount, date, memo, event_id)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT(event_id) DO NOTHING",
+        params![t.sender, t.receiver, t.amount, t.date, t.memo, t.event_id],
+    )?;
+
+    if inserted == 0 {
+        println!("skipping duplicate event {:?}", t.event_id);
+        return Ok(None);
     }
 
-    fn id_exists(self: &Bot, user_id: &UserId) -> anyhow::Result<bool> {
-        let mut stmt = self
-            .conn
-            .prepare(
-                "
-                SELECT COUNT(*)
-                FROM transactions
-                WHERE sender = ?1 OR receiver = ?1
-            ",
-            )
-            .unwrap();
+    Ok(Some(conn.last_insert_rowid()))
+}
 
-        let total: i64 = stmt
-            .query_row(params![user_id.as_str()], |row| row.get(0))
-            .unwrap();
+fn balance(conn: &mut Connection, user_id: &str) -> anyhow::Result<i64> {
+    let mut stmt = conn.prepare(
+        "
+            SELECT COALESCE(SUM(amount), 0)
+            FROM transactions
+            WHERE sender = ?1
+        ",
+    )?;
 
-        Ok(total > 0)
-    }
+    let sent: i64 = stmt.query_row(params![user_id], |row| row.get(0))?;
 
-    async fn on_room_message(
-        self: &Bot,
-        event: SyncMessageEvent<MessageEventContent>,
-        room: Room,
-        client: Client,
-    ) -> anyhow::Result<()> {
-        if let Some((room, sender, message)) = matrix::get_text_message(event, room, client).await {
-            if let Some(command) = matrix::get_command("balance", &message) {
-                self.on_balance_message(room, sender, command).await?;
-            } else if let Some(command) = matrix::get_command("send", &message) {
-                self.on_send_message(room, sender, command).await?;
-            } else if let Some(command) = matrix::get_command("set min", &message) {
-                self.on_set_min_balance_message(room, sender, command)
-                    .await?;
-            } else if let Some(command) = matrix::get_command("get min", &message) {
-                self.on_get_min_balance_message(room, command).await?;
-            } else if let Some(command) = matrix::get_command("ledger", &message) {
-                self.on_ledger_message(room, sender, command).await?;
-            }
-        }
+    let mut stmt = conn.prepare(
+        "
+            SELECT COALESCE(SUM(amount), 0)
+            FROM transactions
+            WHERE receiver = ?1
+        ",
+    )?;
 
-        Ok(())
-    }
+    let received: i64 = stmt.query_row(params![user_id], |row| row.get(0))?;
 
-    async fn on_balance_message(
-        self: &Bot,
-        room: Joined,
-        sender: UserId,
-        command: &str,
-    ) -> anyhow::Result<()> {
-        let sender = matrix::normalize_sender(sender, command)?;
-        let balance = self.get_balance(&sender)?;
-        room.send(text_plain(&format!("{}", balance)), None).await?;
-        Ok(())
-    }
+    Ok(received - sent)
+}
 
-    async fn on_send_message(
-        self: &Bot,
-        room: Joined,
-        sender: UserId,
-        command: &str,
-    ) -> anyhow::Result<()> {
-        let args: Vec<&str> = command
-            .split(' ')
-            .filter(|w| !w.eq_ignore_ascii_case("to"))
-            .filter(|w| !w.trim().is_empty())
-            .collect();
+// What's unusual about a send, if anything: far more than the sender's average, or one too many
+// in a short while. Moving money into savings doesn't count either way.
+fn anomaly(
+    conn: &mut Connection,
+    t: &Transaction,
+    savings: &str,
+) -> anyhow::Result<Option<String>> {
+    let sender = match &t.sender {
+        Some(sender) => sender,
+        None => return Ok(None),
+    };
 
-        if args.len() < 2 {
-            println!("invalid send command {}", command);
-            return Ok(());
-        }
+    let (count, average): (i64, f64) = conn.query_row(
+        "
+        SELECT COUNT(*), COALESCE(AVG(amount), 0)
+        FROM transactions
+        WHERE sender = ?1 AND receiver != ?2 AND amount > 0",
+        params![sender, savings],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
 
-        let (receiver, amount) = if let Ok(amount) = Money::from_str(args[0], iso::USD) {
-            (matrix::create_user_id(args[1])?, amount)
-        } else if let Ok(amount) = Money::from_str(args[1], iso::USD) {
-            (matrix::create_user_id(args[0])?, amount)
-        } else {
-            room.send(text_plain("Please use a valid amount."), None)
-                .await?;
-            return Ok(());
-        };
+    let factor = anomaly_factor();
 
-        if amount.is_negative() && !matrix::is_admin(&sender) {
-            room.send(
-                text_plain("You are not allowed to take money, only send it."),
-                None,
-            )
-            .await?;
-            return Ok(());
-        }
+    if count >= ANOMALY_HISTORY && t.amount as f64 > average * factor as f64 {
+        return Ok(Some(format!(
+            "more than {} times their usual {}",
+            factor,
+            Money::from_minor(average.round() as i64, iso::USD)
+        )));
+    }
 
-        if amount.is_zero() {
-            room.send(text_plain("Wait... what's the point of that?"), None)
-                .await?;
-            return Ok(());
-        }
+    let since = (Utc::now() - chrono::Duration::minutes(BURST_MINUTES)).to_rfc3339();
 
-        if (self.get_balance(&sender)? - amount.clone()) < self.get_min_balance(&sender)? {
-            room.send(text_plain("You don't have enough money!"), None)
+    let recent: i64 = conn.query_row(
+        "
+        SELECT COUNT(*)
+        FROM transactions
+        WHERE sender = ?1 AND receiver != ?2 AND date >= ?3",
+        params![sender, savings, since],
+        |row| row.get(0),
+    )?;
+
+    if recent + 1 >= anomaly_burst() {
+        return Ok(Some(format!(
+            "their {} send in {} minutes",
+            ordinal(recent + 1),
+            BURST_MINUTES
+        )));
+    }
+
+    Ok(None)
+}
+
+// A week (or however long) of a kid's money, for the parents: the allowance that came in, where
+// the rest went (by memo, which is as close to a category as a send gets), how their savings moved,
+// and anything that was flagged as unusual.
+fn money_minute(conn: &mut Connection, kid: &UserId, since: &str) -> anyhow::Result<String> {
+    let user_id = kid.to_string();
+    let savings = savings_account(kid).to_string();
+    let dollars = |amount: i64| Money::from_minor(amount, iso::USD).to_string();
+
+    let allowance: i64 = conn.query_row(
+        "
+        SELECT COALESCE(SUM(amount), 0)
+        FROM transactions
+        WHERE sender = ?1 AND receiver = ?2 AND memo = 'allowance' AND date >= ?3",
+        params![BANK, user_id, since],
+        |row| row.get(0),
+    )?;
+
+    let savings_balance = balance(conn, &savings)?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT COALESCE(LOWER(TRIM(memo)), ''), SUM(amount)
+        FROM transactions
+        WHERE sender = ?1 AND receiver != ?2 AND date >= ?3
+        GROUP BY 1
+        ORDER BY 2 DESC, 1",
+    )?;
+
+    let spending = stmt
+        .query_map(params![user_id, savings, since], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(String, i64)>>>()?;
+
+    let saved: i64 = conn.query_row(
+        "
+        SELECT COALESCE(SUM(CASE WHEN receiver = ?1 THEN amount ELSE -amount END), 0)
+        FROM transactions
+        WHERE (receiver = ?1 OR sender = ?1) AND date >= ?2",
+        params![savings, since],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT description
+        FROM anomalies
+        WHERE user_id = ?1 AND date >= ?2
+        ORDER BY id",
+    )?;
+
+    let flagged = stmt
+        .query_map(params![user_id, since], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    let mut lines = vec![pretty_account(kid)];
+
+    lines.push(if allowance > 0 {
+        format!("Allowance: {}", dollars(allowance))
+    } else {
+        "No allowance this week.".to_string()
+    });
+
+    lines.push(if spending.is_empty() {
+        "Spent nothing.".to_string()
+    } else {
+        let total: i64 = spending.iter().map(|(_, amount)| amount).sum();
+        let categories: Vec<String> = spending
+            .iter()
+            .map(|(memo, amount)| {
+                let memo = if memo.is_empty() { "no memo" } else { memo };
+                format!("{} {}", memo, dollars(*amount))
+            })
+            .collect();
+
+        format!("Spent {}: {}", dollars(total), categories.join(", "))
+    });
+
+    lines.push(match saved {
+        0 => format!("Savings: {} (no change)", dollars(savings_balance)),
+        saved if saved > 0 => format!(
+            "Savings: {} (up {})",
+            dollars(savings_balance),
+            dollars(saved)
+        ),
+        saved => format!(
+            "Savings: {} (down {})",
+            dollars(savings_balance),
+            dollars(-saved)
+        ),
+    });
+
+    for flag in flagged {
+        lines.push(format!("Flagged: {}", flag));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+// Adds a parent's sign-off to a pending withdrawal, returning it and whether that was the last one
+// it needed. A finished one is marked done in the same job, so it can only go out once.
+fn approve(
+    conn: &mut Connection,
+    request_id: &str,
+    approver: &str,
+) -> anyhow::Result<Option<(PendingApproval, bool)>> {
+    let tx = conn.transaction()?;
+
+    let pending = tx
+        .query_row(
+            "SELECT * FROM pending_approvals WHERE request_id = ?1 AND done = 0",
+            params![request_id],
+            PendingApproval::from_row,
+        )
+        .optional()?;
+
+    let mut pending = match pending {
+        Some(pending) => pending,
+        None => return Ok(None),
+    };
+
+    if !pending.approvers.iter().any(|a| a == approver) {
+        pending.approvers.push(approver.to_string());
+    }
+
+    let approved = pending.missing() == 0;
+
+    tx.execute(
+        "UPDATE pending_approvals SET approvers = ?1, done = ?2 WHERE id = ?3",
+        params![pending.approvers.join(","), approved, pending.send.id],
+    )?;
+
+    tx.commit()?;
+
+    Ok(Some((pending, approved)))
+}
+
+fn min_balance(conn: &mut Connection, user_id: &str) -> anyhow::Result<i64> {
+    let mut stmt = conn.prepare(
+        "
+            SELECT COALESCE(SUM(min_balance), 0)
+            FROM users
+            WHERE user_id = ?1
+        ",
+    )?;
+
+    Ok(stmt.query_row(params![user_id], |row| row.get(0))?)
+}
+
+// exports a transaction if it was just inserted, returning whether it was
+fn recorded(id: Option<i64>, t: &Transaction) -> bool {
+    match id {
+        Some(id) => {
+            if ynab::enabled() {
+                export(id, t.clone());
+            }
+
+            true
+        }
+        None => false,
+    }
+}
+
+// The same transaction going the other way, for taking it back.
+fn reversal(t: &Transaction, event_id: &str) -> Transaction {
+    Transaction {
+        sender: Some(t.receiver.clone()),
+        receiver: t.sender.clone().unwrap_or_else(|| BANK.to_string()),
+        amount: t.amount,
+        date: Utc::now().to_rfc3339(),
+        memo: Some(match &t.memo {
+            Some(memo) => format!("taken back: {}", memo),
+            None => "taken back".to_string(),
+        }),
+        event_id: Some(format!("undo:{}", event_id)),
+    }
+}
+
+// a redacted send, and what went in to take it back (nothing, if the money's been spent)
+struct Undone {
+    sent: Transaction,
+    reversals: Vec<(i64, Transaction)>,
+}
+
+// Sends back a send that was redacted by whoever made it (or an admin), along with any round-up it
+// made, but only if the receiver hasn't spent it yet. Returns None if there was nothing to take
+// back, or it already was.
+fn undo(
+    conn: &mut Connection,
+    event_id: &str,
+    redactor: &str,
+    admin: bool,
+) -> anyhow::Result<Option<Undone>> {
+    let sent = conn
+        .query_row(
+            "SELECT * FROM transactions WHERE event_id = ?1 AND (sender = ?2 OR ?3)",
+            params![event_id, redactor, admin],
+            Transaction::from_row,
+        )
+        .optional()?;
+
+    let sent = match sent {
+        Some(sent) if sent.amount > 0 => sent,
+        _ => return Ok(None),
+    };
+
+    let undo_id = format!("undo:{}", event_id);
+
+    let already: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM transactions WHERE event_id = ?1",
+        params![undo_id],
+        |row| row.get(0),
+    )?;
+
+    if already > 0 {
+        return Ok(None);
+    }
+
+    if balance(conn, &sent.receiver)? - sent.amount < min_balance(conn, &sent.receiver)? {
+        return Ok(Some(Undone {
+            sent,
+            reversals: vec![],
+        }));
+    }
+
+    let mut reversals = vec![];
+    let roundup_id = format!("roundup:{}", event_id);
+
+    let roundup = conn
+        .query_row(
+            "SELECT * FROM transactions WHERE event_id = ?1",
+            params![roundup_id],
+            Transaction::from_row,
+        )
+        .optional()?;
+
+    // the spare change stays put if it's been spent out of savings since
+    if let Some(roundup) = roundup {
+        let reversal = reversal(&roundup, &roundup_id);
+
+        if balance(conn, &roundup.receiver)? - roundup.amount
+            >= min_balance(conn, &roundup.receiver)?
+        {
+            if let Some(id) = insert(conn, &reversal)? {
+                reversals.push((id, reversal));
+            }
+        }
+    }
+
+    let reversal = reversal(&sent, event_id);
+
+    if let Some(id) = insert(conn, &reversal)? {
+        reversals.push((id, reversal));
+    }
+
+    Ok(Some(Undone { sent, reversals }))
+}
+
+// pushes a transaction to YNAB in the background, so a slow API doesn't hold up the ledger
+fn export(id: i64, t: Transaction) {
+    task::spawn(async move {
+        let res = ynab::push(
+            id,
+            t.sender.as_deref(),
+            &t.receiver,
+            t.amount,
+            &t.date,
+            t.memo.as_deref(),
+        )
+        .await;
+
+        if let Err(e) = res {
+            println!("Could not export transaction {} to YNAB! {}", id, e);
+        }
+    });
+}
+
+#[derive(Clone)]
+struct Transaction {
+    sender: Option<String>,
+    receiver: String,
+    amount: i64,
+    date: String,
+    memo: Option<String>,
+    // the Matrix event (or scheduled job) that caused this, so it can't happen twice
+    event_id: Option<String>,
+}
+
+impl Transaction {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Transaction> {
+        Ok(Transaction {
+            sender: row.get("sender")?,
+            receiver: row.get("receiver")?,
+            amount: row.get("amount")?,
+            date: row.get("date")?,
+            memo: row.get("memo")?,
+            event_id: row.get("event_id")?,
+        })
+    }
+}
+
+// A send that's waiting on a parent before it goes out, either held for looking off or a withdrawal
+// from savings.
+struct WaitingSend {
+    id: i64,
+    room_id: String,
+    sender: String,
+    receiver: String,
+    amount: i64,
+    memo: Option<String>,
+    // the command, which also keeps the transaction from happening twice
+    event_id: String,
+}
+
+impl WaitingSend {
+    // like "Sent $5.00 to Charlie for lunch."
+    fn describe(&self, verb: &str) -> String {
+        let amount = Money::from_minor(self.amount, iso::USD);
+        let receiver = matrix::create_user_id(&self.receiver)
+            .map(|r| pretty_account(&r))
+            .unwrap_or_else(|_| self.receiver.clone());
+
+        match &self.memo {
+            Some(memo) => format!("{} {} to {} for {}.", verb, amount, receiver, memo),
+            None => format!("{} {} to {}.", verb, amount, receiver),
+        }
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<WaitingSend> {
+        Ok(WaitingSend {
+            id: row.get("id")?,
+            room_id: row.get("room_id")?,
+            sender: row.get("sender")?,
+            receiver: row.get("receiver")?,
+            amount: row.get("amount")?,
+            memo: row.get("memo")?,
+            event_id: row.get("event_id")?,
+        })
+    }
+}
+
+// A withdrawal from savings big enough to need parents to sign off, which they do by reacting to
+// the request the bot posted.
+struct PendingApproval {
+    send: WaitingSend,
+    request_room_id: String,
+    request_id: Option<String>,
+    approvers: Vec<String>,
+}
+
+impl PendingApproval {
+    // like "Take $50.00 out of Chase's savings for a bike."
+    fn describe(&self, verb: &str) -> String {
+        let amount = Money::from_minor(self.send.amount, iso::USD);
+        let savings = matrix::create_user_id(&self.send.sender)
+            .map(|s| pretty_account(&s))
+            .unwrap_or_else(|_| self.send.sender.clone());
+
+        match &self.send.memo {
+            Some(memo) => format!("{} {} out of {} for {}.", verb, amount, savings, memo),
+            None => format!("{} {} out of {}.", verb, amount, savings),
+        }
+    }
+
+    // how many more parents need to sign off
+    fn missing(&self) -> usize {
+        APPROVALS_NEEDED.saturating_sub(self.approvers.len())
+    }
+
+    fn transaction(&self) -> Transaction {
+        Transaction {
+            sender: Some(self.send.sender.clone()),
+            receiver: self.send.receiver.clone(),
+            amount: self.send.amount,
+            date: Utc::now().to_rfc3339(),
+            memo: self.send.memo.clone(),
+            event_id: Some(self.send.event_id.clone()),
+        }
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<PendingApproval> {
+        let approvers: String = row.get("approvers")?;
+
+        Ok(PendingApproval {
+            send: WaitingSend::from_row(row)?,
+            request_room_id: row.get("request_room_id")?,
+            request_id: row.get("request_id")?,
+            approvers: approvers
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_string())
+                .collect(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Period {
+    Weekly,
+    Monthly,
+    // not on a schedule at all, but whenever the user sends money
+    EverySend,
+}
+
+impl Period {
+    fn parse(period: &str) -> Option<Period> {
+        match period {
+            "weekly" => Some(Period::Weekly),
+            "monthly" => Some(Period::Monthly),
+            "every send" => Some(Period::EverySend),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Period::Weekly => "weekly",
+            Period::Monthly => "monthly",
+            Period::EverySend => "every send",
+        }
+    }
+}
+
+// A rule that runs on a schedule: either sweeping anything over `amount` into savings, or
+// charging `amount` as a fee if the user hasn't sent any money during the period. A round-up rule
+// runs with every send instead, rounding it up to the dollar and saving the difference.
+struct Rule {
+    id: i64,
+    kind: String,
+    user_id: String,
+    amount: i64,
+    period: String,
+}
+
+impl Rule {
+    fn describe(&self) -> String {
+        let user = pretty_account(&matrix::create_user_id(&self.user_id).unwrap());
+        let amount = Money::from_minor(self.amount, iso::USD);
+
+        match self.kind.as_str() {
+            "sweep" => format!(
+                "{}. Sweep anything over {} from {} to savings, {}.",
+                self.id, amount, user, self.period
+            ),
+            "roundup" => format!(
+                "{}. Round up what {} sends to the dollar, and put the change in savings.",
+                self.id, user
+            ),
+            _ => format!(
+                "{}. Charge {} {} if they don't send anything, {}.",
+                self.id, user, amount, self.period
+            ),
+        }
+    }
+}
+
+// A weekly chore; it counts as done for the week if it's been marked done since the last
+// allowance.
+struct Chore {
+    id: i64,
+    user_id: String,
+    description: String,
+    done_at: Option<String>,
+}
+
+impl Chore {
+    fn describe(&self, since: &DateTime<chrono_tz::Tz>) -> String {
+        let user = pretty_account(&matrix::create_user_id(&self.user_id).unwrap());
+        let done = if self.done_since(since) {
+            "done"
+        } else {
+            "not done"
+        };
+
+        format!("{}. {}: {} ({})", self.id, user, self.description, done)
+    }
+
+    fn done_since(&self, since: &DateTime<chrono_tz::Tz>) -> bool {
+        match &self.done_at {
+            Some(done_at) => *done_at >= since.with_timezone(&Utc).to_rfc3339(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct BalanceTransaction<'a> {
+    balance: Money<'a, Currency>,
+    user: Option<UserId>,
+    amount: Money<'a, Currency>,
+    date: DateTime<chrono_tz::Tz>,
+    memo: Option<String>,
+}
+
+// The whole schema, for a new database or an old one: the original tables, then everything added
+// since. The tests build theirs with this too.
+fn create_tables(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS transactions (
+            id INTEGER PRIMARY KEY,
+            sender TEXT,
+            receiver TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            date TEXT NOT NULL,
+            memo TEXT
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS transaction_senders ON transactions (sender)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS transaction_receivers ON transactions (receiver)",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS users (
+            user_id TEXT PRIMARY KEY,
+            min_balance INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // tables and columns added after the original schema
+    let has_event_id: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('transactions') WHERE name = 'event_id'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if has_event_id == 0 {
+        conn.execute("ALTER TABLE transactions ADD COLUMN event_id TEXT", [])?;
+    }
+
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS transaction_events ON transactions (event_id)",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS rules (
+            id INTEGER PRIMARY KEY,
+            kind TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            period TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS timezones (
+            user_id TEXT PRIMARY KEY,
+            timezone TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS chores (
+            id INTEGER PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            description TEXT NOT NULL,
+            done_at TEXT
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS redactions (
+            event_id TEXT PRIMARY KEY,
+            sender TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Sends that looked off and are waiting on a parent. Denied ones stay, so a replayed
+    // command isn't held all over again.
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS held_sends (
+            id INTEGER PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            receiver TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            memo TEXT,
+            event_id TEXT NOT NULL UNIQUE,
+            reason TEXT NOT NULL,
+            denied INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // Withdrawals from savings waiting on parents, who react to the request to sign off;
+    // approvers is everyone who has so far. Finished ones stay (as done), so a replayed
+    // command isn't asked about all over again.
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS pending_approvals (
+            id INTEGER PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            receiver TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            memo TEXT,
+            event_id TEXT NOT NULL UNIQUE,
+            request_room_id TEXT NOT NULL,
+            request_id TEXT,
+            approvers TEXT NOT NULL DEFAULT '',
+            expires TEXT NOT NULL,
+            nudged TEXT NOT NULL,
+            done INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // what someone's saving up for, shown on their balance card; one each
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS goals (
+            user_id TEXT PRIMARY KEY,
+            amount INTEGER NOT NULL,
+            memo TEXT
+        )",
+        [],
+    )?;
+
+    // everything that looked unusual, as it was told to the parents, for the money
+    // minute
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS anomalies (
+            id INTEGER PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            date TEXT NOT NULL,
+            description TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // announcements that only go out once, like allowance held back, even if we restart
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS notices (
+            key TEXT PRIMARY KEY
+        )",
+        [],
+    )?;
+
+    // parents who get the money minute
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS money_minute (
+            user_id TEXT PRIMARY KEY
+        )",
+        [],
+    )?;
+
+    // Paydays to pass over: any before the until date, which is the day after the one
+    // payday for a skip, or the day it starts again for a pause.
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS allowance_skips (
+            id INTEGER PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            until TEXT NOT NULL,
+            next_only INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+// Handlers, the allowance, and the rules all share one bot. Its queries run on the database's own
+// thread, one at a time, so nothing waits on a lock while a slow message is being answered, and
+// anything that checks and then writes (like a balance before a send) does both in one job.
+type SharedBot = Arc<Bot>;
+
+struct Bot {
+    db: storage::Db,
+}
+
+impl Bot {
+    async fn new() -> anyhow::Result<Bot> {
+        let db_created = !storage::path("moneybot").exists();
+
+        let bot = Bot {
+            db: storage::Db::open("moneybot")?,
+        };
+
+        bot.db.call(|conn| create_tables(conn)).await?;
+
+        if db_created {
+            bot.seed().await?;
+        }
+
+        Ok(bot)
+    }
+
+    async fn seed(self: &Bot) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+
+        // the two seed transactions
+        self.insert(&Transaction {
+            sender: None,
+            receiver: "@gwen:kulak.us".to_string(),
+            amount: 100_000,
+            date: now.to_string(),
+            memo: Some("seed value".to_string()),
+            event_id: None,
+        })
+        .await?;
+
+        self.insert(&Transaction {
+            sender: None,
+            receiver: "@phil:kulak.us".to_string(),
+            amount: 100_000,
+            date: now,
+            memo: Some("seed value".to_string()),
+            event_id: None,
+        })
+        .await?;
+
+        println!("initialized new database");
+
+        Ok(())
+    }
+
+    // returns false if the event has already been recorded
+    pub async fn send(
+        self: &Bot,
+        from: &str,
+        to: &str,
+        amount: i64,
+        memo: Option<&str>,
+        event_id: &str,
+    ) -> anyhow::Result<bool> {
+        self.insert(&Transaction {
+            sender: Some(from.to_string()),
+            receiver: to.to_string(),
+            amount,
+            date: chrono::Utc::now().to_rfc3339(),
+            memo: memo.map(|s| s.to_string()),
+            event_id: Some(event_id.to_string()),
+        })
+        .await
+    }
+
+    // returns false if the event has already been recorded
+    async fn insert(self: &Bot, t: &Transaction) -> anyhow::Result<bool> {
+        let row = t.clone();
+        let id = self.db.call(move |conn| insert(conn, &row)).await?;
+
+        Ok(recorded(id, t))
+    }
+
+    // Inserts the transaction only if it leaves the sender at or above their minimum balance,
+    // checked in the same job so two sends can't both spend the same money. Returns None if there
+    // wasn't enough, otherwise whether the event was new.
+    async fn insert_within_balance(self: &Bot, t: &Transaction) -> anyhow::Result<Option<bool>> {
+        let row = t.clone();
+
+        let id = self
+            .db
+            .call(move |conn| {
+                if let Some(sender) = &row.sender {
+                    if balance(conn, sender)? - row.amount < min_balance(conn, sender)? {
+                        return Ok(None);
+                    }
+                }
+
+                insert(conn, &row).map(Some)
+            })
+            .await?;
+
+        Ok(id.map(|id| recorded(id, t)))
+    }
+
+    async fn event_handled(self: &Bot, event_id: &str) -> anyhow::Result<bool> {
+        let event_id = event_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT COUNT(*) FROM transactions WHERE event_id = ?1")?;
+
+                let total: i64 = stmt.query_row(params![event_id], |row| row.get(0))?;
+
+                Ok(total > 0)
+            })
+            .await
+    }
+
+    // records a notice, returning false if it's already gone out
+    async fn first_notice(self: &Bot, key: &str) -> anyhow::Result<bool> {
+        let key = key.to_string();
+
+        self.db
+            .call(move |conn| {
+                let inserted = conn.execute(
+                    "INSERT OR IGNORE INTO notices (key) VALUES (?1)",
+                    params![key],
+                )?;
+
+                Ok(inserted > 0)
+            })
+            .await
+    }
+
+    // who redacted an event, if anyone has
+    async fn redacted_by(self: &Bot, event_id: &str) -> anyhow::Result<Option<String>> {
+        let event_id = event_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT sender FROM redactions WHERE event_id = ?1",
+                        params![event_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+    }
+
+    // Records a redaction, and if it was of a send that already went through, sends the money back.
+    // Returns the send, and whether it could be undone.
+    async fn record_redaction(
+        self: &Bot,
+        event_id: &str,
+        sender: &UserId,
+    ) -> anyhow::Result<Option<(Transaction, bool)>> {
+        let event_id = event_id.to_string();
+        let redactor = sender.to_string();
+        let admin = matrix::is_admin(sender);
+
+        let undone = self
+            .db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR IGNORE INTO redactions (event_id, sender) VALUES (?1, ?2)",
+                    params![event_id, redactor],
+                )?;
+
+                undo(conn, &event_id, &redactor, admin)
+            })
+            .await?;
+
+        Ok(undone.map(|undone| {
+            let returned = !undone.reversals.is_empty();
+
+            for (id, reversal) in undone.reversals {
+                recorded(Some(id), &reversal);
+            }
+
+            (undone.sent, returned)
+        }))
+    }
+
+    async fn anomaly(
+        self: &Bot,
+        t: &Transaction,
+        sender: &UserId,
+    ) -> anyhow::Result<Option<String>> {
+        let t = t.clone();
+        let savings = savings_account(sender).to_string();
+
+        self.db.call(move |conn| anomaly(conn, &t, &savings)).await
+    }
+
+    // returns the held send's ID, or None if this command was already held
+    async fn hold_send(
+        self: &Bot,
+        room_id: &RoomId,
+        t: &Transaction,
+        reason: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let room_id = room_id.to_string();
+        let reason = reason.to_string();
+        let t = t.clone();
+
+        self.db
+            .call(move |conn| {
+                let inserted = conn.execute(
+                    "
+                    INSERT OR IGNORE INTO held_sends
+                        (room_id, sender, receiver, amount, memo, event_id, reason)
+                    VALUES
+                        (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![room_id, t.sender, t.receiver, t.amount, t.memo, t.event_id, reason],
+                )?;
+
+                Ok((inserted > 0).then(|| conn.last_insert_rowid()))
+            })
+            .await
+    }
+
+    async fn record_anomaly(self: &Bot, user_id: &UserId, description: &str) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+        let description = description.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO anomalies (user_id, date, description) VALUES (?1, ?2, ?3)",
+                    params![user_id, Utc::now().to_rfc3339(), description],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    // the last week, for every kid who gets an allowance
+    async fn money_minute(self: &Bot) -> anyhow::Result<String> {
+        let since = (Utc::now() - chrono::Duration::days(MONEY_MINUTE_DAYS)).to_rfc3339();
+
+        self.db
+            .call(move |conn| {
+                let mut kids = vec![];
+
+                for user_id in ALLOWANCE_USERS {
+                    kids.push(money_minute(conn, &UserId::try_from(*user_id)?, &since)?);
+                }
+
+                Ok(format!(
+                    "Here's your money minute for the week.\n\n{}",
+                    kids.join("\n\n")
+                ))
+            })
+            .await
+    }
+
+    async fn money_minute_subscribers(self: &Bot) -> anyhow::Result<Vec<UserId>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT user_id FROM money_minute ORDER BY user_id")?;
+
+                let user_ids = stmt
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+
+                Ok(user_ids
+                    .iter()
+                    .filter_map(|user_id| UserId::try_from(user_id.as_str()).ok())
+                    .collect())
+            })
+            .await
+    }
+
+    async fn set_money_minute(self: &Bot, user_id: &UserId, on: bool) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                if on {
+                    conn.execute(
+                        "INSERT OR IGNORE INTO money_minute (user_id) VALUES (?1)",
+                        params![user_id],
+                    )?;
+                } else {
+                    conn.execute(
+                        "DELETE FROM money_minute WHERE user_id = ?1",
+                        params![user_id],
+                    )?;
+                }
+
+                Ok(())
+            })
+            .await
+    }
+
+    // takes a held send off the list, approved or not, if it's still waiting
+    async fn take_held(self: &Bot, id: i64, approved: bool) -> anyhow::Result<Option<WaitingSend>> {
+        self.db
+            .call(move |conn| {
+                let held = conn
+                    .query_row(
+                        "SELECT * FROM held_sends WHERE id = ?1 AND denied = 0",
+                        params![id],
+                        WaitingSend::from_row,
+                    )
+                    .optional()?;
+
+                if held.is_some() {
+                    if approved {
+                        conn.execute("DELETE FROM held_sends WHERE id = ?1", params![id])?;
+                    } else {
+                        conn.execute(
+                            "UPDATE held_sends SET denied = 1 WHERE id = ?1",
+                            params![id],
+                        )?;
+                    }
+                }
+
+                Ok(held)
+            })
+            .await
+    }
+
+    // returns the pending withdrawal's ID, or None if this command was already asked about
+    async fn request_approval(
+        self: &Bot,
+        room_id: &RoomId,
+        request_room_id: &RoomId,
+        t: &Transaction,
+        expires: DateTime<Utc>,
+    ) -> anyhow::Result<Option<i64>> {
+        let room_id = room_id.to_string();
+        let request_room_id = request_room_id.to_string();
+        let now = Utc::now().to_rfc3339();
+        let t = t.clone();
+
+        self.db
+            .call(move |conn| {
+                let inserted = conn.execute(
+                    "
+                    INSERT OR IGNORE INTO pending_approvals
+                        (room_id, sender, receiver, amount, memo, event_id, request_room_id,
+                            expires, nudged)
+                    VALUES
+                        (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        room_id,
+                        t.sender,
+                        t.receiver,
+                        t.amount,
+                        t.memo,
+                        t.event_id,
+                        request_room_id,
+                        expires.to_rfc3339(),
+                        now
+                    ],
+                )?;
+
+                Ok((inserted > 0).then(|| conn.last_insert_rowid()))
+            })
+            .await
+    }
+
+    async fn set_request(self: &Bot, id: i64, request_id: &str) -> anyhow::Result<()> {
+        let request_id = request_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE pending_approvals SET request_id = ?1 WHERE id = ?2",
+                    params![request_id, id],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn approve(
+        self: &Bot,
+        request_id: &str,
+        approver: &UserId,
+    ) -> anyhow::Result<Option<(PendingApproval, bool)>> {
+        let request_id = request_id.to_string();
+        let approver = approver.to_string();
+
+        self.db
+            .call(move |conn| approve(conn, &request_id, &approver))
+            .await
+    }
+
+    // takes a pending withdrawal off the list, if it's still waiting
+    async fn reject(self: &Bot, request_id: &str) -> anyhow::Result<Option<PendingApproval>> {
+        let request_id = request_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                let pending = conn
+                    .query_row(
+                        "SELECT * FROM pending_approvals WHERE request_id = ?1 AND done = 0",
+                        params![request_id],
+                        PendingApproval::from_row,
+                    )
+                    .optional()?;
+
+                if let Some(pending) = &pending {
+                    conn.execute(
+                        "UPDATE pending_approvals SET done = 1 WHERE id = ?1",
+                        params![pending.send.id],
+                    )?;
+                }
+
+                Ok(pending)
+            })
+            .await
+    }
+
+    // takes every withdrawal that's waited too long off the list
+    async fn expire_approvals(self: &Bot) -> anyhow::Result<Vec<PendingApproval>> {
+        let now = Utc::now().to_rfc3339();
+
+        self.db
+            .call(move |conn| {
+                let expired: Vec<PendingApproval> = conn
+                    .prepare("SELECT * FROM pending_approvals WHERE done = 0 AND expires <= ?1")?
+                    .query_map(params![now], PendingApproval::from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+
+                for pending in &expired {
+                    conn.execute(
+                        "UPDATE pending_approvals SET done = 1 WHERE id = ?1",
+                        params![pending.send.id],
+                    )?;
+                }
+
+                Ok(expired)
+            })
+            .await
+    }
+
+    // the withdrawals nobody's been reminded of for a while, marked as reminded now
+    async fn nudge_approvals(self: &Bot) -> anyhow::Result<Vec<PendingApproval>> {
+        let now = Utc::now();
+        let since = (now - chrono::Duration::hours(REMINDER_HOURS)).to_rfc3339();
+        let now = now.to_rfc3339();
+
+        self.db
+            .call(move |conn| {
+                let due: Vec<PendingApproval> = conn
+                    .prepare(
+                        "
+                        SELECT *
+                        FROM pending_approvals
+                        WHERE done = 0 AND request_id IS NOT NULL AND nudged <= ?1
+                    ",
+                    )?
+                    .query_map(params![since], PendingApproval::from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+
+                for pending in &due {
+                    conn.execute(
+                        "UPDATE pending_approvals SET nudged = ?1 WHERE id = ?2",
+                        params![now, pending.send.id],
+                    )?;
+                }
+
+                Ok(due)
+            })
+            .await
+    }
+
+    async fn get_balance(self: &Bot, user_id: &UserId) -> anyhow::Result<Money<'_, Currency>> {
+        let user_id = user_id.to_string();
+        let balance = self.db.call(move |conn| balance(conn, &user_id)).await?;
+
+        Ok(Money::from_minor(balance, iso::USD))
+    }
+
+    async fn get_min_balance(self: &Bot, user_id: &UserId) -> anyhow::Result<Money<'_, Currency>> {
+        let user_id = user_id.to_string();
+        let min = self
+            .db
+            .call(move |conn| min_balance(conn, &user_id))
+            .await?;
+
+        Ok(Money::from_minor(min, iso::USD))
+    }
+
+    async fn get_ledger(self: &Bot, user_id: &UserId) -> anyhow::Result<Vec<Transaction>> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "
+                        SELECT *
+                        FROM transactions
+                        WHERE receiver = ?1 OR sender = ?1
+                        ORDER BY date DESC LIMIT 5
+                    ",
+                )?;
+
+                let res = stmt.query_map(params![user_id], |row| {
+                    Ok(Transaction {
+                        sender: row.get("sender")?,
+                        receiver: row.get("receiver")?,
+                        amount: row.get("amount")?,
+                        date: row.get("date")?,
+                        memo: row.get("memo")?,
+                        event_id: row.get("event_id")?,
+                    })
+                })?;
+
+                Ok(res.into_iter().map(|row| row.unwrap()).collect())
+            })
+            .await
+    }
+
+    async fn set_min_balance(self: &Bot, user_id: &UserId, min_balance: i64) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "
+                    INSERT INTO users
+                        (user_id, min_balance)
+                    VALUES
+                        (?1, ?2)
+                    ON CONFLICT(user_id) DO UPDATE SET min_balance=?2",
+                    params![user_id, min_balance],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    // how much someone's saving up, in cents, and what for
+    async fn get_goal(
+        self: &Bot,
+        user_id: &UserId,
+    ) -> anyhow::Result<Option<(i64, Option<String>)>> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                let goal = conn
+                    .query_row(
+                        "SELECT amount, memo FROM goals WHERE user_id = ?1",
+                        params![user_id],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+
+                Ok(goal)
+            })
+            .await
+    }
+
+    async fn set_goal(
+        self: &Bot,
+        user_id: &UserId,
+        goal: Option<(i64, Option<String>)>,
+    ) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                match goal {
+                    Some((amount, memo)) => conn.execute(
+                        "
+                        INSERT INTO goals
+                            (user_id, amount, memo)
+                        VALUES
+                            (?1, ?2, ?3)
+                        ON CONFLICT(user_id) DO UPDATE SET amount=?2, memo=?3",
+                        params![user_id, amount, memo],
+                    )?,
+                    None => {
+                        conn.execute("DELETE FROM goals WHERE user_id = ?1", params![user_id])?
+                    }
+                };
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn id_exists(self: &Bot, user_id: &UserId) -> anyhow::Result<bool> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn
+                    .prepare(
+                        "
+                        SELECT COUNT(*)
+                        FROM transactions
+                        WHERE sender = ?1 OR receiver = ?1
+                    ",
+                    )
+                    .unwrap();
+
+                let total: i64 = stmt.query_row(params![user_id], |row| row.get(0)).unwrap();
+
+                Ok(total > 0)
+            })
+            .await
+    }
+
+    async fn get_balance_before(
+        self: &Bot,
+        user_id: &UserId,
+        date: &DateTime<chrono_tz::Tz>,
+    ) -> anyhow::Result<Money<'_, Currency>> {
+        let user_id = user_id.to_string();
+        let date = date.with_timezone(&Utc).to_rfc3339();
+
+        let balance: i64 = self
+            .db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "
+                        SELECT
+                            COALESCE(SUM(CASE WHEN receiver = ?1 THEN amount ELSE 0 END), 0) -
+                            COALESCE(SUM(CASE WHEN sender = ?1 THEN amount ELSE 0 END), 0)
+                        FROM transactions
+                        WHERE (receiver = ?1 OR sender = ?1) AND date < ?2
+                    ",
+                )?;
+
+                Ok(stmt.query_row(params![user_id, date], |row| row.get(0))?)
+            })
+            .await?;
+
+        Ok(Money::from_minor(balance, iso::USD))
+    }
+
+    async fn get_transactions_between(
+        self: &Bot,
+        user_id: &UserId,
+        start: &DateTime<chrono_tz::Tz>,
+        end: &DateTime<chrono_tz::Tz>,
+    ) -> anyhow::Result<Vec<Transaction>> {
+        let user_id = user_id.to_string();
+        let start = start.with_timezone(&Utc).to_rfc3339();
+        let end = end.with_timezone(&Utc).to_rfc3339();
+
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "
+                        SELECT *
+                        FROM transactions
+                        WHERE (receiver = ?1 OR sender = ?1) AND date >= ?2 AND date < ?3
+                        ORDER BY date
+                    ",
+                )?;
+
+                let res = stmt.query_map(params![user_id, start, end], |row| {
+                    Ok(Transaction {
+                        sender: row.get("sender")?,
+                        receiver: row.get("receiver")?,
+                        amount: row.get("amount")?,
+                        date: row.get("date")?,
+                        memo: row.get("memo")?,
+                        event_id: row.get("event_id")?,
+                    })
+                })?;
+
+                Ok(res.into_iter().map(|row| row.unwrap()).collect())
+            })
+            .await
+    }
+
+    async fn get_rules(self: &Bot) -> anyhow::Result<Vec<Rule>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT * FROM rules ORDER BY id")?;
+
+                let res = stmt.query_map([], |row| {
+                    Ok(Rule {
+                        id: row.get("id")?,
+                        kind: row.get("kind")?,
+                        user_id: row.get("user_id")?,
+                        amount: row.get("amount")?,
+                        period: row.get("period")?,
+                    })
+                })?;
+
+                Ok(res.into_iter().map(|row| row.unwrap()).collect())
+            })
+            .await
+    }
+
+    async fn add_rule(
+        self: &Bot,
+        kind: &str,
+        user_id: &UserId,
+        amount: i64,
+        period: Period,
+    ) -> anyhow::Result<()> {
+        let kind = kind.to_string();
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "
+                    INSERT INTO rules
+                        (kind, user_id, amount, period)
+                    VALUES
+                        (?1, ?2, ?3, ?4)",
+                    params![kind, user_id, amount, period.name()],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete_rule(self: &Bot, id: i64) -> anyhow::Result<bool> {
+        self.db
+            .call(move |conn| {
+                let deleted = conn.execute("DELETE FROM rules WHERE id = ?1", params![id])?;
+
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    // the user's own timezone, or the default
+    async fn get_timezone(self: &Bot, user_id: &UserId) -> anyhow::Result<Tz> {
+        let user_id = user_id.to_string();
+
+        let tz: Option<String> = self
+            .db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT timezone FROM timezones WHERE user_id = ?1",
+                        params![user_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+
+        Ok(tz
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or_else(scheduler::timezone))
+    }
+
+    async fn set_timezone(self: &Bot, user_id: &UserId, tz: Tz) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "
+                    INSERT INTO timezones
+                        (user_id, timezone)
+                    VALUES
+                        (?1, ?2)
+                    ON CONFLICT(user_id) DO UPDATE SET timezone=?2",
+                    params![user_id, tz.name()],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    // what's keeping someone from being paid on the given day, if anything
+    async fn allowance_skip(
+        self: &Bot,
+        user_id: &UserId,
+        date: NaiveDate,
+    ) -> anyhow::Result<Option<AllowanceSkip>> {
+        let user_id = user_id.to_string();
+        let date = date.format("%Y-%m-%d").to_string();
+
+        let skip: Option<(String, bool)> = self
+            .db
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "
+                        SELECT until, next_only
+                        FROM allowance_skips
+                        WHERE user_id = ?1 AND until > ?2
+                        ORDER BY until DESC
+                        LIMIT 1",
+                        params![user_id, date],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await?;
+
+        Ok(match skip {
+            Some((until, next_only)) => Some(AllowanceSkip {
+                until: NaiveDate::parse_from_str(&until, "%Y-%m-%d")?,
+                next_only,
+            }),
+            None => None,
+        })
+    }
+
+    async fn skip_allowance(
+        self: &Bot,
+        user_id: &UserId,
+        until: NaiveDate,
+        next_only: bool,
+    ) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+        let until = until.format("%Y-%m-%d").to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "
+                    INSERT INTO allowance_skips
+                        (user_id, until, next_only)
+                    VALUES
+                        (?1, ?2, ?3)",
+                    params![user_id, until, next_only],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn resume_allowance(self: &Bot, user_id: &UserId) -> anyhow::Result<usize> {
+        let user_id = user_id.to_string();
+
+        self.db
+            .call(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM allowance_skips WHERE user_id = ?1",
+                    params![user_id],
+                )?)
+            })
+            .await
+    }
+
+    async fn get_chores(self: &Bot) -> anyhow::Result<Vec<Chore>> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT * FROM chores ORDER BY id")?;
+
+                let res = stmt.query_map([], |row| {
+                    Ok(Chore {
+                        id: row.get("id")?,
+                        user_id: row.get("user_id")?,
+                        description: row.get("description")?,
+                        done_at: row.get("done_at")?,
+                    })
+                })?;
+
+                Ok(res.into_iter().map(|row| row.unwrap()).collect())
+            })
+            .await
+    }
+
+    async fn add_chore(self: &Bot, user_id: &UserId, description: &str) -> anyhow::Result<()> {
+        let user_id = user_id.to_string();
+        let description = description.to_string();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO chores (user_id, description) VALUES (?1, ?2)",
+                    params![user_id, description],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn complete_chore(self: &Bot, id: i64) -> anyhow::Result<()> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE chores SET done_at = ?1 WHERE id = ?2",
+                    params![Utc::now().to_rfc3339(), id],
+                )?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete_chore(self: &Bot, id: i64) -> anyhow::Result<bool> {
+        self.db
+            .call(move |conn| {
+                let deleted = conn.execute("DELETE FROM chores WHERE id = ?1", params![id])?;
+
+                Ok(deleted > 0)
+            })
+            .await
+    }
+
+    // the chores a user hasn't done since the given time
+    async fn undone_chores(
+        self: &Bot,
+        user_id: &UserId,
+        since: &DateTime<chrono_tz::Tz>,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .get_chores()
+            .await?
+            .into_iter()
+            .filter(|c| c.user_id == user_id.as_str() && !c.done_since(since))
+            .map(|c| c.description)
+            .collect())
+    }
+
+    async fn sent_since(
+        self: &Bot,
+        user_id: &UserId,
+        since: &DateTime<chrono_tz::Tz>,
+    ) -> anyhow::Result<bool> {
+        let user_id = user_id.to_string();
+        let since = since.with_timezone(&Utc).to_rfc3339();
+
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "
+                        SELECT COUNT(*)
+                        FROM transactions
+                        WHERE sender = ?1 AND date >= ?2
+                    ",
+                )?;
+
+                let total: i64 = stmt.query_row(params![user_id, since], |row| row.get(0))?;
+
+                Ok(total > 0)
+            })
+            .await
+    }
+
+    // Rounds a send up to the next dollar if the sender has a round-up rule, moving the change to
+    // their savings as its own transaction. Returns how much was moved, if anything.
+    async fn round_up(
+        self: &Bot,
+        sender: &UserId,
+        receiver: &UserId,
+        amount: i64,
+        event_id: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let change = (100 - amount % 100) % 100;
+
+        if amount <= 0 || change == 0 || *receiver == savings_account(sender) {
+            return Ok(None);
+        }
+
+        let rounds_up = self
+            .get_rules()
+            .await?
+            .iter()
+            .any(|rule| rule.kind == "roundup" && rule.user_id == sender.as_str());
+
+        if !rounds_up {
+            return Ok(None);
+        }
+
+        // it's only spare change, so it's skipped rather than dipping below the minimum
+        let sent = self
+            .insert_within_balance(&Transaction {
+                sender: Some(sender.to_string()),
+                receiver: savings_account(sender).to_string(),
+                amount: change,
+                date: Utc::now().to_rfc3339(),
+                memo: Some("round-up".to_string()),
+                event_id: Some(format!("roundup:{}", event_id)),
+            })
+            .await?;
+
+        Ok(match sent {
+            Some(true) => Some(change),
+            _ => None,
+        })
+    }
+
+    // runs every rule for the period, returning a line for each one that did something
+    async fn apply_rules(self: &Bot, period: Period) -> anyhow::Result<Vec<String>> {
+        let mut results = vec![];
+        let today = scheduler::now().format("%Y-%m-%d").to_string();
+
+        for rule in self.get_rules().await? {
+            if Period::parse(&rule.period) != Some(period) {
+                continue;
+            }
+
+            let user_id = matrix::create_user_id(&rule.user_id)?;
+            let balance = matrix::money_to_i64(&self.get_balance(&user_id).await?);
+
+            match rule.kind.as_str() {
+                "sweep" if balance > rule.amount => {
+                    let excess = balance - rule.amount;
+                    let savings = savings_account(&user_id);
+
+                    let sent = self
+                        .send(
+                            user_id.as_str(),
+                            savings.as_str(),
+                            excess,
+                            Some("sweep to savings"),
+                            &format!("rule:{}:{}", rule.id, today),
+                        )
+                        .await?;
+
+                    if !sent {
+                        continue;
+                    }
+
+                    results.push(format!(
+                        "Swept {} from {} to savings.",
+                        Money::from_minor(excess, iso::USD),
+                        pretty_account(&user_id)
+                    ));
+                }
+                "fee" => {
+                    let since = match period {
+                        Period::Weekly => scheduler::now() - chrono::Duration::days(7),
+                        Period::Monthly => scheduler::add_months(scheduler::now(), -1),
+                        // fees only ever run on a schedule
+                        Period::EverySend => continue,
+                    };
+
+                    let min = matrix::money_to_i64(&self.get_min_balance(&user_id).await?);
+
+                    if self.sent_since(&user_id, &since).await? || balance - rule.amount < min {
+                        continue;
+                    }
+
+                    let sent = self
+                        .send(
+                            user_id.as_str(),
+                            BANK,
+                            rule.amount,
+                            Some("inactivity fee"),
+                            &format!("rule:{}:{}", rule.id, today),
+                        )
+                        .await?;
+
+                    if !sent {
+                        continue;
+                    }
+
+                    results.push(format!(
+                        "Charged {} an inactivity fee of {}.",
+                        pretty_account(&user_id),
+                        Money::from_minor(rule.amount, iso::USD)
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(results)
+    }
+
+    // a plain text and HTML statement of everything that happened between the two dates
+    async fn statement(
+        self: &Bot,
+        user_id: &UserId,
+        start: DateTime<chrono_tz::Tz>,
+        end: DateTime<chrono_tz::Tz>,
+    ) -> anyhow::Result<(String, String)> {
+        let opening = self.get_balance_before(user_id, &start).await?;
+        let closing = self.get_balance_before(user_id, &end).await?;
+        let transactions = self.get_transactions_between(user_id, &start, &end).await?;
+        let tz = self.get_timezone(user_id).await?;
+
+        let title = format!("{} for {}", pretty_account(user_id), start.format("%B %Y"));
+
+        let mut txt_builder = Builder::default();
+        let mut html_builder = Builder::default();
+
+        txt_builder.append(format!("{}\n\nOpening balance: {}\n\n", title, opening));
+        html_builder.append(format!(
+            "<h2>{}</h2><p>Opening balance: {}</p>",
+            title, opening
+        ));
+
+        html_builder.append("<table>");
+        html_builder.append("<tr><th>Date</th><th>Amount</th><th>To/From</th><th>For</th></tr>");
+
+        for tr in transactions {
+            let (user, amount) = if tr.receiver == user_id.as_str() {
+                (tr.sender, tr.amount)
+            } else {
+                (Some(tr.receiver), -tr.amount)
+            };
+
+            let user = user
+                .map(|u| pretty_account(&matrix::create_user_id(&u).unwrap()))
+                .unwrap_or_default();
+
+            let date = tz
+                .timestamp_millis(DateTime::<Utc>::from_str(&tr.date)?.timestamp_millis())
+                .format("%b %d");
+
+            let amount = Money::from_minor(amount, iso::USD);
+            let memo = tr.memo.unwrap_or_default();
+
+            txt_builder.append(format!("{}  {}  {}  {}\n", date, amount, user, memo));
+            html_builder.append(format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                date, amount, user, memo
+            ));
+        }
+
+        html_builder.append("</table>");
+
+        txt_builder.append(format!("\nClosing balance: {}\n", closing));
+        html_builder.append(format!("<p>Closing balance: {}</p>", closing));
+
+        Ok((txt_builder.string()?, html_builder.string()?))
+    }
+
+    async fn on_room_message(
+        self: &Bot,
+        event: SyncMessageEvent<MessageEventContent>,
+        room: Room,
+        client: Client,
+    ) -> anyhow::Result<()> {
+        if !matrix::routes_to(&client, "money", room.room_id(), &event).await {
+            return Ok(());
+        }
+
+        let mentions = matrix::get_mentions(&event);
+        let event_id = event.event_id.to_string();
+
+        if let Some((room, sender, message)) =
+            matrix::get_text_message(event, room, client.clone()).await
+        {
+            if let Some(command) = matrix::get_command("balance", &message) {
+                self.on_balance_message(&client, room, sender, command)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("send", &message) {
+                self.on_send_message(&client, room, sender, command, mentions, &event_id)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("withdraw", &message) {
+                self.on_withdraw_message(&client, room, sender, command, &event_id)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("approve", &message) {
+                self.on_review_message(&client, room, sender, command, true)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("deny", &message) {
+                self.on_review_message(&client, room, sender, command, false)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("goal", &message) {
+                self.on_goal_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("money minute", &message) {
+                self.on_money_minute_message(&client, room, sender, command)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("set min", &message) {
+                self.on_set_min_balance_message(room, sender, command)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("get min", &message) {
+                self.on_get_min_balance_message(room, command).await?;
+            } else if let Some(command) = matrix::get_command("statement", &message) {
+                self.on_statement_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("ledger", &message) {
+                self.on_ledger_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("savings", &message) {
+                self.on_savings_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("rule", &message) {
+                self.on_rule_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("rules", &message) {
+                self.on_rules_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("timezone", &message) {
+                self.on_timezone_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("chores", &message) {
+                self.on_chores_message(room, sender, command).await?;
+            } else if let Some(command) = matrix::get_command("skip next allowance", &message) {
+                self.on_skip_allowance_message(room, sender, command)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("pause allowance", &message) {
+                self.on_pause_allowance_message(room, sender, command)
+                    .await?;
+            } else if let Some(command) = matrix::get_command("resume allowance", &message) {
+                self.on_resume_allowance_message(room, sender, command)
+                    .await?;
+            } else if matrix::get_command("help", &message).is_some() && !commands::unified() {
+                self.on_help_message(room).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn on_balance_message(
+        self: &Bot,
+        client: &Client,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let sender = matrix::normalize_sender(sender, command)?;
+        let balance = self.get_balance(&sender).await?;
+
+        if balance_cards(client, room.room_id()).await {
+            let goal = self.get_goal(&sender).await?;
+            let name = matrix::pretty_user_id(&sender);
+            let text = format!("{}", balance);
+
+            let goal = goal.map(|(amount, memo)| {
+                let label = format!(
+                    "{}: {}",
+                    memo.unwrap_or("Goal".to_string()),
+                    Money::from_minor(amount, iso::USD)
+                );
+
+                let progress = matrix::money_to_i64(&balance) as f64 / amount as f64;
+
+                (label, progress)
+            });
+
+            let (jpeg, width, height) = task::spawn_blocking({
+                let text = text.clone();
+
+                move || {
+                    let goal = goal.as_ref().map(|(label, progress)| image::Goal {
+                        label,
+                        progress: *progress,
+                    });
+
+                    image::balance_card(&name, &text, goal)
+                }
+            })
+            .await??;
+
+            matrix::send_image(client, &room, &text, &jpeg, width, height).await?;
+        } else {
+            matrix::send(&room, text_plain(&format!("{}", balance))).await?;
+        }
+
+        Ok(())
+    }
+
+    // "goal $150 for a bike" sets what you're saving up for, "goal off" clears it, and "goal" on
+    // its own says what it is
+    async fn on_goal_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let response = match command.to_lowercase().as_str() {
+            "" => match self.get_goal(&sender).await? {
+                Some((amount, memo)) => {
+                    let amount = Money::from_minor(amount, iso::USD);
+
+                    match memo {
+                        Some(memo) => format!("You're saving {} for {}.", amount, memo),
+                        None => format!("You're saving up {}.", amount),
+                    }
+                }
+                None => "You don't have a goal yet.".to_string(),
+            },
+            "off" | "clear" | "none" => {
+                self.set_goal(&sender, None).await?;
+                "Okay, no more goal.".to_string()
+            }
+            _ => {
+                let parsed = parse_send(command);
+
+                match parsed.amount {
+                    Some(amount) if amount > Decimal::ZERO => {
+                        let amount = Money::from_decimal(amount, iso::USD);
+                        let goal = (matrix::money_to_i64(&amount), parsed.memo);
+                        self.set_goal(&sender, Some(goal)).await?;
+
+                        format!("Good luck saving up {}!", amount)
+                    }
+                    _ => usage("goal"),
+                }
+            }
+        };
+
+        matrix::send(&room, text_plain(&response)).await?;
+        Ok(())
+    }
+
+    // "money minute on" (or "off") signs a parent up for the weekly summary, and "money minute" on
+    // its own sends this week's right away
+    async fn on_money_minute_message(
+        self: &Bot,
+        client: &Client,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        if !matrix::is_admin(&sender) {
+            matrix::send(
+                &room,
+                text_plain("You are not allowed to get the money minute."),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let response = match command.to_lowercase().as_str() {
+            "on" => {
+                self.set_money_minute(&sender, true).await?;
+                "You'll get the money minute every week.".to_string()
+            }
+            "off" => {
+                self.set_money_minute(&sender, false).await?;
+                "No more money minute.".to_string()
+            }
+            "" => {
+                let summary = self.money_minute().await?;
+                let direct = matrix::direct_room(client, &sender).await?;
+                matrix::send(&direct, text_plain(&summary)).await?;
+
+                if direct.room_id() == room.room_id() {
+                    return Ok(());
+                }
+
+                "I sent it to you directly.".to_string()
+            }
+            _ => usage("money minute"),
+        };
+
+        matrix::send(&room, text_plain(&response)).await?;
+        Ok(())
+    }
+
+    async fn on_savings_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let sender = matrix::normalize_sender(sender, command)?;
+        let balance = self.get_balance(&savings_account(&sender)).await?;
+        matrix::send(&room, text_plain(&format!("{}", balance))).await?;
+        Ok(())
+    }
+
+    async fn on_send_message(
+        self: &Bot,
+        client: &Client,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+        mentions: Vec<(UserId, String)>,
+        event_id: &str,
+    ) -> anyhow::Result<()> {
+        // the sync can replay events after a crash
+        if self.event_handled(event_id).await? {
+            println!("already handled send {}", event_id);
+            return Ok(());
+        }
+
+        // the sender took it back before we got to it
+        if self.redacted_by(event_id).await?.as_deref() == Some(sender.as_str()) {
+            println!("skipping redacted send {}", event_id);
+            return Ok(());
+        }
+
+        let parsed = parse_send(command);
+
+        // pills are more reliable than whatever display name ended up in the body, but only the one
+        // actually standing in for the receiver; anyone mentioned in the memo is just mentioned
+        let mention = parsed
+            .receiver
+            .as_deref()
+            .and_then(|receiver| mentioned_receiver(&mentions, receiver));
+
+        let receiver = match (mention, &parsed.receiver) {
+            (Some(mention), _) => mention,
+            (None, Some(receiver)) if receiver == "savings" => savings_account(&sender),
+            (None, Some(receiver)) => matrix::create_user_id(receiver)?,
+            (None, None) => {
+                println!("invalid send command {}", command);
+                matrix::send(&room, text_plain(&usage("send"))).await?;
+                return Ok(());
+            }
+        };
+
+        let amount = match parsed.amount {
+            Some(amount) => Money::from_decimal(amount, iso::USD),
+            None => {
+                matrix::send(&room, text_plain("Please use a valid amount.")).await?;
+                return Ok(());
+            }
+        };
+
+        if amount.is_negative() && !matrix::is_admin(&sender) {
+            matrix::send(
+                &room,
+                text_plain("You are not allowed to take money, only send it."),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        if amount.is_zero() {
+            matrix::send(&room, text_plain("Wait... what's the point of that?")).await?;
+            return Ok(());
+        }
+
+        if !self.id_exists(&receiver).await?
+            && !matrix::is_admin(&sender)
+            && receiver != savings_account(&sender)
+        {
+            matrix::send(
+                &room,
+                text_plain(&format!("{} isn't a valid user.", receiver.localpart())),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        if sender == receiver {
+            matrix::send(
+                &room,
+                text_plain("So... you want to send money to yourself, from yourself?"),
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let memo = parsed.memo;
+
+        let transaction = Transaction {
+            sender: Some(sender.to_string()),
+            receiver: receiver.to_string(),
+            amount: matrix::money_to_i64(&amount),
+            date: chrono::Utc::now().to_rfc3339(),
+            memo: memo.clone(),
+            event_id: Some(event_id.to_string()),
+        };
+
+        // anything unusual goes by a parent, either as a heads up or to wait for their say-so
+        if let (Some(admin_room), false) = (anomaly_room(), matrix::is_admin(&sender)) {
+            if let Some(reason) = self.anomaly(&transaction, &sender).await? {
+                let described = format!(
+                    "{} sending {} to {} ({})",
+                    pretty_account(&sender),
+                    amount,
+                    pretty_account(&receiver),
+                    reason
+                );
+
+                self.record_anomaly(&sender, &described).await?;
+
+                if hold_anomalies() {
+                    let id = match self
+                        .hold_send(room.room_id(), &transaction, &reason)
+                        .await?
+                    {
+                        Some(id) => id,
+                        None => return Ok(()),
+                    };
+
+                    let alert = format!(
+                        "Holding {}. Say \"approve {}\" or \"deny {}\".",
+                        described, id, id
+                    );
+                    matrix::send_to(client, &admin_room, text_plain(&alert)).await?;
+
+                    matrix::send(
+                        &room,
+                        text_plain("That's not like you, so I've asked a parent to look it over."),
+                    )
+                    .await?;
+
+                    return Ok(());
+                }
+
+                let alert = format!("Heads up: {}.", described);
+                matrix::send_to(client, &admin_room, text_plain(&alert)).await?;
+            }
+        }
+
+        let sent = self.insert_within_balance(&transaction).await?;
+
+        if sent.is_none() {
+            matrix::send(&room, text_plain("You don't have enough money!")).await?;
+            return Ok(());
+        }
+
+        let pretty_id = pretty_account(&receiver);
+
+        let mut confirmation = match memo {
+            Some(memo) => format!("Sent {} to {} for {}.", amount, pretty_id, memo),
+            None => format!("Sent {} to {}.", amount, pretty_id),
+        };
+
+        let rounded = self
+            .round_up(&sender, &receiver, transaction.amount, event_id)
+            .await?;
+
+        if let Some(change) = rounded {
+            confirmation.push_str(&format!(
+                " Rounded up {} to savings.",
+                Money::from_minor(change, iso::USD)
+            ));
+        }
+
+        // in quiet mode, just check off the message and leave the details in a thread
+        if quiet() {
+            let balance = self.get_balance(&sender).await?;
+            let receipt = format!("{} Your balance is now {}.", confirmation, balance);
+
+            matrix::react(&room, event_id, "✅").await?;
+            matrix::send_thread_reply(&room, event_id, &receipt).await?;
+        } else {
+            matrix::send(&room, text_plain(&confirmation)).await?;
+        }
+
+        Ok(())
+    }
+
+    // a parent's call on a held send; approved ones go out now, if there's still enough money
+    async fn on_review_message(
+        self: &Bot,
+        client: &Client,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+        approved: bool,
+    ) -> anyhow::Result<()> {
+        if !matrix::is_admin(&sender) {
+            matrix::send(&room, text_plain("You are not allowed to review sends.")).await?;
+            return Ok(());
+        }
+
+        let held = match command.trim().parse() {
+            Ok(id) => self.take_held(id, approved).await?,
+            Err(_) => {
+                let verb = if approved { "approve" } else { "deny" };
+                matrix::send(&room, text_plain(&usage(verb))).await?;
+                return Ok(());
+            }
+        };
+
+        let held = match held {
+            Some(held) => held,
+            None => {
+                matrix::send(
+                    &room,
+                    text_plain("There's no send waiting with that number."),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        let message = if !approved {
+            format!("A parent said no to this: {}", held.describe("Send"))
+        } else {
+            let sent = self
+                .insert_within_balance(&Transaction {
+                    sender: Some(held.sender.clone()),
+                    receiver: held.receiver.clone(),
+                    amount: held.amount,
+                    date: Utc::now().to_rfc3339(),
+                    memo: held.memo.clone(),
+                    event_id: Some(held.event_id.clone()),
+                })
+                .await?;
+
+            match sent {
+                Some(_) => held.describe("Sent"),
+                None => format!(
+                    "{} doesn't have enough money for this anymore: {}",
+                    pretty_account(&matrix::create_user_id(&held.sender)?),
+                    held.describe("Send")
+                ),
+            }
+        };
+
+        matrix::send(&room, text_plain(&message)).await?;
+
+        if room.room_id().as_str() != held.room_id {
+            matrix::send_to(
+                client,
+                &RoomId::try_from(held.room_id.as_str())?,
+                text_plain(&message),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    // Moves money out of savings. Anything over MONEY_APPROVAL_LIMIT waits for parents to sign
+    // off, unless it's a parent asking.
+    async fn on_withdraw_message(
+        self: &Bot,
+        client: &Client,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+        event_id: &str,
+    ) -> anyhow::Result<()> {
+        // the sync can replay events after a crash
+        if self.event_handled(event_id).await? {
+            println!("already handled withdrawal {}", event_id);
+            return Ok(());
+        }
+
+        if self.redacted_by(event_id).await?.as_deref() == Some(sender.as_str()) {
+            println!("skipping redacted withdrawal {}", event_id);
+            return Ok(());
+        }
+
+        let parsed = parse_send(command);
+
+        let amount = match parsed.amount {
+            Some(amount) if amount > Decimal::ZERO => Money::from_decimal(amount, iso::USD),
+            _ => {
+                matrix::send(&room, text_plain(&usage("withdraw"))).await?;
+                return Ok(());
+            }
+        };
+
+        let savings = savings_account(&sender);
+
+        let transaction = Transaction {
+            sender: Some(savings.to_string()),
+            receiver: sender.to_string(),
+            amount: matrix::money_to_i64(&amount),
+            date: Utc::now().to_rfc3339(),
+            memo: parsed.memo,
+            event_id: Some(event_id.to_string()),
+        };
+
+        // no sense asking anyone to sign off on money that isn't there
+        if matrix::money_to_i64(&self.get_balance(&savings).await?) < transaction.amount {
+            matrix::send(&room, text_plain("You don't have that much in savings!")).await?;
+            return Ok(());
+        }
+
+        let needs_approval = approval_limit().is_some_and(|limit| transaction.amount > limit)
+            && !matrix::is_admin(&sender);
+
+        if needs_approval {
+            let request_room = anomaly_room()
+                .and_then(|room_id| client.get_joined_room(&room_id))
+                .unwrap_or_else(|| room.clone());
+
+            let id = match self
+                .request_approval(
+                    room.room_id(),
+                    request_room.room_id(),
+                    &transaction,
+                    Utc::now() + chrono::Duration::hours(approval_hours()),
+                )
+                .await?
+            {
+                Some(id) => id,
+                None => return Ok(()),
+            };
+
+            let what = match &transaction.memo {
+                Some(memo) => format!("{} out of savings for {}", amount, memo),
+                None => format!("{} out of savings", amount),
+            };
+
+            let request = format!(
+                "{} wants to take {}. {} parents need to react with {} within {} hours to let it \
+                through, or {} to say no.",
+                pretty_account(&sender),
+                what,
+                APPROVALS_NEEDED,
+                APPROVE_KEY,
+                approval_hours(),
+                REJECT_KEY
+            );
+
+            let response = matrix::send(&request_room, text_plain(&request)).await?;
+            let request_id = response.event_id.to_string();
+            self.set_request(id, &request_id).await?;
+
+            // seed the reactions so signing off is a single tap
+            for key in [APPROVE_KEY, REJECT_KEY] {
+                matrix::react(&request_room, &request_id, key).await?;
+            }
+
+            if request_room.room_id() != room.room_id() {
+                matrix::send(
+                    &room,
+                    text_plain("That's a big one, so I've asked the parents to sign off on it."),
+                )
                 .await?;
+            }
+
+            return Ok(());
+        }
+
+        let message = match self.insert_within_balance(&transaction).await? {
+            Some(_) => format!("Moved {} from savings.", amount),
+            None => "You don't have that much in savings!".to_string(),
+        };
+
+        matrix::send(&room, text_plain(&message)).await?;
+
+        Ok(())
+    }
+
+    // a parent's thumbs up (or down) on a withdrawal that's waiting on them
+    async fn on_reaction(
+        self: &Bot,
+        event: SyncMessageEvent<ReactionEventContent>,
+        client: Client,
+    ) -> anyhow::Result<()> {
+        let sender = matrix::canonical_user_id(&event.sender);
+
+        if !matrix::is_admin(&sender) {
             return Ok(());
         }
 
-        if !self.id_exists(&receiver)? && !matrix::is_admin(&sender) {
-            room.send(
-                text_plain(&format!("{} isn't a valid user.", receiver.localpart())),
-                None,
+        let relation = &event.content.relates_to;
+        let request_id = relation.event_id.as_str();
+
+        // some clients tack a variation selector onto the emoji
+        let key = relation.emoji.trim_end_matches('\u{fe0f}');
+
+        let (pending, message) = if key == APPROVE_KEY {
+            let (pending, approved) = match self.approve(request_id, &sender).await? {
+                Some(approval) => approval,
+                None => return Ok(()),
+            };
+
+            if !approved {
+                let progress = format!("Got it. Waiting on {} more.", pending.missing());
+
+                matrix::send_to(
+                    &client,
+                    &RoomId::try_from(pending.request_room_id.as_str())?,
+                    text_plain(&progress),
+                )
+                .await?;
+
+                return Ok(());
+            }
+
+            let message = match self.insert_within_balance(&pending.transaction()).await? {
+                Some(_) => pending.describe("Took"),
+                None => format!(
+                    "There isn't enough in savings for this anymore: {}",
+                    pending.describe("Take")
+                ),
+            };
+
+            (pending, message)
+        } else if key == REJECT_KEY {
+            match self.reject(request_id).await? {
+                Some(pending) => {
+                    let message = format!("A parent said no to this: {}", pending.describe("Take"));
+                    (pending, message)
+                }
+                None => return Ok(()),
+            }
+        } else {
+            return Ok(());
+        };
+
+        announce(&client, &pending, &message).await
+    }
+
+    async fn on_redaction(self: &Bot, event: matrix::Redaction, room: Room) -> anyhow::Result<()> {
+        let sender = matrix::canonical_user_id(&event.sender);
+
+        let (sent, returned) = match self.record_redaction(&event.redacts, &sender).await? {
+            Some(undone) => undone,
+            None => return Ok(()),
+        };
+
+        let amount = Money::from_minor(sent.amount, iso::USD);
+        let receiver = matrix::create_user_id(&sent.receiver)
+            .map(|r| pretty_account(&r))
+            .unwrap_or_else(|_| sent.receiver.clone());
+
+        let message = if returned {
+            format!("Took back the {} sent to {}.", amount, receiver)
+        } else {
+            format!(
+                "{} already spent the {} sent to them, so it can't be taken back.",
+                receiver, amount
             )
+        };
+
+        if let Room::Joined(room) = room {
+            matrix::send(&room, text_plain(&message)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn on_rule_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        // rounding up only moves someone's own money to their own savings, so anyone can opt in
+        if let Some(user) = matrix::get_command("roundup", command) {
+            return self.on_roundup_message(room, sender, user).await;
+        }
+
+        if !matrix::is_admin(&sender) {
+            matrix::send(&room, text_plain("You are not allowed to change rules.")).await?;
+            return Ok(());
+        }
+
+        let args: Vec<String> = command
+            .split_whitespace()
+            .map(|a| a.to_lowercase())
+            .collect();
+
+        let (kind, user, amount, period) =
+            match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+                ["sweep", user, "over", amount, period] => ("sweep", user, amount, period),
+                ["fee", user, amount, period] => ("fee", user, amount, period),
+                _ => {
+                    matrix::send(&room, text_plain(&usage("rule"))).await?;
+                    return Ok(());
+                }
+            };
+
+        let amount = match Money::from_str(amount.trim_start_matches('$'), iso::USD) {
+            Ok(amount) if amount.is_positive() => amount,
+            _ => {
+                matrix::send(&room, text_plain(&format!("Invalid amount: {}", amount))).await?;
+                return Ok(());
+            }
+        };
+
+        let period = match Period::parse(period) {
+            Some(period) => period,
+            None => {
+                matrix::send(&room, text_plain("Rules can run weekly or monthly.")).await?;
+                return Ok(());
+            }
+        };
+
+        let user_id = matrix::create_user_id(user)?;
+        self.add_rule(kind, &user_id, matrix::money_to_i64(&amount), period)
             .await?;
+
+        matrix::send(&room, text_plain("Added the rule.")).await?;
+
+        Ok(())
+    }
+
+    // "rule roundup" for yourself, or "rule roundup charlie" for a parent
+    async fn on_roundup_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        user: &str,
+    ) -> anyhow::Result<()> {
+        let user_id = if user.is_empty() {
+            sender.clone()
+        } else {
+            matrix::create_user_id(user)?
+        };
+
+        if user_id != sender && !matrix::is_admin(&sender) {
+            matrix::send(&room, text_plain("You can only round up your own sends.")).await?;
             return Ok(());
         }
 
-        if sender == receiver {
-            room.send(
-                text_plain("So... you want to send money to yourself, from yourself?"),
-                None,
+        let exists = self
+            .get_rules()
+            .await?
+            .iter()
+            .any(|rule| rule.kind == "roundup" && rule.user_id == user_id.as_str());
+
+        let response = if exists {
+            format!("{} already rounds up.", pretty_account(&user_id))
+        } else {
+            self.add_rule("roundup", &user_id, 0, Period::EverySend)
+                .await?;
+
+            format!(
+                "{} will round up every send to the dollar, and the change will go to savings.",
+                pretty_account(&user_id)
             )
-            .await?;
+        };
+
+        matrix::send(&room, text_plain(&response)).await?;
+
+        Ok(())
+    }
+
+    async fn on_rules_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        if let Some(id) = matrix::get_command("delete", command) {
+            // anyone can opt back out of their own round-up
+            let own_roundup = self.get_rules().await?.iter().any(|rule| {
+                Some(rule.id) == id.parse().ok()
+                    && rule.kind == "roundup"
+                    && rule.user_id == sender.as_str()
+            });
+
+            if !matrix::is_admin(&sender) && !own_roundup {
+                matrix::send(&room, text_plain("You are not allowed to change rules.")).await?;
+                return Ok(());
+            }
+
+            let response = match id.parse::<i64>() {
+                Ok(id) if self.delete_rule(id).await? => format!("Deleted rule {}.", id),
+                _ => format!("There's no rule {}.", id),
+            };
+
+            matrix::send(&room, text_plain(&response)).await?;
             return Ok(());
         }
 
-        let memo = command.split(" for ").nth(1).map(|s| s.to_string());
+        let rules: Vec<String> = self
+            .get_rules()
+            .await?
+            .iter()
+            .map(|r| r.describe())
+            .collect();
 
-        self.insert(&Transaction {
-            sender: Some(sender.to_string()),
-            receiver: receiver.to_string(),
-            amount: matrix::money_to_i64(&amount),
-            date: chrono::Utc::now().to_rfc3339(),
-            memo: memo.clone(),
-        })?;
+        let response = if rules.is_empty() {
+            "There are no rules.".to_string()
+        } else {
+            rules.join("\n")
+        };
 
-        let pretty_id = matrix::pretty_user_id(&receiver);
+        matrix::send(&room, text_plain(&response)).await?;
 
-        if memo.is_some() {
-            room.send(
-                text_plain(&format!(
-                    "Sent {} to {} for {}.",
-                    amount,
-                    pretty_id,
-                    memo.unwrap()
-                )),
-                None,
+        Ok(())
+    }
+
+    async fn on_timezone_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+
+        let (user_id, tz) = match args[..] {
+            [] => {
+                let tz = self.get_timezone(&sender).await?;
+                matrix::send(&room, text_plain(&format!("You're on {} time.", tz.name()))).await?;
+                return Ok(());
+            }
+            [tz] => (sender.clone(), tz),
+            [user, tz] => (matrix::create_user_id(user)?, tz),
+            _ => {
+                matrix::send(&room, text_plain(&usage("timezone"))).await?;
+                return Ok(());
+            }
+        };
+
+        if user_id != sender && !matrix::is_admin(&sender) {
+            matrix::send(
+                &room,
+                text_plain("You are not allowed to set other people's timezones."),
             )
             .await?;
+            return Ok(());
+        }
+
+        let tz: Tz = match tz.parse() {
+            Ok(tz) => tz,
+            Err(_) => {
+                matrix::send(
+                    &room,
+                    text_plain(&format!(
+                        "I don't know the timezone {}. Try something like America/Chicago.",
+                        tz
+                    )),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        self.set_timezone(&user_id, tz).await?;
+
+        matrix::send(
+            &room,
+            text_plain(&format!(
+                "{} is now on {} time.",
+                pretty_account(&user_id),
+                tz.name()
+            )),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // "skip next allowance", or "skip next allowance for charlie"
+    async fn on_skip_allowance_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let users = match allowance_users(&sender, command) {
+            Ok(users) => users,
+            Err(e) => {
+                matrix::send(&room, text_plain(&e.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        let payday = payday();
+        let mut skipped = vec![];
+
+        for user in users {
+            let now = scheduler::now_in(self.get_timezone(&user).await?);
+
+            let next = payday
+                .next(now)
+                .ok_or_else(|| anyhow::anyhow!("There's no next payday."))?
+                .naive_local()
+                .date();
+
+            self.skip_allowance(&user, next.succ(), true).await?;
+            skipped.push(format!(
+                "{} won't get an allowance on {}.",
+                pretty_account(&user),
+                next.format("%A, %B %-d")
+            ));
+        }
+
+        matrix::send(&room, text_plain(&skipped.join("\n"))).await?;
+
+        Ok(())
+    }
+
+    // "pause allowance until august 1", or "pause allowance for charlie until 8/1"
+    async fn on_pause_allowance_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let lower = command.to_lowercase();
+
+        let (who, date) = match lower.rsplit_once("until ") {
+            Some((who, date)) => (who.trim(), date.trim()),
+            None => {
+                matrix::send(&room, text_plain(&usage("pause allowance"))).await?;
+                return Ok(());
+            }
+        };
+
+        let users = match allowance_users(&sender, who) {
+            Ok(users) => users,
+            Err(e) => {
+                matrix::send(&room, text_plain(&e.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        let today = scheduler::now().naive_local().date();
+
+        let until = match parse_date(date, today) {
+            Some(until) if until > today => until,
+            _ => {
+                matrix::send(
+                    &room,
+                    text_plain(&format!(
+                        "I don't know when {} is. Try something like August 1, or 2024-08-01.",
+                        date
+                    )),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        for user in &users {
+            self.skip_allowance(user, until, false).await?;
+        }
+
+        let names: Vec<String> = users.iter().map(pretty_account).collect();
+
+        matrix::send(
+            &room,
+            text_plain(&format!(
+                "No allowance for {} until {}.",
+                names.join(" or "),
+                until.format("%A, %B %-d")
+            )),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // "resume allowance", or "resume allowance for charlie", calling off any skips and pauses
+    async fn on_resume_allowance_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let users = match allowance_users(&sender, command) {
+            Ok(users) => users,
+            Err(e) => {
+                matrix::send(&room, text_plain(&e.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        let mut resumed = vec![];
+
+        for user in users {
+            if self.resume_allowance(&user).await? > 0 {
+                resumed.push(pretty_account(&user));
+            }
+        }
+
+        let message = if resumed.is_empty() {
+            "Nobody's allowance was skipped or paused.".to_string()
+        } else {
+            format!("The allowance is back on for {}.", resumed.join(" and "))
+        };
+
+        matrix::send(&room, text_plain(&message)).await?;
+
+        Ok(())
+    }
+
+    async fn on_chores_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        // chores are due by the next allowance
+        let since = payday()
+            .previous(scheduler::now())
+            .ok_or_else(|| anyhow::anyhow!("There was no last payday."))?;
+
+        if let Some(args) = matrix::get_command("add", command) {
+            if !matrix::is_admin(&sender) {
+                matrix::send(&room, text_plain("You are not allowed to hand out chores.")).await?;
+                return Ok(());
+            }
+
+            let (user, description) = match args.split_once(' ') {
+                Some((user, description)) if !description.trim().is_empty() => {
+                    (user, description.trim())
+                }
+                _ => {
+                    matrix::send(&room, text_plain(&usage("chores add"))).await?;
+                    return Ok(());
+                }
+            };
+
+            let user_id = matrix::create_user_id(user)?;
+            self.add_chore(&user_id, description).await?;
+
+            matrix::send(&room, text_plain("Added the chore.")).await?;
+            return Ok(());
+        }
+
+        if let Some(id) = matrix::get_command("done", command) {
+            let chore = match id.parse::<i64>() {
+                Ok(id) => self.get_chores().await?.into_iter().find(|c| c.id == id),
+                Err(_) => None,
+            };
+
+            let response = match chore {
+                Some(chore) if chore.user_id == sender.as_str() || matrix::is_admin(&sender) => {
+                    self.complete_chore(chore.id).await?;
+                    format!("Nice work! Marked {} done.", chore.description)
+                }
+                Some(_) => "That's not your chore!".to_string(),
+                None => format!("There's no chore {}.", id),
+            };
+
+            matrix::send(&room, text_plain(&response)).await?;
+            return Ok(());
+        }
+
+        if let Some(id) = matrix::get_command("delete", command) {
+            if !matrix::is_admin(&sender) {
+                matrix::send(&room, text_plain("You are not allowed to change chores.")).await?;
+                return Ok(());
+            }
+
+            let response = match id.parse::<i64>() {
+                Ok(id) if self.delete_chore(id).await? => format!("Deleted chore {}.", id),
+                _ => format!("There's no chore {}.", id),
+            };
+
+            matrix::send(&room, text_plain(&response)).await?;
+            return Ok(());
+        }
+
+        let chores: Vec<String> = self
+            .get_chores()
+            .await?
+            .iter()
+            .map(|c| c.describe(&since))
+            .collect();
+
+        let response = if chores.is_empty() {
+            "There are no chores.".to_string()
         } else {
-            room.send(
-                text_plain(&format!("Sent {} to {}.", amount, pretty_id)),
-                None,
-            )
-            .await?;
+            chores.join("\n")
         };
 
+        matrix::send(&room, text_plain(&response)).await?;
+
+        Ok(())
+    }
+
+    async fn on_help_message(self: &Bot, room: Joined) -> anyhow::Result<()> {
+        let (text, html) = commands::help(commands::MONEY);
+        matrix::send(&room, text_html(&text, &html)).await?;
+
         Ok(())
     }
 
@@ -502,9 +3478,9 @@ impl Bot {
         command: &str,
     ) -> anyhow::Result<()> {
         if !matrix::is_admin(&sender) {
-            room.send(
+            matrix::send(
+                &room,
                 text_plain("You are not allowed to set minimum balances."),
-                None,
             )
             .await?;
             return Ok(());
@@ -513,8 +3489,7 @@ impl Bot {
         let args: Vec<&str> = command.split(' ').collect();
 
         if args.len() != 2 {
-            room.send(text_plain("Usage: set min [user] [amount]."), None)
-                .await?;
+            matrix::send(&room, text_plain(&usage("set min"))).await?;
             return Ok(());
         }
 
@@ -523,21 +3498,21 @@ impl Bot {
         let amount = match Money::from_str(args[1], iso::USD) {
             Ok(amount) => amount,
             Err(_) => {
-                room.send(text_plain(&format!("Invalid amount: {}", args[1])), None)
-                    .await?;
+                matrix::send(&room, text_plain(&format!("Invalid amount: {}", args[1]))).await?;
                 return Ok(());
             }
         };
 
-        self.set_min_balance(&user_id, matrix::money_to_i64(&amount))?;
+        self.set_min_balance(&user_id, matrix::money_to_i64(&amount))
+            .await?;
 
-        room.send(
+        matrix::send(
+            &room,
             text_plain(&format!(
                 "Set minimum balance for {} to {}",
-                matrix::pretty_user_id(&user_id),
+                pretty_account(&user_id),
                 amount
             )),
-            None,
         )
         .await?;
 
@@ -552,15 +3527,62 @@ impl Bot {
         let args: Vec<&str> = command.split(' ').collect();
 
         if args.len() != 1 {
-            room.send(text_plain("Usage: get min [user]."), None)
-                .await?;
+            matrix::send(&room, text_plain(&usage("get min"))).await?;
             return Ok(());
         }
 
         let user_id = matrix::create_user_id(args[0])?;
-        let min = self.get_min_balance(&user_id)?;
+        let min = self.get_min_balance(&user_id).await?;
+
+        matrix::send(&room, text_plain(&format!("{}", min))).await?;
+
+        Ok(())
+    }
+
+    async fn on_statement_message(
+        self: &Bot,
+        room: Joined,
+        sender: UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let mut args = command.split_whitespace();
+
+        let month = match args.next().map(|m| m.parse::<chrono::Month>()) {
+            Some(Ok(month)) => month,
+            _ => {
+                matrix::send(&room, text_plain(&usage("statement"))).await?;
+                return Ok(());
+            }
+        };
+
+        let user_id = matrix::normalize_sender(sender, args.next().unwrap_or_default())?;
+
+        // the most recent one, so in January, "statement december" is last year's
+        let now = scheduler::now();
+        let year = if month.number_from_month() <= now.month() {
+            now.year()
+        } else {
+            now.year() - 1
+        };
 
-        room.send(text_plain(&format!("{}", min)), None).await?;
+        let start = scheduler::timezone()
+            .ymd(year, month.number_from_month(), 1)
+            .and_hms(0, 0, 0);
+        let end = scheduler::add_months(start, 1);
+
+        let (plain, _) = self.statement(&user_id, start, end).await?;
+        let lines: Vec<&str> = plain.lines().collect();
+        let pdf = pdf::render(lines.first().unwrap_or(&"Statement"), &lines)?;
+
+        let file_name = format!("{} {}.pdf", pretty_account(&user_id), start.format("%B %Y"));
+
+        room.send_attachment(
+            &file_name,
+            &mime::APPLICATION_PDF,
+            &mut pdf.as_slice(),
+            None,
+        )
+        .await?;
 
         Ok(())
     }
@@ -581,11 +3603,13 @@ impl Bot {
             }
         };
 
-        let running_balance = &mut self.get_balance(&user_id)?;
+        let running_balance = &mut self.get_balance(&user_id).await?;
+        let tz = self.get_timezone(&user_id).await?;
 
         // grab our ledger and convert to balance entries
         let ledger: Vec<BalanceTransaction> = self
-            .get_ledger(&user_id)?
+            .get_ledger(&user_id)
+            .await?
             .into_iter()
             .map(|tr| {
                 let (user, amount) = if tr.receiver == user_id.as_str() {
@@ -600,7 +3624,7 @@ impl Bot {
                     balance: running_balance.clone(),
                     user: user.map(|l| matrix::create_user_id(&l).unwrap()),
                     amount: Money::from_minor(amount, iso::USD),
-                    date: Pacific.timestamp_millis(
+                    date: tz.timestamp_millis(
                         DateTime::<Utc>::from_str(&tr.date)
                             .unwrap()
                             .timestamp_millis(),
@@ -630,17 +3654,12 @@ impl Bot {
                 "<tr><td>{}<td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
                 tr.balance,
                 tr.amount,
-                tr.user
-                    .map(|u| matrix::pretty_user_id(&u))
-                    .unwrap_or_default(),
+                tr.user.map(|u| pretty_account(&u)).unwrap_or_default(),
                 tr.memo.unwrap_or_default(),
                 tr.date.format("%b %d")
             ));
         }
 
-        let pacific_time = Pacific.ymd(1990, 5, 6).and_hms(12, 30, 45);
-        pacific_time.with_timezone(&Pacific);
-
         html_builder.append("</table>");
 
         // and our text
@@ -657,7 +3676,7 @@ impl Bot {
                 txt_builder.append(format!(
                     "On {} you sent {} {}{}.",
                     tr.date.format("%b %d"),
-                    tr.user.map(|u| matrix::pretty_user_id(&u)).unwrap(),
+                    tr.user.map(|u| pretty_account(&u)).unwrap(),
                     tr.amount * -1,
                     memo
                 ));
@@ -665,7 +3684,7 @@ impl Bot {
                 txt_builder.append(format!(
                     "On {} {} sent you {}{}.",
                     tr.date.format("%b %d"),
-                    tr.user.map(|u| matrix::pretty_user_id(&u)).unwrap(),
+                    tr.user.map(|u| pretty_account(&u)).unwrap(),
                     tr.amount,
                     memo
                 ));
@@ -674,15 +3693,14 @@ impl Bot {
         }
 
         if command.to_lowercase().contains("plain") {
-            room.send(text_plain(&txt_builder.string().unwrap()), None)
-                .await?;
+            matrix::send(&room, text_plain(&txt_builder.string().unwrap())).await?;
         } else {
-            room.send(
+            matrix::send(
+                &room,
                 text_html(
                     &txt_builder.string().unwrap(),
                     &html_builder.string().unwrap(),
                 ),
-                None,
             )
             .await?;
         }
@@ -690,3 +3708,691 @@ impl Bot {
         Ok(())
     }
 }
+
+// Balances come back as a picture (with progress toward a goal) in rooms whose space has the
+// "balance-cards" feature, or everywhere with MONEY_BALANCE_CARDS=true. They're easier to read on
+// a kid's tablet than a line of text.
+async fn balance_cards(client: &Client, room_id: &RoomId) -> bool {
+    env::var("MONEY_BALANCE_CARDS")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+        || matrix::room_features(client, room_id)
+            .await
+            .contains("balance-cards")
+}
+
+// savings accounts live in the ledger right alongside everyone else
+fn savings_account(user_id: &UserId) -> UserId {
+    UserId::parse_with_server_name(
+        format!("{}.savings", user_id.localpart()),
+        user_id.server_name(),
+    )
+    .unwrap()
+}
+
+// Sends that look unusual are reported to MONEY_ADMIN_ROOM, if it's set: anything more than
+// MONEY_ANOMALY_FACTOR times what the sender usually sends (once there are a few to go by), or
+// the MONEY_ANOMALY_BURST-th send in BURST_MINUTES. With MONEY_ANOMALY_ACTION set to "hold", they
+// wait there for a parent to approve them, rather than going through anyway.
+const DEFAULT_ANOMALY_FACTOR: i64 = 5;
+const DEFAULT_ANOMALY_BURST: i64 = 5;
+const BURST_MINUTES: i64 = 10;
+const ANOMALY_HISTORY: i64 = 3;
+
+fn anomaly_room() -> Option<RoomId> {
+    env::var("MONEY_ADMIN_ROOM")
+        .ok()
+        .map(|room| RoomId::try_from(room.as_str()).expect("MONEY_ADMIN_ROOM is not a room ID"))
+}
+
+fn anomaly_factor() -> i64 {
+    env::var("MONEY_ANOMALY_FACTOR")
+        .map(|factor| {
+            factor
+                .parse()
+                .expect("MONEY_ANOMALY_FACTOR is not an integer")
+        })
+        .unwrap_or(DEFAULT_ANOMALY_FACTOR)
+}
+
+fn anomaly_burst() -> i64 {
+    env::var("MONEY_ANOMALY_BURST")
+        .map(|burst| {
+            burst
+                .parse()
+                .expect("MONEY_ANOMALY_BURST is not an integer")
+        })
+        .unwrap_or(DEFAULT_ANOMALY_BURST)
+}
+
+fn hold_anomalies() -> bool {
+    env::var("MONEY_ANOMALY_ACTION").as_deref() == Ok("hold")
+}
+
+// Withdrawals from savings over MONEY_APPROVAL_LIMIT (in cents), if it's set, wait for
+// APPROVALS_NEEDED parents to react with APPROVE_KEY to the request, which goes to MONEY_ADMIN_ROOM
+// if there is one. Any parent can say no with REJECT_KEY. They're called off after
+// MONEY_APPROVAL_HOURS, and the parents are nudged every REMINDER_HOURS until then.
+const APPROVALS_NEEDED: usize = 2;
+const APPROVE_KEY: &str = "👍";
+const REJECT_KEY: &str = "👎";
+const DEFAULT_APPROVAL_HOURS: i64 = 48;
+const REMINDER_HOURS: i64 = 12;
+
+fn approval_limit() -> Option<i64> {
+    env::var("MONEY_APPROVAL_LIMIT").ok().map(|limit| {
+        limit
+            .parse()
+            .expect("MONEY_APPROVAL_LIMIT is not an integer")
+    })
+}
+
+fn approval_hours() -> i64 {
+    env::var("MONEY_APPROVAL_HOURS")
+        .map(|hours| {
+            hours
+                .parse()
+                .expect("MONEY_APPROVAL_HOURS is not an integer")
+        })
+        .unwrap_or(DEFAULT_APPROVAL_HOURS)
+}
+
+// like "3rd"
+fn ordinal(n: i64) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+
+    format!("{}{}", n, suffix)
+}
+
+// acknowledge sends with a reaction and a threaded receipt, rather than a message in the room
+fn quiet() -> bool {
+    env::var("MONEY_QUIET")
+        .map(|quiet| quiet == "true")
+        .unwrap_or(false)
+}
+
+// why an allowance isn't going out
+struct AllowanceSkip {
+    until: NaiveDate,
+    next_only: bool,
+}
+
+impl AllowanceSkip {
+    fn describe(&self, user_id: &UserId) -> String {
+        if self.next_only {
+            format!("Skipped {}'s allowance this time.", pretty_account(user_id))
+        } else {
+            format!(
+                "{}'s allowance is paused until {}.",
+                pretty_account(user_id),
+                self.until.format("%B %-d")
+            )
+        }
+    }
+}
+
+// Who an allowance command is about: "for charlie", or everyone who gets one. Only parents can
+// change the allowance.
+fn allowance_users(sender: &UserId, command: &str) -> anyhow::Result<Vec<UserId>> {
+    if !matrix::is_admin(sender) {
+        anyhow::bail!("You are not allowed to change the allowance.");
+    }
+
+    let who = command.trim();
+    let who = who.strip_prefix("for ").unwrap_or(who).trim();
+
+    if who.is_empty() {
+        return ALLOWANCE_USERS
+            .iter()
+            .map(|user_id| Ok(UserId::try_from(*user_id)?))
+            .collect();
+    }
+
+    let user_id = matrix::create_user_id(who)?;
+
+    if !ALLOWANCE_USERS.contains(&user_id.as_str()) {
+        anyhow::bail!("{} doesn't get an allowance.", pretty_account(&user_id));
+    }
+
+    Ok(vec![user_id])
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+// A date like "2024-08-01", "8/1", or "august 1st". Without a year, it's the next one to come
+// (today included).
+fn parse_date(date: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let date = date.trim().trim_end_matches('.').to_lowercase();
+
+    if let Ok(date) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+        return Some(date);
+    }
+
+    let (month, day) = match date.split_once('/') {
+        Some((month, day)) => (month.parse().ok()?, day),
+        None => {
+            let (month, day) = date.split_once(' ')?;
+            let month = MONTHS.iter().position(|m| month.starts_with(m))? as u32 + 1;
+            (month, day)
+        }
+    };
+
+    let day: u32 = day
+        .trim()
+        .trim_end_matches(|c: char| c.is_alphabetic())
+        .parse()
+        .ok()?;
+
+    let this_year = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+
+    if this_year >= today {
+        Some(this_year)
+    } else {
+        NaiveDate::from_ymd_opt(today.year() + 1, month, day)
+    }
+}
+
+pub fn pretty_account(user_id: &UserId) -> String {
+    match user_id.localpart().strip_suffix(".savings") {
+        Some(owner) => {
+            let owner = UserId::parse_with_server_name(owner, user_id.server_name()).unwrap();
+            format!("{}'s savings", matrix::pretty_user_id(&owner))
+        }
+        None => matrix::pretty_user_id(user_id),
+    }
+}
+
+fn usage(command: &str) -> String {
+    match commands::MONEY
+        .iter()
+        .find(|(usage, _)| usage.starts_with(command))
+    {
+        Some((usage, _)) => format!("Usage: {}.", usage),
+        None => "Say \"help\" to see what I can do.".to_string(),
+    }
+}
+
+// trailing words that don't belong in a memo or a user name
+const POLITE_WORDS: &[&str] = &["please", "pls", "plz", "thank you", "thanks", "thx"];
+
+// a send command, loosely parsed from something like
+// "five dollars to charlie for mowing the lawn please"
+struct SendCommand {
+    receiver: Option<String>,
+    amount: Option<Decimal>,
+    memo: Option<String>,
+}
+
+fn parse_send(command: &str) -> SendCommand {
+    // ASCII lowercasing keeps byte offsets lined up with the original
+    let lower = command.to_ascii_lowercase();
+
+    let (main, memo) = match lower.find(" for ") {
+        Some(i) => (&command[..i], Some(strip_politeness(&command[i + 5..]))),
+        None => (command, None),
+    };
+
+    let tokens: Vec<String> = strip_politeness(main)
+        .split_whitespace()
+        .map(|t| {
+            t.trim_matches(|c: char| c == ',' || c == ':' || c == '!' || c == '?')
+                .trim_end_matches('.')
+                .to_ascii_lowercase()
+        })
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let mut amount: Option<Decimal> = None;
+    let mut pending: Option<Decimal> = None;
+    let mut words = vec![];
+
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).map(|t| t.as_str()).unwrap_or_default();
+
+        if let Ok(n) = Decimal::from_str(&token.replace(&['$', ','][..], "")) {
+            pending = Some(pending.unwrap_or_default() + n);
+        } else if let Some(n) = number_word(token) {
+            pending = Some(if n == Decimal::from(100) {
+                pending.unwrap_or(Decimal::ONE) * n
+            } else {
+                pending.unwrap_or_default() + n
+            });
+        } else if (token == "a" || token == "an")
+            && (is_unit(next) || number_word(next) == Some(Decimal::from(100)))
+        {
+            // "a dollar", or "a hundred"
+            pending = Some(Decimal::ONE);
+        } else if is_unit(token) {
+            let value = pending.take().unwrap_or_default();
+
+            let value = if token.starts_with("cent") {
+                value / Decimal::from(100)
+            } else {
+                value
+            };
+
+            amount = Some(amount.unwrap_or_default() + value);
+        } else if token != "to" && token != "and" {
+            words.push(token.clone());
+        }
+    }
+
+    // a bare number is dollars
+    if let Some(value) = pending {
+        amount = Some(amount.unwrap_or_default() + value);
+    }
+
+    SendCommand {
+        receiver: words.first().cloned(),
+        amount,
+        memo: memo.filter(|m| !m.is_empty()),
+    }
+}
+
+// the mentioned user whose pill is the given receiver, going by the first word of its text
+fn mentioned_receiver(mentions: &[(UserId, String)], receiver: &str) -> Option<UserId> {
+    mentions
+        .iter()
+        .find(|(_, text)| {
+            text.trim_start_matches('@')
+                .split_whitespace()
+                .next()
+                .is_some_and(|first| first.eq_ignore_ascii_case(receiver.trim_start_matches('@')))
+        })
+        .map(|(user_id, _)| user_id.clone())
+}
+
+fn is_unit(word: &str) -> bool {
+    matches!(
+        word,
+        "dollar" | "dollars" | "buck" | "bucks" | "cent" | "cents"
+    )
+}
+
+fn number_word(word: &str) -> Option<Decimal> {
+    // "twenty-five"
+    if word.contains('-') {
+        let mut total = Decimal::ZERO;
+
+        for part in word.split('-') {
+            total += number_word(part)?;
+        }
+
+        return Some(total);
+    }
+
+    let n = match word {
+        "zero" => 0,
+        "one" => 1,
+        "two" => 2,
+        "three" => 3,
+        "four" => 4,
+        "five" => 5,
+        "six" => 6,
+        "seven" => 7,
+        "eight" => 8,
+        "nine" => 9,
+        "ten" => 10,
+        "eleven" => 11,
+        "twelve" => 12,
+        "thirteen" => 13,
+        "fourteen" => 14,
+        "fifteen" => 15,
+        "sixteen" => 16,
+        "seventeen" => 17,
+        "eighteen" => 18,
+        "nineteen" => 19,
+        "twenty" => 20,
+        "thirty" => 30,
+        "forty" => 40,
+        "fifty" => 50,
+        "sixty" => 60,
+        "seventy" => 70,
+        "eighty" => 80,
+        "ninety" => 90,
+        "hundred" => 100,
+        _ => return None,
+    };
+
+    Some(Decimal::from(n))
+}
+
+fn strip_politeness(text: &str) -> String {
+    let trim = |s: &str| {
+        s.trim()
+            .trim_end_matches(['.', '!', ','])
+            .trim()
+            .to_string()
+    };
+
+    let mut text = trim(text);
+
+    loop {
+        let lower = text.to_ascii_lowercase();
+
+        let polite = POLITE_WORDS.iter().find(|word| {
+            lower == **word
+                || lower.ends_with(&format!(" {}", word))
+                || lower.ends_with(&format!(",{}", word))
+        });
+
+        match polite {
+            Some(word) => text = trim(&text[..text.len() - word.len()]),
+            None => return text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spots_anomalies() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        let send = |amount: i64, minutes_ago: i64| Transaction {
+            sender: Some("@chase:kulak.us".to_string()),
+            receiver: "@charlie:kulak.us".to_string(),
+            amount,
+            date: (Utc::now() - chrono::Duration::minutes(minutes_ago)).to_rfc3339(),
+            memo: None,
+            event_id: None,
+        };
+
+        let savings = "@chase.savings:kulak.us";
+
+        for minutes_ago in [60, 50, 40] {
+            insert(&mut conn, &send(200, minutes_ago)).unwrap();
+        }
+
+        assert_eq!(anomaly(&mut conn, &send(500, 0), savings).unwrap(), None);
+        assert_eq!(
+            anomaly(&mut conn, &send(1500, 0), savings).unwrap(),
+            Some("more than 5 times their usual $2.00".to_string())
+        );
+
+        for _ in 0..4 {
+            insert(&mut conn, &send(100, 1)).unwrap();
+        }
+
+        assert_eq!(
+            anomaly(&mut conn, &send(100, 0), savings).unwrap(),
+            Some("their 5th send in 10 minutes".to_string())
+        );
+    }
+
+    #[test]
+    fn sums_up_the_week() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        let kid = UserId::try_from("@chase:kulak.us").unwrap();
+        let savings = savings_account(&kid).to_string();
+        let since = (Utc::now() - chrono::Duration::days(7)).to_rfc3339();
+
+        let mut send = |sender: &str, receiver: &str, amount: i64, days_ago: i64, memo: &str| {
+            let t = Transaction {
+                sender: Some(sender.to_string()),
+                receiver: receiver.to_string(),
+                amount,
+                date: (Utc::now() - chrono::Duration::days(days_ago)).to_rfc3339(),
+                memo: (!memo.is_empty()).then(|| memo.to_string()),
+                event_id: None,
+            };
+
+            insert(&mut conn, &t).unwrap();
+        };
+
+        // last week's don't count, except toward the savings balance
+        send(BANK, "@chase:kulak.us", 1000, 10, "allowance");
+        send("@chase:kulak.us", &savings, 2000, 10, "");
+        send("@chase:kulak.us", "@charlie:kulak.us", 900, 10, "candy");
+
+        send(BANK, "@chase:kulak.us", 1000, 2, "allowance");
+        send("@chase:kulak.us", "@charlie:kulak.us", 300, 2, "Candy");
+        send("@chase:kulak.us", "@charlie:kulak.us", 200, 1, "candy ");
+        send("@chase:kulak.us", "@phil:kulak.us", 100, 1, "");
+        send("@chase:kulak.us", &savings, 500, 1, "");
+
+        assert_eq!(
+            money_minute(&mut conn, &kid, &since).unwrap(),
+            "Chase\nAllowance: $10.00\nSpent $6.00: candy $5.00, no memo $1.00\nSavings: $25.00 (up $5.00)"
+        );
+
+        conn.execute(
+            "INSERT INTO anomalies (user_id, date, description) VALUES (?1, ?2, ?3)",
+            params![
+                "@chase:kulak.us",
+                Utc::now().to_rfc3339(),
+                "Chase sending $50.00 to Charlie (their 5th send in 10 minutes)"
+            ],
+        )
+        .unwrap();
+
+        let quiet = UserId::try_from("@charlie:kulak.us").unwrap();
+
+        assert!(money_minute(&mut conn, &kid, &since)
+            .unwrap()
+            .ends_with("Flagged: Chase sending $50.00 to Charlie (their 5th send in 10 minutes)"));
+        assert_eq!(
+            money_minute(&mut conn, &quiet, &since).unwrap(),
+            "Charlie\nNo allowance this week.\nSpent nothing.\nSavings: $0.00 (no change)"
+        );
+    }
+
+    #[test]
+    fn takes_back_redacted_sends() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        let send = |sender: &str, receiver: &str, amount: i64, event_id: &str| Transaction {
+            sender: Some(sender.to_string()),
+            receiver: receiver.to_string(),
+            amount,
+            date: Utc::now().to_rfc3339(),
+            memo: None,
+            event_id: Some(event_id.to_string()),
+        };
+
+        insert(&mut conn, &send(BANK, "@chase:kulak.us", 1000, "seed")).unwrap();
+        insert(
+            &mut conn,
+            &send("@chase:kulak.us", "@charlie:kulak.us", 450, "$lunch"),
+        )
+        .unwrap();
+        insert(
+            &mut conn,
+            &send(
+                "@chase:kulak.us",
+                "@chase.savings:kulak.us",
+                50,
+                "roundup:$lunch",
+            ),
+        )
+        .unwrap();
+
+        // only whoever sent it can take it back
+        assert!(undo(&mut conn, "$lunch", "@charlie:kulak.us", false)
+            .unwrap()
+            .is_none());
+
+        let undone = undo(&mut conn, "$lunch", "@chase:kulak.us", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(undone.reversals.len(), 2);
+        assert_eq!(balance(&mut conn, "@chase:kulak.us").unwrap(), 1000);
+        assert_eq!(balance(&mut conn, "@charlie:kulak.us").unwrap(), 0);
+        assert_eq!(balance(&mut conn, "@chase.savings:kulak.us").unwrap(), 0);
+
+        // and only once
+        assert!(undo(&mut conn, "$lunch", "@chase:kulak.us", false)
+            .unwrap()
+            .is_none());
+
+        // money that's already been spent stays spent
+        insert(
+            &mut conn,
+            &send("@chase:kulak.us", "@charlie:kulak.us", 300, "$candy"),
+        )
+        .unwrap();
+        insert(
+            &mut conn,
+            &send("@charlie:kulak.us", "@phil:kulak.us", 300, "$gum"),
+        )
+        .unwrap();
+
+        let undone = undo(&mut conn, "$candy", "@chase:kulak.us", false)
+            .unwrap()
+            .unwrap();
+        assert!(undone.reversals.is_empty());
+        assert_eq!(balance(&mut conn, "@chase:kulak.us").unwrap(), 700);
+    }
+
+    #[test]
+    fn waits_for_two_parents() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        conn.execute(
+            "
+            INSERT INTO pending_approvals
+                (room_id, sender, receiver, amount, memo, event_id, request_room_id, request_id,
+                    expires, nudged)
+            VALUES
+                ('!room', '@chase.savings:kulak.us', '@chase:kulak.us', 5000, 'a bike', '$cmd',
+                    '!room', '$request', '2024-07-16T00:00:00Z', '2024-07-15T00:00:00Z')",
+            [],
+        )
+        .unwrap();
+
+        let (pending, approved) = approve(&mut conn, "$request", "@phil:kulak.us")
+            .unwrap()
+            .unwrap();
+        assert!(!approved);
+        assert_eq!(pending.missing(), 1);
+        assert_eq!(
+            pending.describe("Take"),
+            "Take $50.00 out of Chase's savings for a bike."
+        );
+
+        // the same parent twice is still just one
+        let (_, approved) = approve(&mut conn, "$request", "@phil:kulak.us")
+            .unwrap()
+            .unwrap();
+        assert!(!approved);
+
+        let (_, approved) = approve(&mut conn, "$request", "@gwen:kulak.us")
+            .unwrap()
+            .unwrap();
+        assert!(approved);
+
+        // and it only goes out once
+        assert!(approve(&mut conn, "$request", "@gwen:kulak.us")
+            .unwrap()
+            .is_none());
+        assert!(approve(&mut conn, "$other", "@gwen:kulak.us")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn parses_dates() {
+        let today = NaiveDate::from_ymd(2024, 7, 15);
+
+        assert_eq!(
+            parse_date("2024-08-01", today),
+            Some(NaiveDate::from_ymd(2024, 8, 1))
+        );
+        assert_eq!(
+            parse_date("August 1st", today),
+            Some(NaiveDate::from_ymd(2024, 8, 1))
+        );
+        assert_eq!(
+            parse_date("8/1", today),
+            Some(NaiveDate::from_ymd(2024, 8, 1))
+        );
+
+        // already gone by this year, so it's next year's
+        assert_eq!(
+            parse_date("jan 3", today),
+            Some(NaiveDate::from_ymd(2025, 1, 3))
+        );
+
+        assert_eq!(parse_date("someday", today), None);
+        assert_eq!(parse_date("feb 30", today), None);
+    }
+
+    fn parsed(command: &str) -> (Option<String>, Option<Decimal>, Option<String>) {
+        let send = parse_send(command);
+        (send.receiver, send.amount, send.memo)
+    }
+
+    fn dollars(amount: &str) -> Option<Decimal> {
+        Some(Decimal::from_str(amount).unwrap())
+    }
+
+    #[test]
+    fn parses_plain_sends() {
+        let (receiver, amount, memo) = parsed("5 to charlie");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("5"));
+        assert_eq!(memo, None);
+
+        let (receiver, amount, _) = parsed("charlie $2.50");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("2.50"));
+    }
+
+    #[test]
+    fn parses_words() {
+        let (receiver, amount, memo) = parsed("five dollars to charlie for mowing the lawn please");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("5"));
+        assert_eq!(memo.as_deref(), Some("mowing the lawn"));
+
+        let (_, amount, _) = parsed("two dollars and fifty cents to charlie");
+        assert_eq!(amount, dollars("2.50"));
+
+        let (_, amount, _) = parsed("twenty-five bucks to charlie");
+        assert_eq!(amount, dollars("25"));
+    }
+
+    #[test]
+    fn parses_a_and_an() {
+        let (receiver, amount, _) = parsed("a hundred dollars to charlie");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("100"));
+
+        let (receiver, amount, _) = parsed("a dollar to charlie");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("1"));
+
+        let (receiver, amount, _) = parsed("charlie a hundred");
+        assert_eq!(receiver.as_deref(), Some("charlie"));
+        assert_eq!(amount, dollars("100"));
+    }
+
+    #[test]
+    fn only_uses_the_receivers_pill() {
+        let charlie = UserId::try_from("@charlie:kulak.us").unwrap();
+        let dad = UserId::try_from("@phil:kulak.us").unwrap();
+
+        let mentions = vec![
+            (charlie.clone(), "Charlie Kulak".to_string()),
+            (dad, "Dad".to_string()),
+        ];
+
+        assert_eq!(mentioned_receiver(&mentions, "charlie"), Some(charlie));
+        assert_eq!(mentioned_receiver(&mentions[1..], "charlie"), None);
+    }
+}
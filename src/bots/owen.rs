@@ -1,11 +1,18 @@
-use anyhow::{bail, Result};
-use matrix_sdk::room::Room;
+use std::env;
+
+use anyhow::{anyhow, bail, Result};
+use matrix_sdk::room::{Joined, Room};
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
 use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::UserId;
 use matrix_sdk::{Client, SyncSettings};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::Deserialize;
 
+use crate::ai;
+use crate::cache;
 use crate::matrix;
+use crate::storage;
 use crate::webhook;
 
 const TRIGGERS: &[&str] = &[
@@ -25,6 +32,9 @@ const TRIGGERS: &[&str] = &[
     "lol",
 ];
 
+// how many random wows the chat model gets to pick from
+const PICK_FROM: usize = 8;
+
 pub async fn main() -> anyhow::Result<()> {
     let client = matrix::create_client("owenbot").await?;
 
@@ -37,14 +47,44 @@ pub async fn main() -> anyhow::Result<()> {
 }
 
 async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client) {
-    if let Some((joined, _, message)) = matrix::get_text_message(event, room, client).await {
-        let message = message.to_lowercase();
+    if !matrix::routes_to(&client, "owen", room.room_id(), &event).await {
+        return;
+    }
+
+    if let Some((joined, sender, original)) = matrix::get_text_message(event, room, client).await {
+        let message = original.to_lowercase();
+
+        if matrix::get_command("owen ignore me", &message).is_some() {
+            set_ignored(&joined, &sender, true).await.unwrap();
+            return;
+        } else if matrix::get_command("owen include me", &message).is_some() {
+            set_ignored(&joined, &sender, false).await.unwrap();
+            return;
+        }
+
+        if is_ignored(&sender).unwrap() {
+            return;
+        }
 
         for trigger in TRIGGERS {
             if message.contains(trigger) {
-                joined.send(matrix::text_plain("Wow!"), None).await.unwrap();
+                matrix::send_sticker(&joined, "wow", "Wow!").await.unwrap();
+
+                let wow = match ai_picks() {
+                    true => pick_wow(&original).await.unwrap(),
+                    false => get_wow().await.unwrap(),
+                };
+
+                // there are only so many wows, so each is played from the cache after the first
+                let wow = match cache::share_url(&wow).await {
+                    Ok(Some(shared)) => shared,
+                    Ok(None) => wow,
+                    Err(e) => {
+                        println!("Could not cache wow: {}", e);
+                        wow
+                    }
+                };
 
-                let wow = get_wow().await.unwrap();
                 webhook::play_video(wow.as_str()).await.unwrap();
                 return;
             }
@@ -52,8 +92,59 @@ async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Roo
     }
 }
 
+async fn set_ignored(joined: &Joined, user_id: &UserId, ignored: bool) -> Result<()> {
+    let conn = open_db()?;
+
+    let response = if ignored {
+        conn.execute(
+            "INSERT OR IGNORE INTO ignored (user_id) VALUES (?1)",
+            params![user_id.as_str()],
+        )?;
+        "Okay, I'll keep quiet for you."
+    } else {
+        conn.execute(
+            "DELETE FROM ignored WHERE user_id = ?1",
+            params![user_id.as_str()],
+        )?;
+        "Wow! Welcome back."
+    };
+
+    matrix::send(joined, matrix::text_plain(response)).await?;
+
+    Ok(())
+}
+
+fn is_ignored(user_id: &UserId) -> Result<bool> {
+    let ignored = open_db()?
+        .query_row(
+            "SELECT user_id FROM ignored WHERE user_id = ?1",
+            params![user_id.as_str()],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+
+    Ok(ignored.is_some())
+}
+
+// everyone who's asked not to set the bot off
+fn open_db() -> Result<Connection> {
+    let conn = storage::open("owenbot")?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS ignored (
+            user_id TEXT PRIMARY KEY
+        )",
+        [],
+    )?;
+
+    Ok(conn)
+}
+
 #[derive(Deserialize)]
 struct Body {
+    movie: String,
+    full_line: String,
     video: Video,
 }
 
@@ -64,15 +155,22 @@ struct Video {
 }
 
 async fn get_wow() -> Result<String> {
+    match get_wows(1).await?.into_iter().next() {
+        Some(wow) => Ok(wow.video.large),
+        None => bail!("no wows"),
+    }
+}
+
+async fn get_wows(count: usize) -> Result<Vec<Body>> {
     let response = reqwest::Client::new()
         .get("https://owen-wilson-wow-api.herokuapp.com/wows/random")
+        .query(&[("results", count)])
         .send()
-        .await
-        .unwrap();
+        .await?;
 
     match response.status() {
         reqwest::StatusCode::OK => match response.json::<Vec<Body>>().await {
-            Ok(parsed) => Ok(parsed.first().unwrap().video.large.clone()),
+            Ok(parsed) => Ok(parsed),
             Err(_) => bail!("unexpected response"),
         },
         _ => {
@@ -80,3 +178,72 @@ async fn get_wow() -> Result<String> {
         }
     }
 }
+
+// whether the chat model picks the wow, from OWEN_AI_PICKS, rather than it being random
+fn ai_picks() -> bool {
+    env::var("OWEN_AI_PICKS").as_deref() == Ok("true")
+}
+
+// The wow that fits a message best, picked by the chat model from a few random ones by their
+// lines and movies. If it can't make up its mind, it's the first one, which is as random as any.
+async fn pick_wow(message: &str) -> Result<String> {
+    let mut wows = get_wows(PICK_FROM).await?;
+
+    if wows.is_empty() {
+        bail!("no wows");
+    }
+
+    let options: Vec<String> = wows
+        .iter()
+        .enumerate()
+        .map(|(i, wow)| format!("{}. \"{}\" ({})", i + 1, wow.full_line, wow.movie))
+        .collect();
+
+    let prompt = format!(
+        "Someone just said: \"{}\"\n\nWhich of these Owen Wilson \"wow\" clips is the best \
+        reaction?\n{}\n\nAnswer with just the number.",
+        message,
+        options.join("\n")
+    );
+
+    let picked = ai::chat_with_context(&[ai::Message::user(&prompt)], None)
+        .await
+        .and_then(|answer| {
+            parse_pick(&answer, wows.len()).ok_or_else(|| anyhow!("not a pick: {}", answer))
+        });
+
+    let index = match picked {
+        Ok(index) => index,
+        Err(e) => {
+            println!("Could not pick a wow, going with a random one: {}", e);
+            0
+        }
+    };
+
+    Ok(wows.swap_remove(index).video.large)
+}
+
+// the first number in an answer, as an index into the options, if it's one of them
+fn parse_pick(answer: &str, count: usize) -> Option<usize> {
+    answer
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|number| !number.is_empty())?
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=count).contains(n))
+        .map(|n| n - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_picks() {
+        assert_eq!(parse_pick("3", 8), Some(2));
+        assert_eq!(parse_pick("Number 8, definitely.", 8), Some(7));
+        assert_eq!(parse_pick("9", 8), None);
+        assert_eq!(parse_pick("0", 8), None);
+        assert_eq!(parse_pick("The first one", 8), None);
+    }
+}
@@ -1,42 +1,150 @@
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::str::FromStr;
 
+use std::env;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, SyncSender};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{env, fs};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use bytes::Bytes;
+use chrono::{DateTime, Datelike, Utc};
+use lettre::message::header::ContentType;
 use lettre::message::{Attachment, Body, MultiPart};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-use matrix_sdk::room::Room;
+use matrix_sdk::room::{Joined, Room};
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
 use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
 use matrix_sdk::{Client, SyncSettings};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
 use tokio::task;
 
+use crate::ai;
+use crate::archive;
+use crate::cache;
+use crate::commands;
+use crate::config;
+use crate::frame;
+use crate::google_photos;
+use crate::i18n;
 use crate::image;
+use crate::mail;
+use crate::mail::Mailer;
 use crate::matrix;
 use crate::message_buffer::MessageBuffer;
+use crate::scheduler;
+use crate::scheduler::Recurrence;
+use crate::storage;
+use crate::telegram;
+use crate::video;
+use crate::webhook;
+
+// how long to wait for more photos before sending what we have
+const BATCH_WINDOW: u64 = 10;
+
+// how many photos anyone but the parents can send in an hour, unless PHOTO_HOURLY_LIMIT says
+// otherwise
+const DEFAULT_HOURLY_LIMIT: usize = 100;
+
+// the most we'll put in one email, unless PHOTO_MAX_EMAIL_SIZE says otherwise, so a big batch
+// goes out in a few of them
+const DEFAULT_MAX_EMAIL_SIZE: usize = 25 * 1024 * 1024;
+
+// how far a photo too big for an email on its own gets shrunk, a step at a time, until it fits
+const FIT_STEPS: &[(u32, u32, u8)] = &[
+    (2560, 1600, 80),
+    (1920, 1200, 75),
+    (1280, 800, 70),
+    (800, 500, 60),
+];
+
+// how many times we'll try an email before giving up on it, and how long we wait after the first
+// failure (doubling after each one after that)
+const MAX_ATTEMPTS: i64 = 8;
+const RETRY_MINUTES: i64 = 1;
+
+// how often to look for bounced emails
+const BOUNCE_MINUTES: u64 = 10;
+
+// how many emails in a row can fail for an address before we stop sending to it
+const MAX_FAILURES: i64 = 5;
+
+// the reactions on something that came in: while it's being worked on, and how it went
+const WORKING: &str = "⏳";
+const DONE: &str = "✅";
+const FAILED: &str = "❌";
+
+// how many of each room's latest photos and videos are kept around to send again
+const RECENT_LIMIT: usize = 10;
+
+// when each month's collage and best of go out, covering the month before
+const MONTHLY_SCHEDULE: &str = "first of the month";
+
+// the reaction that puts a photo in the month's best of
+const STAR: &str = "⭐";
+
+// when the photo of the day changes, unless PHOTO_OF_THE_DAY_SCHEDULE says otherwise, and the
+// display it's for, unless PHOTO_OF_THE_DAY_SIZE does
+const DEFAULT_PHOTO_OF_THE_DAY_SCHEDULE: &str = "every day at 6am";
+const DEFAULT_DISPLAY_SIZE: (u32, u32) = (800, 480);
+
+// the longest side of the copy the vision model looks at, which is all a caption needs
+const DESCRIBE_SIZE: u32 = 512;
+
+// HEIC decoding, JPEG encoding, and video transcoding all take a while, so they're done off the
+// main loop, a few at a time (PHOTO_WORKERS, or one for each CPU), to keep a burst of photos from
+// holding up commands.
+static WORKERS: Lazy<Semaphore> = Lazy::new(|| {
+    let max = env::var("PHOTO_WORKERS")
+        .map(|max| max.parse().expect("PHOTO_WORKERS is not an integer"))
+        .unwrap_or_else(|_| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+    Semaphore::new(max)
+});
 
 pub async fn main() -> anyhow::Result<()> {
-    let (tx, rx): (SyncSender<MessageEvent>, Receiver<MessageEvent>) = mpsc::sync_channel(1000);
+    let (tx, rx): (SyncSender<Work>, Receiver<Work>) = mpsc::sync_channel(1000);
     let client = matrix::create_client("photobot").await?;
-    let mut bot = Bot::new();
+    // the archives are set up here, so if they aren't set up right, this fails now rather than
+    // with the first photo
+    let mut bot = Bot::new()?;
 
     client
         .clone()
         .register_event_handler({
-            move |event: SyncMessageEvent<MessageEventContent>, room: Room| {
+            let tx = tx.clone();
+
+            move |event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client| {
                 let tx = tx.clone();
                 async move {
-                    tx.send(MessageEvent { event, room }).unwrap();
+                    if matrix::routes_to(&client, "photo", room.room_id(), &event).await {
+                        tx.send(Work::Message(Box::new(MessageEvent { event, room })))
+                            .unwrap();
+                    }
                 }
             }
         })
         .await;
 
+    // reactions are how the collage and best of know what everyone liked
+    if collage_size().is_some() || best_of() {
+        client
+            .clone()
+            .register_event_handler(
+                |event: SyncMessageEvent<ReactionEventContent>, _: Room, client: Client| {
+                    on_reaction(event, client)
+                },
+            )
+            .await;
+    }
+
     task::spawn({
         let client = client.clone();
 
@@ -46,11 +154,140 @@ pub async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // let any room with a filter know it survived the restart
+    for room_id in bot.filters.keys() {
+        if let Some(joined) = client.get_joined_room(room_id) {
+            let message = format!("I'm back! {}", bot.recipients_friendly(room_id));
+            matrix::send(&joined, matrix::text_plain(&message)).await?;
+        }
+    }
+
+    // put things back to normal once a temporary filter runs out, and try any emails that didn't go
+    // through again
+    task::spawn({
+        let client = client.clone();
+
+        async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+
+                if let Err(e) = expire_filter(&client).await {
+                    println!("Could not expire filter! {}", e);
+                }
+
+                if let Err(e) = retry_emails(&client).await {
+                    println!("Could not retry emails! {}", e);
+                }
+            }
+        }
+    });
+
+    // let the admins know when an address stops taking photos, rather than losing them to a typo
+    if mail::bounce_checking_enabled() {
+        // checked now, rather than once the first bounce has nowhere to go
+        let admin_room = env::var("PHOTO_ADMIN_ROOM")
+            .map_err(|_| anyhow!("PHOTO_ADMIN_ROOM environmental variable not set"))?;
+        let admin_room = RoomId::try_from(admin_room.as_str())?;
+
+        task::spawn({
+            let client = client.clone();
+
+            async move {
+                loop {
+                    if let Err(e) = check_bounces(&client, &admin_room).await {
+                        println!("Could not check for bounces! {}", e);
+                    }
+
+                    tokio::time::sleep(std::time::Duration::from_secs(BOUNCE_MINUTES * 60)).await;
+                }
+            }
+        });
+    }
+
+    // hold emails for one digest a day, if that's what PHOTO_DIGEST asks for
+    if let Some(digest) = digest() {
+        let tx = tx.clone();
+
+        scheduler::spawn(
+            "photo digest",
+            scheduler::every(&digest.to_string())?,
+            move || {
+                let tx = tx.clone();
+
+                async move {
+                    tx.send(Work::Digest)
+                        .map_err(|_| anyhow::anyhow!("the photo bot has stopped"))
+                }
+            },
+        );
+    }
+
+    // a collage of each room's favorites at the start of every month, if PHOTO_COLLAGE asks, and
+    // an email of what was starred, if PHOTO_BEST_OF does
+    if collage_size().is_some() || best_of() {
+        scheduler::spawn(
+            "photo month",
+            scheduler::every(MONTHLY_SCHEDULE)?,
+            move || {
+                let tx = tx.clone();
+
+                async move {
+                    tx.send(Work::Monthly)
+                        .map_err(|_| anyhow::anyhow!("the photo bot has stopped"))
+                }
+            },
+        );
+    }
+
+    // a photo from the archive for an e-ink display every morning, if PHOTO_OF_THE_DAY names the
+    // webhook that shows it
+    if let Ok(webhook) = env::var("PHOTO_OF_THE_DAY") {
+        let schedule = env::var("PHOTO_OF_THE_DAY_SCHEDULE")
+            .unwrap_or(DEFAULT_PHOTO_OF_THE_DAY_SCHEDULE.to_string());
+
+        scheduler::spawn(
+            "photo of the day",
+            scheduler::every(&schedule)?,
+            move || {
+                let webhook = webhook.clone();
+
+                async move { photo_of_the_day(&webhook).await }
+            },
+        );
+    }
+
     let mut buffer = MessageBuffer::new(&rx);
 
     loop {
-        let message = buffer.poll();
+        let message = match buffer.poll() {
+            Work::Message(message) => message,
+            // the digest goes out from here, since this loop is what has the bot
+            Work::Digest => {
+                // anything still waiting on the rest of its batch makes it in too
+                bot.finish_rendering(&client).await;
+                bot.hold_pending();
+
+                if let Err(e) = bot.send_digest(&client).await {
+                    println!("Could not send the digest! {}", e);
+                }
+
+                continue;
+            }
+            Work::Monthly => {
+                if let Err(e) = bot.send_collages(&client).await {
+                    println!("Could not send the collages! {}", e);
+                }
+
+                if let Err(e) = bot.send_best_of(&client).await {
+                    println!("Could not send the best of! {}", e);
+                }
+
+                continue;
+            }
+        };
+
         let room = message.room.clone();
+        let event_id = message.event.event_id.to_string();
 
         match bot
             .on_room_message(message.event, message.room, client.clone())
@@ -61,21 +298,82 @@ pub async fn main() -> anyhow::Result<()> {
                     buffer.inc()
                 }
 
+                if let Room::Joined(joined) = &room {
+                    for (event_id, error) in std::mem::take(&mut bot.archive_errors) {
+                        if let Err(e) = matrix::send(joined, matrix::text_plain(&error)).await {
+                            println!("Could not say why a photo wasn't archived! {}", e);
+                        }
+
+                        bot.settle(&client, joined.room_id(), &event_id, FAILED)
+                            .await;
+                    }
+                }
+
+                // photos that come in together go out together
+                if bot.has_pending() {
+                    buffer.wait(batch_window());
+                }
+
                 let total = buffer.get_final_count();
 
                 if total > 0 {
-                    if let Room::Joined(joined) = room {
-                        joined
-                            .send(matrix::text_plain(&bot.recipients_friendly(total)), None)
-                            .await?;
+                    bot.finish_rendering(&client).await;
+
+                    if let Some(digest) = digest() {
+                        // held is as done as it gets until the digest goes out
+                        for pending in bot.pending.clone() {
+                            bot.settle(
+                                &client,
+                                &pending.origin.room_id,
+                                &pending.origin.event_id,
+                                DONE,
+                            )
+                            .await;
+                        }
+
+                        for room_id in bot.hold_pending() {
+                            if let Some(joined) = client.get_joined_room(&room_id) {
+                                let message = i18n::format(
+                                    "Saved for the digest ({when}).",
+                                    bot.language(&room_id).as_deref(),
+                                    &[("when", digest.to_string().as_str())],
+                                );
+
+                                if let Err(e) =
+                                    matrix::send(&joined, matrix::text_plain(&message)).await
+                                {
+                                    println!("Could not say a photo was held! {}", e);
+                                }
+                            }
+                        }
+                    } else {
+                        // kept around for the receipts, since sending takes them
+                        let sent = bot.pending.clone();
+
+                        for (room_id, result) in bot.send_pending().await {
+                            let sent: Vec<Pending> = sent
+                                .iter()
+                                .filter(|p| p.origin.room_id == room_id)
+                                .cloned()
+                                .collect();
+
+                            if let Err(e) = bot.report(&client, &room_id, &sent, result).await {
+                                println!("Could not report on sent photos! {}", e);
+                            }
+                        }
                     }
                 }
             }
             Err(err) => {
                 if let Room::Joined(joined) = room {
-                    joined
-                        .send(matrix::text_plain(&err.to_string()), None)
-                        .await?;
+                    if let Err(e) =
+                        matrix::send(&joined, matrix::text_plain(&err.to_string())).await
+                    {
+                        println!("Could not send an error! {}", e);
+                    }
+
+                    bot.settle(&client, joined.room_id(), &event_id, FAILED)
+                        .await;
                 } else {
                     print!("could not run message loop: {}", err);
                 }
@@ -84,18 +382,604 @@ pub async fn main() -> anyhow::Result<()> {
     }
 }
 
+// what the main loop waits on
+enum Work {
+    Message(Box<MessageEvent>),
+    // PHOTO_DIGEST came around
+    Digest,
+    // a new month, and with it last month's collages and best of
+    Monthly,
+}
+
 struct MessageEvent {
     event: SyncMessageEvent<MessageEventContent>,
     room: Room,
 }
 
 struct Bot {
-    only: Option<HashMap<String, Vec<String>>>,
+    // rooms sending to someone other than everyone, for now
+    filters: HashMap<RoomId, Filter>,
+    conn: Connection,
+    // attachments waiting to be emailed as one batch
+    pending: Vec<Pending>,
+    // attachments still being converted or shrunk, in the order they came in
+    rendering: Vec<Rendering>,
+    // archiving never holds up an email, so what went wrong (and with which event) is told to the
+    // room afterwards
+    archive_errors: Vec<(String, String)>,
+    // the reactions saying something's still being worked on, by the event they're on
+    acks: HashMap<String, EventId>,
+    // when each sender's photos came in over the last hour, and when anyone was last told they
+    // couldn't send any more
+    arrivals: HashMap<UserId, Vec<DateTime<Utc>>>,
+    turned_away: HashMap<UserId, DateTime<Utc>>,
+    // where photos come from, where emails go out, and where originals are kept, so a test can
+    // stand in for any of them
+    media: Box<dyn matrix::Media>,
+    mailer: Box<dyn mail::Mailer>,
+    archives: Vec<Box<dyn archive::Archive>>,
+}
+
+// who a room is sending to instead of everyone, and until when, if it's only for a while
+struct Filter {
+    only: HashMap<String, Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    fn expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= Utc::now())
+    }
+}
+
+// PHOTO_ROOMS (a JSON map of room ID to settings) gives rooms their own recipients and album, like
+// {"!abc:kulak.us": {"recipients": ["grandma", "grandpa"], "album": "Grandparents"}}. Rooms that
+// aren't listed send to everyone, and archive to the usual album.
+#[derive(Clone, Default, Deserialize)]
+struct RoomConfig {
+    recipients: Option<Vec<String>>,
+    album: Option<String>,
+}
+
+static ROOMS: Lazy<HashMap<String, RoomConfig>> = Lazy::new(|| config::load("PHOTO_ROOMS"));
+
+// each room PHOTO_ROOMS sets up, and where its photos go
+pub fn room_routes() -> Vec<(String, String)> {
+    ROOMS
+        .iter()
+        .map(|(room_id, config)| {
+            let to = match &config.recipients {
+                Some(names) => names.join(", "),
+                None => "everyone".to_string(),
+            };
+            let album = config.album.as_deref().unwrap_or("the usual album");
+
+            (room_id.clone(), format!("to {}, archived to {}", to, album))
+        })
+        .collect()
+}
+
+fn room_config(room_id: &RoomId) -> RoomConfig {
+    ROOMS.get(room_id.as_str()).cloned().unwrap_or_default()
+}
+
+#[derive(Clone)]
+struct Pending {
+    data: Bytes,
+    mime_type: String,
+    caption: Option<String>,
+    // what the vision model saw, with PHOTO_AI_CAPTIONS on
+    description: Option<String>,
+    // kept so it can be sent as is, or shrunk differently, for anyone who wants that
+    original: Bytes,
+    original_mime_type: String,
+    enhance: bool,
+    origin: Origin,
+}
+
+// something that came in, still as the original until its job is done and hands back what to
+// email, and as what type
+struct Rendering {
+    pending: Pending,
+    job: task::JoinHandle<anyhow::Result<(Bytes, String)>>,
+    // a photo being described isn't archived until it is, so the archive gets the description too
+    description: Option<task::JoinHandle<Option<String>>>,
+}
+
+// runs image or video work on the blocking pool, once a worker's free
+fn spawn_render<F>(work: F) -> task::JoinHandle<anyhow::Result<(Bytes, String)>>
+where
+    F: FnOnce() -> anyhow::Result<(Bytes, String)> + Send + 'static,
+{
+    task::spawn(async move {
+        let _worker = WORKERS.acquire().await?;
+        task::spawn_blocking(work).await?
+    })
+}
+
+// where something came in, so the receipt can go in its thread and the send can be counted
+#[derive(Clone)]
+struct Origin {
+    room_id: RoomId,
+    event_id: String,
+    sender: String,
+    date: DateTime<Utc>,
+}
+
+// something held for the digest, and who it's going to (as a JSON list of addresses)
+struct Held {
+    id: i64,
+    addresses: String,
+    pending: Pending,
+}
+
+impl Pending {
+    // what a recipient with the given preferences should get
+    fn for_prefs(&self, prefs: &Prefs) -> anyhow::Result<Pending> {
+        let mut pending = self.clone();
+
+        let scrub = image::scrub();
+
+        if prefs.original {
+            pending.mime_type = self.original_mime_type.clone();
+
+            // the archive keeps everything, but what gets emailed loses whatever it's told to
+            pending.data = match self.original_mime_type.as_str() {
+                _ if scrub == image::Scrub::Nothing => self.original.clone(),
+                "image/jpeg" => image::scrub_jpeg(&self.original, scrub)?,
+                "image/png" => image::scrub_png(&self.original, scrub)?,
+                // GIFs have nowhere to keep a location, and re-encoding one would stop it moving
+                "image/gif" => self.original.clone(),
+                video if video.starts_with("video/") => video::scrub(&self.original, video)?,
+                // there's no taking metadata out of anything else in place (like a HEIC), so it's
+                // re-encoded instead, without losing anything it doesn't have to
+                _ => {
+                    pending.mime_type = "image/jpeg".to_string();
+
+                    image::render(
+                        &self.original,
+                        &self.original_mime_type,
+                        false,
+                        image::Output {
+                            max_size: Some((u32::MAX, u32::MAX)),
+                            quality: Some(100),
+                        },
+                    )?
+                }
+            };
+        } else if self.mime_type.starts_with("video/") {
+            // small videos go out as they came, and shrinking them keeps the metadata too
+            if scrub != image::Scrub::Nothing {
+                pending.data = video::scrub(&self.data, &self.mime_type)?;
+            }
+        } else if prefs.output != image::Output::default() && self.mime_type == "image/jpeg" {
+            // animations going out as they are aren't shrunk any differently
+            pending.data = image::render(
+                &self.original,
+                &self.original_mime_type,
+                self.enhance,
+                prefs.output,
+            )?;
+        }
+
+        Ok(pending)
+    }
+}
+
+// how someone likes their photos; anything not set gets the defaults
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+struct Prefs {
+    output: image::Output,
+    original: bool,
+}
+
+impl fmt::Display for Prefs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.original {
+            return write!(f, "originals");
+        }
+
+        let mut parts = vec![];
+
+        if let Some((width, height)) = self.output.max_size {
+            parts.push(format!("{}x{}", width, height));
+        }
+
+        if let Some(quality) = self.output.quality {
+            parts.push(format!("quality {}", quality));
+        }
+
+        if parts.is_empty() {
+            write!(f, "defaults")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
 }
 
 impl Bot {
-    fn new() -> Bot {
-        Bot { only: None }
+    fn new() -> anyhow::Result<Bot> {
+        let bot = Bot::open(
+            storage::open("photobot")?,
+            Box::new(matrix::Homeserver::default()),
+            Box::new(mail::Smtp::configured()),
+            archive::configured()?,
+        )?;
+
+        // SMTP_TO (a JSON map of name to email addresses) seeds the list the first time through
+        let total: i64 = bot
+            .conn
+            .query_row("SELECT COUNT(*) FROM recipients", [], |row| row.get(0))?;
+
+        if total == 0 {
+            let seed: HashMap<String, Vec<String>> = config::load("SMTP_TO");
+
+            for (name, emails) in seed {
+                for email in emails {
+                    bot.add_recipient(&name, &email)?;
+                }
+            }
+        }
+
+        for (room_id, filter) in &bot.filters {
+            println!("{} only sending to {:?}", room_id, filter.only);
+        }
+
+        Ok(bot)
+    }
+
+    // A bot on its database, with everything it needs from outside handed to it, so a test can
+    // make one without any of the real ones.
+    fn open(
+        conn: Connection,
+        media: Box<dyn matrix::Media>,
+        mailer: Box<dyn mail::Mailer>,
+        archives: Vec<Box<dyn archive::Archive>>,
+    ) -> anyhow::Result<Bot> {
+        // each room's filter, if it has one, as a JSON list of recipient names
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS filters (
+                room_id TEXT PRIMARY KEY,
+                names TEXT NOT NULL,
+                expires_at TEXT
+            )",
+            [],
+        )?;
+
+        // There used to be one filter for everything. It carries over to the room it was set in.
+        let has_filter: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'filter'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        if has_filter > 0 {
+            let has_expires_at: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('filter') WHERE name = 'expires_at'",
+                [],
+                |row| row.get(0),
+            )?;
+
+            if has_expires_at == 0 {
+                conn.execute("ALTER TABLE filter ADD COLUMN expires_at TEXT", [])?;
+            }
+
+            conn.execute(
+                "
+                INSERT OR IGNORE INTO filters
+                    (room_id, names, expires_at)
+                SELECT room_id, names, expires_at FROM filter",
+                [],
+            )?;
+
+            conn.execute("DROP TABLE filter", [])?;
+        }
+
+        // what language each room gets its messages in, when it isn't English
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS languages (
+                room_id TEXT PRIMARY KEY,
+                language TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS outbox (
+                id INTEGER PRIMARY KEY,
+                address TEXT NOT NULL,
+                email BLOB NOT NULL,
+                room_id TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                next_attempt TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Anywhere that isn't email gets one photo or video at a time, which waits in the outbox
+        // as is, along with what it is and its caption. Emails have those built in.
+        for (column, definition) in [("mime_type", "TEXT"), ("caption", "TEXT")] {
+            let exists: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('outbox') WHERE name = ?1",
+                params![column],
+                |row| row.get(0),
+            )?;
+
+            if exists == 0 {
+                conn.execute(
+                    &format!("ALTER TABLE outbox ADD COLUMN {} {}", column, definition),
+                    [],
+                )?;
+            }
+        }
+
+        // the frame only ever got photos, even before they said so
+        conn.execute(
+            "UPDATE outbox SET mime_type = 'image/jpeg' WHERE address = ?1 AND mime_type IS NULL",
+            params![frame::ADDRESS],
+        )?;
+
+        // photos held for the digest, along with who they were going to when they came in
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS digest (
+                id INTEGER PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                event_id TEXT NOT NULL,
+                addresses TEXT NOT NULL,
+                data BLOB NOT NULL,
+                mime_type TEXT NOT NULL,
+                caption TEXT,
+                original BLOB NOT NULL,
+                original_mime_type TEXT NOT NULL,
+                enhance INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        let has_sender: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('digest') WHERE name = 'sender'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        if has_sender == 0 {
+            conn.execute(
+                "ALTER TABLE digest ADD COLUMN sender TEXT NOT NULL DEFAULT ''",
+                [],
+            )?;
+        }
+
+        let has_description: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('digest') WHERE name = 'description'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        if has_description == 0 {
+            conn.execute("ALTER TABLE digest ADD COLUMN description TEXT", [])?;
+        }
+
+        // the last few things each room sent, and the name they were archived under, so they can
+        // be sent again
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS recent (
+                id INTEGER PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                event_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                data BLOB NOT NULL,
+                mime_type TEXT NOT NULL,
+                caption TEXT,
+                original BLOB NOT NULL,
+                original_mime_type TEXT NOT NULL,
+                enhance INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // when things came in, for the emails; anything from before this was kept just shows when
+        // it's sent
+        for table in ["digest", "recent"] {
+            let has_received_at: i64 = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = 'received_at'",
+                    table
+                ),
+                [],
+                |row| row.get(0),
+            )?;
+
+            if has_received_at == 0 {
+                conn.execute(
+                    &format!(
+                        "ALTER TABLE {} ADD COLUMN received_at TEXT NOT NULL DEFAULT ''",
+                        table
+                    ),
+                    [],
+                )?;
+            }
+        }
+
+        // A small square of every photo this month and last, and how many reactions each got, for
+        // the monthly collage. The photos themselves only stay in the archive.
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS highlights (
+                event_id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                tile BLOB NOT NULL,
+                reactions INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+
+        // Every photo this month and last, as emailed, and how many stars each got, for the best
+        // of. Anything never starred goes once the month's done.
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS best_of (
+                event_id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                received_at TEXT NOT NULL,
+                data BLOB NOT NULL,
+                mime_type TEXT NOT NULL,
+                caption TEXT,
+                stars INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+
+        // every photo handed off to every address, for the stats
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS sends (
+                id INTEGER PRIMARY KEY,
+                sent_at TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                address TEXT NOT NULL,
+                event_id TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "
+            CREATE TABLE IF NOT EXISTS recipients (
+                name TEXT NOT NULL,
+                email TEXT NOT NULL,
+                PRIMARY KEY (name, email)
+            )",
+            [],
+        )?;
+
+        // how each address likes its photos
+        for (column, definition) in [
+            ("max_width", "INTEGER"),
+            ("max_height", "INTEGER"),
+            ("quality", "INTEGER"),
+            ("original", "INTEGER NOT NULL DEFAULT 0"),
+            // emails that have failed in a row, and whether that's been enough to stop sending
+            ("failures", "INTEGER NOT NULL DEFAULT 0"),
+            ("last_error", "TEXT"),
+            ("paused", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            let exists: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('recipients') WHERE name = ?1",
+                params![column],
+                |row| row.get(0),
+            )?;
+
+            if exists == 0 {
+                conn.execute(
+                    &format!(
+                        "ALTER TABLE recipients ADD COLUMN {} {}",
+                        column, definition
+                    ),
+                    [],
+                )?;
+            }
+        }
+
+        let mut bot = Bot {
+            filters: HashMap::new(),
+            conn,
+            pending: vec![],
+            rendering: vec![],
+            archive_errors: vec![],
+            acks: HashMap::new(),
+            arrivals: HashMap::new(),
+            turned_away: HashMap::new(),
+            media,
+            mailer,
+            archives,
+        };
+
+        bot.filters = bot.load_filters()?;
+
+        Ok(bot)
+    }
+
+    fn load_filters(&self) -> anyhow::Result<HashMap<RoomId, Filter>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, names, expires_at FROM filters")?;
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, Option<String>)>>>()?;
+
+        // anyone who's been taken out of the recipients since is dropped
+        let all = self.all_recipients();
+        let mut filters: HashMap<RoomId, Filter> = HashMap::new();
+
+        for (room_id, names, expires_at) in rows {
+            let names: Vec<String> = serde_json::from_str(&names)?;
+
+            let expires = match expires_at {
+                Some(expires_at) => Some(DateTime::from_str(&expires_at)?),
+                None => None,
+            };
+
+            let only = names
+                .into_iter()
+                .filter_map(|name| all.get(&name).map(|to| (name, to.clone())))
+                .collect();
+
+            filters.insert(
+                RoomId::try_from(room_id.as_str())?,
+                Filter { only, expires },
+            );
+        }
+
+        Ok(filters)
+    }
+
+    fn set_only(
+        &mut self,
+        only: Option<HashMap<String, Vec<String>>>,
+        room_id: &RoomId,
+        expires: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        match only {
+            Some(only) => {
+                let names: Vec<&String> = only.keys().collect();
+
+                self.conn.execute(
+                    "
+                    INSERT INTO filters
+                        (room_id, names, expires_at)
+                    VALUES
+                        (?1, ?2, ?3)
+                    ON CONFLICT(room_id) DO UPDATE SET names=?2, expires_at=?3",
+                    params![
+                        room_id.as_str(),
+                        serde_json::to_string(&names)?,
+                        expires.map(|e| e.to_rfc3339())
+                    ],
+                )?;
+
+                self.filters
+                    .insert(room_id.clone(), Filter { only, expires });
+            }
+            None => {
+                self.conn.execute(
+                    "DELETE FROM filters WHERE room_id = ?1",
+                    params![room_id.as_str()],
+                )?;
+
+                self.filters.remove(room_id);
+            }
+        }
+
+        Ok(())
     }
 
     async fn on_room_message(
@@ -105,14 +989,63 @@ impl Bot {
         client: Client,
     ) -> anyhow::Result<bool> {
         // text messages
-        if let Some((joined, _, message)) =
+        if let Some((joined, sender, message)) =
             matrix::get_text_message(event.clone(), room.clone(), client.clone()).await
         {
             // see what's going on
             if matrix::get_command("who", &message).is_some() {
-                joined
-                    .send(matrix::text_plain(&self.recipients_friendly(0)), None)
+                matrix::send(
+                    &joined,
+                    matrix::text_plain(&self.recipients_friendly(joined.room_id())),
+                )
+                .await?;
+
+            // don't wait for the digest
+            } else if matrix::get_command("send now", &message).is_some() {
+                let response = match self.send_digest(&client).await? {
+                    0 => "There's nothing waiting for the digest.".to_string(),
+                    1 => "Sent the photo that was waiting for the digest.".to_string(),
+                    count => format!(
+                        "Sent the {} photos that were waiting for the digest.",
+                        count
+                    ),
+                };
+
+                matrix::send(&joined, matrix::text_plain(&response)).await?;
+
+            // send something again, without it being uploaded again
+            } else if let Some(command) = matrix::get_command("resend last", &message) {
+                let response = self.on_resend_message(&joined, command).await?;
+                matrix::send(&joined, matrix::text_plain(&response)).await?;
+
+            // dig up something sent a while ago
+            } else if let Some(command) = matrix::get_command("find", &message) {
+                let response = on_find_message(&client, &joined, command).await?;
+                matrix::send(&joined, matrix::text_plain(&response)).await?;
+
+            // how many have gone out lately
+            } else if matrix::get_command("stats", &message).is_some() {
+                matrix::send(&joined, matrix::text_plain(&self.stats()?)).await?;
+
+            // manage who can get photos at all
+            } else if let Some(command) = matrix::get_command("add recipient", &message) {
+                self.on_recipients_message(&joined, &sender, &format!("add {}", command))
                     .await?;
+            } else if let Some(command) = matrix::get_command("remove recipient", &message) {
+                self.on_recipients_message(&joined, &sender, &format!("remove {}", command))
+                    .await?;
+            } else if matrix::get_command("list recipients", &message).is_some() {
+                self.on_recipients_message(&joined, &sender, "list").await?;
+            } else if let Some(command) = matrix::get_command("recipient", &message) {
+                self.on_recipients_message(&joined, &sender, &format!("set {}", command))
+                    .await?;
+
+            // a single word, like the AI bot takes
+            } else if let Some(language) = matrix::get_command("photo language", &message) {
+                if !language.is_empty() && !language.contains(' ') {
+                    let response = self.set_language(joined.room_id(), language)?;
+                    matrix::send(&joined, matrix::text_plain(&response)).await?;
+                }
 
             // reset the recipients
             } else if matrix::find_command(
@@ -121,84 +1054,134 @@ impl Bot {
             )
             .is_some()
             {
-                self.only = None;
-                joined
-                    .send(matrix::text_plain(&self.recipients_friendly(0)), None)
-                    .await?;
+                self.set_only(None, joined.room_id(), None)?;
+                matrix::send(
+                    &joined,
+                    matrix::text_plain(&self.recipients_friendly(joined.room_id())),
+                )
+                .await?;
 
             // help!
-            } else if matrix::get_command("help", &message).is_some() {
-                let text = [
-                    "who: Show who photos are currently being sent to.",
-                    "to mark: Only send photos to Mark.",
-                    "to mark jane: Only send photos to Mark and Jane.",
-                    "not mark: Don't send photos to Mark.",
-                    "reset: Send photos to everyone.",
-                ];
-
-                let html = [
-                    "<ul>",
-                    "<li><strong>who</strong>: Show who photos are currently being sent to.</li>",
-                    "<li><strong>to mark</strong>: Only send photos to Mark.</li>",
-                    "<li><strong>to mark jane</strong>: Only send photos to Mark and Jane.</li>",
-                    "<li><strong>not mark</strong>: Don't send photos to Mark.</li>",
-                    "<li><strong>reset</strong>: Send photos to everyone.</li>",
-                    "</ul>",
-                ];
-
-                joined
-                    .send(matrix::text_html(&text.join("\n"), &html.join("\n")), None)
-                    .await?;
+            } else if matrix::get_command("help", &message).is_some() && !commands::unified() {
+                let (text, html) = commands::help(commands::PHOTO);
+
+                matrix::send(&joined, matrix::text_html(&text, &html)).await?;
 
             // skip some recipients
             } else if let Some(command) = matrix::get_command("not", &message) {
-                let recipients = self.command_as_recipients(command)?;
-                let mut filtered = self.recipients();
+                let (command, expires) = parse_expiry(command);
+                let recipients = self.command_as_recipients(joined.room_id(), command)?;
+                let mut filtered = self.recipients(joined.room_id());
                 for skip in recipients {
                     filtered.remove(&skip);
                 }
-                self.only = Some(filtered.clone());
+                self.set_only(Some(filtered), joined.room_id(), expires)?;
 
-                joined
-                    .send(matrix::text_plain(&self.recipients_friendly(0)), None)
-                    .await?;
+                matrix::send(
+                    &joined,
+                    matrix::text_plain(&self.recipients_friendly(joined.room_id())),
+                )
+                .await?;
 
-                println!("only sending to {:?}", self.only);
+                println!(
+                    "{} only sending to {:?}",
+                    joined.room_id(),
+                    self.recipients(joined.room_id()).keys()
+                );
 
             // only send to some recipients
             } else if let Some(command) =
                 matrix::find_command(vec!["to", "send to", "only"], &message)
             {
-                let recipients = self.command_as_recipients(command)?;
-                let all = Bot::all_recipients();
+                let (command, expires) = parse_expiry(command);
+                let recipients = self.command_as_recipients(joined.room_id(), command)?;
+                let all = self.room_recipients(joined.room_id());
                 let mut filtered: HashMap<String, Vec<String>> = HashMap::new();
                 for to in &recipients {
                     filtered.insert(to.clone(), all[to].clone());
                 }
-                self.only = Some(filtered.clone());
+                self.set_only(Some(filtered), joined.room_id(), expires)?;
 
-                joined
-                    .send(matrix::text_plain(&self.recipients_friendly(0)), None)
-                    .await?;
+                matrix::send(
+                    &joined,
+                    matrix::text_plain(&self.recipients_friendly(joined.room_id())),
+                )
+                .await?;
+
+                println!(
+                    "{} only sending to {:?}",
+                    joined.room_id(),
+                    self.recipients(joined.room_id()).keys()
+                );
+            }
+        }
+
+        // touch up photos, unless the caption says not to
+        let enhance = image::enhance_enabled()
+            && !matrix::get_media_body(&event)
+                .unwrap_or_default()
+                .to_lowercase()
+                .contains("no edit");
+
+        let caption = caption(&event);
+        let origin = Origin {
+            room_id: room.room_id().clone(),
+            event_id: event.event_id.to_string(),
+            sender: matrix::canonical_user_id(&event.sender).to_string(),
+            date: event
+                .origin_server_ts
+                .to_system_time()
+                .map(DateTime::from)
+                .unwrap_or_else(Utc::now),
+        };
+
+        // not everyone the bot shares a room with gets to email the family
+        if matrix::get_media_body(&event).is_some() {
+            if let Err(rejection) = self.admit(&event.sender) {
+                if let (Room::Joined(joined), Some(rejection)) = (&room, rejection) {
+                    matrix::send(joined, matrix::text_plain(&rejection)).await?;
+                }
 
-                println!("only sending to {:?}", self.only);
+                return Ok(false);
             }
         }
 
         // photos
-        if let Some((_, _, uri, info)) =
+        if let Some((joined, _, uri, info)) =
             matrix::get_image_message(event.clone(), room.clone(), client.clone()).await
         {
-            println!("got photo mime type of {:#?}", info.mimetype);
+            let mime_type = info.and_then(|info| info.mimetype);
+            println!("got photo mime type of {:#?}", mime_type);
+            self.acknowledge(&joined, &origin.event_id).await;
 
-            let photo = &matrix::download_photo(&uri).await?;
+            let photo = &self.media.download(&uri).await?;
 
-            let jpeg = match info.mimetype.as_deref() {
-                Some("image/heic") | Some("image/heif") => image::convert_heic_to_jpeg(photo)?,
-                _ => image::shrink_jpeg(photo)?,
+            // Without a type, the bytes say what it is. Anything they don't is left to the
+            // decoder, which guesses the format for itself.
+            let mime_type = match mime_type {
+                Some(mime_type) => mime_type,
+                None => image::sniff_mime_type(photo)
+                    .unwrap_or("image/jpeg")
+                    .to_string(),
             };
 
-            self.send_photo(&jpeg, photo, &info.mimetype.unwrap())
+            self.send_photo(photo, &mime_type, caption, enhance, &origin)
+                .await?;
+
+            return Ok(true);
+        }
+
+        // videos
+        if let Some((joined, _, uri, info)) =
+            matrix::get_video_message(event.clone(), room.clone(), client.clone()).await
+        {
+            println!("got video mime type of {:#?}", info.mimetype);
+            self.acknowledge(&joined, &origin.event_id).await;
+
+            let original = &self.media.download(&uri).await?;
+            let mime_type = info.mimetype.as_deref().unwrap_or("video/mp4");
+
+            self.send_video(original, mime_type, caption, &origin)
                 .await?;
 
             return Ok(true);
@@ -210,21 +1193,32 @@ impl Bot {
         {
             println!("got mime type of {:#?}", info.mimetype);
 
+            // RAW files are archived as they came, and emailed as the preview inside
+            let file_name = matrix::get_media_body(&event).unwrap_or_default();
+
+            if let Some(raw) = image::raw_mime_type(info.mimetype.as_deref(), file_name) {
+                self.acknowledge(&joined, &origin.event_id).await;
+                let photo = &self.media.download(&uri).await?;
+                self.send_photo(photo, raw, caption, enhance, &origin)
+                    .await?;
+                return Ok(true);
+            }
+
             match info.mimetype.as_deref() {
-                Some("image/heic") | Some("image/heif") => {
-                    let photo = &matrix::download_photo(&uri).await?;
-                    let jpeg = image::convert_heic_to_jpeg(photo)?;
-                    self.send_photo(&jpeg, photo, &info.mimetype.unwrap())
+                Some("image/heic") | Some("image/heif") | Some("image/gif")
+                | Some("image/webp") => {
+                    self.acknowledge(&joined, &origin.event_id).await;
+                    let photo = &self.media.download(&uri).await?;
+                    self.send_photo(photo, &info.mimetype.unwrap(), caption, enhance, &origin)
                         .await?;
                     return Ok(true);
                 }
                 _ => {
-                    joined
-                        .send(
-                            matrix::text_plain("I don't know what to do with that file. :("),
-                            None,
-                        )
-                        .await?;
+                    matrix::send(
+                        &joined,
+                        matrix::text_plain("I don't know what to do with that file. :("),
+                    )
+                    .await?;
                 }
             };
         }
@@ -234,143 +1228,2935 @@ impl Bot {
 
     async fn send_photo(
         &mut self,
-        jpeg: &Bytes,
         photo: &Bytes,
         mime_type: &str,
+        caption: Option<String>,
+        enhance: bool,
+        origin: &Origin,
     ) -> anyhow::Result<()> {
-        send_emails(jpeg, "image/jpeg", self.recipients().values().flatten())?;
-        save_photo(photo, mime_type)?;
+        // animations go as they are, if they aren't too big, and as their first frame if they are
+        let job = spawn_render({
+            let photo = photo.clone();
+            let mime_type = mime_type.to_string();
+
+            move || {
+                if image::animated(&photo, &mime_type) && photo.len() <= image::max_animated_size()
+                {
+                    Ok((photo, mime_type))
+                } else {
+                    let jpeg =
+                        image::render(&photo, &mime_type, enhance, image::Output::default())?;
+                    Ok((jpeg, "image/jpeg".to_string()))
+                }
+            }
+        });
+
+        let description = ai_captions().then(|| describe(photo.clone(), mime_type.to_string()));
+        let describing = description.is_some();
+
+        self.rendering.push(Rendering {
+            pending: Pending {
+                data: photo.clone(),
+                mime_type: mime_type.to_string(),
+                caption: caption.clone(),
+                description: None,
+                original: photo.clone(),
+                original_mime_type: mime_type.to_string(),
+                enhance,
+                origin: origin.clone(),
+            },
+            job,
+            description,
+        });
+
+        // Live and motion photos have a few seconds of video in them too, which is kept beside the
+        // still, and emailed along with it with PHOTO_LIVE_VIDEOS on.
+        if matches!(mime_type, "image/jpeg" | "image/heic" | "image/heif") {
+            let motion = task::spawn_blocking({
+                let photo = photo.clone();
+                move || video::motion(&photo)
+            })
+            .await?
+            .map_err(|e| println!("Could not get the video out of a live photo: {}", e))
+            .ok()
+            .flatten();
+
+            if let Some((clip, clip_type)) = motion {
+                if live_videos() {
+                    // which archives it too
+                    self.send_video(&clip, clip_type, caption.clone(), origin)
+                        .await?;
+                } else {
+                    let archived = archive(
+                        &self.archives,
+                        &clip,
+                        clip_type,
+                        caption.as_deref(),
+                        None,
+                        &origin.room_id,
+                    )
+                    .await;
+
+                    if let Err(e) = archived {
+                        self.archive_errors
+                            .push((origin.event_id.clone(), e.to_string()));
+                    }
+                }
+            }
+        }
+
+        if describing {
+            return Ok(());
+        }
+
+        let archived = archive(
+            &self.archives,
+            photo,
+            mime_type,
+            caption.as_deref(),
+            None,
+            &origin.room_id,
+        )
+        .await;
+
+        if let Err(e) = archived {
+            self.archive_errors
+                .push((origin.event_id.clone(), e.to_string()));
+        }
 
         Ok(())
     }
 
-    fn all_recipients() -> HashMap<String, Vec<String>> {
-        let json = env::var("SMTP_TO").expect("SMTP_TO environmental variable not set");
-        serde_json::from_str(json.as_str()).unwrap()
+    async fn send_video(
+        &mut self,
+        video: &Bytes,
+        mime_type: &str,
+        caption: Option<String>,
+        origin: &Origin,
+    ) -> anyhow::Result<()> {
+        // always keep the original, even if it's too big to email
+        let archived = archive(
+            &self.archives,
+            video,
+            mime_type,
+            caption.as_deref(),
+            None,
+            &origin.room_id,
+        )
+        .await;
+
+        if let Err(e) = archived {
+            self.archive_errors
+                .push((origin.event_id.clone(), e.to_string()));
+        }
+
+        let job = spawn_render({
+            let video = video.clone();
+            let mime_type = mime_type.to_string();
+
+            move || {
+                let shrunk = video::shrink(&video)?;
+
+                // anything re-encoded is an MP4 now
+                if video.len() <= video::max_size() {
+                    Ok((shrunk, mime_type))
+                } else {
+                    Ok((shrunk, "video/mp4".to_string()))
+                }
+            }
+        });
+
+        self.rendering.push(Rendering {
+            pending: Pending {
+                data: video.clone(),
+                mime_type: mime_type.to_string(),
+                caption,
+                description: None,
+                original: video.clone(),
+                original_mime_type: mime_type.to_string(),
+                enhance: false,
+                origin: origin.clone(),
+            },
+            job,
+            description: None,
+        });
+
+        Ok(())
     }
 
-    fn recipients(&self) -> HashMap<String, Vec<String>> {
-        match self.only.clone() {
-            Some(recipients) => recipients,
-            None => Bot::all_recipients(),
+    // Waits on everything still being worked on, adding it to the batch in the order it came in,
+    // so each room's receipts stay in order no matter which finished first. Anything that couldn't
+    // be converted is left out, and its room told why. One going wrong doesn't stop the rest.
+    async fn finish_rendering(&mut self, client: &Client) {
+        for rendering in std::mem::take(&mut self.rendering) {
+            if let Err(e) = self.finish_one(client, rendering).await {
+                println!("Could not finish rendering a photo! {}", e);
+            }
         }
     }
 
-    fn command_as_recipients(&self, command: &str) -> anyhow::Result<HashSet<String>> {
-        let all = Bot::all_recipients();
-        let mut collected: HashSet<String> = HashSet::new();
+    async fn finish_one(&mut self, client: &Client, rendering: Rendering) -> anyhow::Result<()> {
+        let mut pending = rendering.pending;
+        let room_id = pending.origin.room_id.clone();
+        let event_id = pending.origin.event_id.clone();
 
-        for recip in command.split(' ') {
-            let r = recip.to_lowercase();
+        // the original always makes it to the archive, whatever happened with the rest
+        if let Some(description) = rendering.description {
+            pending.description = description.await.unwrap_or_default();
 
-            // allow sending to the Google album only
-            if r == "google" {
-                return Ok(HashSet::new());
+            let archived = archive(
+                &self.archives,
+                &pending.original,
+                &pending.original_mime_type,
+                pending.caption.as_deref(),
+                pending.description.as_deref(),
+                &room_id,
+            )
+            .await;
+
+            if let Err(e) = archived {
+                if let Some(joined) = client.get_joined_room(&room_id) {
+                    if let Err(e) = matrix::send(&joined, matrix::text_plain(&e.to_string())).await
+                    {
+                        println!("Could not say why a photo wasn't archived! {}", e);
+                    }
+                }
+
+                self.settle(client, &room_id, &event_id, FAILED).await;
             }
+        }
 
-            match all.get(&r) {
-                Some(_) => collected.insert(r.to_string()),
-                None => bail!("I don't know who {} is!", recip),
-            };
+        let (error, failed) = match rendering.job.await {
+            Ok(Ok((data, mime_type))) => {
+                pending.data = data;
+                pending.mime_type = mime_type;
+
+                let remembered = self.remember(&pending);
+                self.pending.push(pending);
+
+                match remembered {
+                    Ok(()) => return Ok(()),
+                    Err(e) => (e.to_string(), false),
+                }
+            }
+            Ok(Err(e)) => (e.to_string(), true),
+            Err(e) => (e.to_string(), true),
+        };
+
+        if failed {
+            self.settle(client, &room_id, &event_id, FAILED).await;
         }
 
-        Ok(collected)
+        if let Some(joined) = client.get_joined_room(&room_id) {
+            matrix::send(&joined, matrix::text_plain(&error)).await?;
+        }
+
+        Ok(())
     }
 
-    fn recipients_friendly(&self, total: usize) -> String {
-        let mut rec: Vec<String> = self.recipients().keys().map(|k| name_case(k)).collect();
+    // Whether someone can have a photo emailed right now: they have to be in PHOTO_SENDERS, and
+    // under the hourly limit, though parents always can. Anyone turned away is only told why once
+    // an hour (the rest of the time there's no reason), so a burst doesn't get a reply per photo.
+    fn admit(&mut self, sender: &UserId) -> Result<(), Option<String>> {
+        let sender = matrix::canonical_user_id(sender);
 
-        rec.sort();
+        if matrix::is_admin(&sender) {
+            return Ok(());
+        }
 
-        let who = match rec.len() {
-            0 => "the Google album only".to_string(),
-            1 => String::from(rec.first().unwrap()),
-            2 => format!("{} and {}", rec[0], rec[1]),
+        let now = Utc::now();
+        let hour_ago = now - chrono::Duration::hours(1);
+        let name = matrix::pretty_user_id(&sender);
+
+        let rejection = if !allowed_sender(&sender) {
+            format!(
+                "Sorry {}, I only email photos from the family, so that one stays here.",
+                name
+            )
+        } else {
+            let arrivals = self.arrivals.entry(sender.clone()).or_default();
+            arrivals.retain(|at| *at > hour_ago);
+
+            if arrivals.len() < hourly_limit() {
+                arrivals.push(now);
+                return Ok(());
+            }
+
+            format!(
+                "Sorry {}, that's more than {} in an hour, so I'll hold off on emailing any more \
+                for a bit. They're still here in the room.",
+                name,
+                counted(hourly_limit(), 0)
+            )
+        };
+
+        match self.turned_away.get(&sender) {
+            Some(told) if *told > hour_ago => Err(None),
             _ => {
-                let head = &rec[0..rec.len() - 2].join(", ");
-                let tail = format!("{} and {}", rec[rec.len() - 2], rec[rec.len() - 1]);
-                format!("{}, {}", head, tail)
+                self.turned_away.insert(sender, now);
+                Err(Some(rejection))
+            }
+        }
+    }
+
+    // lets the sender know something's come in, and is being worked on
+    async fn acknowledge(&mut self, joined: &Joined, event_id: &str) {
+        match matrix::react(joined, event_id, WORKING).await {
+            Ok(reaction) => {
+                self.acks.insert(event_id.to_string(), reaction);
             }
+            Err(e) => println!("Could not acknowledge {}! {}", event_id, e),
+        }
+    }
+
+    // Swaps the reaction saying something's being worked on for one saying how it went. Whatever
+    // settles it first wins, so a failure along the way isn't covered up by the batch going out.
+    async fn settle(&mut self, client: &Client, room_id: &RoomId, event_id: &str, key: &str) {
+        let reaction = match self.acks.remove(event_id) {
+            Some(reaction) => reaction,
+            None => return,
         };
 
-        if total > 0 {
-            let label = if total == 1 { "photo" } else { "photos" };
-            format!("Sent {} {} to {}.", total, label, who)
-        } else {
-            format!("Photos will be sent to {}.", who)
+        let joined = match client.get_joined_room(room_id) {
+            Some(joined) => joined,
+            None => return,
+        };
+
+        if let Err(e) = matrix::redact(&joined, &reaction).await {
+            println!("Could not take back a reaction! {}", e);
+        }
+
+        if let Err(e) = matrix::react(&joined, event_id, key).await {
+            println!("Could not react to {}! {}", event_id, e);
         }
     }
-}
 
-fn name_case(s: &str) -> String {
-    let mut c = s.chars();
-    match c.next() {
-        None => String::new(),
-        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    // keeps something that just came in, dropping whatever's older than the room's last few
+    fn remember(&self, pending: &Pending) -> anyhow::Result<()> {
+        let room_id = pending.origin.room_id.as_str();
+
+        self.conn.execute(
+            "
+            INSERT INTO recent
+                (room_id, event_id, sender, file_name, data, mime_type, caption, original,
+                original_mime_type, enhance, received_at)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                room_id,
+                pending.origin.event_id,
+                pending.origin.sender,
+                get_filename(&pending.original_mime_type, pending.caption.as_deref()),
+                pending.data.to_vec(),
+                pending.mime_type,
+                pending.caption,
+                pending.original.to_vec(),
+                pending.original_mime_type,
+                pending.enhance,
+                pending.origin.date.to_rfc3339(),
+            ],
+        )?;
+
+        self.conn.execute(
+            "
+            DELETE FROM recent
+            WHERE room_id = ?1 AND id NOT IN (
+                SELECT id FROM recent WHERE room_id = ?1 ORDER BY id DESC LIMIT ?2
+            )",
+            params![room_id, RECENT_LIMIT],
+        )?;
+
+        if collage_size().is_some() && pending.mime_type == "image/jpeg" {
+            match image::collage_tile(&pending.data) {
+                Ok(tile) => {
+                    self.conn.execute(
+                        "
+                        INSERT OR IGNORE INTO highlights
+                            (event_id, room_id, received_at, tile)
+                        VALUES
+                            (?1, ?2, ?3, ?4)",
+                        params![
+                            pending.origin.event_id,
+                            room_id,
+                            pending.origin.date.to_rfc3339(),
+                            tile.to_vec()
+                        ],
+                    )?;
+                }
+                Err(e) => println!("Could not make a collage tile! {}", e),
+            }
+        }
+
+        if best_of() && pending.mime_type.starts_with("image/") {
+            self.conn.execute(
+                "
+                INSERT OR IGNORE INTO best_of
+                    (event_id, room_id, sender, received_at, data, mime_type, caption)
+                VALUES
+                    (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    pending.origin.event_id,
+                    room_id,
+                    pending.origin.sender,
+                    pending.origin.date.to_rfc3339(),
+                    pending.data.to_vec(),
+                    pending.mime_type,
+                    pending.caption
+                ],
+            )?;
+        }
+
+        Ok(())
     }
-}
 
-fn get_filename(mime_type: &str) -> String {
-    let ext = mime_type.split('/').last().unwrap().to_lowercase();
+    // Emails each room's recipients every photo starred last month, all together, and puts them
+    // in a Google Photos album of their own if PHOTO_BEST_OF_ALBUM (like "Best of") says to.
+    async fn send_best_of(&mut self, client: &Client) -> anyhow::Result<()> {
+        if !best_of() {
+            return Ok(());
+        }
 
-    match ext.as_str() {
-        "jpeg" => "photo.jpg".to_string(),
-        _ => format!("photo.{}", ext),
+        let (last_month, from, until) = last_month();
+        let month = last_month.format("%B").to_string();
+
+        let starred: Vec<Pending> = {
+            let mut stmt = self.conn.prepare(
+                "
+                SELECT room_id, event_id, sender, received_at, data, mime_type
+                FROM best_of
+                WHERE stars > 0 AND received_at >= ?1 AND received_at < ?2
+                ORDER BY received_at",
+            )?;
+
+            type Row = (String, String, String, String, Vec<u8>, String);
+
+            let rows = stmt.query_map(params![from, until], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            })?;
+
+            let mut starred = vec![];
+
+            for row in rows {
+                let (room_id, event_id, sender, received_at, data, mime_type): Row = row?;
+                let data = Bytes::from(data);
+
+                starred.push(Pending {
+                    original: data.clone(),
+                    original_mime_type: mime_type.clone(),
+                    data,
+                    mime_type,
+                    caption: None,
+                    description: None,
+                    enhance: false,
+                    origin: Origin {
+                        room_id: RoomId::try_from(room_id.as_str())?,
+                        event_id,
+                        sender,
+                        date: DateTime::parse_from_rfc3339(&received_at)?.with_timezone(&Utc),
+                    },
+                });
+            }
+
+            starred
+        };
+
+        let mut room_ids: Vec<RoomId> = vec![];
+
+        for pending in &starred {
+            if !room_ids.contains(&pending.origin.room_id) {
+                room_ids.push(pending.origin.room_id.clone());
+            }
+        }
+
+        for room_id in room_ids {
+            let title = i18n::format(
+                "The best of {month}.",
+                self.language(&room_id).as_deref(),
+                &[("month", month.as_str())],
+            );
+
+            // the title stands in for the captions, so it's what the email is called
+            let sent: Vec<Pending> = starred
+                .iter()
+                .filter(|p| p.origin.room_id == room_id)
+                .map(|p| Pending {
+                    caption: Some(title.clone()),
+                    ..p.clone()
+                })
+                .collect();
+
+            if let Ok(prefix) = env::var("PHOTO_BEST_OF_ALBUM") {
+                if google_photos::enabled() {
+                    let album = format!("{} {}", prefix, last_month.format("%B %Y"));
+
+                    for pending in &sent {
+                        let file_name = get_filename(&pending.mime_type, None);
+
+                        if let Err(e) = google_photos::upload(
+                            &pending.data,
+                            &pending.mime_type,
+                            &file_name,
+                            None,
+                            Some(&album),
+                        )
+                        .await
+                        {
+                            println!("Could not add to the best of album! {}", e);
+                        }
+                    }
+                }
+            }
+
+            let addresses: Vec<String> =
+                self.recipients(&room_id).into_values().flatten().collect();
+            let result = self.send_batch(&sent, &addresses, &room_id).await;
+
+            self.report(client, &room_id, &sent, result).await?;
+        }
+
+        // nothing from before this month can be starred into a best of anymore
+        self.conn
+            .execute("DELETE FROM best_of WHERE received_at < ?1", params![until])?;
+
+        Ok(())
     }
-}
 
-fn save_photo(photo: &Bytes, mime_type: &str) -> anyhow::Result<()> {
-    let ext = mime_type.split('/').last().unwrap();
+    // Posts a collage of last month's favorites to every room that had any photos: the most
+    // reacted to, then the latest. Its recipients get it too, if PHOTO_COLLAGE_EMAIL is "true".
+    async fn send_collages(&self, client: &Client) -> anyhow::Result<()> {
+        let size = match collage_size() {
+            Some(size) => size,
+            None => return Ok(()),
+        };
 
-    let prefix = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+        let (last_month, from, until) = last_month();
 
-    let dir = env::var("DROPBOX").expect("DROPBOX environmental variable not set");
+        let room_ids: Vec<String> = {
+            let mut stmt = self.conn.prepare(
+                "
+                SELECT DISTINCT room_id
+                FROM highlights
+                WHERE received_at >= ?1 AND received_at < ?2",
+            )?;
 
-    let path = format!("{}/{}.{}", dir, prefix, ext);
+            let rows = stmt.query_map(params![from, until], |row| row.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
 
-    Ok(fs::write(path, photo)?)
-}
+        for room_id in room_ids {
+            let room_id = RoomId::try_from(room_id.as_str())?;
 
-// TODO: this should be async
-fn send_emails<'a, I>(photo: &Bytes, mime_type: &str, to: I) -> anyhow::Result<()>
-where
-    I: Iterator<Item = &'a String>,
-{
-    let to = Vec::from_iter(to);
+            let joined = match client.get_joined_room(&room_id) {
+                Some(joined) => joined,
+                None => continue,
+            };
 
-    let username = env::var("SMTP_USERNAME").expect("SMTP_USERNAME environmental variable not set");
+            let tiles: Vec<Bytes> = {
+                let mut stmt = self.conn.prepare(
+                    "
+                    SELECT tile
+                    FROM highlights
+                    WHERE room_id = ?1 AND received_at >= ?2 AND received_at < ?3
+                    ORDER BY reactions DESC, received_at DESC
+                    LIMIT ?4",
+                )?;
 
-    let password = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD environmental variable not set");
+                let rows = stmt.query_map(params![room_id.as_str(), from, until, size], |row| {
+                    row.get::<_, Vec<u8>>(0)
+                })?;
 
-    let server = env::var("SMTP_SERVER").expect("SMTP_SERVER environmental variable not set");
+                rows.map(|row| row.map(Bytes::from))
+                    .collect::<Result<_, _>>()?
+            };
+
+            let (jpeg, width, height) =
+                task::spawn_blocking(move || image::collage(&tiles)).await??;
 
-    let from = env::var("SMTP_FROM").expect("SMTP_FROM environmental variable not set");
+            let caption = i18n::format(
+                "The best of {month}.",
+                self.language(&room_id).as_deref(),
+                &[("month", last_month.format("%B").to_string().as_str())],
+            );
 
-    let creds = Credentials::new(username, password);
-    let body = Body::new(photo.to_vec());
+            let event_id =
+                matrix::send_image(client, &joined, &caption, &jpeg, width, height).await?;
+
+            if !collage_email() {
+                continue;
+            }
 
-    let mailer = SmtpTransport::relay(&server)
-        .unwrap()
-        .credentials(creds)
-        .build();
+            let collage = Pending {
+                data: jpeg.clone(),
+                mime_type: "image/jpeg".to_string(),
+                caption: Some(caption),
+                description: None,
+                original: jpeg,
+                original_mime_type: "image/jpeg".to_string(),
+                enhance: false,
+                origin: Origin {
+                    room_id: room_id.clone(),
+                    event_id: event_id.to_string(),
+                    sender: client
+                        .user_id()
+                        .await
+                        .map(|u| u.to_string())
+                        .unwrap_or_default(),
+                    date: Utc::now(),
+                },
+            };
 
-    for address in to {
-        let email = Message::builder()
-            .from(from.parse()?)
-            .to(address.parse()?)
-            .subject("Photo")
-            .multipart(MultiPart::mixed().singlepart(
-                Attachment::new(get_filename(mime_type)).body(body.clone(), mime_type.parse()?),
-            ))?;
+            let addresses: Vec<String> =
+                self.recipients(&room_id).into_values().flatten().collect();
 
-        match mailer.send(&email) {
-            Ok(_) => println!("Sent photo to {}", address),
-            Err(e) => panic!("Could not send email: {:?}", e),
+            if let Err(e) = self.send_batch(&[collage], &addresses, &room_id).await {
+                println!("Could not email the collage! {}", e);
+            }
         }
+
+        // nothing older than last month is ever needed again
+        self.conn.execute(
+            "DELETE FROM highlights WHERE received_at < ?1",
+            params![from],
+        )?;
+
+        Ok(())
     }
 
-    Ok(())
+    // the last few things that came into a room, oldest first, and the names they were archived
+    // under
+    fn recent(&self, room_id: &RoomId, count: usize) -> anyhow::Result<Vec<(Pending, String)>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT * FROM (
+                SELECT * FROM recent WHERE room_id = ?1 ORDER BY id DESC LIMIT ?2
+            ) ORDER BY id",
+        )?;
+
+        let rows = stmt.query_map(params![room_id.as_str(), count], |row| {
+            let data: Vec<u8> = row.get("data")?;
+            let original: Vec<u8> = row.get("original")?;
+
+            let pending = Pending {
+                data: Bytes::from(data),
+                mime_type: row.get("mime_type")?,
+                caption: row.get("caption")?,
+                description: None,
+                original: Bytes::from(original),
+                original_mime_type: row.get("original_mime_type")?,
+                enhance: row.get("enhance")?,
+                origin: Origin {
+                    room_id: room_id.clone(),
+                    event_id: row.get("event_id")?,
+                    sender: row.get("sender")?,
+                    date: received_at(&row.get::<_, String>("received_at")?),
+                },
+            };
+
+            Ok((pending, row.get("file_name")?))
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    // "resend last to jane", or "resend last 3 to jane and grandma", emailing the room's latest
+    // photos again to just the people named
+    async fn on_resend_message(&self, joined: &Joined, command: &str) -> anyhow::Result<String> {
+        let room_id = joined.room_id();
+
+        let (count, names) = match command.strip_prefix("to ") {
+            Some(names) => ("", names),
+            None => match command.split_once(" to ") {
+                Some(split) => split,
+                None => return Ok("Usage: resend last [count] to [names].".to_string()),
+            },
+        };
+
+        // "3", "3 photos", or nothing at all for just the one
+        let count = match count.split_whitespace().next() {
+            Some(count) => count.parse().unwrap_or(1),
+            None => 1,
+        }
+        .clamp(1, RECENT_LIMIT);
+
+        let names: Vec<&str> = names
+            .split([' ', ','])
+            .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("and"))
+            .collect();
+
+        let names = self.command_as_recipients(room_id, &names.join(" "))?;
+        let all = self.room_recipients(room_id);
+        let paused = self.paused()?;
+
+        let addresses: Vec<String> = names
+            .iter()
+            .flat_map(|name| all[name].clone())
+            .filter(|email| !paused.contains(email))
+            .collect();
+
+        if addresses.is_empty() {
+            return Ok("There's nobody to send to.".to_string());
+        }
+
+        let recent = self.recent(room_id, count)?;
+
+        if recent.is_empty() {
+            return Ok("There's nothing to send again.".to_string());
+        }
+
+        let (pending, file_names): (Vec<Pending>, Vec<String>) = recent.into_iter().unzip();
+        println!("resending {} to {:?}", file_names.join(", "), addresses);
+
+        let delivery = self.send_batch(&pending, &addresses, room_id).await?;
+
+        let mut names: Vec<String> = names.iter().map(|name| name_case(name)).collect();
+        names.sort();
+
+        let mut lines = vec![match pending.len() {
+            1 => format!("Sent {} to {} again.", file_names[0], names.join(" and ")),
+            count => format!("Sent the last {} to {} again.", count, names.join(" and ")),
+        }];
+
+        lines.extend(delivery.notes);
+
+        if !delivery.queued.is_empty() {
+            lines.push(i18n::format(
+                "I couldn't reach {who} yet, but I'll keep trying.",
+                self.language(room_id).as_deref(),
+                &[("who", delivery.queued.join(", ").as_str())],
+            ));
+        }
+
+        for (address, error) in &delivery.paused {
+            lines.push(paused_warning(address, error));
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.pending.is_empty() || !self.rendering.is_empty()
+    }
+
+    // Emails everything that's built up, one batch per room, returning for each room anyone we
+    // couldn't reach; their emails are queued up to try again.
+    async fn send_pending(&mut self) -> Vec<(RoomId, anyhow::Result<Delivery>)> {
+        let mut rooms: Vec<RoomId> = vec![];
+
+        for pending in &self.pending {
+            if !rooms.contains(&pending.origin.room_id) {
+                rooms.push(pending.origin.room_id.clone());
+            }
+        }
+
+        let mut results = vec![];
+
+        for room_id in rooms {
+            let result = self.send_room_pending(&room_id).await;
+            results.push((room_id, result));
+        }
+
+        results
+    }
+
+    async fn send_room_pending(&mut self, room_id: &RoomId) -> anyhow::Result<Delivery> {
+        // nothing comes off the pending list until it's been sent or queued, so an error here
+        // just means it all goes out with the next batch
+        let pending: Vec<Pending> = self
+            .pending
+            .iter()
+            .filter(|p| &p.origin.room_id == room_id)
+            .cloned()
+            .collect();
+
+        let addresses: Vec<String> = self.recipients(room_id).into_values().flatten().collect();
+        let delivery = self.send_batch(&pending, &addresses, room_id).await?;
+        self.pending.retain(|p| &p.origin.room_id != room_id);
+
+        Ok(delivery)
+    }
+
+    // emails attachments to the given addresses, however each likes them, returning anyone we
+    // couldn't reach
+    async fn send_batch(
+        &self,
+        pending: &[Pending],
+        addresses: &[String],
+        room_id: &RoomId,
+    ) -> anyhow::Result<Delivery> {
+        let prefs = self.prefs()?;
+
+        let to: Vec<(String, Prefs)> = addresses
+            .iter()
+            .filter(|address| !direct(address))
+            .map(|address| {
+                let address_prefs = prefs.get(address).copied().unwrap_or_default();
+                (address.clone(), address_prefs)
+            })
+            .collect();
+
+        let (mut failed, notes) = send_emails(pending, &to, self.mailer.as_ref()).await?;
+
+        for address in addresses.iter().filter(|address| direct(address)) {
+            failed.extend(send_direct(pending, address).await);
+        }
+
+        let mut delivery = Delivery {
+            notes,
+            ..Delivery::default()
+        };
+
+        // anything that has to wait in the outbox counts too; it'll get there
+        let sent_at = Utc::now().to_rfc3339();
+
+        for p in pending {
+            for address in addresses {
+                self.conn.execute(
+                    "
+                    INSERT INTO sends
+                        (sent_at, sender, address, event_id)
+                    VALUES
+                        (?1, ?2, ?3, ?4)",
+                    params![sent_at, p.origin.sender, address, p.origin.event_id],
+                )?;
+            }
+        }
+
+        // a failed batch counts once against an address, however many emails it took
+        for address in addresses {
+            match failed.iter().find(|u| &u.address == address) {
+                Some(undelivered) => {
+                    let error = &undelivered.error;
+
+                    if record_failure(&self.conn, address, error)? {
+                        delivery.paused.push((address.clone(), error.clone()));
+                    } else {
+                        delivery.queued.push(address.clone());
+                    }
+                }
+                None => record_success(&self.conn, address)?,
+            }
+        }
+
+        for undelivered in failed {
+            if !delivery.queued.contains(&undelivered.address) {
+                continue;
+            }
+
+            self.conn.execute(
+                "
+                INSERT INTO outbox
+                    (address, email, room_id, attempts, next_attempt, mime_type, caption)
+                VALUES
+                    (?1, ?2, ?3, 1, ?4, ?5, ?6)",
+                params![
+                    undelivered.address,
+                    undelivered.data,
+                    room_id.as_str(),
+                    (Utc::now() + backoff(1)).to_rfc3339(),
+                    undelivered.mime_type,
+                    undelivered.caption
+                ],
+            )?;
+        }
+
+        Ok(delivery)
+    }
+
+    // lets a room know how sending went: a receipt for everything that went out, and who's still
+    // waiting on it
+    async fn report(
+        &mut self,
+        client: &Client,
+        room_id: &RoomId,
+        sent: &[Pending],
+        result: anyhow::Result<Delivery>,
+    ) -> anyhow::Result<()> {
+        let joined = match client.get_joined_room(room_id) {
+            Some(joined) => joined,
+            None => return Ok(()),
+        };
+
+        let settled = if result.is_ok() { DONE } else { FAILED };
+
+        for pending in sent {
+            self.settle(client, room_id, &pending.origin.event_id, settled)
+                .await;
+        }
+
+        let messages = match result {
+            Ok(delivery) => {
+                for pending in sent {
+                    if let Err(e) = self.send_receipt(client, &joined, pending).await {
+                        println!("Could not send receipt! {}", e);
+                    }
+                }
+
+                let mut messages = delivery.notes;
+
+                if !delivery.queued.is_empty() {
+                    messages.push(i18n::format(
+                        "I couldn't reach {who} yet, but I'll keep trying.",
+                        self.language(room_id).as_deref(),
+                        &[("who", delivery.queued.join(", ").as_str())],
+                    ));
+                }
+
+                for (address, error) in &delivery.paused {
+                    messages.push(paused_warning(address, error));
+                }
+
+                messages
+            }
+            Err(e) => vec![format!("Could not email photos! {}", e)],
+        };
+
+        for message in messages {
+            matrix::send(&joined, matrix::text_plain(&message)).await?;
+        }
+
+        Ok(())
+    }
+
+    // Puts everything pending away for the digest, returning the rooms it came from. Whoever it
+    // would have gone to right now is who it goes to then, even if a filter's run out by then.
+    // Anything that can't be put away stays pending, to go out with the next batch instead.
+    fn hold_pending(&mut self) -> Vec<RoomId> {
+        let mut rooms: Vec<RoomId> = vec![];
+
+        for pending in std::mem::take(&mut self.pending) {
+            if let Err(e) = self.hold(&pending) {
+                println!("Could not hold a photo for the digest! {}", e);
+                self.pending.push(pending);
+                continue;
+            }
+
+            if !rooms.contains(&pending.origin.room_id) {
+                rooms.push(pending.origin.room_id);
+            }
+        }
+
+        rooms
+    }
+
+    fn hold(&self, pending: &Pending) -> anyhow::Result<()> {
+        let addresses: Vec<String> = self
+            .recipients(&pending.origin.room_id)
+            .into_values()
+            .flatten()
+            .collect();
+        let addresses = serde_json::to_string(&addresses)?;
+
+        self.conn.execute(
+            "
+            INSERT INTO digest
+                (room_id, event_id, sender, addresses, data, mime_type, caption, original,
+                original_mime_type, enhance, received_at, description)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                pending.origin.room_id.as_str(),
+                pending.origin.event_id,
+                pending.origin.sender,
+                addresses,
+                pending.data.to_vec(),
+                pending.mime_type,
+                pending.caption,
+                pending.original.to_vec(),
+                pending.original_mime_type,
+                pending.enhance,
+                pending.origin.date.to_rfc3339(),
+                pending.description,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn held(&self) -> anyhow::Result<Vec<Held>> {
+        let mut stmt = self.conn.prepare("SELECT * FROM digest ORDER BY id")?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                let room_id: String = row.get("room_id")?;
+                let data: Vec<u8> = row.get("data")?;
+                let original: Vec<u8> = row.get("original")?;
+
+                Ok((
+                    row.get("id")?,
+                    room_id,
+                    data,
+                    original,
+                    (
+                        row.get("mime_type")?,
+                        row.get("caption")?,
+                        row.get("original_mime_type")?,
+                        row.get("enhance")?,
+                        row.get("event_id")?,
+                        row.get("sender")?,
+                        row.get::<_, String>("received_at")?,
+                        row.get("description")?,
+                    ),
+                    row.get("addresses")?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut held = vec![];
+
+        for (id, room_id, data, original, details, addresses) in rows {
+            let (
+                mime_type,
+                caption,
+                original_mime_type,
+                enhance,
+                event_id,
+                sender,
+                date,
+                description,
+            ) = details;
+
+            let pending = Pending {
+                data: Bytes::from(data),
+                mime_type,
+                caption,
+                description,
+                original: Bytes::from(original),
+                original_mime_type,
+                enhance,
+                origin: Origin {
+                    room_id: RoomId::try_from(room_id.as_str())?,
+                    event_id,
+                    sender,
+                    date: received_at(&date),
+                },
+            };
+
+            held.push(Held {
+                id,
+                addresses,
+                pending,
+            });
+        }
+
+        Ok(held)
+    }
+
+    // Emails everything held for the digest, one batch for each room and set of addresses,
+    // returning how many photos went out. Anything that can't be sent stays for next time.
+    async fn send_digest(&mut self, client: &Client) -> anyhow::Result<usize> {
+        let mut batches: Vec<Vec<Held>> = vec![];
+
+        for held in self.held()? {
+            let batch = batches.iter_mut().find(|batch| {
+                batch[0].pending.origin.room_id == held.pending.origin.room_id
+                    && batch[0].addresses == held.addresses
+            });
+
+            match batch {
+                Some(batch) => batch.push(held),
+                None => batches.push(vec![held]),
+            }
+        }
+
+        let mut total = 0;
+
+        for batch in batches {
+            let room_id = batch[0].pending.origin.room_id.clone();
+            let addresses: Vec<String> = serde_json::from_str(&batch[0].addresses)?;
+            let sent: Vec<Pending> = batch.iter().map(|held| held.pending.clone()).collect();
+
+            let result = self.send_batch(&sent, &addresses, &room_id).await;
+
+            if result.is_ok() {
+                for held in &batch {
+                    self.conn
+                        .execute("DELETE FROM digest WHERE id = ?1", params![held.id])?;
+                }
+
+                total += sent.len();
+            }
+
+            self.report(client, &room_id, &sent, result).await?;
+        }
+
+        Ok(total)
+    }
+
+    // How many photos went out this week, this month, and this year, by who sent them and who got
+    // them. A photo counts once however many addresses it went to.
+    fn stats(&self) -> anyhow::Result<String> {
+        let now = scheduler::now();
+        let since = |days: u32| scheduler::at_hour(now - chrono::Duration::days(days as i64), 0);
+
+        let periods = [
+            ("This week", since(now.weekday().num_days_from_monday())),
+            ("This month", since(now.day0())),
+            ("This year", since(now.ordinal0())),
+        ];
+
+        let mut names: HashMap<String, String> = HashMap::new();
+
+        for (name, emails) in self.all_recipients() {
+            for email in emails {
+                names.insert(email, name_case(&name));
+            }
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT sender, address, event_id FROM sends WHERE sent_at >= ?1")?;
+
+        let mut lines: Vec<String> = vec![];
+
+        for (label, start) in periods {
+            let rows = stmt
+                .query_map(params![start.with_timezone(&Utc).to_rfc3339()], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, String, String)>>>()?;
+
+            let mut photos: HashSet<String> = HashSet::new();
+            let mut senders: HashMap<String, HashSet<String>> = HashMap::new();
+            let mut recipients: HashMap<String, HashSet<String>> = HashMap::new();
+
+            for (sender, address, event_id) in rows {
+                let sender = sender_name(&sender);
+                let recipient = names.get(&address).cloned().unwrap_or(address);
+
+                photos.insert(event_id.clone());
+                senders.entry(sender).or_default().insert(event_id.clone());
+                recipients.entry(recipient).or_default().insert(event_id);
+            }
+
+            match photos.len() {
+                0 => lines.push(format!("{}: no photos", label)),
+                1 => lines.push(format!("{}: 1 photo", label)),
+                count => lines.push(format!("{}: {} photos", label, count)),
+            }
+
+            if !photos.is_empty() {
+                lines.push(format!("  From: {}", tally(senders)));
+                lines.push(format!("  To: {}", tally(recipients)));
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn all_recipients(&self) -> HashMap<String, Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, email FROM recipients ORDER BY name, email")
+            .expect("could not read recipients");
+
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("could not read recipients");
+
+        let mut all: HashMap<String, Vec<String>> = HashMap::new();
+
+        for row in rows {
+            let (name, email): (String, String) = row.expect("could not read recipients");
+            all.entry(name).or_default().push(email);
+        }
+
+        // the photo frame isn't in the table, but is picked like anyone else
+        if frame::enabled() {
+            all.insert(frame::ADDRESS.to_string(), vec![frame::ADDRESS.to_string()]);
+        }
+
+        all
+    }
+
+    fn prefs(&self) -> anyhow::Result<HashMap<String, Prefs>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT email, max_width, max_height, quality, original FROM recipients")?;
+
+        let rows = stmt.query_map([], |row| {
+            let width: Option<u32> = row.get(1)?;
+            let height: Option<u32> = row.get(2)?;
+
+            let prefs = Prefs {
+                output: image::Output {
+                    max_size: width.zip(height),
+                    quality: row.get(3)?,
+                },
+                original: row.get(4)?,
+            };
+
+            Ok((row.get(0)?, prefs))
+        })?;
+
+        let mut prefs = HashMap::new();
+
+        for row in rows {
+            let (email, address_prefs): (String, Prefs) = row?;
+            prefs.insert(email, address_prefs);
+        }
+
+        Ok(prefs)
+    }
+
+    // returns false if there's no recipient with that address
+    fn set_prefs(&self, email: &str, prefs: &Prefs) -> anyhow::Result<bool> {
+        let (width, height) = prefs.output.max_size.unzip();
+
+        let updated = self.conn.execute(
+            "
+            UPDATE recipients
+            SET max_width = ?2, max_height = ?3, quality = ?4, original = ?5
+            WHERE email = ?1",
+            params![email, width, height, prefs.output.quality, prefs.original],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    fn add_recipient(&self, name: &str, email: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO recipients (name, email) VALUES (?1, ?2)",
+            params![name, email],
+        )?;
+
+        Ok(())
+    }
+
+    // removes one address, or everything for the name if there isn't one
+    fn remove_recipient(&mut self, name: &str, email: Option<&str>) -> anyhow::Result<bool> {
+        let removed = match email {
+            Some(email) => self.conn.execute(
+                "DELETE FROM recipients WHERE name = ?1 AND email = ?2",
+                params![name, email],
+            )?,
+            None => self
+                .conn
+                .execute("DELETE FROM recipients WHERE name = ?1", params![name])?,
+        };
+
+        // and make sure no room's filter keeps sending to them
+        let all = self.all_recipients();
+
+        for filter in self.filters.values_mut() {
+            for (name, emails) in filter.only.iter_mut() {
+                emails.retain(|e| all.get(name).map(|a| a.contains(e)).unwrap_or(false));
+            }
+
+            filter.only.retain(|_, emails| !emails.is_empty());
+        }
+
+        Ok(removed > 0)
+    }
+
+    async fn on_recipients_message(
+        &mut self,
+        joined: &Joined,
+        sender: &UserId,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        if !matrix::is_admin(sender) {
+            bail!("You are not allowed to change recipients.");
+        }
+
+        let args: Vec<&str> = command.split_whitespace().collect();
+
+        let response = match args[..] {
+            ["add", name, address] if telegram::chat(address).is_some() => {
+                if !telegram::enabled() {
+                    bail!("Telegram isn't set up. It needs TELEGRAM_BOT_TOKEN.");
+                }
+
+                self.add_recipient(&name.to_lowercase(), address)?;
+                format!("Added {} for {}.", address, name_case(&name.to_lowercase()))
+            }
+            ["add", name, email] if email.parse::<lettre::Address>().is_ok() => {
+                self.add_recipient(&name.to_lowercase(), email)?;
+                format!("Added {} for {}.", email, name_case(&name.to_lowercase()))
+            }
+            ["remove", name] | ["remove", name, _] => {
+                let name = name.to_lowercase();
+                let email = args.get(2).copied();
+
+                match (self.remove_recipient(&name, email)?, email) {
+                    (true, Some(email)) => format!("Removed {}.", email),
+                    (true, None) => format!("Removed {}.", name_case(&name)),
+                    (false, _) => format!("I don't know who {} is!", name),
+                }
+            }
+            ["list"] => {
+                let mut all: Vec<(String, Vec<String>)> =
+                    self.all_recipients().into_iter().collect();
+                all.sort();
+
+                let prefs = self.prefs()?;
+                let paused = self.paused()?;
+
+                // only mention preferences for the addresses that have them
+                let describe = |email: &String| {
+                    let mut notes = vec![];
+
+                    match prefs.get(email) {
+                        Some(p) if *p != Prefs::default() => notes.push(p.to_string()),
+                        _ => (),
+                    }
+
+                    if paused.contains(email) {
+                        notes.push("paused".to_string());
+                    }
+
+                    if notes.is_empty() {
+                        email.clone()
+                    } else {
+                        format!("{} ({})", email, notes.join(", "))
+                    }
+                };
+
+                if all.is_empty() {
+                    "There are no recipients.".to_string()
+                } else {
+                    all.iter()
+                        .map(|(name, emails)| {
+                            let emails: Vec<String> = emails.iter().map(describe).collect();
+                            format!("{}: {}", name_case(name), emails.join(", "))
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                }
+            }
+            ["set", email, "resume"] => {
+                if self.resume(email)? {
+                    format!("{} will get photos again.", email)
+                } else {
+                    format!("I don't know who {} is!", email)
+                }
+            }
+            ["set", email, ..] if email.contains('@') => {
+                let mut prefs = self.prefs()?.get(email).copied().unwrap_or_default();
+
+                match args[2..] {
+                    ["size", size] => {
+                        let (width, height) = size
+                            .split_once('x')
+                            .and_then(|(w, h)| w.parse().ok().zip(h.parse().ok()))
+                            .filter(|&(w, h)| w > 0 && h > 0)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("{} isn't a size, like 1920x1080.", size)
+                            })?;
+
+                        prefs.output.max_size = Some((width, height));
+                        prefs.original = false;
+                    }
+                    ["quality", quality] => match quality.parse::<u8>() {
+                        Ok(quality) if (1..=100).contains(&quality) => {
+                            prefs.output.quality = Some(quality);
+                            prefs.original = false;
+                        }
+                        _ => bail!("Quality goes from 1 to 100."),
+                    },
+                    ["original"] | ["originals"] => {
+                        prefs = Prefs {
+                            original: true,
+                            ..Prefs::default()
+                        }
+                    }
+                    ["default"] | ["defaults"] => prefs = Prefs::default(),
+                    _ => bail!(
+                        "Usage: recipient [email] size [width]x[height], recipient [email] \
+                        quality [1-100], recipient [email] original, recipient [email] default, \
+                        or recipient [email] resume."
+                    ),
+                }
+
+                if self.set_prefs(email, &prefs)? {
+                    format!("{} will get {}.", email, prefs)
+                } else {
+                    format!("I don't know who {} is!", email)
+                }
+            }
+            _ => "Usage: add recipient [name] [email], remove recipient [name] [email], \
+                list recipients, or recipient [email] [size/quality/original/default/resume]."
+                .to_string(),
+        };
+
+        matrix::send(joined, matrix::text_plain(&response)).await?;
+
+        Ok(())
+    }
+
+    // who a room's photos go to right now, leaving out any address that's been paused
+    fn recipients(&self, room_id: &RoomId) -> HashMap<String, Vec<String>> {
+        let mut recipients = match self.filters.get(room_id) {
+            Some(filter) if !filter.expired() => filter.only.clone(),
+            _ => self.room_recipients(room_id),
+        };
+
+        let paused = self.paused().expect("could not read recipients");
+
+        for emails in recipients.values_mut() {
+            emails.retain(|email| !paused.contains(email));
+        }
+
+        recipients.retain(|_, emails| !emails.is_empty());
+        recipients
+    }
+
+    // addresses we've stopped sending to after too many failures
+    fn paused(&self) -> anyhow::Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT email FROM recipients WHERE paused = 1")?;
+
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        let mut paused = HashSet::new();
+
+        for row in rows {
+            paused.insert(row?);
+        }
+
+        Ok(paused)
+    }
+
+    // starts sending to a paused address again, returning false if there's no such recipient
+    fn resume(&self, email: &str) -> anyhow::Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE recipients SET paused = 0, failures = 0, last_error = NULL WHERE email = ?1",
+            params![email],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    // who a room's photos go to without a filter: everyone, or whoever PHOTO_ROOMS gives it
+    fn room_recipients(&self, room_id: &RoomId) -> HashMap<String, Vec<String>> {
+        let mut all = self.all_recipients();
+
+        if let Some(names) = room_config(room_id).recipients {
+            let names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+            all.retain(|name, _| names.contains(name));
+        }
+
+        all
+    }
+
+    fn command_as_recipients(
+        &self,
+        room_id: &RoomId,
+        command: &str,
+    ) -> anyhow::Result<HashSet<String>> {
+        let all = self.room_recipients(room_id);
+        let mut collected: HashSet<String> = HashSet::new();
+
+        for recip in command.split(' ') {
+            let r = recip.to_lowercase();
+
+            // allow sending to the Google album only
+            if r == "google" {
+                return Ok(HashSet::new());
+            }
+
+            match all.get(&r) {
+                Some(_) => collected.insert(r.to_string()),
+                None if self.all_recipients().contains_key(&r) => {
+                    bail!("{} doesn't get photos from this room.", name_case(&r))
+                }
+                None => bail!("I don't know who {} is!", recip),
+            };
+        }
+
+        Ok(collected)
+    }
+
+    // the room's language, if it's been given one
+    fn language(&self, room_id: &RoomId) -> Option<String> {
+        self.conn
+            .query_row(
+                "SELECT language FROM languages WHERE room_id = ?1",
+                params![room_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                println!("Could not read the language for {}: {}", room_id, e);
+                None
+            })
+    }
+
+    // returns what to tell the room
+    fn set_language(&self, room_id: &RoomId, language: &str) -> anyhow::Result<String> {
+        let language = language.to_lowercase();
+
+        // english is what we'd do anyway
+        if matches!(language.as_str(), "english" | "off" | "reset") {
+            self.conn.execute(
+                "DELETE FROM languages WHERE room_id = ?1",
+                params![room_id.as_str()],
+            )?;
+
+            return Ok(i18n::translate(
+                "Okay, photo messages will be in English.",
+                None,
+            ));
+        }
+
+        self.conn.execute(
+            "
+            INSERT INTO languages
+                (room_id, language)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT(room_id) DO UPDATE SET language=?2",
+            params![room_id.as_str(), language],
+        )?;
+
+        Ok(i18n::format(
+            "Okay, photo messages will be in {language}.",
+            Some(language.as_str()),
+            &[("language", name_case(&language).as_str())],
+        ))
+    }
+
+    // Replies to the photo itself with a thumbnail and who it went to, so whoever sent it can see
+    // that the right one went out. Videos just get the text.
+    async fn send_receipt(
+        &self,
+        client: &Client,
+        joined: &Joined,
+        pending: &Pending,
+    ) -> anyhow::Result<()> {
+        let language = self.language(joined.room_id());
+        let language = language.as_deref();
+
+        let receipt = i18n::format(
+            "Sent to {who} ({before} → {after}).",
+            language,
+            &[
+                ("who", self.who(&pending.origin.room_id, language).as_str()),
+                ("before", friendly_size(pending.original.len()).as_str()),
+                ("after", friendly_size(pending.data.len()).as_str()),
+            ],
+        );
+
+        if pending.mime_type != "image/jpeg" {
+            return matrix::send_thread_reply(joined, &pending.origin.event_id, &receipt).await;
+        }
+
+        let (thumbnail, width, height) = image::thumbnail(&pending.data)?;
+
+        matrix::send_thread_image(
+            client,
+            joined,
+            &pending.origin.event_id,
+            &receipt,
+            &thumbnail,
+            width,
+            height,
+        )
+        .await
+    }
+
+    // everyone a room's photos are going to, all in one phrase
+    fn who(&self, room_id: &RoomId, language: Option<&str>) -> String {
+        let mut rec: Vec<String> = self
+            .recipients(room_id)
+            .keys()
+            .map(|k| name_case(k))
+            .collect();
+
+        rec.sort();
+
+        match rec.len() {
+            0 => i18n::translate("the Google album only", language),
+            1 => String::from(rec.first().unwrap()),
+            _ => i18n::format(
+                "{first} and {last}",
+                language,
+                &[
+                    ("first", rec[0..rec.len() - 1].join(", ").as_str()),
+                    ("last", rec[rec.len() - 1].as_str()),
+                ],
+            ),
+        }
+    }
+
+    fn recipients_friendly(&self, room_id: &RoomId) -> String {
+        let language = self.language(room_id);
+        let language = language.as_deref();
+
+        let who = self.who(room_id, language);
+
+        match self.filters.get(room_id) {
+            Some(Filter {
+                expires: Some(expires),
+                ..
+            }) if expires > &Utc::now() => {
+                let until = expires.with_timezone(&scheduler::timezone());
+
+                i18n::format(
+                    "Photos will be sent to {who} until {time}.",
+                    language,
+                    &[
+                        ("who", who.as_str()),
+                        ("time", until.format("%-I:%M %p").to_string().as_str()),
+                    ],
+                )
+            }
+            _ => i18n::format(
+                "Photos will be sent to {who}.",
+                language,
+                &[("who", who.as_str())],
+            ),
+        }
+    }
+}
+
+// pulls something like "for 2 hours" or "today" off the end of a command, returning the rest of
+// the command and when it should stop applying
+fn parse_expiry(command: &str) -> (&str, Option<DateTime<Utc>>) {
+    let lower = command.to_lowercase();
+
+    if let Some(rest) = lower.strip_suffix(" today") {
+        let midnight = scheduler::at_hour(scheduler::now(), 0) + chrono::Duration::days(1);
+        return (&command[..rest.len()], Some(midnight.with_timezone(&Utc)));
+    }
+
+    let (rest, duration) = match lower.rsplit_once(" for ") {
+        Some(split) => split,
+        None => return (command, None),
+    };
+
+    let mut words = duration.split_whitespace();
+
+    // it has to be some time from now, so no zero (or negative) counts
+    let count = match words.next() {
+        Some("a") | Some("an") => 1,
+        Some(count) => match count.parse::<u32>() {
+            Ok(count) if count > 0 => count as i64,
+            _ => return (command, None),
+        },
+        None => return (command, None),
+    };
+
+    let duration = match words.next() {
+        Some(unit) if unit.starts_with("minute") => chrono::Duration::minutes(count),
+        Some(unit) if unit.starts_with("hour") => chrono::Duration::hours(count),
+        Some(unit) if unit.starts_with("day") => chrono::Duration::days(count),
+        _ => return (command, None),
+    };
+
+    // and not so far off there's no date for it
+    match Utc::now().checked_add_signed(duration) {
+        Some(until) => (&command[..rest.len()], Some(until)),
+        None => (command, None),
+    }
+}
+
+// how a batch went for anyone who didn't get it right away
+#[derive(Default)]
+struct Delivery {
+    // addresses with emails waiting in the outbox
+    queued: Vec<String>,
+    // addresses that just failed too many times in a row, and the last error
+    paused: Vec<(String, String)>,
+    // anything done to make it all fit in an email
+    notes: Vec<String>,
+}
+
+// Counts a failed email against an address, pausing it (and dropping anything of its still in the
+// outbox) once MAX_FAILURES go by without one getting through. Returns true if that just happened.
+fn record_failure(conn: &Connection, address: &str, error: &str) -> anyhow::Result<bool> {
+    conn.execute(
+        "
+        UPDATE recipients
+        SET failures = failures + 1, last_error = ?2
+        WHERE email = ?1 AND paused = 0",
+        params![address, error],
+    )?;
+
+    let paused = conn.execute(
+        "UPDATE recipients SET paused = 1 WHERE email = ?1 AND paused = 0 AND failures >= ?2",
+        params![address, MAX_FAILURES],
+    )?;
+
+    if paused > 0 {
+        println!("Pausing {} after {} failures", address, MAX_FAILURES);
+        conn.execute("DELETE FROM outbox WHERE address = ?1", params![address])?;
+    }
+
+    Ok(paused > 0)
+}
+
+fn record_success(conn: &Connection, address: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE recipients SET failures = 0, last_error = NULL WHERE email = ?1",
+        params![address],
+    )?;
+
+    Ok(())
+}
+
+fn paused_warning(address: &str, error: &str) -> String {
+    format!(
+        "Emails to {} keep failing ({}), so I've stopped sending them photos. Say \"recipient {} \
+        resume\" to start again.",
+        address, error, address
+    )
+}
+
+// how long to wait after a number of failed attempts
+fn backoff(attempts: i64) -> chrono::Duration {
+    chrono::Duration::minutes(RETRY_MINUTES << (attempts - 1).clamp(0, 16))
+}
+
+// sends whatever's due in the outbox, giving up (and telling the room) after too many tries
+async fn retry_emails(client: &Client) -> anyhow::Result<()> {
+    type Due = (
+        i64,
+        String,
+        Vec<u8>,
+        String,
+        i64,
+        Option<String>,
+        Option<String>,
+    );
+
+    let due: Vec<Due> = {
+        let conn = storage::open("photobot")?;
+
+        let mut stmt = conn.prepare(
+            "
+            SELECT id, address, email, room_id, attempts, mime_type, caption
+            FROM outbox
+            WHERE next_attempt <= ?1",
+        )?;
+
+        let rows = stmt.query_map(params![Utc::now().to_rfc3339()], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+            ))
+        })?;
+
+        let mut due = vec![];
+
+        for row in rows {
+            due.push(row?);
+        }
+
+        due
+    };
+
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    let mailer = mail::Smtp::configured();
+
+    // anything else for an address that's paused partway through has already left the outbox
+    let mut paused: Vec<String> = vec![];
+
+    for (id, address, email, room_id, attempts, mime_type, caption) in due {
+        if paused.contains(&address) {
+            continue;
+        }
+
+        let result = match mime_type {
+            Some(mime_type) => deliver(&address, &email, &mime_type, caption.as_deref()).await,
+            None => mailer.send_raw(&address, &email).await,
+        };
+        let attempts = attempts + 1;
+
+        let gave_up = {
+            let conn = storage::open("photobot")?;
+
+            let paused_now = match &result {
+                Ok(_) => {
+                    record_success(&conn, &address)?;
+                    false
+                }
+                Err(e) => record_failure(&conn, &address, &e.to_string())?,
+            };
+
+            match result {
+                Ok(_) => {
+                    println!("Sent queued email to {}", address);
+                    conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+                    None
+                }
+                Err(e) if paused_now => {
+                    paused.push(address.clone());
+                    Some(paused_warning(&address, &e.to_string()))
+                }
+                Err(e) if attempts >= MAX_ATTEMPTS => {
+                    println!("Giving up on email to {}: {}", address, e);
+                    conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+
+                    Some(format!(
+                        "I couldn't email photos to {} after {} tries, so I gave up. ({})",
+                        address, attempts, e
+                    ))
+                }
+                Err(e) => {
+                    println!("Could not send queued email to {}: {}", address, e);
+                    conn.execute(
+                        "UPDATE outbox SET attempts = ?2, next_attempt = ?3 WHERE id = ?1",
+                        params![id, attempts, (Utc::now() + backoff(attempts)).to_rfc3339()],
+                    )?;
+                    None
+                }
+            }
+        };
+
+        if let Some(message) = gave_up {
+            if let Some(joined) = client.get_joined_room(&RoomId::try_from(room_id.as_str())?) {
+                matrix::send(&joined, matrix::text_plain(&message)).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// tells PHOTO_ADMIN_ROOM about any recipient whose photos bounced since the last check
+async fn check_bounces(client: &Client, admin_room: &RoomId) -> anyhow::Result<()> {
+    let bounces = task::spawn_blocking(mail::bounces).await??;
+
+    if bounces.is_empty() {
+        return Ok(());
+    }
+
+    let recipients: Vec<(String, String)> = {
+        let conn = storage::open("photobot")?;
+        let mut stmt = conn.prepare("SELECT name, email FROM recipients ORDER BY name, email")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut recipients = vec![];
+
+        for row in rows {
+            recipients.push(row?);
+        }
+
+        recipients
+    };
+
+    let mut broken: Vec<String> = vec![];
+    let mut bounced_emails: Vec<String> = vec![];
+
+    for bounce in bounces {
+        let failed = mail::failed_recipients(&bounce);
+        let text = bounce.to_lowercase();
+
+        for (name, email) in &recipients {
+            let email = email.to_lowercase();
+
+            // not every server sends a proper status report, so fall back to finding the address
+            let bounced = if failed.is_empty() {
+                text.contains(&email)
+            } else {
+                failed.contains(&email)
+            };
+
+            let line = format!("{} ({})", email, name_case(name));
+
+            if bounced && !broken.contains(&line) {
+                broken.push(line);
+                bounced_emails.push(email);
+            }
+        }
+    }
+
+    if broken.is_empty() {
+        return Ok(());
+    }
+
+    println!("photo emails bounced for {}", broken.join(", "));
+
+    // a bounce counts against an address like any other failure
+    let mut paused: Vec<String> = vec![];
+
+    {
+        let conn = storage::open("photobot")?;
+
+        for email in bounced_emails {
+            if record_failure(&conn, &email, "bounced")? {
+                paused.push(email);
+            }
+        }
+    }
+
+    if let Some(joined) = client.get_joined_room(admin_room) {
+        let mut message = format!(
+            "Photos bounced for {}. The address might be wrong, or the mailbox full.",
+            broken.join(", ")
+        );
+
+        for email in paused {
+            message.push(' ');
+            message.push_str(&paused_warning(&email, "bounced"));
+        }
+
+        matrix::send(&joined, matrix::text_plain(&message)).await?;
+    }
+
+    Ok(())
+}
+
+// clears temporary filters once they've run out, and lets their rooms know
+async fn expire_filter(client: &Client) -> anyhow::Result<()> {
+    let room_ids: Vec<String> = {
+        let conn = storage::open("photobot")?;
+        let now = Utc::now().to_rfc3339();
+
+        let room_ids = conn
+            .prepare("SELECT room_id FROM filters WHERE expires_at <= ?1")?
+            .query_map(params![now], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        conn.execute("DELETE FROM filters WHERE expires_at <= ?1", params![now])?;
+
+        room_ids
+    };
+
+    for room_id in room_ids {
+        if let Some(joined) = client.get_joined_room(&RoomId::try_from(room_id.as_str())?) {
+            matrix::send(
+                &joined,
+                matrix::text_plain("Time's up! Photos will be sent to everyone again."),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// like "2.1 MB" or "480 KB"
+fn friendly_size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{} KB", (bytes + 512) / 1024)
+    }
+}
+
+// "Mom 4, Dad 2", most first
+fn tally(counts: HashMap<String, HashSet<String>>) -> String {
+    let mut counts: Vec<(String, usize)> = counts
+        .into_iter()
+        .map(|(name, photos)| (name, photos.len()))
+        .collect();
+
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    counts
+        .iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn name_case(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    }
+}
+
+// the caption sent along with a photo, if there is one; clients put the file name in the body when
+// there isn't
+fn caption(event: &SyncMessageEvent<MessageEventContent>) -> Option<String> {
+    let body = matrix::get_media_body(event)?.trim();
+
+    if body.is_empty() || (!body.contains(' ') && body.contains('.')) {
+        return None;
+    }
+
+    // "no edit" is an instruction, not part of the caption
+    let no_edit = body.char_indices().map(|(i, _)| i).find(|&i| {
+        body[i..]
+            .get(.."no edit".len())
+            .map(|s| s.eq_ignore_ascii_case("no edit"))
+            .unwrap_or(false)
+    });
+
+    let caption = match no_edit {
+        Some(i) => format!("{} {}", &body[..i], &body[i + "no edit".len()..]),
+        None => body.to_string(),
+    };
+
+    let caption = caption.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    if caption.is_empty() {
+        None
+    } else {
+        Some(caption)
+    }
+}
+
+// keeps the original, in every archive there is
+// every archive gets a try, even if one before it failed
+async fn archive(
+    archives: &[Box<dyn archive::Archive>],
+    original: &Bytes,
+    mime_type: &str,
+    caption: Option<&str>,
+    description: Option<&str>,
+    room_id: &RoomId,
+) -> anyhow::Result<()> {
+    let file_name = get_filename(mime_type, caption);
+    let album = room_config(room_id).album;
+    let mut failed = vec![];
+
+    for target in archives {
+        let saved = target
+            .save(
+                original,
+                mime_type,
+                &file_name,
+                caption,
+                description,
+                album.as_deref(),
+            )
+            .await;
+
+        if let Err(e) = saved {
+            println!(
+                "Could not archive {} to {}: {}",
+                file_name,
+                target.name(),
+                e
+            );
+            failed.push(target.name());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Could not archive {} to {}.", file_name, failed.join(", "));
+    }
+
+    Ok(())
+}
+
+fn get_filename(mime_type: &str, caption: Option<&str>) -> String {
+    let ext = mime_type.split('/').next_back().unwrap().to_lowercase();
+
+    let stem = match caption.map(slug) {
+        Some(slug) if !slug.is_empty() => slug,
+        _ if mime_type.starts_with("video/") => "video".to_string(),
+        _ => "photo".to_string(),
+    };
+
+    match ext.as_str() {
+        "jpeg" => format!("{}.jpg", stem),
+        "quicktime" => format!("{}.mov", stem),
+        "x-adobe-dng" => format!("{}.dng", stem),
+        "x-canon-cr2" => format!("{}.cr2", stem),
+        "x-nikon-nef" => format!("{}.nef", stem),
+        _ => format!("{}.{}", stem, ext),
+    }
+}
+
+// a caption cut down to something that's safe in a file name
+fn slug(caption: &str) -> String {
+    let words: Vec<String> = caption
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+
+    words.join("-").chars().take(50).collect()
+}
+
+// PHOTO_SENDERS (a JSON list of Matrix IDs) is who, besides the parents, can have photos emailed;
+// without it, anyone in a room with the bot can
+static SENDERS: Lazy<Option<Vec<String>>> = Lazy::new(|| config::load("PHOTO_SENDERS"));
+
+fn allowed_sender(sender: &UserId) -> bool {
+    match SENDERS.as_ref() {
+        Some(senders) => senders
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(sender.as_str())),
+        None => true,
+    }
+}
+
+// how many photos anyone but the parents can send in an hour, from PHOTO_HOURLY_LIMIT
+fn hourly_limit() -> usize {
+    env::var("PHOTO_HOURLY_LIMIT")
+        .map(|limit| limit.parse().expect("PHOTO_HOURLY_LIMIT is not an integer"))
+        .unwrap_or(DEFAULT_HOURLY_LIMIT)
+}
+
+fn batch_window() -> std::time::Duration {
+    let seconds = env::var("PHOTO_BATCH_WINDOW")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(BATCH_WINDOW);
+
+    std::time::Duration::from_secs(seconds)
+}
+
+// The display's resolution, from PHOTO_OF_THE_DAY_SIZE (like 800x480). The photo is cropped to
+// fill it, since e-ink displays show an image at exactly their size.
+fn display_size() -> (u32, u32) {
+    match env::var("PHOTO_OF_THE_DAY_SIZE") {
+        Ok(size) => size
+            .split_once('x')
+            .and_then(|(w, h)| w.parse().ok().zip(h.parse().ok()))
+            .filter(|&(w, h)| w > 0 && h > 0)
+            .expect("PHOTO_OF_THE_DAY_SIZE is not a size, like 800x480"),
+        Err(_) => DEFAULT_DISPLAY_SIZE,
+    }
+}
+
+// Picks a photo from the archive at random, fits it to the display, and calls the display's
+// webhook with a link to it, served by the dashboard from MEDIA_URL. The webhook gets the link as
+// "what", like any other, for an automation to hand to the display (an ESPHome online_image,
+// say).
+async fn photo_of_the_day(webhook_name: &str) -> anyhow::Result<()> {
+    let photos: Vec<(PathBuf, &str)> = archive::local_files()?
+        .into_iter()
+        .filter_map(|path| {
+            let mime_type = image::file_mime_type(path.file_name()?.to_str()?)?;
+            Some((path, mime_type))
+        })
+        .collect();
+
+    if photos.is_empty() {
+        bail!("there's nothing in the archive for the photo of the day");
+    }
+
+    // no need for a whole crate for one pick a day
+    let pick = RandomState::new().build_hasher().finish() as usize % photos.len();
+    let (path, mime_type) = photos[pick].clone();
+
+    println!("photo of the day is {:?}", path);
+
+    let photo = Bytes::from(tokio::fs::read(&path).await?);
+    let (width, height) = display_size();
+
+    let jpeg = task::spawn_blocking(move || {
+        let jpeg = image::render(&photo, mime_type, false, image::Output::default())?;
+        image::fill(&jpeg, width, height)
+    })
+    .await??;
+
+    let key = format!("photo of the day {}", scheduler::now().format("%Y-%m-%d"));
+
+    let url = match cache::share(&key, &jpeg)? {
+        Some(url) => url,
+        None => bail!("MEDIA_URL environmental variable not set"),
+    };
+
+    webhook::trigger(webhook_name, &url).await
+}
+
+// Whether each photo gets a caption from the vision model, from PHOTO_AI_CAPTIONS. It's one
+// request a photo, so it's off unless it's asked for. PHOTO_AI_CAPTION_CONTEXT is anything the
+// model should know first, like who's who in the family.
+fn ai_captions() -> bool {
+    env::var("PHOTO_AI_CAPTIONS").as_deref() == Ok("true")
+}
+
+// whether the video in a live photo is emailed too, from PHOTO_LIVE_VIDEOS; it's archived either way
+fn live_videos() -> bool {
+    env::var("PHOTO_LIVE_VIDEOS").as_deref() == Ok("true")
+}
+
+// a description of a photo, from a small copy; anything that goes wrong just means there isn't one
+fn describe(photo: Bytes, mime_type: String) -> task::JoinHandle<Option<String>> {
+    task::spawn(async move {
+        let described = async {
+            let preview = task::spawn_blocking(move || {
+                let output = image::Output {
+                    max_size: Some((DESCRIBE_SIZE, DESCRIBE_SIZE)),
+                    quality: None,
+                };
+
+                image::render(&photo, &mime_type, false, output)
+            })
+            .await??;
+
+            let context = env::var("PHOTO_AI_CAPTION_CONTEXT").ok();
+            ai::describe_photo(&preview, context.as_deref()).await
+        };
+
+        described
+            .await
+            .map_err(|e| println!("Could not describe a photo: {}", e))
+            .ok()
+    })
+}
+
+// how many photos go in each room's monthly collage, from PHOTO_COLLAGE; there's no collage
+// without it
+fn collage_size() -> Option<usize> {
+    env::var("PHOTO_COLLAGE")
+        .ok()
+        .map(|size| size.parse().expect("PHOTO_COLLAGE is not an integer"))
+}
+
+// whether photos starred with a reaction go out again at the end of the month, from PHOTO_BEST_OF
+fn best_of() -> bool {
+    env::var("PHOTO_BEST_OF").as_deref() == Ok("true")
+}
+
+// the start of last month, and it and the start of this month as they're kept in the database
+fn last_month() -> (DateTime<chrono_tz::Tz>, String, String) {
+    let this_month = scheduler::at_hour(scheduler::now().with_day(1).unwrap(), 0);
+    let last_month = scheduler::add_months(this_month, -1);
+
+    (
+        last_month,
+        last_month.with_timezone(&Utc).to_rfc3339(),
+        this_month.with_timezone(&Utc).to_rfc3339(),
+    )
+}
+
+fn collage_email() -> bool {
+    env::var("PHOTO_COLLAGE_EMAIL").as_deref() == Ok("true")
+}
+
+// one more reaction for a photo that might make the collage, unless it's one of our own
+async fn on_reaction(event: SyncMessageEvent<ReactionEventContent>, client: Client) {
+    if client.user_id().await.as_ref() == Some(&event.sender) {
+        return;
+    }
+
+    let relation = &event.content.relates_to;
+
+    // some keyboards add a variation selector to the star
+    let starred = relation.emoji.trim_end_matches('\u{fe0f}') == STAR;
+
+    let counted = storage::open("photobot").and_then(|conn| {
+        conn.execute(
+            "UPDATE highlights SET reactions = reactions + 1 WHERE event_id = ?1",
+            params![relation.event_id.as_str()],
+        )?;
+
+        if starred {
+            conn.execute(
+                "UPDATE best_of SET stars = stars + 1 WHERE event_id = ?1",
+                params![relation.event_id.as_str()],
+            )?;
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = counted {
+        println!("Could not count reaction! {}", e);
+    }
+}
+
+// when held photos go out, from PHOTO_DIGEST (like "7pm" or "weekdays at 6pm"); without it,
+// everything goes out as it comes in
+fn digest() -> Option<Recurrence> {
+    env::var("PHOTO_DIGEST")
+        .ok()
+        .map(|digest| digest.parse().expect("PHOTO_DIGEST is not a schedule"))
+}
+
+// the biggest email we'll send, from PHOTO_MAX_EMAIL_SIZE (in bytes)
+fn max_email_size() -> usize {
+    env::var("PHOTO_MAX_EMAIL_SIZE")
+        .map(|size| {
+            size.parse()
+                .expect("PHOTO_MAX_EMAIL_SIZE is not an integer")
+        })
+        .unwrap_or(DEFAULT_MAX_EMAIL_SIZE)
+}
+
+// how big an attachment is once it's encoded for email, which is what the mail server counts
+fn encoded_size(attachment: &Pending) -> usize {
+    attachment.data.len().div_ceil(3) * 4
+}
+
+// Shrinks a photo that's too big for an email on its own until it fits, or returns None if it
+// can't be (like a video, which has already been shrunk as far as it goes).
+fn fit(attachment: Pending) -> anyhow::Result<Option<Pending>> {
+    if encoded_size(&attachment) <= max_email_size() {
+        return Ok(Some(attachment));
+    }
+
+    if !attachment.mime_type.starts_with("image/") {
+        return Ok(None);
+    }
+
+    for &(width, height, quality) in FIT_STEPS {
+        let data = image::render(
+            &attachment.original,
+            &attachment.original_mime_type,
+            attachment.enhance,
+            image::Output {
+                max_size: Some((width, height)),
+                quality: Some(quality),
+            },
+        )?;
+
+        let fitted = Pending {
+            data,
+            mime_type: "image/jpeg".to_string(),
+            ..attachment.clone()
+        };
+
+        if encoded_size(&fitted) <= max_email_size() {
+            return Ok(Some(fitted));
+        }
+    }
+
+    Ok(None)
+}
+
+// splits attachments into groups that each fit in one email
+fn batches(attachments: &[Pending]) -> Vec<&[Pending]> {
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut size = 0;
+
+    for (i, attachment) in attachments.iter().enumerate() {
+        if i > start && size + encoded_size(attachment) > max_email_size() {
+            batches.push(&attachments[start..i]);
+            start = i;
+            size = 0;
+        }
+
+        size += encoded_size(attachment);
+    }
+
+    batches.push(&attachments[start..]);
+    batches
+}
+
+// something that didn't go through, to wait in the outbox: a whole email, or a single photo or
+// video (with its type and caption) for anywhere that isn't email
+struct Undelivered {
+    address: String,
+    data: Vec<u8>,
+    mime_type: Option<String>,
+    caption: Option<String>,
+    error: String,
+}
+
+// whether an address gets its photos some other way than email
+fn direct(address: &str) -> bool {
+    address == frame::ADDRESS || telegram::chat(address).is_some()
+}
+
+// Sends everything to an address that takes one photo or video at a time, as already shrunk the
+// default way, handing back whatever doesn't make it to go through the outbox like an email would.
+async fn send_direct(pending: &[Pending], address: &str) -> Vec<Undelivered> {
+    let mut failed = vec![];
+
+    for p in pending {
+        if let Err(e) = deliver(address, &p.data, &p.mime_type, p.caption.as_deref()).await {
+            println!("Could not send to {}: {}", address, e);
+
+            failed.push(Undelivered {
+                address: address.to_string(),
+                data: p.data.to_vec(),
+                mime_type: Some(p.mime_type.clone()),
+                caption: p.caption.clone(),
+                error: e.to_string(),
+            });
+        }
+    }
+
+    failed
+}
+
+// sends a single photo or video somewhere that isn't email
+async fn deliver(
+    address: &str,
+    data: &[u8],
+    mime_type: &str,
+    caption: Option<&str>,
+) -> anyhow::Result<()> {
+    match telegram::chat(address) {
+        Some(chat) => telegram::send(chat, data, mime_type, caption).await,
+        // the frame only shows photos, so videos (and animations) are left out
+        None if mime_type == "image/jpeg" => frame::push(data).await,
+        None => Ok(()),
+    }
+}
+
+// Returns the formatted emails that didn't go through, who they were for, and why, along with
+// anything that had to be done to fit everything in an email.
+async fn send_emails(
+    attachments: &[Pending],
+    to: &[(String, Prefs)],
+    mailer: &dyn mail::Mailer,
+) -> anyhow::Result<(Vec<Undelivered>, Vec<String>)> {
+    let mut captions: Vec<&str> = vec![];
+
+    for caption in attachments.iter().filter_map(|a| a.caption.as_deref()) {
+        if !captions.contains(&caption) {
+            captions.push(caption);
+        }
+    }
+
+    let subject = match captions.len() {
+        0 if attachments.len() == 1 => "Photo".to_string(),
+        0 => "Photos".to_string(),
+        _ => captions.join(", "),
+    };
+
+    // everyone who wants the same thing gets the same emails
+    let mut groups: Vec<(Prefs, Vec<&String>)> = vec![];
+
+    for (address, prefs) in to {
+        match groups.iter_mut().find(|(p, _)| p == prefs) {
+            Some((_, addresses)) => addresses.push(address),
+            None => groups.push((*prefs, vec![address])),
+        }
+    }
+
+    // everything is built before anything is sent, so an error here leaves nobody with half
+    // a batch, and the caller can hang on to it for next time
+    let mut outgoing = vec![];
+    let mut notes: Vec<String> = vec![];
+
+    for (prefs, addresses) in groups {
+        // anything that can't be done the way they asked goes out the default way, and
+        // anything that can't even be done that way (like a video that couldn't be scrubbed)
+        // is left out rather than sent with everything still in it
+        let attachments: Vec<Pending> = attachments
+            .iter()
+            .filter_map(|a| {
+                let pending = a.for_prefs(&prefs).or_else(|e| {
+                    println!("Could not prepare attachment, trying the default: {}", e);
+                    a.for_prefs(&Prefs::default())
+                });
+
+                match pending {
+                    Ok(pending) => Some(pending),
+                    Err(e) => {
+                        println!("Could not prepare attachment, leaving it out: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        // anything too big for an email on its own is shrunk until it fits, or left out
+        let mut fitted = vec![];
+        let (mut shrunk, mut left_out) = ((0, 0), (0, 0));
+
+        for attachment in attachments {
+            let size = attachment.data.len();
+            let video = attachment.mime_type.starts_with("video/");
+
+            match fit(attachment) {
+                Ok(Some(attachment)) => {
+                    if attachment.data.len() != size {
+                        shrunk = tally_kind(shrunk, video);
+                    }
+
+                    fitted.push(attachment);
+                }
+                Ok(None) => left_out = tally_kind(left_out, video),
+                Err(e) => {
+                    println!("Could not shrink attachment, leaving it out: {}", e);
+                    left_out = tally_kind(left_out, video);
+                }
+            }
+        }
+
+        let mut group_notes = vec![];
+
+        if shrunk != (0, 0) {
+            group_notes.push(format!(
+                "Shrank {} to fit in an email.",
+                counted(shrunk.0, shrunk.1)
+            ));
+        }
+
+        if left_out != (0, 0) {
+            group_notes.push(format!(
+                "Left out {} too big to email. It's still in the archive.",
+                counted(left_out.0, left_out.1)
+            ));
+        }
+
+        if fitted.is_empty() {
+            notes.extend(group_notes);
+            continue;
+        }
+
+        let emails = build_emails(&fitted)?;
+
+        if emails.len() > 1 {
+            group_notes.push(format!(
+                "That was too much for one email, so it went out in {}.",
+                emails.len()
+            ));
+        }
+
+        for note in group_notes {
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+
+        for address in addresses {
+            for (multipart, count) in &emails {
+                let email =
+                    mail::build(mailer.from(), address, &subject, multipart.clone())?.formatted();
+                outgoing.push((address, email, *count));
+            }
+        }
+    }
+
+    let mut failed = vec![];
+
+    for (address, email, count) in outgoing {
+        match mailer.send_raw(address, &email).await {
+            Ok(_) => println!("Sent {} attachments to {}", count, address),
+            Err(e) => {
+                println!("Could not send email to {}: {}", address, e);
+                failed.push(Undelivered {
+                    address: address.clone(),
+                    data: email,
+                    mime_type: None,
+                    caption: None,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok((failed, notes))
+}
+
+// adds one to the count of photos or videos, whichever it is
+fn tally_kind((photos, videos): (usize, usize), video: bool) -> (usize, usize) {
+    if video {
+        (photos, videos + 1)
+    } else {
+        (photos + 1, videos)
+    }
+}
+
+// everyone who sent something, in the order they first show up
+fn senders(attachments: &[Pending]) -> Vec<String> {
+    let mut senders: Vec<String> = vec![];
+
+    for attachment in attachments {
+        if !senders.contains(&attachment.origin.sender) {
+            senders.push(attachment.origin.sender.clone());
+        }
+    }
+
+    senders
+}
+
+// The body of an email: a section for each person with something in it (their name, how many
+// they sent, and a preview, caption, and date for each), as plain text and HTML, along with the
+// previews the HTML points to.
+fn email_body(attachments: &[Pending]) -> (String, String, Vec<(String, Bytes)>) {
+    let tz = scheduler::timezone();
+    let mut plain = vec![];
+    let mut html = String::new();
+    let mut previews = vec![];
+
+    for sender in senders(attachments) {
+        let sent: Vec<&Pending> = attachments
+            .iter()
+            .filter(|a| a.origin.sender == sender)
+            .collect();
+
+        let videos = sent
+            .iter()
+            .filter(|a| a.mime_type.starts_with("video/"))
+            .count();
+        let count = counted(sent.len() - videos, videos);
+        let name = sender_name(&sender);
+
+        plain.push(format!("From {} ({}):", name, count));
+        html.push_str(&format!(
+            "<h3>From {} <small>({})</small></h3>",
+            matrix::escape_html(&name),
+            count
+        ));
+
+        for attachment in sent {
+            let date = attachment
+                .origin
+                .date
+                .with_timezone(&tz)
+                .format("%A, %B %-d")
+                .to_string();
+
+            let preview = if attachment.mime_type.starts_with("image/") {
+                image::thumbnail(&attachment.data)
+                    .map_err(|e| println!("Could not make a preview: {}", e))
+                    .ok()
+            } else {
+                None
+            };
+
+            html.push_str("<p>");
+
+            match preview {
+                Some((preview, width, height)) => {
+                    let content_id = format!("preview{}", previews.len());
+                    html.push_str(&format!(
+                        "<img src=\"cid:{}\" width=\"{}\" height=\"{}\"><br>",
+                        content_id, width, height
+                    ));
+                    previews.push((content_id, preview));
+                }
+                None if attachment.mime_type.starts_with("video/") => {
+                    html.push_str("<em>A video (it's attached)</em><br>")
+                }
+                None => html.push_str("<em>A photo (it's attached)</em><br>"),
+            }
+
+            match &attachment.caption {
+                Some(caption) => {
+                    plain.push(format!("  {} ({})", caption, date));
+                    html.push_str(&format!("{}<br>", matrix::escape_html(caption)));
+                }
+                None => plain.push(format!("  {}", date)),
+            }
+
+            if let Some(description) = &attachment.description {
+                plain.push(format!("  {}", description));
+                html.push_str(&format!(
+                    "<em>{}</em><br>",
+                    matrix::escape_html(description)
+                ));
+            }
+
+            html.push_str(&format!("<small>{}</small></p>", date));
+        }
+    }
+
+    plain.push(String::new());
+    plain.push("Full size copies are attached.".to_string());
+
+    let html = format!(
+        "<html><body style=\"font-family: sans-serif\">{}\
+        <p style=\"color: #888\"><small>Full size copies are attached.</small></p>\
+        </body></html>",
+        html
+    );
+
+    (plain.join("\n"), html, previews)
+}
+
+// like "3 photos", or "1 photo and 2 videos"
+fn counted(photos: usize, videos: usize) -> String {
+    let plural = |count: usize, noun: &str| match count {
+        1 => format!("1 {}", noun),
+        count => format!("{} {}s", count, noun),
+    };
+
+    match (photos, videos) {
+        (_, 0) => plural(photos, "photo"),
+        (0, _) => plural(videos, "video"),
+        _ => format!(
+            "{} and {}",
+            plural(photos, "photo"),
+            plural(videos, "video")
+        ),
+    }
+}
+
+// how many matches a find answers with
+const FIND_LIMIT: usize = 3;
+
+// words that say what's being looked for, but not what it looks like
+const FIND_FILLER: &[&str] = &[
+    "a", "an", "the", "of", "with", "photo", "photos", "picture", "pictures", "pic", "pics",
+    "video", "videos",
+];
+
+// Looks for photos and videos in the room, like "find the photo mom sent of the cat" or "find
+// cat from gwen", and links to the latest few.
+async fn on_find_message(
+    client: &Client,
+    joined: &Joined,
+    command: &str,
+) -> anyhow::Result<String> {
+    let (words, name) = parse_find(command);
+
+    if words.is_empty() {
+        return Ok("Usage: find [words] from [name].".to_string());
+    }
+
+    let sender = match name {
+        Some(name) => match matrix::create_user_id(&name) {
+            Ok(user_id) => Some(user_id),
+            Err(_) => return Ok(format!("I don't know who {} is.", name)),
+        },
+        None => None,
+    };
+
+    // ask for plenty, since texts that mention the words come back too
+    let found: Vec<matrix::Found> = matrix::search(client, joined, &words, sender.as_ref(), 50)
+        .await?
+        .into_iter()
+        .filter(|f| f.msgtype == "m.image" || f.msgtype == "m.video")
+        .take(FIND_LIMIT)
+        .collect();
+
+    if found.is_empty() {
+        return Ok("I couldn't find anything like that.".to_string());
+    }
+
+    let tz = scheduler::timezone();
+    let lines: Vec<String> = found
+        .iter()
+        .map(|f| {
+            format!(
+                "{} sent it on {}: {}",
+                matrix::pretty_user_id(&f.sender),
+                f.date.with_timezone(&tz).format("%A, %B %-d, %Y"),
+                f.link(joined.room_id())
+            )
+        })
+        .collect();
+
+    Ok(lines.join("\n"))
+}
+
+// splits a find into the words to look for and, if someone's named, who sent it
+fn parse_find(command: &str) -> (String, Option<String>) {
+    let mut words: Vec<String> = command
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect();
+    let mut name = None;
+
+    if let Some(i) = words
+        .iter()
+        .position(|word| word == "sent")
+        .filter(|&i| i > 0)
+    {
+        name = Some(words.remove(i - 1));
+        words.remove(i - 1);
+    } else if let Some(i) = words
+        .iter()
+        .position(|word| word == "from")
+        .filter(|&i| i + 1 < words.len())
+    {
+        name = Some(words.remove(i + 1));
+        words.remove(i);
+    }
+
+    words.retain(|word| !FIND_FILLER.contains(&word.as_str()));
+
+    (words.join(" "), name)
+}
+
+// the name of whoever sent something, which older digests don't know
+fn sender_name(sender: &str) -> String {
+    match UserId::try_from(sender) {
+        Ok(user_id) => matrix::pretty_user_id(&user_id),
+        Err(_) => "Someone".to_string(),
+    }
+}
+
+// when something kept in the database came in, or now, if it's from before that was kept
+fn received_at(date: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+}
+
+// encodes everything once, rather than once per recipient
+fn build_emails(attachments: &[Pending]) -> anyhow::Result<Vec<(MultiPart, usize)>> {
+    let mut emails = vec![];
+
+    // everyone's photos go together, under their name
+    let senders = senders(attachments);
+    let mut attachments = attachments.to_vec();
+    attachments.sort_by_key(|a| senders.iter().position(|s| *s == a.origin.sender));
+
+    for batch in batches(&attachments) {
+        // Some mail apps tuck attachments away where nobody looks, so the body shows everything
+        // too, with the full size copies attached after it.
+        let (plain, html, previews) = email_body(batch);
+        let mut body =
+            MultiPart::related().multipart(MultiPart::alternative_plain_html(plain, html));
+
+        for (content_id, preview) in previews {
+            body = body.singlepart(Attachment::new_inline(content_id).body(
+                Body::new(preview.to_vec()),
+                ContentType::parse("image/jpeg")?,
+            ));
+        }
+
+        let mut multipart = MultiPart::mixed().multipart(body);
+
+        for attachment in batch {
+            let file_name = get_filename(&attachment.mime_type, attachment.caption.as_deref());
+
+            let content_type = ContentType::parse(&attachment.mime_type)
+                .or_else(|_| ContentType::parse("application/octet-stream"))?;
+
+            multipart = multipart.singlepart(
+                Attachment::new(file_name).body(Body::new(attachment.data.to_vec()), content_type),
+            );
+        }
+
+        emails.push((multipart, batch.len()));
+    }
+
+    Ok(emails)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use matrix_sdk::ruma::MxcUri;
+
+    use super::*;
+    use crate::testing::{MediaServer, SmtpSink};
+
+    #[test]
+    fn parses_expiries() {
+        let minutes = chrono::Duration::minutes;
+
+        for (command, rest, lasts) in [
+            ("only grandma for 2 hours", "only grandma", minutes(120)),
+            ("only grandma for an hour", "only grandma", minutes(60)),
+            ("Only Grandma For 30 Minutes", "Only Grandma", minutes(30)),
+            ("only grandma for a day", "only grandma", minutes(24 * 60)),
+            ("only grandma for 1 minute", "only grandma", minutes(1)),
+        ] {
+            let (parsed, until) = parse_expiry(command);
+            let left = until.unwrap() - Utc::now();
+
+            assert_eq!(parsed, rest, "{:?}", command);
+            assert!(left <= lasts && left > lasts - minutes(1), "{:?}", command);
+        }
+
+        let (parsed, until) = parse_expiry("only grandma today");
+        assert_eq!(parsed, "only grandma");
+        assert!(until.unwrap() > Utc::now());
+
+        for command in [
+            "only grandma",
+            "",
+            "only grandma for 0 hours",
+            "only grandma for -2 hours",
+            "only grandma for 2 fortnights",
+            "only grandma for 4000000000 days",
+            "only grandma for 99999999999 days",
+            "only grandma for hours",
+            "only photos for grandpa",
+        ] {
+            assert_eq!(parse_expiry(command), (command, None), "{:?}", command);
+        }
+    }
+
+    // Everything a photo goes through after it's posted: downloaded from the homeserver,
+    // converted, emailed, and archived, with nothing outside the test but the local disk.
+    #[tokio::test]
+    async fn emails_and_archives_a_photo() {
+        let dir = env::temp_dir().join(format!("photobot-test-{}", std::process::id()));
+        let dropbox = dir.join("dropbox");
+        std::fs::create_dir_all(&dropbox).unwrap();
+        let dropbox = dropbox.to_string_lossy().to_string();
+
+        let mut png = vec![];
+        ::image::RgbImage::from_pixel(64, 48, ::image::Rgb([200, 120, 40]))
+            .write_to(&mut Cursor::new(&mut png), ::image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = Bytes::from(png);
+
+        let media =
+            MediaServer::start(HashMap::from([("beach".to_string(), png.clone())])).unwrap();
+        let smtp = SmtpSink::start().await.unwrap();
+
+        let mut bot = Bot::open(
+            Connection::open_in_memory().unwrap(),
+            Box::new(media.homeserver()),
+            Box::new(smtp.mailer("photos@example.com")),
+            vec![Box::new(archive::Local::new(&dropbox))],
+        )
+        .unwrap();
+
+        bot.add_recipient("grandma", "grandma@example.com").unwrap();
+
+        let uri = MxcUri::from("mxc://kulak.us/beach");
+        let photo = bot.media.download(&uri).await.unwrap();
+        assert_eq!(photo, png);
+
+        let mime_type = image::sniff_mime_type(&photo).unwrap();
+        let jpeg = image::render(&photo, mime_type, false, image::Output::default()).unwrap();
+
+        let room_id = RoomId::try_from("!family:kulak.us").unwrap();
+
+        bot.pending.push(Pending {
+            data: jpeg,
+            mime_type: "image/jpeg".to_string(),
+            caption: Some("At the beach".to_string()),
+            description: Some("Charlie building a sandcastle".to_string()),
+            original: photo.clone(),
+            original_mime_type: mime_type.to_string(),
+            enhance: false,
+            origin: Origin {
+                room_id: room_id.clone(),
+                event_id: "$photo".to_string(),
+                sender: "@phil:kulak.us".to_string(),
+                date: Utc::now(),
+            },
+        });
+
+        let results = bot.send_pending().await;
+        assert_eq!(results.len(), 1);
+
+        let delivery = results[0].1.as_ref().unwrap();
+        assert!(delivery.queued.is_empty());
+        assert!(delivery.paused.is_empty());
+        assert!(bot.pending.is_empty());
+
+        let received = smtp.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].to, ["grandma@example.com"]);
+        assert!(received[0].data.contains("From: photos@example.com"));
+        assert!(received[0].data.contains("Subject: At the beach"));
+        assert!(received[0].data.contains("image/jpeg"));
+        assert!(received[0].data.contains("Charlie building a sandcastle"));
+
+        archive(
+            &bot.archives,
+            &photo,
+            mime_type,
+            Some("At the beach"),
+            Some("Charlie building a sandcastle"),
+            &room_id,
+        )
+        .await
+        .unwrap();
+
+        // the description is kept beside the photo, and isn't counted as one itself
+        let archived = archive::files_in(&dropbox).unwrap();
+        assert_eq!(archived.len(), 1);
+        assert!(archived[0].to_string_lossy().ends_with(".png"));
+        assert_eq!(std::fs::read(&archived[0]).unwrap(), png);
+
+        let sidecar = format!("{}.json", archived[0].to_string_lossy());
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(sidecar).unwrap()).unwrap();
+        assert_eq!(sidecar["description"], "Charlie building a sandcastle");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
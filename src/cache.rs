@@ -0,0 +1,280 @@
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use tokio::task;
+
+use crate::storage::{self, Area};
+
+// how big the cache gets before the least recently used files go, unless MEDIA_CACHE_MB says
+const DEFAULT_CAP_MB: u64 = 1024;
+
+fn cap() -> u64 {
+    let mb = match env::var("MEDIA_CACHE_MB") {
+        Ok(mb) => mb.parse().expect("MEDIA_CACHE_MB is not an integer"),
+        Err(_) => DEFAULT_CAP_MB,
+    };
+
+    mb * 1024 * 1024
+}
+
+// Something downloaded before, under whatever named it (like its URL), or fetched now and kept
+// for next time. Files are stored by the hash of what's in them, so the same thing under two
+// names is only kept once. The files and the database are both off the runtime, on a blocking
+// thread.
+pub async fn get<F>(key: &str, fetch: F) -> anyhow::Result<Bytes>
+where
+    F: Future<Output = anyhow::Result<Bytes>>,
+{
+    let owned = key.to_string();
+
+    if let Some(data) = task::spawn_blocking(move || lookup(&owned)).await?? {
+        return Ok(data);
+    }
+
+    let data = fetch.await?;
+
+    task::spawn_blocking({
+        let key = key.to_string();
+        let data = data.clone();
+        move || insert(&key, &data)
+    })
+    .await??;
+
+    Ok(data)
+}
+
+pub async fn get_url(url: &str) -> anyhow::Result<Bytes> {
+    get(url, async {
+        let response = reqwest::get(url).await?.error_for_status()?;
+        Ok(response.bytes().await?)
+    })
+    .await
+}
+
+// A link to a copy of whatever's at a URL, served by the dashboard from MEDIA_URL (wherever it
+// can be reached from), so whatever plays it doesn't download it from the internet every time.
+// Nothing if there's no MEDIA_URL.
+pub async fn share_url(url: &str) -> anyhow::Result<Option<String>> {
+    let base = match env::var("MEDIA_URL") {
+        Ok(base) => base,
+        Err(_) => return Ok(None),
+    };
+
+    let data = get_url(url).await?;
+
+    Ok(Some(link(&base, &data)))
+}
+
+// Keeps something we made ourselves, under a name, and links to it the same way. Nothing if
+// there's no MEDIA_URL.
+pub fn share(key: &str, data: &[u8]) -> anyhow::Result<Option<String>> {
+    let base = match env::var("MEDIA_URL") {
+        Ok(base) => base,
+        Err(_) => return Ok(None),
+    };
+
+    insert(key, data)?;
+
+    Ok(Some(link(&base, data)))
+}
+
+fn link(base: &str, data: &[u8]) -> String {
+    format!("{}/media/{}", base.trim_end_matches('/'), hash(data))
+}
+
+// the file for a hash, if it's in the cache
+pub fn file(hash: &str) -> Option<PathBuf> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    dir()
+        .ok()
+        .map(|dir| path(&dir, hash))
+        .filter(|path| path.exists())
+}
+
+fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn dir() -> anyhow::Result<PathBuf> {
+    storage::dir("bots", Area::Cache)
+}
+
+fn path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(&hash[..2]).join(hash)
+}
+
+fn lookup(key: &str) -> anyhow::Result<Option<Bytes>> {
+    let conn = open_db()?;
+
+    let hash: Option<String> = conn
+        .query_row(
+            "SELECT hash FROM media_cache WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let hash = match hash {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    match fs::read(path(&dir()?, &hash)) {
+        Ok(data) => {
+            conn.execute(
+                "UPDATE media_cache SET used_at = ?2 WHERE key = ?1",
+                params![key, Utc::now().to_rfc3339()],
+            )?;
+            Ok(Some(Bytes::from(data)))
+        }
+        // someone cleaned up by hand; it'll be fetched again
+        Err(_) => {
+            conn.execute("DELETE FROM media_cache WHERE hash = ?1", params![hash])?;
+            Ok(None)
+        }
+    }
+}
+
+fn insert(key: &str, data: &[u8]) -> anyhow::Result<()> {
+    let conn = open_db()?;
+    let dir = dir()?;
+
+    store(&conn, &dir, key, data)?;
+    evict(&conn, &dir, cap())
+}
+
+fn store(conn: &Connection, dir: &Path, key: &str, data: &[u8]) -> anyhow::Result<()> {
+    let hash = hash(data);
+    let path = path(dir, &hash);
+
+    if !path.exists() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // written off to the side first, so nothing ever reads half a file
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)?;
+    }
+
+    conn.execute(
+        "
+        INSERT OR REPLACE INTO media_cache
+            (key, hash, size, used_at)
+        VALUES
+            (?1, ?2, ?3, ?4)",
+        params![key, hash, data.len() as i64, Utc::now().to_rfc3339()],
+    )?;
+
+    Ok(())
+}
+
+// drops whatever was used longest ago until everything fits under the cap
+fn evict(conn: &Connection, dir: &Path, cap: u64) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT hash, MAX(size), MAX(used_at) AS used
+        FROM media_cache
+        GROUP BY hash
+        ORDER BY used DESC",
+    )?;
+
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut total = 0;
+    let mut evicted: Vec<String> = vec![];
+
+    for row in rows {
+        let (hash, size): (String, i64) = row?;
+        total += size as u64;
+
+        if total > cap {
+            evicted.push(hash);
+        }
+    }
+
+    for hash in evicted {
+        println!("evicting {} from the media cache", hash);
+        conn.execute("DELETE FROM media_cache WHERE hash = ?1", params![hash])?;
+
+        if let Err(e) = fs::remove_file(path(dir, &hash)) {
+            println!("Could not remove {} from the media cache: {}", hash, e);
+        }
+    }
+
+    Ok(())
+}
+
+fn open_db() -> anyhow::Result<Connection> {
+    let conn = storage::open_shared()?;
+    create_table(&conn)?;
+
+    Ok(conn)
+}
+
+fn create_table(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS media_cache (
+            key TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            used_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_oldest() {
+        let dir = env::temp_dir().join(format!("media-cache-test-{}", std::process::id()));
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+
+        // three files of 10 bytes each, used a minute apart
+        for (i, key) in ["old", "middle", "new"].iter().enumerate() {
+            store(&conn, &dir, key, &[i as u8; 10]).unwrap();
+
+            conn.execute(
+                "UPDATE media_cache SET used_at = ?2 WHERE key = ?1",
+                params![key, format!("2024-01-01T00:0{}:00+00:00", i)],
+            )
+            .unwrap();
+        }
+
+        // only room for two
+        evict(&conn, &dir, 25).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT key FROM media_cache ORDER BY used_at")
+            .unwrap();
+        let keys: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+
+        assert_eq!(keys, ["middle", "new"]);
+        assert!(!path(&dir, &hash(&[0; 10])).exists());
+        assert!(path(&dir, &hash(&[1; 10])).exists());
+        assert!(path(&dir, &hash(&[2; 10])).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
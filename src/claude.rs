@@ -0,0 +1,289 @@
+// Chat from Anthropic's Claude models, as an alternative to Open AI. This needs an API key in
+// ANTHROPIC_KEY.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::env;
+use tokio::sync::watch;
+
+use crate::ai::{read_stream, take_turn, ChatBackend, Message};
+
+const URL: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+
+// Claude wants to be told how long an answer can be, and this is plenty for a chat
+const MAX_TOKENS: usize = 4096;
+
+#[derive(Deserialize)]
+struct Response {
+    content: Vec<Block>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    #[serde(default)]
+    text: String,
+}
+
+// one event of a streamed answer; only the text deltas and errors matter to us
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    delta: Option<Delta>,
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+pub struct Claude;
+
+#[async_trait]
+impl ChatBackend for Claude {
+    fn name(&self) -> &'static str {
+        "Claude"
+    }
+
+    // there's no asking for more than one answer at a time, so each is its own request
+    async fn chat(&self, messages: &[Message], model: &str, n: usize) -> Result<Vec<String>> {
+        let body = body(messages, model, None, false);
+        let answers = (0..n).map(|_| answer(&body));
+
+        futures::future::try_join_all(answers).await
+    }
+
+    async fn chat_streaming(
+        &self,
+        messages: &[Message],
+        model: &str,
+        progress: watch::Sender<String>,
+    ) -> Result<String> {
+        let body = body(messages, model, None, true);
+
+        let _turn = take_turn().await?;
+        let response = send(&body).await?;
+        let answer = read_stream(response, progress, stream_delta).await?;
+
+        if answer.is_empty() {
+            bail!("no answer from Claude");
+        }
+
+        Ok(answer)
+    }
+
+    // Claude takes a good look at every photo, so there's no detail to pick
+    async fn look(
+        &self,
+        messages: &[Message],
+        model: &str,
+        jpeg: &[u8],
+        _detail: &str,
+    ) -> Result<String> {
+        answer(&body(messages, model, Some(jpeg), false)).await
+    }
+}
+
+async fn send(body: &serde_json::Value) -> Result<reqwest::Response> {
+    let key = env::var("ANTHROPIC_KEY").expect("ANTHROPIC_KEY environmental variable not set");
+
+    let response = reqwest::Client::new()
+        .post(URL)
+        .header("x-api-key", key)
+        .header("anthropic-version", API_VERSION)
+        .header("Content-Type", "application/json")
+        .json(body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from Claude: {}",
+            response.status()
+        );
+    }
+
+    Ok(response)
+}
+
+async fn answer(body: &serde_json::Value) -> Result<String> {
+    let _turn = take_turn().await?;
+    let response = send(body).await?.json::<Response>().await?;
+
+    let answer: String = response
+        .content
+        .into_iter()
+        .map(|block| block.text)
+        .collect();
+
+    if answer.is_empty() {
+        bail!("no answer from Claude");
+    }
+
+    Ok(answer)
+}
+
+// The request for a conversation, in the shape Claude takes it: the system prompt goes on its own,
+// and the rest has to take turns, starting with the user. A photo (a JPEG) goes along with the last
+// message.
+fn body(
+    messages: &[Message],
+    model: &str,
+    photo: Option<&[u8]>,
+    stream: bool,
+) -> serde_json::Value {
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| m.content.as_str())
+        .collect();
+
+    let mut turns: Vec<(&str, String)> = vec![];
+
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let role = if message.role == "assistant" {
+            "assistant"
+        } else {
+            "user"
+        };
+
+        // Claude wants the user first, so an answer with nothing before it (like after the
+        // history was trimmed) is left out
+        if turns.is_empty() && role == "assistant" {
+            continue;
+        }
+
+        match turns.last_mut() {
+            Some((last, content)) if *last == role => {
+                content.push_str("\n\n");
+                content.push_str(&message.content);
+            }
+            _ => turns.push((role, message.content.clone())),
+        }
+    }
+
+    let last = turns.len().saturating_sub(1);
+
+    let turns: Vec<serde_json::Value> = turns
+        .into_iter()
+        .enumerate()
+        .map(|(i, (role, content))| match photo {
+            Some(jpeg) if i == last => serde_json::json!({
+                "role": role,
+                "content": [
+                    {
+                        "type": "image",
+                        "source": {
+                            "type": "base64",
+                            "media_type": "image/jpeg",
+                            "data": base64::encode(jpeg),
+                        },
+                    },
+                    { "type": "text", "text": content },
+                ],
+            }),
+            _ => serde_json::json!({ "role": role, "content": content }),
+        })
+        .collect();
+
+    let mut body = serde_json::json!({
+        "model": model,
+        "max_tokens": MAX_TOKENS,
+        "messages": turns,
+    });
+
+    if !system.is_empty() {
+        body["system"] = system.join("\n\n").into();
+    }
+
+    if stream {
+        body["stream"] = true.into();
+    }
+
+    body
+}
+
+// the text in one line of a streamed answer, if there's any
+fn stream_delta(line: &str) -> Result<Option<String>> {
+    let data = match line.trim().strip_prefix("data:") {
+        Some(data) => data.trim(),
+        None => return Ok(None),
+    };
+
+    let event: Event = serde_json::from_str(data)?;
+
+    if let Some(error) = event.error {
+        bail!("Claude stopped answering: {}", error.message);
+    }
+
+    if event.kind != "content_block_delta" {
+        return Ok(None);
+    }
+
+    Ok(event
+        .delta
+        .and_then(|delta| delta.text)
+        .filter(|text| !text.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_turns() {
+        let messages = [
+            Message::assistant("Hi, I'm Sherman."),
+            Message::system("You are Sherman."),
+            Message::user("Hello"),
+            Message::user("Anyone there?"),
+            Message::assistant("Yes!"),
+            Message::user("What's this?"),
+        ];
+
+        let body = body(&messages, "claude-sonnet-4-5", None, false);
+
+        assert_eq!(body["system"], "You are Sherman.");
+        assert_eq!(body["stream"], serde_json::Value::Null);
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                { "role": "user", "content": "Hello\n\nAnyone there?" },
+                { "role": "assistant", "content": "Yes!" },
+                { "role": "user", "content": "What's this?" },
+            ])
+        );
+
+        // and the photo goes with the question
+        let with_photo = super::body(&messages, "claude-sonnet-4-5", Some(b"jpeg"), true);
+        let question = &with_photo["messages"][2]["content"];
+
+        assert_eq!(with_photo["stream"], true);
+        assert_eq!(question[0]["source"]["data"], base64::encode(b"jpeg"));
+        assert_eq!(question[1]["text"], "What's this?");
+    }
+
+    #[test]
+    fn reads_stream_deltas() {
+        let line = r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#;
+        assert_eq!(stream_delta(line).unwrap(), Some("Hel".to_string()));
+
+        // everything else that comes along says nothing
+        let start = r#"data: {"type":"message_start","message":{"id":"msg_1","content":[]}}"#;
+        assert_eq!(stream_delta(start).unwrap(), None);
+        assert_eq!(stream_delta("event: content_block_delta").unwrap(), None);
+        assert_eq!(stream_delta("").unwrap(), None);
+
+        let error =
+            r#"data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        assert!(stream_delta(error).is_err());
+    }
+}
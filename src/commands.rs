@@ -0,0 +1,375 @@
+use std::sync::Mutex;
+
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::room::message::MessageEventContent;
+use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::{Client, SyncSettings};
+
+use crate::matrix;
+
+// usage and description for every command, used to build the help messages
+pub const HOME: &[(&str, &str)] = &[
+    ("say [message]", "Say something over the speakers."),
+    (
+        "notify [message]",
+        "Send a notification to everyone's phones.",
+    ),
+    (
+        "trigger [webhook] [message]",
+        "Call one of the configured webhooks.",
+    ),
+    (
+        "webhook history",
+        "Show the last few webhook calls and how they went (parents only).",
+    ),
+    (
+        "webhook test [webhook]",
+        "Show what a webhook would get, without calling it (parents only).",
+    ),
+    (
+        "storage",
+        "Show how much each bot has on disk (parents only).",
+    ),
+    (
+        "storage clean [bot]",
+        "Clear out old files from the media caches, of one bot or all of them (parents only).",
+    ),
+    ("in [number] minutes [command]", "Run a command later."),
+    ("routines", "List the routines."),
+    ("routine [name]", "Run a routine."),
+    (
+        "routine [name] = [command]; [command]",
+        "Save a routine (parents only).",
+    ),
+    (
+        "routine [name] [schedule]",
+        "Run a routine on a schedule, like \"weekdays at 7am\", or \"off\" to stop (parents only).",
+    ),
+    ("routine delete [name]", "Delete a routine (parents only)."),
+    ("alerts", "List the sensor alerts."),
+    (
+        "alert if [sensor] > [number]",
+        "Say something when a sensor goes over (or under, with <) a number. Add \"and notify\" to send a notification too (parents only).",
+    ),
+    ("alert delete [sensor]", "Delete a sensor's alerts (parents only)."),
+    (
+        "power [number] days",
+        "Show how much electricity we used each day, and which days went over budget.",
+    ),
+];
+
+pub const MONEY: &[(&str, &str)] = &[
+    (
+        "balance [user]",
+        "Show your balance, or someone else's. Some rooms show it as a card, with your goal.",
+    ),
+    (
+        "goal [amount] for [thing]",
+        "Set what you're saving up for, or \"goal off\" to clear it.",
+    ),
+    (
+        "send [amount] to [user] for [memo]",
+        "Send money to someone. The memo is optional.",
+    ),
+    (
+        "ledger [user] [plain]",
+        "Show the last few transactions, optionally as plain text.",
+    ),
+    (
+        "statement [month] [user]",
+        "Get a month's statement as a PDF, for printing.",
+    ),
+    (
+        "savings [user]",
+        "Show your savings balance, or someone else's.",
+    ),
+    (
+        "withdraw [amount] for [memo]",
+        "Move money out of your savings. Big withdrawals need two parents to sign off.",
+    ),
+    (
+        "approve [number]",
+        "Let a send held for looking unusual go through (parents only).",
+    ),
+    (
+        "deny [number]",
+        "Call off a send held for looking unusual (parents only).",
+    ),
+    (
+        "money minute [on/off]",
+        "Get a weekly summary of the kids' money as a direct message, or this week's right now (parents only).",
+    ),
+    ("get min [user]", "Show the minimum balance for a user."),
+    (
+        "set min [user] [amount]",
+        "Set the minimum balance for a user (parents only).",
+    ),
+    (
+        "rule sweep [user] over [amount] [weekly/monthly]",
+        "Move anything over an amount into savings (parents only).",
+    ),
+    (
+        "rule fee [user] [amount] [weekly/monthly]",
+        "Charge a fee if someone doesn't send anything (parents only).",
+    ),
+    (
+        "rule roundup [user]",
+        "Round up every send to the dollar and put the change in savings.",
+    ),
+    ("rules", "List the automatic rules."),
+    (
+        "rules delete [number]",
+        "Delete a rule (parents only, or anyone for their own round-up).",
+    ),
+    (
+        "timezone [user] [zone]",
+        "Show or set the timezone used for dates and the allowance.",
+    ),
+    (
+        "chores",
+        "List the chores, and whether they're done this week.",
+    ),
+    (
+        "chores add [user] [chore]",
+        "Give someone a weekly chore (parents only).",
+    ),
+    ("chores done [number]", "Mark a chore done for the week."),
+    ("chores delete [number]", "Delete a chore (parents only)."),
+    (
+        "skip next allowance [for user]",
+        "Skip the next allowance, for everyone or just one (parents only).",
+    ),
+    (
+        "pause allowance [for user] until [date]",
+        "Hold off on the allowance until a date (parents only).",
+    ),
+    (
+        "resume allowance [for user]",
+        "Call off any skips or pauses (parents only).",
+    ),
+    ("help", "Show this message."),
+];
+
+pub const AI: &[(&str, &str)] = &[
+    ("sherman, [prompt]", "Ask Sherman anything."),
+    ("show me [prompt]", "Have Sherman draw a picture."),
+    (
+        "sherman, [question] (as a reply to a photo)",
+        "Ask Sherman about a photo; in rooms where he answers everything, he'll say what's in it on his own.",
+    ),
+    (
+        "sherman, search [question]",
+        "Ask Sherman something he'll look up on the web, with links to where he found it.",
+    ),
+    (
+        "sherman, for the next [number] [minutes/hours] [prompt]",
+        "Change how Sherman talks for a while.",
+    ),
+    (
+        "sherman, give me [number] options for [prompt]",
+        "Get a few answers to choose from; react with a number to pick one.",
+    ),
+    (
+        "setup",
+        "Pick who Sherman is in this room, which model he uses, when he answers, and whether to keep it kid-safe.",
+    ),
+    (
+        "sherman, set persona [prompt]",
+        "Tell Sherman who to be in this room, in your own words (parents only).",
+    ),
+    (
+        "reset persona",
+        "Go back to the persona picked during setup (parents only).",
+    ),
+    (
+        "language [language]",
+        "Have Sherman speak another language in this room.",
+    ),
+    (
+        "voice [on/off]",
+        "Keep answers short and speakable, for rooms bridged to a voice assistant.",
+    ),
+    (
+        "schedule [schedule]: [prompt]",
+        "Have Sherman answer a prompt here on a schedule, like \"every sunday at 5pm: suggest three dinners\" (parents only).",
+    ),
+    ("schedules", "List this room's scheduled prompts."),
+    (
+        "unschedule [number]",
+        "Stop a scheduled prompt (parents only).",
+    ),
+    (
+        "context show",
+        "Show what Sherman remembers (parents only).",
+    ),
+    (
+        "context drop [number]...",
+        "Make Sherman forget something (parents only).",
+    ),
+];
+
+pub const OWEN: &[(&str, &str)] = &[
+    ("wow", "Wow!"),
+    ("owen ignore me", "Never wow at anything you say."),
+    ("owen include me", "Wow at what you say again."),
+];
+
+pub const PHOTO: &[(&str, &str)] = &[
+    ("who", "Show who this room's photos are being sent to."),
+    ("to mark", "Only send this room's photos to Mark."),
+    ("to mark jane", "Only send photos to Mark and Jane."),
+    (
+        "to mark frame",
+        "Send photos to Mark and the photo frame, if there is one.",
+    ),
+    ("not mark", "Don't send photos to Mark."),
+    (
+        "to mark for 2 hours",
+        "Only send photos to Mark for a while.",
+    ),
+    (
+        "not jane today",
+        "Don't send photos to Jane until tomorrow.",
+    ),
+    ("reset", "Send this room's photos to everyone again."),
+    (
+        "send now",
+        "Send the photos being held for the digest without waiting.",
+    ),
+    (
+        "resend last [count] to [names]",
+        "Email the latest photo (or the last few) again to just the people named.",
+    ),
+    (
+        "find [words] from [name]",
+        "Link to photos or videos sent here before, like \"find the photo mom sent of the cat\".",
+    ),
+    (
+        "stats",
+        "How many photos went out this week, month, and year, and who sent and got them.",
+    ),
+    (
+        "photo language [language]",
+        "Have the photo bot answer in another language in this room.",
+    ),
+    (
+        "add recipient [name] [email]",
+        "Start sending photos to someone (parents only).",
+    ),
+    (
+        "add recipient [name] telegram:[chat]",
+        "Send someone's photos to a Telegram chat (parents only).",
+    ),
+    (
+        "remove recipient [name] [email]",
+        "Stop sending photos to someone, or just one of their addresses (parents only).",
+    ),
+    (
+        "list recipients",
+        "Show everyone who can get photos (parents only).",
+    ),
+    (
+        "recipient [email] size [width]x[height]",
+        "Shrink someone's photos to fit a size (parents only).",
+    ),
+    (
+        "recipient [email] quality [1-100]",
+        "Set the JPEG quality for someone's photos (parents only).",
+    ),
+    (
+        "recipient [email] original",
+        "Send someone the originals, or \"default\" to go back (parents only).",
+    ),
+    (
+        "recipient [email] resume",
+        "Start sending to someone again after their emails kept failing (parents only).",
+    ),
+];
+
+pub const ALL: &[(&str, &[(&str, &str)])] = &[
+    ("home", HOME),
+    ("money", MONEY),
+    ("ai", AI),
+    ("owen", OWEN),
+    ("photo", PHOTO),
+];
+
+// the bots running in this process, when they're all run together
+static RUNNING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn set_running(bots: &[String]) {
+    *RUNNING.lock().unwrap() = bots.to_vec();
+}
+
+// when the bots run together, help is answered once for all of them, rather than by each bot
+pub fn unified() -> bool {
+    !RUNNING.lock().unwrap().is_empty()
+}
+
+// the plain and HTML versions of a list of commands
+pub fn help(commands: &[(&str, &str)]) -> (String, String) {
+    let text: Vec<String> = commands
+        .iter()
+        .map(|(usage, description)| format!("{}: {}", usage, description))
+        .collect();
+
+    let mut html: Vec<String> = commands
+        .iter()
+        .map(|(usage, description)| format!("<li><strong>{}</strong>: {}</li>", usage, description))
+        .collect();
+
+    html.insert(0, "<ul>".to_string());
+    html.push("</ul>".to_string());
+
+    (text.join("\n"), html.join("\n"))
+}
+
+// answers help for every running bot that's active in the room
+pub async fn main() -> anyhow::Result<()> {
+    let client = matrix::create_client("helpbot").await?;
+
+    client.register_event_handler(on_room_message).await;
+
+    let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
+    client.sync(settings).await;
+
+    Ok(())
+}
+
+async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Room, client: Client) {
+    let (joined, _, message) = match matrix::get_text_message(event, room, client.clone()).await {
+        Some(message) => message,
+        None => return,
+    };
+
+    if matrix::get_command("help", &message).is_none() {
+        return;
+    }
+
+    let running = RUNNING.lock().unwrap().clone();
+    let mut text = vec![];
+    let mut html = vec![];
+
+    for (bot, commands) in ALL {
+        if !running.iter().any(|r| r == *bot)
+            || !matrix::active_in(&client, bot, joined.room_id()).await
+        {
+            continue;
+        }
+
+        let (bot_text, bot_html) = help(commands);
+        text.push(format!("{}bot:\n{}", bot, bot_text));
+        html.push(format!("<p><strong>{}bot</strong></p>\n{}", bot, bot_html));
+    }
+
+    let (text, html) = if text.is_empty() {
+        let none = "There are no bots active in this room.".to_string();
+        (none.clone(), none)
+    } else {
+        (text.join("\n\n"), html.join("\n"))
+    };
+
+    if let Err(e) = matrix::send(&joined, matrix::text_html(&text, &html)).await {
+        println!("Could not send help! {}", e);
+    }
+}
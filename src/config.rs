@@ -0,0 +1,242 @@
+use std::env;
+
+use anyhow::{anyhow, bail};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+// the bots there are, for anything that names them
+pub const BOTS: &[&str] = &["home", "money", "owen", "ai", "photo"];
+
+// what a JSON setting is allowed to look like
+pub enum Schema {
+    // a string, which has to be one of these, if there are any
+    Text(&'static [&'static str]),
+    List(&'static Schema),
+    // a map with keys of our choosing (like room IDs), and values that all look alike
+    Map(&'static Schema),
+    // a map with only these keys, none of which have to be there
+    Object(&'static [(&'static str, Schema)]),
+    // anything at all, passed along as is
+    Any,
+}
+
+// Every setting that's JSON, and what it has to look like. Serde would happily skip a misspelled
+// key (and leave whatever it was for to its default), so they're all checked against these first.
+const SETTINGS: &[(&str, Schema)] = &[
+    ("BRIDGED_USERS", Schema::Map(&Schema::Text(&[]))),
+    ("COMMAND_PREFIXES", Schema::Map(&Schema::Text(&[]))),
+    (
+        "I18N_MESSAGES",
+        Schema::Map(&Schema::Map(&Schema::Text(&[]))),
+    ),
+    ("JOIN_ROOMS", Schema::List(&Schema::Text(&[]))),
+    (
+        "PHOTO_ROOMS",
+        Schema::Map(&Schema::Object(&[
+            ("recipients", Schema::List(&Schema::Text(&[]))),
+            ("album", Schema::Text(&[])),
+        ])),
+    ),
+    ("PHOTO_SENDERS", Schema::List(&Schema::Text(&[]))),
+    ("ROOM_BOTS", Schema::Map(&Schema::List(&Schema::Text(BOTS)))),
+    ("ROUTINES", Schema::Map(&Schema::Text(&[]))),
+    ("SMTP_TO", Schema::Map(&Schema::List(&Schema::Text(&[])))),
+    ("SPACES", Schema::Map(&Schema::List(&Schema::Text(&[])))),
+    ("STATEMENTS", Schema::Map(&Schema::Text(&[]))),
+    (
+        "STICKERS",
+        Schema::Map(&Schema::Object(&[
+            ("url", Schema::Text(&[])),
+            ("info", Schema::Any),
+        ])),
+    ),
+    ("WEBHOOKS", Schema::Map(&Schema::Text(&[]))),
+    ("YNAB_ACCOUNTS", Schema::Map(&Schema::Text(&[]))),
+];
+
+// checks every JSON setting that's set, so a typo stops us at startup rather than whenever the
+// setting is first needed
+pub fn check_all() -> anyhow::Result<()> {
+    for (var, _) in SETTINGS {
+        parse::<Value>(var)?;
+    }
+
+    Ok(())
+}
+
+// A JSON setting, or its default if it isn't set. Anything that doesn't fit stops the bot, with
+// where it went wrong.
+pub fn load<T: DeserializeOwned + Default>(var: &str) -> T {
+    match parse(var) {
+        Ok(value) => value,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+pub fn parse<T: DeserializeOwned + Default>(var: &str) -> anyhow::Result<T> {
+    match env::var(var) {
+        Ok(json) => from_json(var, &json),
+        Err(_) => Ok(T::default()),
+    }
+}
+
+fn from_json<T: DeserializeOwned>(var: &str, json: &str) -> anyhow::Result<T> {
+    let schema = match SETTINGS.iter().find(|(name, _)| *name == var) {
+        Some((_, schema)) => schema,
+        None => bail!("{} isn't a JSON setting", var),
+    };
+
+    let value: Value =
+        serde_json::from_str(json).map_err(|e| anyhow!("{} is not valid JSON: {}", var, e))?;
+
+    check(&value, schema, var)?;
+
+    serde_json::from_value(value).map_err(|e| anyhow!("{} is not valid: {}", var, e))
+}
+
+// walks the value alongside its schema, naming the first thing that doesn't fit by its path
+fn check(value: &Value, schema: &Schema, path: &str) -> anyhow::Result<()> {
+    match (schema, value) {
+        (Schema::Any, _) => {}
+        (Schema::Text(allowed), Value::String(text)) => {
+            if !allowed.is_empty() && !allowed.contains(&text.as_str()) {
+                bail!(
+                    "{} can't be \"{}\"{}. It can be {}.",
+                    path,
+                    text,
+                    suggestion(text, allowed),
+                    allowed.join(", ")
+                );
+            }
+        }
+        (Schema::List(item), Value::Array(items)) => {
+            for (i, value) in items.iter().enumerate() {
+                check(value, item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        (Schema::Map(item), Value::Object(map)) => {
+            for (key, value) in map {
+                check(value, item, &format!("{}[\"{}\"]", path, key))?;
+            }
+        }
+        (Schema::Object(fields), Value::Object(map)) => {
+            let names: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+
+            for (key, value) in map {
+                match fields.iter().find(|(name, _)| name == key) {
+                    Some((_, schema)) => check(value, schema, &format!("{}.{}", path, key))?,
+                    None => bail!(
+                        "{}.{} isn't a setting{}. It can have {}.",
+                        path,
+                        key,
+                        suggestion(key, &names),
+                        names.join(", ")
+                    ),
+                }
+            }
+        }
+        (schema, value) => bail!(
+            "{} should be {}, not {}.",
+            path,
+            expected(schema),
+            kind(value)
+        ),
+    }
+
+    Ok(())
+}
+
+fn expected(schema: &Schema) -> &'static str {
+    match schema {
+        Schema::Text(_) => "a string",
+        Schema::List(_) => "a list",
+        Schema::Map(_) | Schema::Object(_) => "a map",
+        Schema::Any => "anything",
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "true or false",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "a map",
+    }
+}
+
+// the closest of what's allowed, if it's close enough to have been a typo
+fn suggestion(word: &str, allowed: &[&str]) -> String {
+    allowed
+        .iter()
+        .map(|candidate| (distance(word, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, candidate)| format!(" (did you mean \"{}\"?)", candidate))
+        .unwrap_or_default()
+}
+
+// how many letters have to change to get from one word to the other
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn points_at_typos() {
+        let error = from_json::<Value>(
+            "PHOTO_ROOMS",
+            r#"{"!abc:kulak.us": {"recipents": ["grandma"]}}"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "PHOTO_ROOMS[\"!abc:kulak.us\"].recipents isn't a setting (did you mean \
+            \"recipients\"?). It can have recipients, album."
+        );
+
+        let error = from_json::<Value>("ROOM_BOTS", r#"{"!abc:kulak.us": ["mony"]}"#).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "ROOM_BOTS[\"!abc:kulak.us\"][0] can't be \"mony\" (did you mean \"money\"?). It can \
+            be home, money, owen, ai, photo."
+        );
+
+        let error = from_json::<Value>("SMTP_TO", r#"{"grandma": "g@example.com"}"#).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "SMTP_TO[\"grandma\"] should be a list, not a string."
+        );
+    }
+
+    #[test]
+    fn reads_what_fits() {
+        let rooms: HashMap<String, Vec<String>> =
+            from_json("ROOM_BOTS", r#"{"!abc:kulak.us": ["money", "photo"]}"#).unwrap();
+
+        assert_eq!(rooms["!abc:kulak.us"], vec!["money", "photo"]);
+    }
+}
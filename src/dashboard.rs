@@ -0,0 +1,538 @@
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use axum::extract::{Form, Path};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use chrono::{DateTime, Utc};
+use matrix_sdk::ruma::UserId;
+use once_cell::sync::OnceCell;
+use rusqlite::params;
+use rusty_money::{iso, Money};
+use serde::Deserialize;
+
+use crate::bots::{money, photo};
+use crate::cache;
+use crate::login::Login;
+use crate::matrix::escape_html;
+use crate::scheduler;
+use crate::storage;
+use crate::webhook;
+
+// how many rows the ledger and the list of errors show
+const LEDGER_ROWS: usize = 25;
+const ERROR_ROWS: usize = 20;
+
+// what the session is kept in, once someone's logged in
+const COOKIE: &str = "dashboard_session";
+
+static LOGIN: OnceCell<Login> = OnceCell::new();
+
+// how each bot in this process is doing: when it started, or when and why it stopped
+static STATUS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+pub fn started(bot: &str) {
+    set_status(bot, format!("running since {}", pretty_date(&Utc::now())));
+}
+
+pub fn stopped(bot: &str, reason: &str) {
+    set_status(
+        bot,
+        format!("stopped {}: {}", pretty_date(&Utc::now()), reason),
+    );
+}
+
+fn set_status(bot: &str, status: String) {
+    let mut all = STATUS.lock().unwrap();
+
+    match all.iter_mut().find(|(name, _)| name == bot) {
+        Some((_, current)) => *current = status,
+        None => all.push((bot.to_string(), status)),
+    }
+}
+
+// Serves a little admin page on DASHBOARD_ADDR, if it's set, for anyone with DASHBOARD_TOKEN. It
+// shows what the bots are up to, and has buttons for the things that would otherwise take a chat
+// command. Everything it needs is checked before it's started, so a bad setting stops us right
+// away rather than on the first visit.
+pub fn serve() -> anyhow::Result<()> {
+    let addr: SocketAddr = match env::var("DASHBOARD_ADDR") {
+        Ok(addr) => addr
+            .parse()
+            .map_err(|e| anyhow!("invalid DASHBOARD_ADDR: {}", e))?,
+        Err(_) => return Ok(()),
+    };
+
+    let token = env::var("DASHBOARD_TOKEN")
+        .map_err(|_| anyhow!("DASHBOARD_TOKEN environmental variable not set"))?;
+
+    LOGIN.get_or_init(|| Login::new(COOKIE, token));
+
+    let server = axum::Server::try_bind(&addr)?;
+
+    let app = Router::new()
+        .route("/", get(on_index))
+        .route("/login", post(on_login))
+        .route("/recipients/resume", post(on_resume_recipient))
+        .route("/outbox/retry", post(on_retry_email))
+        .route("/allowance/resume", post(on_resume_allowance))
+        .route("/media/:hash", get(on_media));
+
+    println!("dashboard listening on {}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            println!("Could not run dashboard! {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    LOGIN
+        .get()
+        .map(|login| login.authorized(headers))
+        .unwrap_or(false)
+}
+
+async fn on_index(headers: HeaderMap) -> Html<String> {
+    if !authorized(&headers) {
+        return Html(page(
+            "<form method=\"post\" action=\"/login\">\
+            <input type=\"password\" name=\"token\" placeholder=\"Token\" autofocus> \
+            <button>Log in</button></form>",
+        ));
+    }
+
+    let sections = [
+        section("Bots", bots()),
+        section("Problems", problems()),
+        section("Coming up", coming_up()),
+        section("Ledger", ledger()),
+        section("Photo routing", photo_routing()),
+    ];
+
+    Html(page(&sections.join("\n")))
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    token: String,
+}
+
+async fn on_login(Form(form): Form<LoginForm>) -> Response {
+    match LOGIN.get() {
+        Some(login) => login.log_in(&form.token, "That's not the token."),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// anything in the media cache, for whatever's been handed a link to it (like the video player
+// owen uses), as long as it's logged in
+async fn on_media(headers: HeaderMap, Path(hash): Path<String>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let data = match cache::file(&hash) {
+        Some(file) => tokio::fs::read(file).await.ok(),
+        None => None,
+    };
+
+    match data {
+        Some(data) => ([(header::CONTENT_TYPE, content_type(&data))], data).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// the cache doesn't keep types, but what it has is easy enough to tell apart
+fn content_type(data: &[u8]) -> &'static str {
+    match data {
+        [0xFF, 0xD8, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Deserialize)]
+struct Email {
+    email: String,
+}
+
+async fn on_resume_recipient(headers: HeaderMap, Form(form): Form<Email>) -> Response {
+    action(&headers, || {
+        let conn = storage::open("photobot")?;
+
+        conn.execute(
+            "UPDATE recipients SET paused = 0, failures = 0, last_error = NULL WHERE email = ?1",
+            params![form.email],
+        )?;
+
+        Ok(())
+    })
+}
+
+#[derive(Deserialize)]
+struct Id {
+    id: i64,
+}
+
+// the outbox is checked every minute, so this is as good as sending it now
+async fn on_retry_email(headers: HeaderMap, Form(form): Form<Id>) -> Response {
+    action(&headers, || {
+        let conn = storage::open("photobot")?;
+
+        conn.execute(
+            "UPDATE outbox SET next_attempt = ?1 WHERE id = ?2",
+            params![Utc::now().to_rfc3339(), form.id],
+        )?;
+
+        Ok(())
+    })
+}
+
+async fn on_resume_allowance(headers: HeaderMap, Form(form): Form<Id>) -> Response {
+    action(&headers, || {
+        let conn = storage::open("moneybot")?;
+        conn.execute(
+            "DELETE FROM allowance_skips WHERE id = ?1",
+            params![form.id],
+        )?;
+
+        Ok(())
+    })
+}
+
+// runs a button's change, then goes back to the page to show how it went
+fn action<F>(headers: &HeaderMap, f: F) -> Response
+where
+    F: FnOnce() -> anyhow::Result<()>,
+{
+    if !authorized(headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match f() {
+        Ok(()) => Redirect::to("/").into_response(),
+        Err(e) => {
+            println!("Could not run dashboard action! {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+fn bots() -> anyhow::Result<String> {
+    let status = STATUS.lock().unwrap();
+
+    if status.is_empty() {
+        return Ok("<p>No bots are running here.</p>".to_string());
+    }
+
+    Ok(list(status.iter().map(|(bot, status)| {
+        format!("<strong>{}bot</strong>: {}", bot, escape_html(status))
+    })))
+}
+
+// webhooks that didn't go through, and emails that haven't yet
+fn problems() -> anyhow::Result<String> {
+    let mut items: Vec<String> = webhook::history(ERROR_ROWS)?
+        .into_iter()
+        .filter(|call| call.status != "ok")
+        .map(|call| {
+            format!(
+                "{}: the {} webhook {}",
+                pretty_rfc3339(&call.date),
+                escape_html(&call.name),
+                call.status
+            )
+        })
+        .collect();
+
+    let conn = storage::open("photobot")?;
+
+    let mut stmt = conn.prepare(
+        "SELECT id, address, attempts, next_attempt FROM outbox ORDER BY next_attempt LIMIT ?1",
+    )?;
+
+    let retries = stmt
+        .query_map(params![ERROR_ROWS], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (id, address, attempts, next_attempt) in retries {
+        items.push(format!(
+            "An email to {} has failed {} times; trying again {}. {}",
+            escape_html(&address),
+            attempts,
+            pretty_rfc3339(&next_attempt),
+            button("/outbox/retry", "id", &id.to_string(), "Try now")
+        ));
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT name, email, last_error FROM recipients WHERE paused = 1")?;
+
+    let paused = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (name, email, error) in paused {
+        items.push(format!(
+            "Photos to {} ({}) are paused: {}. {}",
+            escape_html(&name),
+            escape_html(&email),
+            escape_html(error.as_deref().unwrap_or("too many failures")),
+            button("/recipients/resume", "email", &email, "Resume")
+        ));
+    }
+
+    if items.is_empty() {
+        return Ok("<p>Nothing's wrong.</p>".to_string());
+    }
+
+    Ok(list(items.into_iter()))
+}
+
+// allowances on hold, and routines on a schedule
+fn coming_up() -> anyhow::Result<String> {
+    let mut items = vec![];
+    let conn = storage::open("moneybot")?;
+
+    let today = Utc::now()
+        .with_timezone(&scheduler::timezone())
+        .format("%Y-%m-%d")
+        .to_string();
+
+    let mut stmt = conn.prepare(
+        "SELECT id, user_id, until, next_only FROM allowance_skips WHERE until > ?1 ORDER BY until",
+    )?;
+
+    let skips = stmt
+        .query_map(params![today], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (id, user_id, until, next_only) in skips {
+        let what = if next_only {
+            "skips the next allowance"
+        } else {
+            "has allowance paused"
+        };
+
+        items.push(format!(
+            "{} {} (until {}). {}",
+            account(&user_id),
+            what,
+            until,
+            button("/allowance/resume", "id", &id.to_string(), "Resume")
+        ));
+    }
+
+    let conn = storage::open("homebot")?;
+
+    let mut stmt = conn
+        .prepare("SELECT name, schedule FROM routines WHERE schedule IS NOT NULL ORDER BY name")?;
+
+    let routines = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (name, schedule) in routines {
+        items.push(format!(
+            "The {} routine runs {}.",
+            escape_html(&name),
+            escape_html(&schedule)
+        ));
+    }
+
+    if items.is_empty() {
+        return Ok("<p>Nothing's scheduled.</p>".to_string());
+    }
+
+    Ok(list(items.into_iter()))
+}
+
+fn ledger() -> anyhow::Result<String> {
+    let conn = storage::open("moneybot")?;
+
+    let mut stmt = conn.prepare(
+        "SELECT date, sender, receiver, amount, memo FROM transactions ORDER BY id DESC LIMIT ?1",
+    )?;
+
+    let rows = stmt
+        .query_map(params![LEDGER_ROWS], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut html = vec![
+        "<table><tr><th>When</th><th>From</th><th>To</th><th>Amount</th>\
+        <th>Memo</th></tr>"
+            .to_string(),
+    ];
+
+    for (date, sender, receiver, amount, memo) in rows {
+        html.push(format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            pretty_rfc3339(&date),
+            sender.as_deref().map(account).unwrap_or_default(),
+            account(&receiver),
+            Money::from_minor(amount, iso::USD),
+            escape_html(memo.as_deref().unwrap_or_default())
+        ));
+    }
+
+    html.push("</table>".to_string());
+
+    Ok(html.join("\n"))
+}
+
+// who each room sends to, for good and for now, and everyone who can get photos at all
+fn photo_routing() -> anyhow::Result<String> {
+    let mut items: Vec<String> = photo::room_routes()
+        .into_iter()
+        .map(|(room_id, route)| format!("{}: {}", escape_html(&room_id), escape_html(&route)))
+        .collect();
+
+    let conn = storage::open("photobot")?;
+    let mut stmt = conn.prepare("SELECT room_id, names, expires_at FROM filters")?;
+
+    let filters = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (room_id, names, expires_at) in filters {
+        let names: Vec<String> = serde_json::from_str(&names)?;
+        let until = expires_at
+            .map(|e| format!(" until {}", pretty_rfc3339(&e)))
+            .unwrap_or_default();
+
+        items.push(format!(
+            "{}: only to {}{}",
+            escape_html(&room_id),
+            escape_html(&names.join(", ")),
+            until
+        ));
+    }
+
+    let mut stmt = conn.prepare("SELECT name, email, paused FROM recipients ORDER BY name")?;
+
+    let recipients = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, bool>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (name, email, paused) in recipients {
+        let paused = if paused { " (paused)" } else { "" };
+
+        items.push(format!(
+            "{} gets photos at {}{}",
+            escape_html(&name),
+            escape_html(&email),
+            paused
+        ));
+    }
+
+    Ok(list(items.into_iter()))
+}
+
+// A section of the page, or what went wrong building it. Any bot might not have run here yet, so
+// one can't take down the rest.
+fn section(title: &str, html: anyhow::Result<String>) -> String {
+    let html =
+        html.unwrap_or_else(|e| format!("<p>Not available: {}</p>", escape_html(&e.to_string())));
+    format!("<section><h2>{}</h2>\n{}</section>", title, html)
+}
+
+fn page(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>Bots</title><style>\
+        body {{ font-family: sans-serif; max-width: 50em; margin: auto; padding: 1em; }}\
+        table {{ border-collapse: collapse; width: 100%; }}\
+        td, th {{ border-bottom: 1px solid #ddd; padding: 0.25em; text-align: left; }}\
+        form {{ display: inline; }}\
+        </style></head><body><h1>Bots</h1>\n{}</body></html>",
+        body
+    )
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    let items: Vec<String> = items.map(|item| format!("<li>{}</li>", item)).collect();
+    format!("<ul>\n{}\n</ul>", items.join("\n"))
+}
+
+fn button(action: &str, name: &str, value: &str, label: &str) -> String {
+    format!(
+        "<form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"{}\" value=\"{}\">\
+        <button>{}</button></form>",
+        escape_html(action),
+        escape_html(name),
+        escape_html(value),
+        escape_html(label)
+    )
+}
+
+fn account(user_id: &str) -> String {
+    match UserId::try_from(user_id) {
+        Ok(user_id) => escape_html(&money::pretty_account(&user_id)),
+        Err(_) => escape_html(user_id),
+    }
+}
+
+fn pretty_date(date: &DateTime<Utc>) -> String {
+    date.with_timezone(&scheduler::timezone())
+        .format("%b %-d %-I:%M%P")
+        .to_string()
+}
+
+fn pretty_rfc3339(date: &str) -> String {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => pretty_date(&date.with_timezone(&Utc)),
+        Err(_) => escape_html(date),
+    }
+}
@@ -0,0 +1,326 @@
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use axum::extract::{Form, Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::archive;
+use crate::cache;
+use crate::image;
+use crate::login::Login;
+use crate::matrix::escape_html;
+
+// how many thumbnails are on a page
+const PAGE_SIZE: usize = 60;
+
+// what the session is kept in, once someone's logged in
+const COOKIE: &str = "gallery_session";
+
+// the longest side of a photo opened from the gallery
+const SCREEN_SIZE: u32 = 2048;
+
+static LOGIN: OnceCell<Login> = OnceCell::new();
+
+// Serves the photos archived to the DROPBOX directory as a gallery on GALLERY_ADDR, if it's set,
+// for anyone with GALLERY_PASSWORD, so the family can look through them without a Dropbox account.
+// It only knows the local archive; photos archived anywhere else already have somewhere to be
+// looked at. Like the dashboard, everything it needs is checked before it's started.
+pub fn serve() -> anyhow::Result<()> {
+    let addr: SocketAddr = match env::var("GALLERY_ADDR") {
+        Ok(addr) => addr
+            .parse()
+            .map_err(|e| anyhow!("invalid GALLERY_ADDR: {}", e))?,
+        Err(_) => return Ok(()),
+    };
+
+    let password = env::var("GALLERY_PASSWORD")
+        .map_err(|_| anyhow!("GALLERY_PASSWORD environmental variable not set"))?;
+
+    if env::var("DROPBOX").is_err() {
+        bail!("DROPBOX environmental variable not set");
+    }
+
+    LOGIN.get_or_init(|| Login::new(COOKIE, password));
+
+    let server = axum::Server::try_bind(&addr)?;
+
+    let app = Router::new()
+        .route("/", get(on_index))
+        .route("/login", post(on_login))
+        .route("/thumb/:name", get(on_thumbnail))
+        .route("/photo/:name", get(on_photo))
+        .route("/original/:name", get(on_original));
+
+    println!("gallery listening on {}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            println!("Could not run gallery! {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn dir() -> PathBuf {
+    PathBuf::from(env::var("DROPBOX").expect("DROPBOX environmental variable not set"))
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    LOGIN
+        .get()
+        .map(|login| login.authorized(headers))
+        .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+struct Page {
+    page: Option<usize>,
+}
+
+async fn on_index(headers: HeaderMap, Query(page): Query<Page>) -> Html<String> {
+    if !authorized(&headers) {
+        return Html(page_html(
+            "<form method=\"post\" action=\"/login\">\
+            <input type=\"password\" name=\"password\" placeholder=\"Password\" autofocus> \
+            <button>Log in</button></form>",
+        ));
+    }
+
+    let names = match photos() {
+        Ok(names) => names,
+        Err(e) => {
+            return Html(page_html(&format!(
+                "<p>Not available: {}</p>",
+                escape_html(&e.to_string())
+            )))
+        }
+    };
+
+    let page = page.page.unwrap_or(0);
+    let pages = names.len().div_ceil(PAGE_SIZE);
+
+    let tiles: Vec<String> = names
+        .iter()
+        .skip(page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|name| {
+            let path = encode(name);
+            let label = escape_html(name);
+            match viewable(name) {
+                true => format!(
+                    "<a href=\"/photo/{}\"><img src=\"/thumb/{}\" loading=\"lazy\" alt=\"{}\"></a>",
+                    path, path, label
+                ),
+                false => format!(
+                    "<a href=\"/original/{}\" class=\"file\">{}</a>",
+                    path, label
+                ),
+            }
+        })
+        .collect();
+
+    let mut nav = vec![];
+
+    if page > 0 {
+        nav.push(format!("<a href=\"/?page={}\">Newer</a>", page - 1));
+    }
+
+    if page + 1 < pages {
+        nav.push(format!("<a href=\"/?page={}\">Older</a>", page + 1));
+    }
+
+    Html(page_html(&format!(
+        "<div class=\"grid\">\n{}\n</div>\n<nav>{}</nav>",
+        tiles.join("\n"),
+        nav.join(" ")
+    )))
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    password: String,
+}
+
+async fn on_login(Form(form): Form<LoginForm>) -> Response {
+    match LOGIN.get() {
+        Some(login) => login.log_in(&form.password, "That's not the password."),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Thumbnails are made the first time they're asked for, then kept in the media cache. The file's
+// size and modified time are part of the key, so a photo that's replaced gets a new one.
+async fn on_thumbnail(headers: HeaderMap, Path(name): Path<String>) -> Response {
+    let path = match file(&headers, &name) {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+
+    let key = match fs::metadata(&path) {
+        Ok(meta) => format!("gallery:{}:{}:{:?}", name, meta.len(), meta.modified().ok()),
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let thumbnail = cache::get(&key, async {
+        let data = Bytes::from(tokio::fs::read(&path).await?);
+        tokio::task::spawn_blocking(move || {
+            let jpeg = render(&data, &name, image::Output::default())?;
+            Ok(image::thumbnail(&jpeg)?.0)
+        })
+        .await?
+    })
+    .await;
+
+    jpeg_response(thumbnail)
+}
+
+// a photo as big as a screen needs, and as something a browser can show
+async fn on_photo(headers: HeaderMap, Path(name): Path<String>) -> Response {
+    let path = match file(&headers, &name) {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+
+    let photo = async {
+        let data = Bytes::from(tokio::fs::read(&path).await?);
+        tokio::task::spawn_blocking(move || {
+            let output = image::Output {
+                max_size: Some((SCREEN_SIZE, SCREEN_SIZE)),
+                quality: None,
+            };
+
+            render(&data, &name, output)
+        })
+        .await?
+    };
+
+    jpeg_response(photo.await)
+}
+
+async fn on_original(headers: HeaderMap, Path(name): Path<String>) -> Response {
+    let path = match file(&headers, &name) {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+
+    match tokio::fs::read(path).await {
+        Ok(data) => (
+            [(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name.replace('"', "")),
+            )],
+            data,
+        )
+            .into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// the path to an archived file, as long as whoever's asking is logged in, and the name is only a
+// name (and not a way out of the directory)
+fn file(headers: &HeaderMap, name: &str) -> Result<PathBuf, StatusCode> {
+    if !authorized(headers) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if !plain_name(name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let path = dir().join(name);
+
+    match path.is_file() {
+        true => Ok(path),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+fn plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+// everything in the archive, newest first
+fn photos() -> anyhow::Result<Vec<String>> {
+    Ok(archive::local_files()?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str().map(|name| name.to_string()))
+        .filter(|name| plain_name(name))
+        .collect())
+}
+
+// a file name as it goes in a link, since phones name things with spaces and worse
+fn encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// whether there's a picture to be made of a file; videos and the like are only downloaded
+fn viewable(name: &str) -> bool {
+    image::file_mime_type(name).is_some()
+}
+
+fn render(data: &Bytes, name: &str, output: image::Output) -> anyhow::Result<Bytes> {
+    let mime_type = image::file_mime_type(name).unwrap_or("image/jpeg");
+    image::render(data, mime_type, false, output)
+}
+
+fn jpeg_response(jpeg: anyhow::Result<Bytes>) -> Response {
+    match jpeg {
+        Ok(jpeg) => ([(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response(),
+        Err(e) => {
+            println!("Could not render a photo for the gallery: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn page_html(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>Photos</title><style>\
+        body {{ font-family: sans-serif; margin: auto; padding: 1em; }}\
+        .grid {{ display: flex; flex-wrap: wrap; gap: 0.5em; }}\
+        .grid img {{ height: 160px; }}\
+        .file {{ display: flex; align-items: center; height: 160px; padding: 0 1em; \
+        background: #eee; }}\
+        nav {{ margin-top: 1em; }}\
+        </style></head><body><h1>Photos</h1>\n{}</body></html>",
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_takes_plain_names() {
+        assert!(plain_name("1700000000-1-IMG_0001.jpg"));
+        assert!(!plain_name("../secrets"));
+        assert!(!plain_name("a/b.jpg"));
+        assert!(!plain_name(".hidden"));
+        assert!(!plain_name(""));
+    }
+
+    #[test]
+    fn encodes_names() {
+        assert_eq!(encode("IMG 0001 #2.jpg"), "IMG%200001%20%232.jpg");
+    }
+}
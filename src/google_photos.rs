@@ -0,0 +1,294 @@
+use std::env;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use chrono::{Duration, Utc};
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::storage;
+
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const API_URL: &str = "https://photoslibrary.googleapis.com/v1";
+
+// how many times we'll pick an upload back up where it left off before giving up
+const UPLOAD_ATTEMPTS: usize = 5;
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: i64,
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Album {
+    id: String,
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Albums {
+    #[serde(default)]
+    albums: Vec<Album>,
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+}
+
+// Uploading is only turned on when GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET, and GOOGLE_ALBUM (the
+// title of the album to upload to) are set. GOOGLE_REFRESH_TOKEN seeds the token store the first
+// time; after that, whatever Google hands back is kept in the database.
+pub fn enabled() -> bool {
+    env::var("GOOGLE_CLIENT_ID").is_ok()
+        && env::var("GOOGLE_CLIENT_SECRET").is_ok()
+        && env::var("GOOGLE_ALBUM").is_ok()
+}
+
+// uploads a photo and adds it to the given album, or the configured one
+pub async fn upload(
+    photo: &Bytes,
+    mime_type: &str,
+    file_name: &str,
+    description: Option<&str>,
+    album: Option<&str>,
+) -> Result<()> {
+    let title = match album {
+        Some(album) => album.to_string(),
+        None => env::var("GOOGLE_ALBUM").expect("GOOGLE_ALBUM environmental variable not set"),
+    };
+
+    let token = access_token().await?;
+    let album_id = album_id(&token, &title).await?;
+    let upload_token = upload_bytes(&token, photo, mime_type).await?;
+
+    let body = json!({
+        "albumId": album_id,
+        "newMediaItems": [{
+            "description": description.unwrap_or_default(),
+            "simpleMediaItem": {
+                "uploadToken": upload_token,
+                "fileName": file_name,
+            }
+        }]
+    });
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/mediaItems:batchCreate", API_URL))
+        .bearer_auth(&token)
+        .json(&body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from Google Photos: {}",
+            response.status()
+        );
+    }
+
+    println!("uploaded {} to Google Photos", file_name);
+
+    Ok(())
+}
+
+fn init() -> Result<rusqlite::Connection> {
+    let conn = storage::open("photobot")?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS google (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(conn)
+}
+
+fn get(key: &str) -> Result<Option<String>> {
+    let conn = init()?;
+
+    Ok(conn
+        .query_row(
+            "SELECT value FROM google WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn set(key: &str, value: &str) -> Result<()> {
+    let conn = init()?;
+
+    conn.execute(
+        "INSERT INTO google (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value=?2",
+        params![key, value],
+    )?;
+
+    Ok(())
+}
+
+// a current access token, refreshing it if it's expired (or about to)
+async fn access_token() -> Result<String> {
+    if let (Some(token), Some(expires)) = (get("access_token")?, get("expires_at")?) {
+        if expires > (Utc::now() + Duration::minutes(1)).to_rfc3339() {
+            return Ok(token);
+        }
+    }
+
+    let refresh_token = match get("refresh_token")? {
+        Some(token) => token,
+        None => env::var("GOOGLE_REFRESH_TOKEN")
+            .expect("GOOGLE_REFRESH_TOKEN environmental variable not set"),
+    };
+
+    let client_id =
+        env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID environmental variable not set");
+    let client_secret = env::var("GOOGLE_CLIENT_SECRET")
+        .expect("GOOGLE_CLIENT_SECRET environmental variable not set");
+
+    let response = reqwest::Client::new()
+        .post(TOKEN_URL)
+        .form(&[
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("refresh_token", refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+        ])
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!("could not refresh Google token: {}", response.status());
+    }
+
+    let token: Token = response.json().await?;
+    let expires_at = Utc::now() + Duration::seconds(token.expires_in);
+
+    set("access_token", &token.access_token)?;
+    set("expires_at", &expires_at.to_rfc3339())?;
+    set(
+        "refresh_token",
+        token.refresh_token.as_deref().unwrap_or(&refresh_token),
+    )?;
+
+    Ok(token.access_token)
+}
+
+// finds the album with the given title, creating it if needed; apps can only add to albums they
+// created, so an album made by hand won't work
+async fn album_id(token: &str, title: &str) -> Result<String> {
+    let key = format!("album:{}", title);
+
+    if let Some(id) = get(&key)? {
+        return Ok(id);
+    }
+
+    let client = reqwest::Client::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut request = client
+            .get(format!("{}/albums", API_URL))
+            .bearer_auth(token)
+            .query(&[("excludeNonAppCreatedData", "true")]);
+
+        if let Some(page) = &page_token {
+            request = request.query(&[("pageToken", page)]);
+        }
+
+        let albums: Albums = request.send().await?.error_for_status()?.json().await?;
+
+        if let Some(album) = albums
+            .albums
+            .into_iter()
+            .find(|a| a.title.as_deref() == Some(title))
+        {
+            set(&key, &album.id)?;
+            return Ok(album.id);
+        }
+
+        match albums.next_page_token {
+            Some(next) => page_token = Some(next),
+            None => break,
+        }
+    }
+
+    let album: Album = client
+        .post(format!("{}/albums", API_URL))
+        .bearer_auth(token)
+        .json(&json!({ "album": { "title": title } }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    println!("created Google Photos album {}", title);
+    set(&key, &album.id)?;
+
+    Ok(album.id)
+}
+
+// a resumable upload, returning the upload token
+async fn upload_bytes(token: &str, photo: &Bytes, mime_type: &str) -> Result<String> {
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/uploads", API_URL))
+        .bearer_auth(token)
+        .header("Content-Length", "0")
+        .header("X-Goog-Upload-Command", "start")
+        .header("X-Goog-Upload-Content-Type", mime_type)
+        .header("X-Goog-Upload-Protocol", "resumable")
+        .header("X-Goog-Upload-Raw-Size", photo.len().to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let url = response
+        .headers()
+        .get("X-Goog-Upload-URL")
+        .and_then(|url| url.to_str().ok())
+        .ok_or_else(|| anyhow!("no upload URL from Google Photos"))?
+        .to_string();
+
+    let mut offset = 0;
+
+    for attempt in 1..=UPLOAD_ATTEMPTS {
+        let response = client
+            .post(&url)
+            .bearer_auth(token)
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .header("X-Goog-Upload-Offset", offset.to_string())
+            .body(photo.slice(offset..))
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => return Ok(response.text().await?),
+            Ok(response) => println!("upload attempt {} failed: {}", attempt, response.status()),
+            Err(e) => println!("upload attempt {} failed: {}", attempt, e),
+        }
+
+        // ask how much made it, and pick up from there
+        let status = client
+            .post(&url)
+            .bearer_auth(token)
+            .header("Content-Length", "0")
+            .header("X-Goog-Upload-Command", "query")
+            .send()
+            .await?;
+
+        offset = status
+            .headers()
+            .get("X-Goog-Upload-Size-Received")
+            .and_then(|size| size.to_str().ok())
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(0);
+    }
+
+    bail!("could not upload to Google Photos")
+}
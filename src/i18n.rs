@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+
+use crate::config;
+
+// Canned bot messages in the languages we know, keyed by the English. Anything missing just
+// stays in English. Words in braces, like {who}, are filled in after translating.
+const CATALOG: &[(&str, &[(&str, &str)])] = &[
+    (
+        "Let's see...",
+        &[
+            ("spanish", "A ver..."),
+            ("french", "Voyons voir..."),
+            ("german", "Mal sehen..."),
+            ("italian", "Vediamo..."),
+            ("portuguese", "Vamos ver..."),
+        ],
+    ),
+    (
+        "Oh no! I couldn't do it. :(",
+        &[
+            ("spanish", "¡Oh no! No pude hacerlo. :("),
+            ("french", "Oh non ! Je n'ai pas réussi. :("),
+            ("german", "Oh nein! Das hat nicht geklappt. :("),
+            ("italian", "Oh no! Non ci sono riuscito. :("),
+            ("portuguese", "Ah não! Não consegui. :("),
+        ],
+    ),
+    (
+        "I have no words. :(",
+        &[
+            ("spanish", "No tengo palabras. :("),
+            ("french", "Je n'ai pas de mots. :("),
+            ("german", "Mir fehlen die Worte. :("),
+            ("italian", "Non ho parole. :("),
+            ("portuguese", "Estou sem palavras. :("),
+        ],
+    ),
+    (
+        "You got it!",
+        &[
+            ("spanish", "¡Entendido!"),
+            ("french", "C'est noté !"),
+            ("german", "Alles klar!"),
+            ("italian", "Ricevuto!"),
+            ("portuguese", "Pode deixar!"),
+        ],
+    ),
+    (
+        "Okay, back to normal.",
+        &[
+            ("spanish", "Bueno, todo vuelve a la normalidad."),
+            ("french", "Bon, retour à la normale."),
+            ("german", "Okay, wieder alles beim Alten."),
+            ("italian", "Ok, si torna alla normalità."),
+            ("portuguese", "Certo, de volta ao normal."),
+        ],
+    ),
+    (
+        "Thinking... you're next.",
+        &[
+            ("spanish", "Pensando... eres el siguiente."),
+            ("french", "Je réfléchis... vous êtes le prochain."),
+            ("german", "Ich denke nach... du bist als Nächstes dran."),
+            ("italian", "Sto pensando... sei il prossimo."),
+            ("portuguese", "Pensando... você é o próximo."),
+        ],
+    ),
+    (
+        "Thinking... {count} ahead of you.",
+        &[
+            ("spanish", "Pensando... hay {count} antes que tú."),
+            ("french", "Je réfléchis... {count} avant vous."),
+            ("german", "Ich denke nach... {count} vor dir."),
+            ("italian", "Sto pensando... {count} prima di te."),
+            ("portuguese", "Pensando... {count} na sua frente."),
+        ],
+    ),
+    (
+        "Saved for the digest ({when}).",
+        &[
+            ("spanish", "Guardada para el resumen ({when})."),
+            ("french", "Gardée pour le résumé ({when})."),
+            ("german", "Für die Sammelmail aufgehoben ({when})."),
+            ("italian", "Salvata per il riepilogo ({when})."),
+            ("portuguese", "Guardada para o resumo ({when})."),
+        ],
+    ),
+    (
+        "The best of {month}.",
+        &[
+            ("spanish", "Lo mejor de {month}."),
+            ("french", "Le meilleur de {month}."),
+            ("german", "Das Beste aus {month}."),
+            ("italian", "Il meglio di {month}."),
+            ("portuguese", "O melhor de {month}."),
+        ],
+    ),
+    (
+        "Sent to {who} ({before} → {after}).",
+        &[
+            ("spanish", "Enviada a {who} ({before} → {after})."),
+            ("french", "Envoyée à {who} ({before} → {after})."),
+            ("german", "An {who} geschickt ({before} → {after})."),
+            ("italian", "Inviata a {who} ({before} → {after})."),
+            ("portuguese", "Enviada para {who} ({before} → {after})."),
+        ],
+    ),
+    (
+        "Photos will be sent to {who}.",
+        &[
+            ("spanish", "Las fotos se enviarán a {who}."),
+            ("french", "Les photos seront envoyées à {who}."),
+            ("german", "Fotos gehen an {who}."),
+            ("italian", "Le foto saranno inviate a {who}."),
+            ("portuguese", "As fotos serão enviadas para {who}."),
+        ],
+    ),
+    (
+        "Photos will be sent to {who} until {time}.",
+        &[
+            ("spanish", "Las fotos se enviarán a {who} hasta las {time}."),
+            (
+                "french",
+                "Les photos seront envoyées à {who} jusqu'à {time}.",
+            ),
+            ("german", "Fotos gehen bis {time} an {who}."),
+            (
+                "italian",
+                "Le foto saranno inviate a {who} fino alle {time}.",
+            ),
+            (
+                "portuguese",
+                "As fotos serão enviadas para {who} até {time}.",
+            ),
+        ],
+    ),
+    (
+        "the Google album only",
+        &[
+            ("spanish", "solo el álbum de Google"),
+            ("french", "l'album Google seulement"),
+            ("german", "nur das Google-Album"),
+            ("italian", "solo l'album di Google"),
+            ("portuguese", "só o álbum do Google"),
+        ],
+    ),
+    (
+        "{first} and {last}",
+        &[
+            ("spanish", "{first} y {last}"),
+            ("french", "{first} et {last}"),
+            ("german", "{first} und {last}"),
+            ("italian", "{first} e {last}"),
+            ("portuguese", "{first} e {last}"),
+        ],
+    ),
+    (
+        "I couldn't reach {who} yet, but I'll keep trying.",
+        &[
+            (
+                "spanish",
+                "Todavía no pude llegar a {who}, pero lo seguiré intentando.",
+            ),
+            (
+                "french",
+                "Je n'ai pas encore pu joindre {who}, mais je continue d'essayer.",
+            ),
+            (
+                "german",
+                "{who} war noch nicht erreichbar, aber ich versuche es weiter.",
+            ),
+            (
+                "italian",
+                "Non sono ancora riuscito a raggiungere {who}, ma continuo a provare.",
+            ),
+            (
+                "portuguese",
+                "Ainda não consegui chegar a {who}, mas vou continuar tentando.",
+            ),
+        ],
+    ),
+    (
+        "Okay, photo messages will be in English.",
+        &[
+            ("spanish", "Bueno, los mensajes de fotos estarán en inglés."),
+            ("french", "D'accord, les messages photo seront en anglais."),
+            ("german", "Okay, Foto-Nachrichten kommen auf Englisch."),
+            ("italian", "Ok, i messaggi delle foto saranno in inglese."),
+            (
+                "portuguese",
+                "Certo, as mensagens de fotos serão em inglês.",
+            ),
+        ],
+    ),
+    (
+        "Okay, photo messages will be in {language}.",
+        &[
+            (
+                "spanish",
+                "Bueno, los mensajes de fotos estarán en español.",
+            ),
+            ("french", "D'accord, les messages photo seront en français."),
+            ("german", "Okay, Foto-Nachrichten kommen auf Deutsch."),
+            ("italian", "Ok, i messaggi delle foto saranno in italiano."),
+            (
+                "portuguese",
+                "Certo, as mensagens de fotos serão em português.",
+            ),
+        ],
+    ),
+];
+
+// Any message can be reworded, or put into a language that isn't above, with I18N_MESSAGES: a JSON
+// map of the English to a map of language to wording, where "english" replaces the English itself.
+fn overrides() -> HashMap<String, HashMap<String, String>> {
+    config::load("I18N_MESSAGES")
+}
+
+// the message in the given language, if we have it
+pub fn translate(message: &str, language: Option<&str>) -> String {
+    let language = language
+        .map(|language| language.to_lowercase())
+        .unwrap_or_else(|| "english".to_string());
+
+    if let Some(wording) = overrides().get(message).and_then(|o| o.get(&language)) {
+        return wording.clone();
+    }
+
+    CATALOG
+        .iter()
+        .find(|(english, _)| *english == message)
+        .and_then(|(_, translations)| translations.iter().find(|(l, _)| *l == language))
+        .map(|(_, translated)| *translated)
+        .unwrap_or(message)
+        .to_string()
+}
+
+// translates a message, then fills in its {placeholders}
+pub fn format(message: &str, language: Option<&str>, args: &[(&str, &str)]) -> String {
+    let mut text = translate(message, language);
+
+    for (name, value) in args {
+        text = text.replace(&format!("{{{}}}", name), value);
+    }
+
+    text
+}
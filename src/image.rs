@@ -1,32 +1,291 @@
+use anyhow::bail;
 use bytes::Bytes;
-use exif::{In, Tag};
+use exif::{Context, Field, In, Tag};
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
 use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
-use image::{DynamicImage, ImageBuffer, Rgb};
+use image::{AnimationDecoder, DynamicImage, ImageBuffer, Rgb};
+use std::env;
 use std::io::Cursor;
 
-use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
+use libheif_rs::{ColorSpace, HeifContext, ImageHandle, ItemId, RgbChroma};
 
 extern crate image;
 
-pub fn convert_heic_to_jpeg(image: &Bytes) -> anyhow::Result<Bytes> {
+// whether photos get touched up before they're emailed, from PHOTO_ENHANCE
+pub fn enhance_enabled() -> bool {
+    env::var("PHOTO_ENHANCE")
+        .map(|enhance| enhance == "true")
+        .unwrap_or(false)
+}
+
+// how much metadata comes out of a photo before it's emailed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scrub {
+    Nothing,
+    Location,
+    All,
+}
+
+// from PHOTO_STRIP_METADATA ("none", "location", or "all"), taking out the location by default
+pub fn scrub() -> Scrub {
+    match env::var("PHOTO_STRIP_METADATA").as_deref() {
+        Ok("none") => Scrub::Nothing,
+        Ok("all") => Scrub::All,
+        Ok("location") | Err(_) => Scrub::Location,
+        Ok(other) => panic!(
+            "PHOTO_STRIP_METADATA is not none, location, or all: {}",
+            other
+        ),
+    }
+}
+
+// overrides for how a photo is shrunk, for anyone who wants something other than the defaults
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct Output {
+    pub max_size: Option<(u32, u32)>,
+    pub quality: Option<u8>,
+}
+
+// most mail servers won't take much more than this
+const DEFAULT_MAX_ANIMATED_SIZE: usize = 20 * 1024 * 1024;
+
+// the biggest animation we'll email as it is, from PHOTO_MAX_ANIMATED_SIZE (in bytes)
+pub fn max_animated_size() -> usize {
+    env::var("PHOTO_MAX_ANIMATED_SIZE")
+        .map(|size| {
+            size.parse()
+                .expect("PHOTO_MAX_ANIMATED_SIZE is not an integer")
+        })
+        .unwrap_or(DEFAULT_MAX_ANIMATED_SIZE)
+}
+
+// a JPEG of any kind of photo, or of the first frame of an animated one
+pub fn render(
+    photo: &Bytes,
+    mime_type: &str,
+    enhance: bool,
+    output: Output,
+) -> anyhow::Result<Bytes> {
+    match mime_type {
+        "image/heic" | "image/heif" => convert_heic_to_jpeg(photo, enhance, output),
+        raw if is_raw(raw) => convert_raw_to_jpeg(photo, enhance, output),
+        _ if animated(photo, mime_type) => first_frame(photo, mime_type, enhance, output),
+        _ => shrink_jpeg(photo, enhance, output),
+    }
+}
+
+// What kind of image a file is, going by its name, if it's one we can do anything with. Anything
+// else (like a video) is None.
+pub fn file_mime_type(file_name: &str) -> Option<&'static str> {
+    let extension = file_name
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "heic" => Some("image/heic"),
+        "heif" => Some("image/heif"),
+        _ => raw_mime_type(None, file_name),
+    }
+}
+
+// What kind of image something is, from the first few bytes of it, for clients that don't say.
+// HEIF files are MP4-style boxes, with a brand saying what's inside.
+pub fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => match brand.get(..4)? {
+            b"heic" | b"heix" | b"heim" | b"heis" => Some("image/heic"),
+            b"mif1" | b"msf1" | b"heif" => Some("image/heif"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// whether a GIF or WebP has more than one frame
+pub fn animated(image: &Bytes, mime_type: &str) -> bool {
+    match mime_type {
+        "image/gif" => GifDecoder::new(Cursor::new(image.as_ref()))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        // an extended WebP says so in its header, with a flag in the VP8X chunk
+        "image/webp" => image.len() > 20 && &image[12..16] == b"VP8X" && image[20] & 0x02 != 0,
+        _ => false,
+    }
+}
+
+// the first frame of an animated GIF or WebP, as a JPEG
+pub fn first_frame(
+    image: &Bytes,
+    mime_type: &str,
+    enhance: bool,
+    output: Output,
+) -> anyhow::Result<Bytes> {
+    let frame = match mime_type {
+        "image/gif" => GifDecoder::new(Cursor::new(image.as_ref()))?
+            .into_frames()
+            .next(),
+        "image/webp" => WebPDecoder::new(Cursor::new(image.as_ref()))?
+            .into_frames()
+            .next(),
+        _ => bail!("{} isn't animated", mime_type),
+    };
+
+    let frame = match frame {
+        Some(frame) => frame?,
+        None => bail!("there's nothing in that image"),
+    };
+
+    let rgb = DynamicImage::ImageRgba8(frame.into_buffer()).into_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    shrink_to_jpeg(&Bytes::from(rgb.into_raw()), width, height, enhance, output)
+}
+
+pub fn convert_heic_to_jpeg(image: &Bytes, enhance: bool, output: Output) -> anyhow::Result<Bytes> {
     println!("decoding HEIC");
 
     let ctx = HeifContext::read_from_bytes(image)?;
-    let handle = ctx.primary_image_handle()?;
+    let handle = main_image(&ctx)?;
     let decoded = handle.decode(ColorSpace::Rgb(RgbChroma::Rgb), false)?;
-    let data = Bytes::copy_from_slice(decoded.planes().interleaved.unwrap().data);
 
-    shrink_to_jpeg(&data, handle.width(), handle.height())
+    let plane = match decoded.planes().interleaved {
+        Some(plane) => plane,
+        None => bail!("HEIC decoded without RGB"),
+    };
+
+    // rows can be padded out past the pixels, and the padding has to go
+    let width = plane.width as usize * 3;
+    let data: Vec<u8> = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..width])
+        .copied()
+        .collect();
+
+    shrink_to_jpeg(
+        &Bytes::from(data),
+        plane.width,
+        plane.height,
+        enhance,
+        output,
+    )
+}
+
+// an image in a HEIC, as far as picking the photo out of it goes
+#[derive(Clone, Copy, Debug)]
+struct HeifItem {
+    id: ItemId,
+    pixels: u64,
+    // the depth map of another image, from a portrait-mode shot
+    depth: bool,
+}
+
+// Portrait-mode HEICs carry a depth map (and sometimes a matte) along with the photo. Those are
+// meant to hang off the primary image, but some files list them as images of their own, and
+// some even mark one as primary. This finds the photo, and says so when it isn't the primary.
+fn main_image(ctx: &HeifContext) -> anyhow::Result<ImageHandle<'_>> {
+    let mut ids = vec![0; ctx.number_of_top_level_images()];
+    let count = ctx.top_level_image_ids(&mut ids);
+    ids.truncate(count);
+
+    let mut handles = vec![];
+    let mut depth_ids = vec![];
+
+    for id in ids {
+        let handle = ctx.image_handle(id)?;
+
+        if handle.has_depth_image() {
+            let mut ids = vec![0; handle.number_of_depth_images().max(0) as usize];
+            let count = handle.depth_image_ids(&mut ids);
+            depth_ids.extend(&ids[..count]);
+        }
+
+        handles.push((id, handle));
+    }
+
+    let items: Vec<HeifItem> = handles
+        .iter()
+        .map(|(id, handle)| HeifItem {
+            id: *id,
+            pixels: handle.width() as u64 * handle.height() as u64,
+            depth: depth_ids.contains(id),
+        })
+        .collect();
+
+    let primary = handles
+        .iter()
+        .find(|(_, handle)| handle.is_primary())
+        .map(|(id, _)| *id);
+
+    let main = match main_item(primary, &items) {
+        Some(main) => main,
+        None => bail!("HEIC has nothing but depth maps in it"),
+    };
+
+    // every portrait photo comes with a depth map, so this is only news when we had to pick
+    if primary != Some(main) {
+        println!(
+            "HEIC has no primary photo, using image {} instead of {:?}",
+            main, primary
+        );
+    }
+
+    Ok(ctx.image_handle(main)?)
+}
+
+// the primary image, unless it's a depth map, in which case the biggest image that isn't
+fn main_item(primary: Option<ItemId>, items: &[HeifItem]) -> Option<ItemId> {
+    let photos = items.iter().filter(|item| !item.depth);
+
+    match primary {
+        Some(primary) if photos.clone().any(|item| item.id == primary) => Some(primary),
+        _ => photos.max_by_key(|item| item.pixels).map(|item| item.id),
+    }
+}
+
+pub fn shrink_jpeg(image: &Bytes, enhance: bool, output: Output) -> anyhow::Result<Bytes> {
+    shrink_oriented(image, image, enhance, output)
+}
+
+// the JPEG preview a camera tucks inside its RAW files, shrunk like any other photo
+pub fn convert_raw_to_jpeg(raw: &Bytes, enhance: bool, output: Output) -> anyhow::Result<Bytes> {
+    println!("extracting RAW preview");
+
+    let preview = match raw_preview(raw) {
+        Some(preview) => preview,
+        None => bail!("There's no preview in that RAW file."),
+    };
+
+    // the preview doesn't know which way the camera was held, but the RAW file does
+    shrink_oriented(&preview, raw, enhance, output)
 }
 
-pub fn shrink_jpeg(image: &Bytes) -> anyhow::Result<Bytes> {
+// Shrinks an image, turned the way the EXIF in `metadata` says. That's usually the image itself.
+fn shrink_oriented(
+    image: &Bytes,
+    metadata: &Bytes,
+    enhance: bool,
+    output: Output,
+) -> anyhow::Result<Bytes> {
     let mut decoded = ImageReader::new(Cursor::new(image.to_vec()))
         .with_guessed_format()?
         .decode()?;
 
     // rotate, if needed
-    if let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(image.to_vec())) {
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(metadata.to_vec())) {
         if let Some(orientation) = exif.get_field(Tag::Orientation, In::PRIMARY) {
             if let Some(o) = orientation.value.get_uint(0) {
                 println!("Orientation: {}", o);
@@ -65,29 +324,799 @@ pub fn shrink_jpeg(image: &Bytes) -> anyhow::Result<Bytes> {
     let width = decoded.width();
     let height = decoded.height();
 
-    shrink_to_jpeg(&Bytes::from(decoded.into_bytes()), width, height)
+    // anything with transparency (or more than 8 bits) has to come down to plain RGB first
+    shrink_to_jpeg(
+        &Bytes::from(decoded.into_rgb8().into_raw()),
+        width,
+        height,
+        enhance,
+        output,
+    )
+}
+
+// The RAW formats we can get a photo out of, by MIME type and extension. They're all TIFF
+// underneath, with a JPEG preview inside.
+const RAW_FORMATS: &[(&str, &str)] = &[
+    ("image/x-adobe-dng", "dng"),
+    ("image/x-canon-cr2", "cr2"),
+    ("image/x-nikon-nef", "nef"),
+];
+
+// The MIME type of a RAW file, going by the one it came with, or its name if that's no help
+// (they often come in as application/octet-stream).
+pub fn raw_mime_type(mime_type: Option<&str>, file_name: &str) -> Option<&'static str> {
+    let extension = file_name
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    RAW_FORMATS
+        .iter()
+        .find(|(raw, ext)| mime_type == Some(*raw) || extension == *ext)
+        .map(|(raw, _)| *raw)
+}
+
+pub fn is_raw(mime_type: &str) -> bool {
+    RAW_FORMATS.iter().any(|(raw, _)| *raw == mime_type)
+}
+
+// how many IFDs we'll look through before deciding a file is broken (or looping)
+const MAX_IFDS: usize = 32;
+
+// Walks the TIFF structure of a RAW file, and all the IFDs hanging off it, for the biggest JPEG
+// in there. That's the full size preview, when the camera made one.
+fn raw_preview(raw: &[u8]) -> Option<Bytes> {
+    let big_endian = match raw.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+
+    let read = |at: usize, size: usize| -> Option<u32> {
+        let bytes = raw.get(at..at.checked_add(size)?)?;
+
+        Some(bytes.iter().enumerate().fold(0u32, |value, (i, b)| {
+            if big_endian {
+                (value << 8) | *b as u32
+            } else {
+                value | (*b as u32) << (8 * i)
+            }
+        }))
+    };
+
+    let mut ifds = vec![read(4, 4)? as usize];
+    let mut seen = 0;
+    let mut best: Option<&[u8]> = None;
+
+    while let Some(ifd) = ifds.pop() {
+        seen += 1;
+
+        if ifd == 0 || seen > MAX_IFDS {
+            continue;
+        }
+
+        let count = read(ifd, 2)? as usize;
+        let mut tags: Vec<(u16, Vec<u32>)> = vec![];
+
+        for i in 0..count {
+            let entry = ifd + 2 + i * 12;
+            let tag = read(entry, 2)? as u16;
+            let kind = read(entry + 2, 2)?;
+            let values = read(entry + 4, 4)? as usize;
+
+            // only the SHORT and LONG values are anything we need
+            let size = match kind {
+                3 => 2,
+                4 | 13 => 4,
+                _ => continue,
+            };
+
+            let at = if size * values <= 4 {
+                entry + 8
+            } else {
+                read(entry + 8, 4)? as usize
+            };
+
+            let values = (0..values.min(MAX_IFDS))
+                .filter_map(|v| read(at + v * size, size))
+                .collect();
+
+            tags.push((tag, values));
+        }
+
+        let first = |tag: u16| {
+            tags.iter()
+                .find(|(t, _)| *t == tag)
+                .and_then(|(_, values)| values.first().copied())
+        };
+
+        // a JPEG can be in an IFD as a thumbnail, or as the image itself in one strip
+        let candidates = [
+            (first(0x0201), first(0x0202)),
+            (first(0x0111), first(0x0117)),
+        ];
+
+        for (offset, length) in candidates {
+            if let (Some(offset), Some(length)) = (offset, length) {
+                let jpeg = raw.get(offset as usize..offset as usize + length as usize);
+
+                if let Some(jpeg) = jpeg.filter(|jpeg| jpeg.starts_with(&[0xFF, 0xD8])) {
+                    if best.is_none_or(|best| jpeg.len() > best.len()) {
+                        best = Some(jpeg);
+                    }
+                }
+            }
+        }
+
+        // sub IFDs, then the next one in the chain
+        if let Some((_, subs)) = tags.iter().find(|(t, _)| *t == 0x014A) {
+            ifds.extend(subs.iter().map(|s| *s as usize));
+        }
+
+        if let Some(next) = read(ifd + 2 + count * 12, 4) {
+            ifds.push(next as usize);
+        }
+    }
+
+    best.map(Bytes::copy_from_slice)
+}
+
+// the longest side of a thumbnail
+const THUMBNAIL_SIZE: u32 = 320;
+
+// a small copy of an already rendered JPEG, along with its width and height
+pub fn thumbnail(jpeg: &Bytes) -> anyhow::Result<(Bytes, u32, u32)> {
+    let decoded = ImageReader::new(Cursor::new(jpeg.to_vec()))
+        .with_guessed_format()?
+        .decode()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .into_rgb8();
+
+    let (width, height) = decoded.dimensions();
+
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(width as usize, height as usize);
+    comp.set_quality(70.0);
+
+    let mut comp = comp.start_compress(Vec::new())?;
+    comp.write_scanlines(decoded.as_raw())?;
+
+    Ok((Bytes::from(comp.finish()?), width, height))
+}
+
+// the side of each square in a collage, and the white space around them
+const TILE_SIZE: u32 = 480;
+const TILE_GAP: u32 = 12;
+
+// a square from the middle of an already rendered JPEG, small enough to keep a month of around
+pub fn collage_tile(jpeg: &Bytes) -> anyhow::Result<Bytes> {
+    fill(jpeg, TILE_SIZE, TILE_SIZE)
+}
+
+// an image cropped to the shape of the given size, and scaled to fill it exactly
+pub fn fill(jpeg: &Bytes, width: u32, height: u32) -> anyhow::Result<Bytes> {
+    let filled = ImageReader::new(Cursor::new(jpeg.to_vec()))
+        .with_guessed_format()?
+        .decode()?
+        .resize_to_fill(width, height, FilterType::Lanczos3)
+        .into_rgb8();
+
+    encode_jpeg(&filled, 85.0)
+}
+
+// Lays tiles out in a grid as close to square as it gets, with any short last row centered,
+// returning the JPEG along with its width and height.
+pub fn collage(tiles: &[Bytes]) -> anyhow::Result<(Bytes, u32, u32)> {
+    if tiles.is_empty() {
+        bail!("nothing to make a collage of");
+    }
+
+    let count = tiles.len() as u32;
+    let columns = (count as f64).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+
+    let span = |tiles: u32| tiles * TILE_SIZE + (tiles + 1) * TILE_GAP;
+    let (width, height) = (span(columns), span(rows));
+    let mut canvas = ImageBuffer::from_pixel(width, height, Rgb([255u8, 255, 255]));
+
+    for (i, tile) in tiles.iter().enumerate() {
+        let (row, column) = (i as u32 / columns, i as u32 % columns);
+        let in_row = columns.min(count - row * columns);
+        let offset = (width - span(in_row)) / 2;
+
+        let tile = ImageReader::new(Cursor::new(tile.to_vec()))
+            .with_guessed_format()?
+            .decode()?
+            .resize_to_fill(TILE_SIZE, TILE_SIZE, FilterType::Lanczos3)
+            .into_rgb8();
+
+        image::imageops::overlay(
+            &mut canvas,
+            &tile,
+            (offset + TILE_GAP + column * (TILE_SIZE + TILE_GAP)) as i64,
+            (TILE_GAP + row * (TILE_SIZE + TILE_GAP)) as i64,
+        );
+    }
+
+    Ok((encode_jpeg(&canvas, 85.0)?, width, height))
+}
+
+// the size of a chart, and the space around its bars
+const CHART_WIDTH: u32 = 700;
+const CHART_HEIGHT: u32 = 400;
+const CHART_MARGIN: u32 = 20;
+
+// A bar for each value, red where it's past the limit, with a dashed line across at the limit.
+// There are no labels, so it goes along with something that has the numbers. Returns the JPEG
+// along with its width and height.
+pub fn bar_chart(values: &[f64], limit: Option<f64>) -> anyhow::Result<(Bytes, u32, u32)> {
+    if values.is_empty() {
+        bail!("nothing to chart");
+    }
+
+    let top = values
+        .iter()
+        .copied()
+        .chain(limit)
+        .fold(0.0, f64::max)
+        .max(f64::EPSILON);
+
+    let plot = (CHART_HEIGHT - 2 * CHART_MARGIN) as f64;
+    let bottom = CHART_HEIGHT - CHART_MARGIN;
+    let slot = (CHART_WIDTH - 2 * CHART_MARGIN) / values.len() as u32;
+    let bar = (slot * 3 / 4).max(1);
+    let y = |value: f64| bottom - (value.max(0.0) / top * plot).round() as u32;
+
+    let mut canvas = ImageBuffer::from_pixel(CHART_WIDTH, CHART_HEIGHT, Rgb([255u8, 255, 255]));
+
+    for (i, value) in values.iter().enumerate() {
+        let color = match limit {
+            Some(limit) if *value > limit => Rgb([210u8, 60, 50]),
+            _ => Rgb([70u8, 130, 180]),
+        };
+
+        let left = CHART_MARGIN + i as u32 * slot + (slot - bar) / 2;
+
+        for x in left..left + bar {
+            for y in y(*value)..bottom {
+                canvas.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    for x in CHART_MARGIN..CHART_WIDTH - CHART_MARGIN {
+        canvas.put_pixel(x, bottom, Rgb([150, 150, 150]));
+
+        if let Some(limit) = limit {
+            if (x / 8) % 2 == 0 {
+                canvas.put_pixel(x, y(limit), Rgb([40, 40, 40]));
+            }
+        }
+    }
+
+    Ok((encode_jpeg(&canvas, 90.0)?, CHART_WIDTH, CHART_HEIGHT))
+}
+
+// A 5x7 pixel font, just capitals, digits, and what money and names need, since there's nothing
+// else here to draw text with. Each row is five bits, left to right.
+const FONT: &[(char, [u8; 7])] = &[
+    (
+        'A',
+        [
+            0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'B',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110,
+        ],
+    ),
+    (
+        'C',
+        [
+            0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'D',
+        [
+            0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110,
+        ],
+    ),
+    (
+        'E',
+        [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        'F',
+        [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+    ),
+    (
+        'G',
+        [
+            0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111,
+        ],
+    ),
+    (
+        'H',
+        [
+            0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'I',
+        [
+            0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+    ),
+    (
+        'J',
+        [
+            0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100,
+        ],
+    ),
+    (
+        'K',
+        [
+            0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001,
+        ],
+    ),
+    (
+        'L',
+        [
+            0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        'M',
+        [
+            0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'N',
+        [
+            0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'O',
+        [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'P',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+    ),
+    (
+        'Q',
+        [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101,
+        ],
+    ),
+    (
+        'R',
+        [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001,
+        ],
+    ),
+    (
+        'S',
+        [
+            0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110,
+        ],
+    ),
+    (
+        'T',
+        [
+            0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+    ),
+    (
+        'U',
+        [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        'V',
+        [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+        ],
+    ),
+    (
+        'W',
+        [
+            0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010,
+        ],
+    ),
+    (
+        'X',
+        [
+            0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001,
+        ],
+    ),
+    (
+        'Y',
+        [
+            0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100,
+        ],
+    ),
+    (
+        'Z',
+        [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111,
+        ],
+    ),
+    (
+        '0',
+        [
+            0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '1',
+        [
+            0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+    ),
+    (
+        '2',
+        [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111,
+        ],
+    ),
+    (
+        '3',
+        [
+            0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '4',
+        [
+            0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010,
+        ],
+    ),
+    (
+        '5',
+        [
+            0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '6',
+        [
+            0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '7',
+        [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000,
+        ],
+    ),
+    (
+        '8',
+        [
+            0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110,
+        ],
+    ),
+    (
+        '9',
+        [
+            0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100,
+        ],
+    ),
+    (
+        '$',
+        [
+            0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100,
+        ],
+    ),
+    (
+        '.',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100,
+        ],
+    ),
+    (
+        ',',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000,
+        ],
+    ),
+    (
+        '-',
+        [
+            0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '\'',
+        [
+            0b00100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+    (
+        '!',
+        [
+            0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100,
+        ],
+    ),
+    (
+        ':',
+        [
+            0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000,
+        ],
+    ),
+    (
+        '/',
+        [
+            0b00001, 0b00010, 0b00010, 0b00100, 0b01000, 0b01000, 0b10000,
+        ],
+    ),
+    (
+        '%',
+        [
+            0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011,
+        ],
+    ),
+    (
+        '?',
+        [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100,
+        ],
+    ),
+    (
+        ' ',
+        [
+            0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000,
+        ],
+    ),
+];
+
+const GLYPH_WIDTH: u32 = 5;
+
+// how wide text is at a scale, with a pixel (scaled) between letters
+fn text_width(text: &str, scale: u32) -> u32 {
+    (text.chars().count() as u32 * (GLYPH_WIDTH + 1)).saturating_sub(1) * scale
+}
+
+fn glyph(c: char) -> Option<[u8; 7]> {
+    FONT.iter()
+        .find(|(glyph, _)| *glyph == c)
+        .map(|(_, rows)| *rows)
+}
+
+// the biggest scale (up to `max`) that fits the text in the width
+fn fit_scale(text: &str, width: u32, max: u32) -> u32 {
+    (1..=max)
+        .rev()
+        .find(|scale| text_width(text, *scale) <= width)
+        .unwrap_or(1)
+}
+
+// Draws text in capitals with its top left at x, y. Anything the font doesn't have comes out as a
+// question mark, and anything past the edge is cut off.
+fn draw_text(
+    canvas: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+    text: &str,
+    x: u32,
+    y: u32,
+    scale: u32,
+    color: Rgb<u8>,
+) {
+    for (i, c) in text.to_uppercase().chars().enumerate() {
+        let rows = glyph(c).or_else(|| glyph('?')).unwrap_or_default();
+
+        let left = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = left + column * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+
+                        if px < canvas.width() && py < canvas.height() {
+                            canvas.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fill_rect(
+    canvas: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    color: Rgb<u8>,
+) {
+    for py in y..(y + height).min(canvas.height()) {
+        for px in x..(x + width).min(canvas.width()) {
+            canvas.put_pixel(px, py, color);
+        }
+    }
+}
+
+// the size of a balance card, and the space around everything on it
+const CARD_WIDTH: u32 = 800;
+const CARD_HEIGHT: u32 = 260;
+const CARD_GOAL_HEIGHT: u32 = 420;
+const CARD_MARGIN: u32 = 40;
+
+// A goal on a balance card: what it's for (already written out, like "Bike: $150.00") and how far
+// along it is, from 0 to 1.
+pub struct Goal<'a> {
+    pub label: &'a str,
+    pub progress: f64,
+}
+
+// A big, simple card with someone's name and balance, and a bar for how close they are to their
+// goal if they have one. Returns the JPEG along with its width and height.
+pub fn balance_card(
+    name: &str,
+    balance: &str,
+    goal: Option<Goal>,
+) -> anyhow::Result<(Bytes, u32, u32)> {
+    let height = if goal.is_some() {
+        CARD_GOAL_HEIGHT
+    } else {
+        CARD_HEIGHT
+    };
+
+    let inside = CARD_WIDTH - 2 * CARD_MARGIN;
+    let ink = Rgb([40u8, 40, 60]);
+
+    let mut canvas = ImageBuffer::from_pixel(CARD_WIDTH, height, Rgb([250u8, 244, 225]));
+
+    draw_text(
+        &mut canvas,
+        name,
+        CARD_MARGIN,
+        CARD_MARGIN,
+        fit_scale(name, inside, 5),
+        ink,
+    );
+
+    // money that's owed shows up red
+    let color = if balance.starts_with('-') {
+        Rgb([200u8, 50, 40])
+    } else {
+        Rgb([40u8, 130, 70])
+    };
+
+    draw_text(
+        &mut canvas,
+        balance,
+        CARD_MARGIN,
+        CARD_MARGIN + 60,
+        fit_scale(balance, inside, 16),
+        color,
+    );
+
+    if let Some(goal) = goal {
+        draw_text(
+            &mut canvas,
+            goal.label,
+            CARD_MARGIN,
+            240,
+            fit_scale(goal.label, inside, 4),
+            ink,
+        );
+
+        let filled = (inside as f64 * goal.progress.clamp(0.0, 1.0)).round() as u32;
+
+        fill_rect(
+            &mut canvas,
+            CARD_MARGIN,
+            300,
+            inside,
+            60,
+            Rgb([220, 210, 190]),
+        );
+        fill_rect(
+            &mut canvas,
+            CARD_MARGIN,
+            300,
+            filled,
+            60,
+            Rgb([90, 170, 90]),
+        );
+    }
+
+    Ok((encode_jpeg(&canvas, 90.0)?, CARD_WIDTH, height))
+}
+
+fn encode_jpeg(image: &ImageBuffer<Rgb<u8>, Vec<u8>>, quality: f32) -> anyhow::Result<Bytes> {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(image.width() as usize, image.height() as usize);
+    comp.set_quality(quality);
+
+    let mut comp = comp.start_compress(Vec::new())?;
+    comp.write_scanlines(image.as_raw())?;
+
+    Ok(Bytes::from(comp.finish()?))
 }
 
 const WIDTH: u32 = 2560;
 const HEIGHT: u32 = 1600;
 
-pub fn shrink_to_jpeg(img: &Bytes, width: u32, height: u32) -> anyhow::Result<Bytes> {
+// Panoramas squeezed into the box above end up too thin to make anything out, so past this
+// aspect ratio, only the short side is limited (up to a point).
+const PANORAMA_RATIO: f64 = 2.5;
+const PANORAMA_SHORT_SIDE: u32 = 1600;
+const PANORAMA_LONG_SIDE: u32 = 12000;
+
+// the box to fit an image of the given size into
+fn bounds(width: u32, height: u32) -> (u32, u32) {
+    let ratio = width as f64 / height as f64;
+
+    if ratio >= PANORAMA_RATIO {
+        println!("wide panorama");
+        (PANORAMA_LONG_SIDE, PANORAMA_SHORT_SIDE)
+    } else if 1.0 / ratio >= PANORAMA_RATIO {
+        println!("tall panorama");
+        (PANORAMA_SHORT_SIDE, PANORAMA_LONG_SIDE)
+    } else {
+        (WIDTH, HEIGHT)
+    }
+}
+
+pub fn shrink_to_jpeg(
+    img: &Bytes,
+    width: u32,
+    height: u32,
+    enhance: bool,
+    output: Output,
+) -> anyhow::Result<Bytes> {
     println!("resizing");
 
     let buffer = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_raw(width, height, img.to_vec()).unwrap();
     let image = DynamicImage::from(buffer);
 
-    let resized = if width > WIDTH || height > HEIGHT {
-        image.resize(WIDTH, HEIGHT, FilterType::Lanczos3)
+    let (max_width, max_height) = output.max_size.unwrap_or_else(|| bounds(width, height));
+
+    let resized = if width > max_width || height > max_height {
+        image.resize(max_width, max_height, FilterType::Lanczos3)
     } else {
         image
     };
 
+    let resized = if enhance {
+        println!("enhancing");
+        auto_enhance(resized)
+    } else {
+        resized
+    };
+
     println!("encoding as JPEG");
 
     let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
     comp.set_size(resized.width() as usize, resized.height() as usize);
 
+    if let Some(quality) = output.quality {
+        comp.set_quality(quality as f32);
+    }
+
     let mut comp = comp.start_compress(Vec::new())?;
     comp.write_scanlines(resized.as_bytes())?;
 
@@ -95,3 +1124,391 @@ pub fn shrink_to_jpeg(img: &Bytes, width: u32, height: u32) -> anyhow::Result<By
 
     Ok(Bytes::from(writer))
 }
+
+// how much of each end of the histogram to ignore when stretching, so a few stray pixels don't
+// stop the rest from being stretched
+const CLIP: f64 = 0.005;
+
+// Stretches each channel so its darkest and brightest parts reach black and white, which also
+// takes care of most color casts, then sharpens a little.
+fn auto_enhance(image: DynamicImage) -> DynamicImage {
+    let mut rgb = image.into_rgb8();
+    let mut histograms = [[0u64; 256]; 3];
+
+    for pixel in rgb.pixels() {
+        for (histogram, value) in histograms.iter_mut().zip(pixel.0) {
+            histogram[value as usize] += 1;
+        }
+    }
+
+    let clip = (rgb.width() as f64 * rgb.height() as f64 * CLIP) as u64;
+
+    let ranges: Vec<(u32, u32)> = histograms
+        .iter()
+        .map(|h| (low_end(h.iter(), clip), 255 - low_end(h.iter().rev(), clip)))
+        .collect();
+
+    for pixel in rgb.pixels_mut() {
+        for (value, &(low, high)) in pixel.0.iter_mut().zip(&ranges) {
+            if high > low {
+                let clamped = (*value as u32).clamp(low, high);
+                *value = ((clamped - low) * 255 / (high - low)) as u8;
+            }
+        }
+    }
+
+    DynamicImage::ImageRgb8(rgb).unsharpen(1.0, 2)
+}
+
+// the first value past the clipped pixels at one end of a histogram
+fn low_end<'a, I>(histogram: I, clip: u64) -> u32
+where
+    I: Iterator<Item = &'a u64>,
+{
+    let mut total = 0;
+
+    for (i, count) in histogram.enumerate() {
+        total += count;
+
+        if total > clip {
+            return i as u32;
+        }
+    }
+
+    0
+}
+
+// Takes metadata out of a JPEG without touching the image itself. Either way, XMP goes (it can
+// carry a copy of the location) and the orientation stays, so nothing ends up sideways. Encoding
+// with mozjpeg never writes any metadata, so this is only needed for photos sent as they came.
+pub fn scrub_jpeg(jpeg: &Bytes, scrub: Scrub) -> anyhow::Result<Bytes> {
+    if scrub == Scrub::Nothing {
+        return Ok(jpeg.clone());
+    }
+
+    let data = jpeg.as_ref();
+
+    if data.len() < 4 || data[0..2] != [0xFF, 0xD8] {
+        bail!("not a JPEG");
+    }
+
+    let mut scrubbed = data[0..2].to_vec();
+    let mut i = 2;
+
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            bail!("bad JPEG marker at {}", i);
+        }
+
+        let marker = data[i + 1];
+
+        // start of scan; everything after this is the image
+        if marker == 0xDA {
+            break;
+        }
+
+        let end = i + 2 + u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+
+        if end > data.len() {
+            bail!("truncated JPEG");
+        }
+
+        let segment = &data[i..end];
+        let payload = &segment[4..];
+
+        match marker {
+            // Exif
+            0xE1 if payload.starts_with(b"Exif\0\0") => {
+                let keep = |field: &Field| match scrub {
+                    Scrub::All => field.tag == Tag::Orientation,
+                    _ => field.tag.context() != Context::Gps,
+                };
+
+                match rebuild_exif(&payload[6..], keep) {
+                    Ok(exif) if exif.len() + 8 <= u16::MAX as usize => {
+                        scrubbed.extend([0xFF, 0xE1]);
+                        scrubbed.extend(((exif.len() + 8) as u16).to_be_bytes());
+                        scrubbed.extend(b"Exif\0\0");
+                        scrubbed.extend(exif);
+                    }
+                    Ok(_) => println!("dropping all EXIF, since it's too big to rewrite"),
+                    Err(e) => println!("dropping all EXIF, since it couldn't be rewritten: {}", e),
+                }
+            }
+            // XMP, and anything else in an APP1
+            0xE1 => {}
+            // IPTC and comments
+            0xED | 0xFE if scrub == Scrub::All => {}
+            _ => scrubbed.extend(segment),
+        }
+
+        i = end;
+    }
+
+    scrubbed.extend(&data[i..]);
+
+    Ok(Bytes::from(scrubbed))
+}
+
+// Takes metadata out of a PNG without touching the image itself. Exif and text chunks (which is
+// where XMP goes) can carry the location, so they go either way; the time it was taken only goes
+// with everything else.
+pub fn scrub_png(png: &Bytes, scrub: Scrub) -> anyhow::Result<Bytes> {
+    const SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    if scrub == Scrub::Nothing {
+        return Ok(png.clone());
+    }
+
+    let data = png.as_ref();
+
+    if !data.starts_with(SIGNATURE) {
+        bail!("not a PNG");
+    }
+
+    let mut scrubbed = SIGNATURE.to_vec();
+    let mut i = SIGNATURE.len();
+
+    while i + 8 <= data.len() {
+        let length = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        let end = i + 12 + length;
+
+        if end > data.len() {
+            bail!("truncated PNG");
+        }
+
+        match &data[i + 4..i + 8] {
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" => {}
+            b"tIME" if scrub == Scrub::All => {}
+            _ => scrubbed.extend(&data[i..end]),
+        }
+
+        i = end;
+    }
+
+    Ok(Bytes::from(scrubbed))
+}
+
+// Writes the Exif back out with only the fields asked for. Thumbnails and maker notes never make
+// it, since they point to data elsewhere in the original that won't be there anymore.
+fn rebuild_exif<F>(tiff: &[u8], keep: F) -> anyhow::Result<Vec<u8>>
+where
+    F: Fn(&Field) -> bool,
+{
+    let exif = exif::Reader::new().read_raw(tiff.to_vec())?;
+    let mut writer = exif::experimental::Writer::new();
+
+    for field in exif.fields() {
+        let pointer = matches!(
+            field.tag,
+            Tag::ExifIFDPointer | Tag::GPSInfoIFDPointer | Tag::InteropIFDPointer
+        );
+
+        if field.ifd_num == In::PRIMARY && !pointer && field.tag != Tag::MakerNote && keep(field) {
+            writer.push_field(field);
+        }
+    }
+
+    let mut rebuilt = Cursor::new(Vec::new());
+    writer.write(&mut rebuilt, exif.little_endian())?;
+
+    Ok(rebuilt.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_photo_in_a_heic() {
+        let photo = HeifItem {
+            id: 1,
+            pixels: 4032 * 3024,
+            depth: false,
+        };
+        let depth = HeifItem {
+            id: 2,
+            pixels: 768 * 576,
+            depth: true,
+        };
+        let thumbnail = HeifItem {
+            id: 3,
+            pixels: 320 * 240,
+            depth: false,
+        };
+
+        let items = [photo, depth, thumbnail];
+
+        assert_eq!(main_item(Some(1), &items), Some(1));
+        assert_eq!(main_item(Some(3), &items), Some(3));
+
+        // a depth map marked as primary, or nothing marked at all
+        assert_eq!(main_item(Some(2), &items), Some(1));
+        assert_eq!(main_item(None, &items), Some(1));
+
+        assert_eq!(main_item(Some(2), &[depth]), None);
+    }
+
+    #[test]
+    fn only_caps_the_short_side_of_panoramas() {
+        let panorama = (PANORAMA_LONG_SIDE, PANORAMA_SHORT_SIDE);
+        let tall = (PANORAMA_SHORT_SIDE, PANORAMA_LONG_SIDE);
+
+        for (width, height, bounded) in [
+            (4032, 3024, (WIDTH, HEIGHT)),
+            (3024, 4032, (WIDTH, HEIGHT)),
+            (1000, 1000, (WIDTH, HEIGHT)),
+            // right up to the ratio is still a photo, and right on it is a panorama
+            (2499, 1000, (WIDTH, HEIGHT)),
+            (2500, 1000, panorama),
+            (1000, 2500, tall),
+            (16000, 3000, panorama),
+            (1, 1, (WIDTH, HEIGHT)),
+            // and an image with no size to it still gets a box
+            (100, 0, panorama),
+            (0, 100, tall),
+            (0, 0, (WIDTH, HEIGHT)),
+        ] {
+            assert_eq!(bounds(width, height), bounded, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn fits_text() {
+        assert_eq!(text_width("$5", 1), 11);
+        assert_eq!(fit_scale("$5.00", 1000, 16), 16);
+        assert_eq!(fit_scale("$1,234,567.89", 300, 16), 3);
+
+        let (_, width, height) = balance_card("Charlie", "$12.50", None).unwrap();
+        assert_eq!((width, height), (CARD_WIDTH, CARD_HEIGHT));
+
+        let goal = Goal {
+            label: "Bike: $150.00",
+            progress: 1.5,
+        };
+
+        let (_, _, height) = balance_card("Charlie", "$12.50", Some(goal)).unwrap();
+        assert_eq!(height, CARD_GOAL_HEIGHT);
+    }
+
+    #[test]
+    fn knows_image_files() {
+        assert_eq!(
+            file_mime_type("1700000000-1-IMG_0001.JPG"),
+            Some("image/jpeg")
+        );
+        assert_eq!(file_mime_type("IMG_0002.heic"), Some("image/heic"));
+        assert_eq!(file_mime_type("clip.mp4"), None);
+    }
+
+    #[test]
+    fn sniffs_mime_types() {
+        assert_eq!(
+            sniff_mime_type(&[0xFF, 0xD8, 0xFF, 0xE1]),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_mime_type(b"\x89PNG\r\n"), Some("image/png"));
+        assert_eq!(sniff_mime_type(b"GIF89a"), Some("image/gif"));
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WEBPVP8X"), Some("image/webp"));
+        assert_eq!(sniff_mime_type(b"\0\0\0\x18ftypheic"), Some("image/heic"));
+        assert_eq!(sniff_mime_type(b"\0\0\0\x18ftypisom"), None);
+        assert_eq!(sniff_mime_type(b"hello"), None);
+    }
+
+    // a little-endian TIFF with a small JPEG thumbnail in IFD0, and a bigger one in a sub IFD
+    fn tiff(thumbnail: &[u8], preview: &[u8]) -> Vec<u8> {
+        let entry = |tag: u16, kind: u16, value: u32| {
+            let mut e = vec![];
+            e.extend_from_slice(&tag.to_le_bytes());
+            e.extend_from_slice(&kind.to_le_bytes());
+            e.extend_from_slice(&1u32.to_le_bytes());
+            e.extend_from_slice(&value.to_le_bytes());
+            e
+        };
+
+        // header (8), IFD0 with 3 entries (2 + 36 + 4), sub IFD with 2 entries (2 + 24 + 4)
+        let sub_ifd = 8 + 42;
+        let thumbnail_at = sub_ifd + 30;
+        let preview_at = thumbnail_at + thumbnail.len();
+
+        let mut file = b"II*\0".to_vec();
+        file.extend_from_slice(&8u32.to_le_bytes());
+
+        file.extend_from_slice(&3u16.to_le_bytes());
+        file.extend(entry(0x014A, 4, sub_ifd as u32));
+        file.extend(entry(0x0201, 4, thumbnail_at as u32));
+        file.extend(entry(0x0202, 4, thumbnail.len() as u32));
+        file.extend_from_slice(&0u32.to_le_bytes());
+
+        file.extend_from_slice(&2u16.to_le_bytes());
+        file.extend(entry(0x0201, 4, preview_at as u32));
+        file.extend(entry(0x0202, 4, preview.len() as u32));
+        file.extend_from_slice(&0u32.to_le_bytes());
+
+        file.extend_from_slice(thumbnail);
+        file.extend_from_slice(preview);
+
+        file
+    }
+
+    #[test]
+    fn finds_the_biggest_raw_preview() {
+        let thumbnail = [0xFF, 0xD8, 0xFF, 0xD9];
+        let preview = [0xFF, 0xD8, 1, 2, 3, 4, 0xFF, 0xD9];
+
+        assert_eq!(
+            raw_preview(&tiff(&thumbnail, &preview)).as_deref(),
+            Some(&preview[..])
+        );
+    }
+
+    #[test]
+    fn ignores_what_isnt_a_jpeg() {
+        let thumbnail = [0xFF, 0xD8, 0xFF, 0xD9];
+
+        assert_eq!(
+            raw_preview(&tiff(&thumbnail, &[1, 2, 3, 4])).as_deref(),
+            Some(&thumbnail[..])
+        );
+        assert_eq!(raw_preview(b"not a tiff at all"), None);
+    }
+
+    #[test]
+    fn knows_raw_files_by_name() {
+        assert_eq!(
+            raw_mime_type(Some("application/octet-stream"), "IMG_0001.CR2"),
+            Some("image/x-canon-cr2")
+        );
+        assert_eq!(
+            raw_mime_type(Some("image/x-adobe-dng"), "photo"),
+            Some("image/x-adobe-dng")
+        );
+        assert_eq!(raw_mime_type(Some("image/jpeg"), "photo.jpg"), None);
+    }
+
+    #[test]
+    fn lays_out_collages() {
+        let photo = ImageBuffer::from_pixel(800, 600, Rgb([200u8, 40, 40]));
+        let tile = collage_tile(&encode_jpeg(&photo, 90.0).unwrap()).unwrap();
+
+        // five go in a three by two grid
+        let (_, width, height) = collage(&vec![tile; 5]).unwrap();
+
+        assert_eq!(width, 3 * TILE_SIZE + 4 * TILE_GAP);
+        assert_eq!(height, 2 * TILE_SIZE + 3 * TILE_GAP);
+        assert!(collage(&[]).is_err());
+    }
+
+    #[test]
+    fn draws_bar_charts() {
+        let (jpeg, width, height) = bar_chart(&[12.0, 30.5, 0.0, -1.0], Some(20.0)).unwrap();
+        let decoded = ImageReader::new(Cursor::new(jpeg.to_vec()))
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+        assert!(bar_chart(&[], None).is_err());
+    }
+}
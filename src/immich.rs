@@ -0,0 +1,146 @@
+use std::env;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use chrono::Utc;
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use serde_json::json;
+
+// what the server calls us, so everything we upload shows up as coming from one device
+const DEVICE_ID: &str = "bots";
+
+#[derive(Deserialize)]
+struct Asset {
+    id: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct Album {
+    id: String,
+    #[serde(rename = "albumName")]
+    album_name: String,
+}
+
+// Uploading is only turned on when IMMICH_URL (like https://photos.example.com) and IMMICH_API_KEY
+// are set. If IMMICH_ALBUM is set too, everything is added to the album with that name.
+pub fn enabled() -> bool {
+    env::var("IMMICH_URL").is_ok() && env::var("IMMICH_API_KEY").is_ok()
+}
+
+// uploads an original, adding it to the given album, or the configured one, if there is one
+pub async fn upload(
+    photo: &Bytes,
+    mime_type: &str,
+    file_name: &str,
+    description: Option<&str>,
+    album: Option<&str>,
+) -> Result<()> {
+    let now = Utc::now();
+
+    let part = Part::bytes(photo.to_vec())
+        .file_name(file_name.to_string())
+        .mime_str(mime_type)?;
+
+    let form = Form::new()
+        .text(
+            "deviceAssetId",
+            format!("{}-{}", now.timestamp_millis(), file_name),
+        )
+        .text("deviceId", DEVICE_ID)
+        .text("fileCreatedAt", now.to_rfc3339())
+        .text("fileModifiedAt", now.to_rfc3339())
+        .part("assetData", part);
+
+    let response = client().post(url("assets")).multipart(form).send().await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from Immich: {}",
+            response.status()
+        );
+    }
+
+    let asset: Asset = response.json().await?;
+
+    if asset.status == "duplicate" {
+        println!("{} is already in Immich", file_name);
+    }
+
+    if let Some(description) = description {
+        client()
+            .put(url(&format!("assets/{}", asset.id)))
+            .json(&json!({ "description": description }))
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    if let Some(album) = album
+        .map(|album| album.to_string())
+        .or_else(|| env::var("IMMICH_ALBUM").ok())
+    {
+        let album_id = album_id(&album).await?;
+
+        client()
+            .put(url(&format!("albums/{}/assets", album_id)))
+            .json(&json!({ "ids": [asset.id] }))
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    println!("uploaded {} to Immich", file_name);
+
+    Ok(())
+}
+
+// finds the album with the given name, creating it if needed
+async fn album_id(name: &str) -> Result<String> {
+    let albums: Vec<Album> = client()
+        .get(url("albums"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if let Some(album) = albums.into_iter().find(|a| a.album_name == name) {
+        return Ok(album.id);
+    }
+
+    let album: Album = client()
+        .post(url("albums"))
+        .json(&json!({ "albumName": name }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    println!("created Immich album {}", name);
+
+    Ok(album.id)
+}
+
+fn url(path: &str) -> String {
+    let base = env::var("IMMICH_URL").expect("IMMICH_URL environmental variable not set");
+    format!("{}/api/{}", base.trim_end_matches('/'), path)
+}
+
+// a client that sends the API key with every request
+fn client() -> reqwest::Client {
+    let key = env::var("IMMICH_API_KEY").expect("IMMICH_API_KEY environmental variable not set");
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        "x-api-key",
+        key.parse().expect("IMMICH_API_KEY is not a valid header"),
+    );
+
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .expect("could not build the Immich client")
+}
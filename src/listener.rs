@@ -0,0 +1,50 @@
+use std::env;
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use axum::Router;
+
+use crate::login;
+
+// Serves inbound webhooks (from Home Assistant and the like) on LISTEN_ADDR, which defaults to
+// 127.0.0.1:8080, for anyone with LISTENER_TOKEN. Both are checked before it's started.
+pub fn serve(app: Router) -> anyhow::Result<()> {
+    let addr: SocketAddr = env::var("LISTEN_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8080".to_string())
+        .parse()
+        .map_err(|e| anyhow!("invalid LISTEN_ADDR: {}", e))?;
+
+    token()?;
+
+    let server = axum::Server::try_bind(&addr)?;
+
+    println!("listening on {}", addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            println!("Could not run listener! {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn token() -> anyhow::Result<String> {
+    env::var("LISTENER_TOKEN").map_err(|_| anyhow!("LISTENER_TOKEN environmental variable not set"))
+}
+
+// requests need to send LISTENER_TOKEN as a bearer token
+pub fn authorized(headers: &HeaderMap) -> bool {
+    let token = match token() {
+        Ok(token) => token,
+        Err(_) => return false,
+    };
+
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|h| login::same(h, &token))
+        .unwrap_or(false)
+}
@@ -0,0 +1,107 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// how long a login lasts, unless we're restarted first
+const MAX_AGE: u32 = 31536000;
+
+// The login for one of the pages we serve (the dashboard, the gallery): a secret that's typed in
+// once, then a session in a cookie after that. The session is signed with a key that's made up
+// when we start, so the secret itself never ends up in a browser, and a restart logs everyone out.
+pub struct Login {
+    cookie: &'static str,
+    key: [u8; 32],
+    session: String,
+}
+
+impl Login {
+    pub fn new(cookie: &'static str, secret: String) -> Login {
+        let key: [u8; 32] = rand::random();
+        let session = sign(&key, &secret);
+
+        Login {
+            cookie,
+            key,
+            session,
+        }
+    }
+
+    // whether the request comes with a session cookie
+    pub fn authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .any(|(name, value)| name == self.cookie && same(value, &self.session))
+    }
+
+    // Checks what was typed into the login form, and hands out a session if it's the secret. The
+    // session is the signed secret, so what was typed is signed the same way and compared to it.
+    pub fn log_in(&self, typed: &str, wrong: &'static str) -> Response {
+        if !same(&sign(&self.key, typed), &self.session) {
+            return (StatusCode::UNAUTHORIZED, wrong).into_response();
+        }
+
+        // strict, so no other site can post the buttons for us
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+            self.cookie, self.session, MAX_AGE
+        );
+
+        ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+    }
+}
+
+fn sign(key: &[u8], text: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(text.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// compares two secrets in the same time, however much of them matches
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_in_with_a_session() {
+        let login = Login::new("test", "hunter2".to_string());
+
+        let response = login.log_in("hunter3", "nope");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = login.log_in("hunter2", "nope");
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(!cookie.contains("hunter2"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            cookie.split(';').next().unwrap().parse().unwrap(),
+        );
+        assert!(login.authorized(&headers));
+
+        // and the secret itself doesn't get anyone in
+        headers.insert(header::COOKIE, "test=hunter2".parse().unwrap());
+        assert!(!login.authorized(&headers));
+    }
+
+    #[test]
+    fn compares() {
+        assert!(same("abc", "abc"));
+        assert!(!same("abc", "abd"));
+        assert!(!same("abc", "ab"));
+        assert!(same("", ""));
+    }
+}
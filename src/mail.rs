@@ -0,0 +1,160 @@
+use std::env;
+
+use async_trait::async_trait;
+use lettre::address::Envelope;
+use lettre::message::MultiPart;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use native_tls::TlsConnector;
+
+const IMAP_PORT: u16 = 993;
+
+// something formatted emails go out through: SMTP, or whatever stands in for it in a test
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    // who the emails are from
+    fn from(&self) -> &str;
+
+    // sends an already formatted email, so one can be kept around and tried again later
+    async fn send_raw(&self, to: &str, email: &[u8]) -> anyhow::Result<()>;
+}
+
+// an SMTP server, and who we send as
+pub struct Smtp {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl Smtp {
+    pub fn new(transport: AsyncSmtpTransport<Tokio1Executor>, from: &str) -> Smtp {
+        Smtp {
+            transport,
+            from: from.to_string(),
+        }
+    }
+
+    // SMTP_SERVER, logged into with SMTP_USERNAME and SMTP_PASSWORD, sending as SMTP_FROM
+    pub fn configured() -> Smtp {
+        let username =
+            env::var("SMTP_USERNAME").expect("SMTP_USERNAME environmental variable not set");
+
+        let password =
+            env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD environmental variable not set");
+
+        let server = env::var("SMTP_SERVER").expect("SMTP_SERVER environmental variable not set");
+
+        let from = env::var("SMTP_FROM").expect("SMTP_FROM environmental variable not set");
+
+        let creds = Credentials::new(username, password);
+
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(&server)
+            .unwrap()
+            .credentials(creds)
+            .build();
+
+        Smtp::new(transport, &from)
+    }
+}
+
+#[async_trait]
+impl Mailer for Smtp {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    async fn send_raw(&self, to: &str, email: &[u8]) -> anyhow::Result<()> {
+        let envelope = Envelope::new(Some(self.from.parse()?), vec![to.parse()?])?;
+
+        AsyncTransport::send_raw(&self.transport, &envelope, email).await?;
+
+        Ok(())
+    }
+}
+
+pub fn build(from: &str, to: &str, subject: &str, body: MultiPart) -> anyhow::Result<Message> {
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .multipart(body)?)
+}
+
+// Bounce checking is only turned on when IMAP_SERVER (the sending mailbox's server, with an optional
+// port) is set. It logs in with IMAP_USERNAME and IMAP_PASSWORD, or the SMTP ones if those aren't
+// set.
+pub fn bounce_checking_enabled() -> bool {
+    env::var("IMAP_SERVER").is_ok()
+}
+
+// Every delivery failure in the inbox that hasn't been read yet. Fetching them marks them read, so
+// each one only comes back once. This blocks, so it belongs on its own thread.
+pub fn bounces() -> anyhow::Result<Vec<String>> {
+    let server = env::var("IMAP_SERVER").expect("IMAP_SERVER environmental variable not set");
+
+    let username = env::var("IMAP_USERNAME")
+        .or_else(|_| env::var("SMTP_USERNAME"))
+        .expect("IMAP_USERNAME environmental variable not set");
+
+    let password = env::var("IMAP_PASSWORD")
+        .or_else(|_| env::var("SMTP_PASSWORD"))
+        .expect("IMAP_PASSWORD environmental variable not set");
+
+    let (host, port) = match server.split_once(':') {
+        Some((host, port)) => (host.to_string(), port.parse()?),
+        None => (server.clone(), IMAP_PORT),
+    };
+
+    let tls = TlsConnector::builder().build()?;
+    let client = imap::connect((host.as_str(), port), &host, &tls)?;
+    let mut session = client.login(username, password).map_err(|(e, _)| e)?;
+
+    session.select("INBOX")?;
+
+    let ids = session.search("UNSEEN OR FROM \"mailer-daemon\" FROM \"postmaster\"")?;
+    let mut bounces = vec![];
+
+    if !ids.is_empty() {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+        for message in session.fetch(ids.join(","), "BODY[]")?.iter() {
+            if let Some(body) = message.body() {
+                bounces.push(String::from_utf8_lossy(body).to_string());
+            }
+        }
+    }
+
+    session.logout()?;
+
+    Ok(bounces)
+}
+
+// the addresses a delivery status notification says couldn't be reached, if it has any
+pub fn failed_recipients(bounce: &str) -> Vec<String> {
+    let mut failed = vec![];
+
+    for line in bounce.lines() {
+        let (name, value) = match line.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+
+        if !name.trim().eq_ignore_ascii_case("final-recipient") {
+            continue;
+        }
+
+        // like "rfc822; someone@example.com"
+        let address = value
+            .rsplit(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches(|c| c == '<' || c == '>')
+            .to_lowercase();
+
+        if address.contains('@') && !failed.contains(&address) {
+            failed.push(address);
+        }
+    }
+
+    failed
+}
@@ -3,21 +3,46 @@ extern crate core;
 use std::env;
 
 mod ai;
+mod archive;
 mod bots;
+mod cache;
+mod claude;
+mod commands;
+mod config;
+mod dashboard;
+mod frame;
+#[cfg(feature = "gallery")]
+mod gallery;
+mod google_photos;
+mod i18n;
 mod image;
+mod immich;
+mod listener;
+mod login;
+mod mail;
 mod matrix;
 mod message_buffer;
+mod pdf;
+mod scheduler;
+mod storage;
+mod telegram;
+#[cfg(test)]
+mod testing;
+mod video;
 mod webhook;
+mod ynab;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     if let Some(bot) = env::args().nth(1) {
+        config::check_all()?;
+
+        #[cfg(feature = "gallery")]
+        gallery::serve()?;
+
         match bot.as_str() {
-            "home" => bots::home::main().await?,
-            "money" => bots::money::main().await?,
-            "owen" => bots::owen::main().await?,
-            "ai" => bots::ai::main().await?,
-            "photo" => bots::photo::main().await?,
+            "all" => run_all().await?,
+            "home" | "money" | "owen" | "ai" | "photo" => run(&bot).await?,
             _ => {
                 println!("unknown bot: {}", bot);
                 return Ok(());
@@ -29,3 +54,48 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+async fn run(bot: &str) -> anyhow::Result<()> {
+    dashboard::started(bot);
+
+    match bot {
+        "home" => bots::home::main().await,
+        "money" => bots::money::main().await,
+        "owen" => bots::owen::main().await,
+        "ai" => bots::ai::main().await,
+        "photo" => bots::photo::main().await,
+        _ => anyhow::bail!("unknown bot: {}", bot),
+    }
+}
+
+// Runs every bot listed in BOTS (comma separated, defaulting to all of them) in one process. Each
+// gets its own thread and runtime, since some of them block while waiting for messages.
+async fn run_all() -> anyhow::Result<()> {
+    let bots: Vec<String> = env::var("BOTS")
+        .unwrap_or("home,money,owen,ai,photo".to_string())
+        .split(',')
+        .map(|b| b.trim().to_string())
+        .filter(|b| !b.is_empty())
+        .collect();
+
+    commands::set_running(&bots);
+
+    // the dashboard shows every bot in the process, so it's only served when they're all here
+    dashboard::serve()?;
+
+    for bot in bots {
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().expect("could not start runtime");
+
+            match runtime.block_on(run(&bot)) {
+                Ok(()) => dashboard::stopped(&bot, "finished"),
+                Err(e) => {
+                    println!("{} stopped: {}", bot, e);
+                    dashboard::stopped(&bot, &e.to_string());
+                }
+            }
+        });
+    }
+
+    commands::main().await
+}
@@ -1,28 +1,60 @@
+use anyhow::bail;
+use async_trait::async_trait;
 use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::io::Cursor;
+use std::sync::Mutex;
 
+use chrono::{DateTime, Utc};
+use matrix_sdk::event_handler::{EventKind, SyncEvent};
 use matrix_sdk::room::Joined;
 use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::r0::config::set_global_account_data;
+use matrix_sdk::ruma::api::client::r0::filter::RoomEventFilter;
+use matrix_sdk::ruma::api::client::r0::knock::knock_room;
+use matrix_sdk::ruma::api::client::r0::message::{get_message_events, send_message_event};
+use matrix_sdk::ruma::api::client::r0::room::create_room::{self, RoomPreset};
+use matrix_sdk::ruma::api::client::r0::search::search_events;
+use matrix_sdk::ruma::events::custom::CustomEventContent;
+use matrix_sdk::ruma::events::direct::DirectEventContent;
 use matrix_sdk::ruma::events::room::member::MemberEventContent;
 use matrix_sdk::ruma::events::room::message::MessageType;
 use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
 use matrix_sdk::ruma::events::room::message::{
-    FileInfo, FileMessageEventContent, ImageMessageEventContent, MessageEventContent,
+    AudioMessageEventContent, EmoteMessageEventContent, NoticeMessageEventContent,
 };
+use matrix_sdk::ruma::events::room::message::{
+    FileInfo, FileMessageEventContent, ImageMessageEventContent, MessageEventContent, VideoInfo,
+    VideoMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::pinned_events::PinnedEventsEventContent;
 use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::events::space::child::ChildEventContent;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::events::EventType;
 use matrix_sdk::ruma::events::StrippedStateEvent;
 use matrix_sdk::ruma::events::SyncMessageEvent;
-use matrix_sdk::ruma::{MxcUri, ServerName, UserId};
+use matrix_sdk::ruma::events::SyncStateEvent;
+use matrix_sdk::ruma::events::{AnyGlobalAccountDataEventContent, EventContent};
+use matrix_sdk::ruma::events::{AnyMessageEvent, AnyRoomEvent, MessageEvent};
+use matrix_sdk::ruma::{EventId, MxcUri, RoomId, RoomIdOrAliasId, ServerName, UInt, UserId};
 use matrix_sdk::ClientConfig;
 use matrix_sdk::{Client, SyncSettings};
+use once_cell::sync::Lazy;
 use reqwest::Url;
 use rust_decimal::prelude::*;
 use rusty_money::iso::Currency;
 use rusty_money::Money;
+use serde::Deserialize;
 use tokio::time;
 use tokio::time::Duration;
 
+use crate::cache;
+use crate::config;
+use crate::storage;
+use crate::storage::Area;
+
 pub async fn get_text_message(
     event: SyncMessageEvent<MessageEventContent>,
     room: Room,
@@ -42,7 +74,8 @@ pub async fn get_text_message(
             if sender.eq(&client.user_id().await.unwrap()) {
                 None
             } else {
-                Some((room, sender, body))
+                let body = strip_command_prefix(room.room_id(), &body)?;
+                Some((room, canonical_user_id(&sender), body))
             }
         } else {
             Option::None
@@ -52,17 +85,83 @@ pub async fn get_text_message(
     }
 }
 
+// A reply's body starts with a quote of what it's replying to, for clients that don't know about
+// replies. This is just what was said.
+pub fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+
+    while rest.starts_with("> ") || rest.starts_with(">\n") {
+        rest = match rest.split_once('\n') {
+            Some((_, rest)) => rest,
+            None => "",
+        };
+    }
+
+    rest.trim_start()
+}
+
+// Busy (usually bridged) rooms can require a prefix before anything a bot will act on, like
+// "!send" or "!say", configured as a JSON map of room ID to prefix in COMMAND_PREFIXES.
+static COMMAND_PREFIXES: Lazy<HashMap<String, String>> =
+    Lazy::new(|| config::load("COMMAND_PREFIXES"));
+
+// the message without the room's prefix, or None if it's missing; rooms that aren't listed don't
+// need one
+fn strip_command_prefix(room_id: &RoomId, message: &str) -> Option<String> {
+    match COMMAND_PREFIXES.get(room_id.as_str()) {
+        Some(prefix) => message
+            .strip_prefix(prefix.as_str())
+            .map(|m| m.trim_start().to_string()),
+        None => Some(message.to_string()),
+    }
+}
+
+// some clients leave out the info, so there may be nothing to go on but the image itself
 pub async fn get_image_message(
     event: SyncMessageEvent<MessageEventContent>,
     room: Room,
     client: Client,
-) -> Option<(Joined, UserId, MxcUri, Box<ImageInfo>)> {
+) -> Option<(Joined, UserId, MxcUri, Option<Box<ImageInfo>>)> {
     if let Room::Joined(room) = room {
         if let SyncMessageEvent {
             content:
                 MessageEventContent {
                     msgtype:
                         MessageType::Image(ImageMessageEventContent {
+                            url: Some(uri),
+                            info,
+                            ..
+                        }),
+                    ..
+                },
+            sender,
+            ..
+        } = event
+        {
+            if sender.eq(&client.user_id().await.unwrap()) {
+                None
+            } else {
+                Some((room, canonical_user_id(&sender), uri, info))
+            }
+        } else {
+            Option::None
+        }
+    } else {
+        Option::None
+    }
+}
+
+pub async fn get_video_message(
+    event: SyncMessageEvent<MessageEventContent>,
+    room: Room,
+    client: Client,
+) -> Option<(Joined, UserId, MxcUri, Box<VideoInfo>)> {
+    if let Room::Joined(room) = room {
+        if let SyncMessageEvent {
+            content:
+                MessageEventContent {
+                    msgtype:
+                        MessageType::Video(VideoMessageEventContent {
                             url: Some(uri),
                             info: Some(info),
                             ..
@@ -76,7 +175,7 @@ pub async fn get_image_message(
             if sender.eq(&client.user_id().await.unwrap()) {
                 None
             } else {
-                Some((room, sender, uri, info))
+                Some((room, canonical_user_id(&sender), uri, info))
             }
         } else {
             Option::None
@@ -86,6 +185,16 @@ pub async fn get_image_message(
     }
 }
 
+// the body of a media message, which is usually just the file name but can be a caption
+pub fn get_media_body(event: &SyncMessageEvent<MessageEventContent>) -> Option<&str> {
+    match &event.content.msgtype {
+        MessageType::Image(ImageMessageEventContent { body, .. })
+        | MessageType::Video(VideoMessageEventContent { body, .. })
+        | MessageType::File(FileMessageEventContent { body, .. }) => Some(body),
+        _ => None,
+    }
+}
+
 pub async fn get_file_message(
     event: SyncMessageEvent<MessageEventContent>,
     room: Room,
@@ -110,7 +219,7 @@ pub async fn get_file_message(
             if sender.eq(&client.user_id().await.unwrap()) {
                 None
             } else {
-                Some((room, sender, uri, info))
+                Some((room, canonical_user_id(&sender), uri, info))
             }
         } else {
             Option::None
@@ -120,6 +229,443 @@ pub async fn get_file_message(
     }
 }
 
+// how far back we'll look through a room ourselves when the server can't search it, and how much
+// we ask for at a time
+const SEARCH_HISTORY_LIMIT: usize = 1000;
+const SEARCH_PAGE_SIZE: u32 = 100;
+
+// a message turned up by a search
+pub struct Found {
+    pub event_id: EventId,
+    pub sender: UserId,
+    pub date: DateTime<Utc>,
+    pub msgtype: String,
+    pub body: String,
+}
+
+impl Found {
+    // a link that opens the message in any Matrix client
+    pub fn link(&self, room_id: &RoomId) -> String {
+        format!("https://matrix.to/#/{}/{}", room_id, self.event_id)
+    }
+}
+
+// Finds the messages in a room with every one of the words, newest first, from anyone or just the
+// one sender. The server's own search goes first, but not every server has one, and none can see
+// into encrypted rooms, so if it comes up empty, we look back through the room's history ourselves.
+pub async fn search(
+    client: &Client,
+    room: &Joined,
+    words: &str,
+    sender: Option<&UserId>,
+    limit: usize,
+) -> anyhow::Result<Vec<Found>> {
+    let mut found = match server_search(client, room, words).await {
+        Ok(found) => found,
+        Err(e) => {
+            println!(
+                "server search failed, looking through history instead: {}",
+                e
+            );
+            vec![]
+        }
+    };
+
+    // the server matches words its own way, but senders are matched here, bridges and all
+    found.retain(|f| sender.is_none_or(|sender| f.sender == *sender));
+
+    if found.is_empty() {
+        found = history_search(room, words, sender, limit).await?;
+    }
+
+    found.truncate(limit);
+
+    Ok(found)
+}
+
+async fn server_search(client: &Client, room: &Joined, words: &str) -> anyhow::Result<Vec<Found>> {
+    let rooms = [room.room_id().clone()];
+
+    let mut filter = RoomEventFilter::empty();
+    filter.rooms = Some(&rooms);
+
+    let mut criteria = search_events::Criteria::new(words);
+    criteria.filter = Some(filter);
+    criteria.order_by = Some(search_events::OrderBy::Recent);
+
+    let mut categories = search_events::Categories::new();
+    categories.room_events = Some(criteria);
+
+    let response = client
+        .send(search_events::Request::new(categories), None)
+        .await?;
+
+    Ok(response
+        .search_categories
+        .room_events
+        .results
+        .into_iter()
+        .filter_map(|result| result.result?.deserialize().ok())
+        .filter_map(found)
+        .collect())
+}
+
+async fn history_search(
+    room: &Joined,
+    words: &str,
+    sender: Option<&UserId>,
+    limit: usize,
+) -> anyhow::Result<Vec<Found>> {
+    let mut from = match room.last_prev_batch() {
+        Some(from) => from,
+        None => return Ok(vec![]),
+    };
+
+    let mut found = vec![];
+    let mut scanned = 0;
+
+    while scanned < SEARCH_HISTORY_LIMIT && found.len() < limit {
+        let mut request = get_message_events::Request::backward(room.room_id(), &from);
+        request.limit = UInt::from(SEARCH_PAGE_SIZE);
+
+        let response = room.messages(request).await?;
+
+        if response.chunk.is_empty() {
+            break;
+        }
+
+        scanned += response.chunk.len();
+
+        for event in response
+            .chunk
+            .iter()
+            .filter_map(|raw| raw.deserialize().ok())
+        {
+            if let Some(message) = found_message(event, words, sender) {
+                found.push(message);
+            }
+        }
+
+        from = match response.end {
+            Some(end) => end,
+            None => break,
+        };
+    }
+
+    Ok(found)
+}
+
+fn found_message(event: AnyRoomEvent, words: &str, sender: Option<&UserId>) -> Option<Found> {
+    let message = found(event)?;
+
+    if sender.is_some_and(|sender| message.sender != *sender) {
+        return None;
+    }
+
+    if !has_words(&message.body, words) {
+        return None;
+    }
+
+    Some(message)
+}
+
+fn found(event: AnyRoomEvent) -> Option<Found> {
+    let event = match event {
+        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(event)) => event,
+        _ => return None,
+    };
+
+    let MessageEvent {
+        event_id,
+        sender,
+        origin_server_ts,
+        content,
+        ..
+    } = event;
+
+    Some(Found {
+        event_id,
+        sender: canonical_user_id(&sender),
+        date: DateTime::from(origin_server_ts.to_system_time()?),
+        msgtype: content.msgtype.msgtype().to_string(),
+        body: message_body(&content)?.to_string(),
+    })
+}
+
+// the text of any kind of message, which for media is the file name or a caption
+fn message_body(content: &MessageEventContent) -> Option<&str> {
+    match &content.msgtype {
+        MessageType::Text(TextMessageEventContent { body, .. })
+        | MessageType::Notice(NoticeMessageEventContent { body, .. })
+        | MessageType::Emote(EmoteMessageEventContent { body, .. })
+        | MessageType::Image(ImageMessageEventContent { body, .. })
+        | MessageType::Video(VideoMessageEventContent { body, .. })
+        | MessageType::Audio(AudioMessageEventContent { body, .. })
+        | MessageType::File(FileMessageEventContent { body, .. }) => Some(body),
+        _ => None,
+    }
+}
+
+// whether every word shows up somewhere in the text, in any case
+fn has_words(text: &str, words: &str) -> bool {
+    let text = text.to_lowercase();
+
+    words
+        .to_lowercase()
+        .split_whitespace()
+        .all(|word| text.contains(word))
+}
+
+// pulls the users mentioned with pills out of the formatted body of a text message, along with
+// the text each pill shows (usually their display name)
+pub fn get_mentions(event: &SyncMessageEvent<MessageEventContent>) -> Vec<(UserId, String)> {
+    let formatted = match &event.content.msgtype {
+        MessageType::Text(TextMessageEventContent {
+            formatted: Some(formatted),
+            ..
+        }) => &formatted.body,
+        _ => return vec![],
+    };
+
+    formatted
+        .split("https://matrix.to/#/")
+        .skip(1)
+        .filter_map(|link| {
+            let end = link.find(['"', '\'', '?', '>']).unwrap_or(link.len());
+
+            let id = link[..end]
+                .replace("%40", "@")
+                .replace("%3A", ":")
+                .replace("%3a", ":");
+
+            let text = link
+                .split_once('>')
+                .and_then(|(_, rest)| rest.split_once("</a>"))
+                .map(|(text, _)| text.trim().to_string())
+                .unwrap_or_default();
+
+            UserId::try_from(id.as_str()).ok().map(|id| (id, text))
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct SpaceChild {
+    state_key: String,
+    content: serde_json::Value,
+}
+
+// rooms can be configured in bulk by the spaces they're in, with SPACES set to something like
+// {"Family": ["money"], "Kids": ["money", "kid-safe"]}
+static SPACES: Lazy<HashMap<String, Vec<String>>> = Lazy::new(|| config::load("SPACES"));
+
+// The rooms in each configured space. Looking them up takes a trip to the homeserver per space, so
+// they're kept until a space's children, or the rooms we're in, change.
+static SPACE_ROOMS: Mutex<Option<HashMap<String, HashSet<RoomId>>>> = Mutex::new(None);
+
+// finds a joined space by its name or room ID
+pub async fn find_space(client: &Client, name: &str) -> Option<Joined> {
+    for room in client.joined_rooms() {
+        if room.room_id().as_str() == name {
+            return Some(room);
+        }
+
+        if let Ok(display_name) = room.display_name().await {
+            if display_name.eq_ignore_ascii_case(name) {
+                return Some(room);
+            }
+        }
+    }
+
+    None
+}
+
+// the rooms directly inside a space
+pub async fn space_rooms(space: &Joined) -> anyhow::Result<Vec<RoomId>> {
+    Ok(space_children(space)
+        .await?
+        .into_iter()
+        .map(|(room_id, _)| room_id)
+        .collect())
+}
+
+// the rooms directly inside a space, with the servers to join them through
+async fn space_children(space: &Joined) -> anyhow::Result<Vec<(RoomId, Vec<Box<ServerName>>)>> {
+    let events = space
+        .get_state_events(EventType::from("m.space.child"))
+        .await?;
+
+    Ok(events
+        .iter()
+        .filter_map(|raw| raw.deserialize_as::<SpaceChild>().ok())
+        // a child is removed from a space by blanking out its content
+        .filter_map(|child| {
+            let via = child.content.get("via")?.as_array()?;
+            let via = via
+                .iter()
+                .filter_map(|server| <Box<ServerName>>::try_from(server.as_str()?).ok())
+                .collect();
+
+            Some((RoomId::try_from(child.state_key.as_str()).ok()?, via))
+        })
+        .collect())
+}
+
+// the rooms in every configured space, from the cache if we can
+async fn all_space_rooms(client: &Client) -> HashMap<String, HashSet<RoomId>> {
+    if let Some(cached) = SPACE_ROOMS.lock().unwrap().as_ref() {
+        return cached.clone();
+    }
+
+    let mut all = HashMap::new();
+    // errors are tried again next time, rather than cached
+    let mut complete = true;
+
+    for name in SPACES.keys() {
+        let space = match find_space(client, name).await {
+            Some(space) => space,
+            None => {
+                // joining it later clears the cache
+                println!("not in a space called {}", name);
+                continue;
+            }
+        };
+
+        match space_rooms(&space).await {
+            Ok(rooms) => {
+                all.insert(name.clone(), rooms.into_iter().collect());
+            }
+            Err(e) => {
+                println!("could not list the rooms in {}: {}", name, e);
+                complete = false;
+            }
+        }
+    }
+
+    if complete {
+        *SPACE_ROOMS.lock().unwrap() = Some(all.clone());
+    }
+
+    all
+}
+
+fn forget_space_rooms() {
+    *SPACE_ROOMS.lock().unwrap() = None;
+}
+
+async fn on_space_child(
+    event: SyncStateEvent<ChildEventContent>,
+    room: Room,
+    client: Client,
+    bot: &str,
+) {
+    forget_space_rooms();
+
+    // a room just added to one of our spaces might be one we can only get into through the space
+    let via = match event.content.via {
+        Some(via) => via,
+        None => return,
+    };
+
+    if let (Room::Joined(space), Ok(room_id)) = (room, RoomId::try_from(event.state_key.as_str())) {
+        if joins_space(&space, bot).await
+            && allowed_in(bot, room_id.as_str())
+            && client.get_joined_room(&room_id).is_none()
+        {
+            join_uninvited(&client, &room_id.into(), &via).await;
+        }
+    }
+}
+
+// whether a space is one of the ones in SPACES, and turns on the bot
+async fn joins_space(space: &Joined, bot: &str) -> bool {
+    let display_name = space.display_name().await.unwrap_or_default();
+
+    SPACES.iter().any(|(name, features)| {
+        (name.as_str() == space.room_id().as_str() || name.eq_ignore_ascii_case(&display_name))
+            && features.iter().any(|f| f == bot)
+    })
+}
+
+async fn on_room_member(event: SyncStateEvent<MemberEventContent>, client: Client) {
+    if let Some(user_id) = client.user_id().await {
+        if event.state_key == user_id.as_str() {
+            forget_space_rooms();
+        }
+    }
+}
+
+// everything turned on for a room by the spaces it's in
+pub async fn room_features(client: &Client, room_id: &RoomId) -> HashSet<String> {
+    let mut features = HashSet::new();
+
+    if SPACES.is_empty() {
+        return features;
+    }
+
+    for (name, rooms) in all_space_rooms(client).await {
+        if rooms.contains(room_id) {
+            features.extend(SPACES[&name].iter().cloned());
+        }
+    }
+
+    features
+}
+
+// whether a feature applies to a room; a feature no space asks for applies everywhere
+pub async fn feature_enabled(client: &Client, room_id: &RoomId, feature: &str) -> bool {
+    let configured = SPACES.values().flatten().any(|f| f.as_str() == feature);
+
+    !configured || room_features(client, room_id).await.contains(feature)
+}
+
+// the message types each bot wants, so media only wakes the bots that can do something with it
+const MESSAGE_TYPES: &[(&str, &[&str])] = &[
+    ("home", &["m.text"]),
+    ("money", &["m.text"]),
+    ("owen", &["m.text"]),
+    ("ai", &["m.text"]),
+    ("photo", &["m.text", "m.image", "m.video", "m.file"]),
+];
+
+// ROOM_BOTS (a JSON map of room ID to bot names) pins rooms to particular bots; rooms that aren't
+// listed are open to all of them
+static ROOM_BOTS: Lazy<HashMap<String, Vec<String>>> = Lazy::new(|| config::load("ROOM_BOTS"));
+
+// Every bot logs in to the same account, so every bot sees every event. This decides which of them
+// should actually handle one: the bot has to care about that type of message, the room has to
+// allow the bot, and the room's space has to have it turned on. The cheap checks go first, and
+// the spaces come from the cache, so most events are dropped without asking the homeserver.
+pub async fn routes_to(
+    client: &Client,
+    bot: &str,
+    room_id: &RoomId,
+    event: &SyncMessageEvent<MessageEventContent>,
+) -> bool {
+    let msgtype = event.content.msgtype.msgtype();
+
+    let wanted = MESSAGE_TYPES
+        .iter()
+        .find(|(name, _)| *name == bot)
+        .map(|(_, types)| types.contains(&msgtype))
+        .unwrap_or(true);
+
+    wanted && active_in(client, bot, room_id).await
+}
+
+// whether a bot is allowed in a room at all, whatever the message
+pub async fn active_in(client: &Client, bot: &str, room_id: &RoomId) -> bool {
+    allowed_in(bot, room_id.as_str()) && feature_enabled(client, room_id, bot).await
+}
+
+// whether ROOM_BOTS lets a bot into a room (by ID or alias); rooms it doesn't list let in anyone
+fn allowed_in(bot: &str, room: &str) -> bool {
+    ROOM_BOTS
+        .get(room)
+        .map(|bots| bots.iter().any(|b| b == bot))
+        .unwrap_or(true)
+}
+
 pub fn find_command<'a>(prefixes: Vec<&str>, message: &'a str) -> Option<&'a str> {
     for prefix in &prefixes {
         if let Some(command) = get_command(prefix, message) {
@@ -149,6 +695,19 @@ pub fn get_command<'a>(prefix: &str, message: &'a str) -> Option<&'a str> {
     Option::None
 }
 
+// A redaction, with just what the bots need. The SDK won't hand ruma's own redaction type to an
+// event handler, so this stands in for it.
+#[derive(Deserialize)]
+pub struct Redaction {
+    pub redacts: String,
+    pub sender: UserId,
+}
+
+impl SyncEvent for Redaction {
+    const ID: (EventKind, &'static str) =
+        (EventKind::Message { redacted: false }, "m.room.redaction");
+}
+
 async fn on_room_invitation(
     room_member: StrippedStateEvent<MemberEventContent>,
     client: Client,
@@ -185,6 +744,79 @@ async fn on_room_invitation(
     }
 }
 
+// Invites only go so far: a room with a "restricted" join rule lets in anyone from the spaces it
+// names, without one, and a "knock" room wants to be asked. So at startup, each bot joins the rooms
+// in JOIN_ROOMS (a JSON list of room IDs or aliases) that ROOM_BOTS lets it into, and every room
+// in the spaces in SPACES that turn it on, that it isn't in already. Anything that won't have us
+// gets a knock, which turns into an invite (and so a join) once someone lets us in.
+async fn join_rooms(client: Client, bot: String) {
+    let mut rooms: Vec<(RoomIdOrAliasId, Vec<Box<ServerName>>)> = vec![];
+
+    for room in config::load::<Vec<String>>("JOIN_ROOMS") {
+        match RoomIdOrAliasId::try_from(room.as_str()) {
+            Ok(room) => rooms.push((room, vec![])),
+            Err(_) => println!("{} in JOIN_ROOMS is not a room ID or alias", room),
+        }
+    }
+
+    for (name, features) in SPACES.iter() {
+        if !features.contains(&bot) {
+            continue;
+        }
+
+        let space = match find_space(&client, name).await {
+            Some(space) => space,
+            None => continue,
+        };
+
+        match space_children(&space).await {
+            Ok(children) => {
+                for (room_id, via) in children {
+                    rooms.push((room_id.into(), via));
+                }
+            }
+            Err(e) => println!("could not list the rooms in {}: {}", name, e),
+        }
+    }
+
+    for (room, via) in rooms {
+        if !allowed_in(&bot, room.as_str()) {
+            continue;
+        }
+
+        let joined = RoomId::try_from(room.as_str())
+            .map(|room_id| client.get_joined_room(&room_id).is_some())
+            .unwrap_or(false);
+
+        if !joined {
+            join_uninvited(&client, &room, &via).await;
+        }
+    }
+}
+
+// joins a room without an invite if it'll have us, or knocks if it won't, logging how it went
+async fn join_uninvited(client: &Client, room: &RoomIdOrAliasId, via: &[Box<ServerName>]) {
+    let join_error = match client.join_room_by_id_or_alias(room, via).await {
+        Ok(_) => {
+            println!("joined {} without an invite", room);
+            return;
+        }
+        Err(e) => e,
+    };
+
+    let mut request = knock_room::Request::new(room.clone());
+    request.reason = Some("A bot would like to join.");
+    request.server_name = via;
+
+    match client.send(request, None).await {
+        Ok(_) => println!(
+            "knocked on {}, since we couldn't join ({})",
+            room, join_error
+        ),
+        Err(e) => println!("could not join {} ({}) or knock ({})", room, join_error, e),
+    }
+}
+
 pub async fn create_client(bot_name: &str) -> anyhow::Result<Client> {
     let username = env::var("USERNAME").expect("USERNAME environmental variable not set");
 
@@ -192,12 +824,11 @@ pub async fn create_client(bot_name: &str) -> anyhow::Result<Client> {
 
     let homeserver = env::var("HOMESERVER").expect("HOMESERVER environmental variable not set");
 
-    let mut config = dirs::config_dir().expect("no config directory found");
-    config.push(bot_name);
+    let store = storage::dir(bot_name, Area::Store)?;
 
-    println!("saving configuration to {:?}", config);
+    println!("saving configuration to {:?}", store);
 
-    let client_config = ClientConfig::new().store_path(config);
+    let client_config = ClientConfig::new().store_path(store);
     let homeserver_url = Url::parse(&homeserver).expect("invalid homeserver url");
     let client = Client::new_with_config(homeserver_url, client_config).unwrap();
 
@@ -207,8 +838,30 @@ pub async fn create_client(bot_name: &str) -> anyhow::Result<Client> {
 
     println!("logged in as {}", username);
 
+    // bad config should stop us now, not when the first message comes in
+    Lazy::force(&SPACES);
+    Lazy::force(&COMMAND_PREFIXES);
+    Lazy::force(&ROOM_BOTS);
+
     client.sync_once(SyncSettings::default()).await.unwrap();
     client.register_event_handler(on_room_invitation).await;
+    // the bot's name without the "bot", as it goes in SPACES and ROOM_BOTS
+    let bot = bot_name.trim_end_matches("bot").to_string();
+
+    client
+        .register_event_handler({
+            let bot = bot.clone();
+
+            move |event: SyncStateEvent<ChildEventContent>, room: Room, client: Client| {
+                let bot = bot.clone();
+
+                async move { on_space_child(event, room, client, &bot).await }
+            }
+        })
+        .await;
+    client.register_event_handler(on_room_member).await;
+
+    tokio::spawn(join_rooms(client.clone(), bot));
 
     Ok(client)
 }
@@ -221,6 +874,435 @@ pub fn text_html(plain: &str, html: &str) -> impl Into<AnyMessageEventContent> {
     AnyMessageEventContent::RoomMessage(MessageEventContent::text_html(plain, html))
 }
 
+// Events can't be over 64KB, and the homeserver turns away anything that is. That's the whole
+// event, not just its content, so what the homeserver adds to ours when it's sent (its ID, hashes,
+// signatures and the events it comes after) gets some room too.
+const MAX_EVENT_SIZE: usize = 64 * 1024;
+const SERVER_FIELDS_SIZE: usize = 4 * 1024;
+
+// how much text goes in each message when a long one is split up, and how many messages it's
+// split into before it goes as a file instead
+const SPLIT_SIZE: usize = 16 * 1024;
+const MAX_PARTS: usize = 4;
+
+// Sends a message, like Joined::send, except one too big for a single event is split into a few
+// at its line breaks, or uploaded as a file if it'd take more than that. Split messages lose their
+// formatting, since there's no cutting HTML safely; a file keeps it. Returns the first message.
+pub async fn send(
+    room: &Joined,
+    content: impl Into<AnyMessageEventContent>,
+) -> anyhow::Result<send_message_event::Response> {
+    let content = content.into();
+
+    if event_size(room, &content)? + SERVER_FIELDS_SIZE <= MAX_EVENT_SIZE {
+        return Ok(room.send(content, None).await?);
+    }
+
+    let (body, html) = match content {
+        AnyMessageEventContent::RoomMessage(MessageEventContent {
+            msgtype: MessageType::Text(text),
+            ..
+        }) => (text.body, text.formatted.map(|f| f.body)),
+        _ => anyhow::bail!("That message is too big to send."),
+    };
+
+    let parts = split_text(&body, SPLIT_SIZE);
+
+    if parts.len() > MAX_PARTS {
+        println!("sending a {} byte message as a file", body.len());
+
+        let (name, mime_type, data) = match html {
+            Some(html) => ("message.html", mime::TEXT_HTML, html),
+            None => ("message.txt", mime::TEXT_PLAIN, body),
+        };
+
+        return Ok(room
+            .send_attachment(name, &mime_type, &mut data.as_bytes(), None)
+            .await?);
+    }
+
+    println!("splitting a {} byte message in {}", body.len(), parts.len());
+
+    let mut first = None;
+
+    for part in parts {
+        let response = room.send(text_plain(&part), None).await?;
+        first.get_or_insert(response);
+    }
+
+    Ok(first.expect("a message too big to send has at least one part"))
+}
+
+// sends a message to a room we aren't already answering something in
+pub async fn send_to(
+    client: &Client,
+    room_id: &RoomId,
+    content: impl Into<AnyMessageEventContent>,
+) -> anyhow::Result<send_message_event::Response> {
+    match client.get_joined_room(room_id) {
+        Some(room) => send(&room, content).await,
+        None => bail!("not in room {}", room_id),
+    }
+}
+
+// how big an event is, as we send it
+fn event_size(room: &Joined, content: &AnyMessageEventContent) -> anyhow::Result<usize> {
+    let event = serde_json::json!({
+        "type": content.event_type(),
+        "room_id": room.room_id(),
+        "sender": room.own_user_id(),
+        "content": content,
+    });
+
+    Ok(serde_json::to_vec(&event)?.len())
+}
+
+// Cuts text into pieces of no more than `size` bytes, between lines where it can, and anywhere
+// (but the middle of a character) where it can't.
+fn split_text(text: &str, size: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+
+    for line in text.split_inclusive('\n') {
+        if part.len() + line.len() > size && !part.is_empty() {
+            parts.push(part.trim_end().to_string());
+            part.clear();
+        }
+
+        let mut line = line;
+
+        while line.len() > size {
+            let mut cut = size;
+
+            while !line.is_char_boundary(cut) {
+                cut -= 1;
+            }
+
+            parts.push(line[..cut].to_string());
+            line = &line[cut..];
+        }
+
+        part.push_str(line);
+    }
+
+    if !part.trim().is_empty() {
+        parts.push(part.trim_end().to_string());
+    }
+
+    parts
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Sends event content as is, for anything ruma doesn't have a type for yet (like threads), or
+// that's easier to write out by hand, returning the ID of the new event.
+pub async fn send_raw(
+    room: &Joined,
+    event_type: &str,
+    content: serde_json::Value,
+) -> anyhow::Result<EventId> {
+    let content = CustomEventContent {
+        event_type: event_type.to_string(),
+        data: serde_json::from_value(content)?,
+    };
+
+    let response = room
+        .send(AnyMessageEventContent::_Custom(content), None)
+        .await?;
+
+    Ok(response.event_id)
+}
+
+// reacts to an event with an emoji (or any other key), returning the reaction so it can be taken
+// back later
+pub async fn react(room: &Joined, event_id: &str, key: &str) -> anyhow::Result<EventId> {
+    let content = serde_json::json!({
+        "m.relates_to": {
+            "rel_type": "m.annotation",
+            "event_id": event_id,
+            "key": key,
+        }
+    });
+
+    send_raw(room, "m.reaction", content).await
+}
+
+// takes back something the bot sent, like a reaction that isn't true anymore
+pub async fn redact(room: &Joined, event_id: &EventId) -> anyhow::Result<()> {
+    room.redact(event_id, None, None).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Sticker {
+    url: String,
+    info: Option<serde_json::Value>,
+}
+
+// STICKERS is a JSON map of sticker name to its mxc:// URL and, optionally, its info (w, h,
+// mimetype), so clients can size it before it loads
+fn sticker_pack() -> HashMap<String, Sticker> {
+    config::load("STICKERS")
+}
+
+// sends a sticker from the pack, or the fallback as plain text if there's no sticker by that name
+pub async fn send_sticker(room: &Joined, name: &str, fallback: &str) -> anyhow::Result<()> {
+    let sticker = match sticker_pack().remove(name) {
+        Some(sticker) => sticker,
+        None => {
+            room.send(text_plain(fallback), None).await?;
+            return Ok(());
+        }
+    };
+
+    let mut content = serde_json::json!({
+        "body": fallback,
+        "url": sticker.url,
+    });
+
+    if let Some(info) = sticker.info {
+        content["info"] = info;
+    }
+
+    send_raw(room, "m.sticker", content).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Pinned {
+    content: PinnedEventsEventContent,
+}
+
+// what's pinned to the top of a room, as of the last sync
+async fn pinned_events(room: &Joined) -> anyhow::Result<Vec<EventId>> {
+    let event = room
+        .get_state_event(EventType::RoomPinnedEvents, "")
+        .await?;
+
+    Ok(event
+        .and_then(|raw| raw.deserialize_as::<Pinned>().ok())
+        .map(|pinned| pinned.content.pinned)
+        .unwrap_or_default())
+}
+
+// pins an event to the top of the room, next to anything that's already there
+pub async fn pin(room: &Joined, event_id: &EventId) -> anyhow::Result<()> {
+    let mut pinned = pinned_events(room).await?;
+
+    if !pinned.contains(event_id) {
+        pinned.push(event_id.clone());
+        room.send_state_event(PinnedEventsEventContent::new(pinned), "")
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn unpin(room: &Joined, event_id: &EventId) -> anyhow::Result<()> {
+    let mut pinned = pinned_events(room).await?;
+
+    if pinned.contains(event_id) {
+        pinned.retain(|id| id != event_id);
+        room.send_state_event(PinnedEventsEventContent::new(pinned), "")
+            .await?;
+    }
+
+    Ok(())
+}
+
+// Changes the text of a message we've sent. Clients that don't know about edits show the new text
+// as a message of its own, starred.
+pub async fn edit(room: &Joined, event_id: &EventId, message: &str) -> anyhow::Result<()> {
+    let content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": format!("* {}", message),
+        "m.new_content": {
+            "msgtype": "m.text",
+            "body": message,
+        },
+        "m.relates_to": {
+            "rel_type": "m.replace",
+            "event_id": event_id.as_str(),
+        }
+    });
+
+    send_raw(room, "m.room.message", content).await?;
+
+    Ok(())
+}
+
+// how long a new direct chat gets to show up in a sync before we give up on it
+const DIRECT_ROOM_WAIT: Duration = Duration::from_secs(10);
+
+// Our direct chat with someone, started (and marked as direct, so it's found next time) if there
+// isn't one yet. They still have to accept the invite to see anything in it.
+pub async fn direct_room(client: &Client, user_id: &UserId) -> anyhow::Result<Joined> {
+    if let Some(room) = client
+        .joined_rooms()
+        .into_iter()
+        .find(|room| room.is_direct() && room.direct_target().as_ref() == Some(user_id))
+    {
+        return Ok(room);
+    }
+
+    println!("starting a direct chat with {}", user_id);
+
+    let invite = [user_id.clone()];
+    let mut request = create_room::Request::new();
+    request.invite = &invite;
+    request.is_direct = true;
+    request.preset = Some(RoomPreset::TrustedPrivateChat);
+
+    let room_id = client.create_room(request).await?.room_id;
+
+    // m.direct is the whole list, so this one is added to whatever's there
+    let mut direct = client
+        .store()
+        .get_account_data_event(EventType::Direct)
+        .await?
+        .map(|raw| raw.deserialize())
+        .transpose()?
+        .and_then(|event| match event.content() {
+            AnyGlobalAccountDataEventContent::Direct(direct) => Some(direct),
+            _ => None,
+        })
+        .unwrap_or_else(|| DirectEventContent(Default::default()));
+
+    direct
+        .entry(user_id.clone())
+        .or_default()
+        .push(room_id.clone());
+
+    let own_user_id = match client.user_id().await {
+        Some(user_id) => user_id,
+        None => bail!("not logged in"),
+    };
+
+    let content = AnyGlobalAccountDataEventContent::Direct(direct);
+    let data = serde_json::value::to_raw_value(&content)?;
+    let request = set_global_account_data::Request::new(&data, content.event_type(), &own_user_id);
+    client.send(request, None).await?;
+
+    // the room isn't ours to use until a sync has brought it in
+    let started = time::Instant::now();
+
+    loop {
+        if let Some(room) = client.get_joined_room(&room_id) {
+            return Ok(room);
+        }
+
+        if started.elapsed() > DIRECT_ROOM_WAIT {
+            bail!("the direct chat with {} never showed up", user_id);
+        }
+
+        time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+// replies in a thread off the given event, falling back to a plain reply for clients that don't
+// know about threads
+pub async fn send_thread_reply(room: &Joined, root: &str, message: &str) -> anyhow::Result<()> {
+    let content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": message,
+        "m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": root,
+            "is_falling_back": true,
+            "m.in_reply_to": {
+                "event_id": root,
+            },
+        }
+    });
+
+    send_raw(room, "m.room.message", content).await?;
+
+    Ok(())
+}
+
+// puts a JPEG on the homeserver, returning its mxc:// URL
+pub async fn upload_jpeg(client: &Client, jpeg: &Bytes) -> anyhow::Result<String> {
+    let response = client
+        .upload(&mime::IMAGE_JPEG, &mut Cursor::new(jpeg.to_vec()))
+        .await?;
+
+    Ok(response.content_uri.to_string())
+}
+
+// uploads a JPEG and posts it to the room, with the body as its caption
+pub async fn send_image(
+    client: &Client,
+    room: &Joined,
+    caption: &str,
+    jpeg: &Bytes,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<EventId> {
+    let url = upload_jpeg(client, jpeg).await?;
+
+    let content = serde_json::json!({
+        "msgtype": "m.image",
+        "body": caption,
+        "filename": "collage.jpg",
+        "url": url,
+        "info": {
+            "mimetype": "image/jpeg",
+            "size": jpeg.len(),
+            "w": width,
+            "h": height,
+        },
+    });
+
+    send_raw(room, "m.room.message", content).await
+}
+
+// Uploads a JPEG and replies with it in a thread off the given event. The body is the caption,
+// which clients that know about captions show under the image.
+pub async fn send_thread_image(
+    client: &Client,
+    room: &Joined,
+    root: &str,
+    caption: &str,
+    jpeg: &Bytes,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<()> {
+    let url = upload_jpeg(client, jpeg).await?;
+
+    let content = serde_json::json!({
+        "msgtype": "m.image",
+        "body": caption,
+        "filename": "thumbnail.jpg",
+        "url": url,
+        "info": {
+            "mimetype": "image/jpeg",
+            "size": jpeg.len(),
+            "w": width,
+            "h": height,
+        },
+        "m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": root,
+            "is_falling_back": true,
+            "m.in_reply_to": {
+                "event_id": root,
+            },
+        }
+    });
+
+    send_raw(room, "m.room.message", content).await?;
+
+    Ok(())
+}
+
 pub fn normalize_sender(sender: UserId, command: &str) -> anyhow::Result<UserId> {
     let sender = if !command.is_empty() {
         create_user_id(command)?
@@ -231,10 +1313,34 @@ pub fn normalize_sender(sender: UserId, command: &str) -> anyhow::Result<UserId>
     Ok(sender)
 }
 
+// Bridges (WhatsApp, Signal, and the like) relay messages from ghost users, like
+// @whatsapp_15551234567:kulak.us, each standing in for someone in the family. BRIDGED_USERS is a
+// JSON map of ghost user ID to whose they are, so the bots treat them as one person.
+static BRIDGED_USERS: Lazy<HashMap<String, UserId>> = Lazy::new(|| {
+    let bridged: HashMap<String, String> = config::load("BRIDGED_USERS");
+
+    bridged
+        .into_iter()
+        .map(|(ghost, user_id)| {
+            let user_id =
+                UserId::try_from(user_id.as_str()).expect("BRIDGED_USERS has an invalid user ID");
+            (ghost.to_lowercase(), user_id)
+        })
+        .collect()
+});
+
+// the family member behind a bridged ghost user, or the user itself if it isn't one
+pub fn canonical_user_id(user_id: &UserId) -> UserId {
+    BRIDGED_USERS
+        .get(&user_id.as_str().to_lowercase())
+        .cloned()
+        .unwrap_or_else(|| user_id.clone())
+}
+
 pub fn create_user_id(id: &str) -> anyhow::Result<UserId> {
     let id = id.to_lowercase();
     let id = id.trim();
-    let id = id.trim_end_matches(&['.', '!', '?']);
+    let id = id.trim_end_matches(['.', '!', '?']);
 
     let id = if id == "dad" {
         UserId::try_from("@phil:kulak.us")?
@@ -244,7 +1350,7 @@ pub fn create_user_id(id: &str) -> anyhow::Result<UserId> {
         UserId::parse_with_server_name(id, <&ServerName>::try_from("kulak.us")?)?
     };
 
-    Ok(id)
+    Ok(canonical_user_id(&id))
 }
 
 pub fn pretty_user_id(user_id: &UserId) -> String {
@@ -264,6 +1370,8 @@ pub fn pretty_user_id(user_id: &UserId) -> String {
 }
 
 pub fn is_admin(user_id: &UserId) -> bool {
+    let user_id = canonical_user_id(user_id);
+
     user_id.as_ref().eq_ignore_ascii_case("@phil:kulak.us")
         || user_id.as_ref().eq_ignore_ascii_case("@gwen:kulak.us")
 }
@@ -272,13 +1380,78 @@ pub fn money_to_i64(money: &Money<Currency>) -> i64 {
     (money.clone() * 100isize).amount().to_i64().unwrap()
 }
 
-pub async fn download_photo(uri: &MxcUri) -> anyhow::Result<Bytes> {
-    let id = uri.media_id().unwrap();
-    let url = format!("https://kulak.us/_matrix/media/r0/download/kulak.us/{}", id);
+// where media is downloaded from, unless something else is standing in for it
+const MEDIA_SERVER: &str = "https://kulak.us";
 
-    // download the image to memory
-    let response = reqwest::Client::new().get(url).send().await?;
-    let photo = response.bytes().await?;
+// somewhere media is downloaded from: the homeserver, or whatever stands in for it in a test
+#[async_trait]
+pub trait Media: Send + Sync {
+    async fn download(&self, uri: &MxcUri) -> anyhow::Result<Bytes>;
+}
 
-    Ok(photo)
+// the media API of a homeserver, at its base URL
+pub struct Homeserver {
+    url: String,
+}
+
+impl Homeserver {
+    pub fn new(url: &str) -> Homeserver {
+        Homeserver {
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl Default for Homeserver {
+    fn default() -> Homeserver {
+        Homeserver::new(MEDIA_SERVER)
+    }
+}
+
+#[async_trait]
+impl Media for Homeserver {
+    async fn download(&self, uri: &MxcUri) -> anyhow::Result<Bytes> {
+        let (server, id) = match uri.parts() {
+            Some(parts) => parts,
+            None => bail!("not a media URI: {}", uri),
+        };
+
+        let url = format!("{}/_matrix/media/r0/download/{}/{}", self.url, server, id);
+
+        // media never changes once it's uploaded, so anything seen before comes from the cache
+        cache::get_url(&url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_text() {
+        assert_eq!(split_text("one\ntwo\nthree\n", 8), ["one\ntwo", "three"]);
+        assert_eq!(split_text("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+
+        // never through the middle of a character
+        assert_eq!(split_text("ééé", 3), ["é", "é", "é"]);
+    }
+
+    #[test]
+    fn strips_reply_fallbacks() {
+        let reply = "> <@phil:kulak.us> sent an image.\n\nsherman, what is this?";
+        assert_eq!(strip_reply_fallback(reply), "sherman, what is this?");
+
+        let quote = "> first line\n>\n> second\n\nwhat's that?";
+        assert_eq!(strip_reply_fallback(quote), "what's that?");
+
+        assert_eq!(strip_reply_fallback("just a message"), "just a message");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
 pub struct MessageBuffer<'a, T> {
     counter: usize,
@@ -8,7 +9,7 @@ pub struct MessageBuffer<'a, T> {
 
 // todo: this needs to be async
 impl<'a, T> MessageBuffer<'a, T> {
-    pub fn new(channel: &Receiver<T>) -> MessageBuffer<T> {
+    pub fn new(channel: &Receiver<T>) -> MessageBuffer<'_, T> {
         MessageBuffer {
             counter: 0,
             buffer: vec![],
@@ -42,6 +43,17 @@ impl<'a, T> MessageBuffer<'a, T> {
         0
     }
 
+    // if nothing's waiting, give another message up to `timeout` to show up
+    pub fn wait(&mut self, timeout: Duration) {
+        self.fill();
+
+        if self.buffer.is_empty() {
+            if let Ok(message) = self.channel.recv_timeout(timeout) {
+                self.buffer.push(message);
+            }
+        }
+    }
+
     pub fn inc(&mut self) {
         self.counter += 1;
     }
@@ -0,0 +1,32 @@
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+
+// US letter, in millimeters
+const PAGE_WIDTH: f64 = 215.9;
+const PAGE_HEIGHT: f64 = 279.4;
+
+const MARGIN: f64 = 20.0;
+const FONT_SIZE: f64 = 10.0;
+const LINE_HEIGHT: f64 = 5.0;
+
+// renders lines of plain text onto as many pages as it takes, in a monospace font so anything
+// lined up in the text stays lined up on paper
+pub fn render(title: &str, lines: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "text");
+    let font = doc.add_builtin_font(BuiltinFont::Courier)?;
+
+    let mut layer = doc.get_page(page).get_layer(layer);
+    let mut y = PAGE_HEIGHT - MARGIN;
+
+    for line in lines {
+        if y < MARGIN {
+            let (page, next) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "text");
+            layer = doc.get_page(page).get_layer(next);
+            y = PAGE_HEIGHT - MARGIN;
+        }
+
+        layer.use_text(*line, FONT_SIZE, Mm(MARGIN), Mm(y), &font);
+        y -= LINE_HEIGHT;
+    }
+
+    Ok(doc.save_to_bytes()?)
+}
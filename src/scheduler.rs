@@ -0,0 +1,517 @@
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Weekday};
+use chrono_tz::Tz;
+use chrono_tz::US::Pacific;
+
+// Runs a job forever, sleeping until whatever time `next` picks after the current time. Errors
+// are logged, and don't stop the schedule.
+pub fn spawn<N, F, Fut>(name: &'static str, next: N, job: F)
+where
+    N: Fn(DateTime<Tz>) -> DateTime<Tz> + Send + 'static,
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    tokio::spawn(async move {
+        loop {
+            let now = now();
+            let duration = next(now).signed_duration_since(now);
+            println!("{} due in {:?} minutes", name, duration.num_minutes());
+
+            tokio::time::sleep(duration.to_std().unwrap_or_default()).await;
+
+            if let Err(e) = job().await {
+                println!("Could not run {}! {}", name, e);
+            }
+
+            // sleep for a tad just to make sure we cycle over
+            tokio::time::sleep(Duration::minutes(1).to_std().unwrap()).await;
+        }
+    });
+}
+
+// the default timezone for everything, from TIMEZONE (like "America/Chicago")
+pub fn timezone() -> Tz {
+    match env::var("TIMEZONE") {
+        Ok(tz) => tz.parse().expect("TIMEZONE is not a valid timezone"),
+        Err(_) => Pacific,
+    }
+}
+
+pub fn now() -> DateTime<Tz> {
+    now_in(timezone())
+}
+
+pub fn now_in(tz: Tz) -> DateTime<Tz> {
+    tz.timestamp_millis(chrono::Utc::now().timestamp_millis())
+}
+
+// The given hour on the same day. When the clocks go back and the hour happens twice, that's the
+// first of them, and when they go forward and it's skipped, it's the next hour that does exist.
+pub fn at_hour(date: DateTime<Tz>, hour: u32) -> DateTime<Tz> {
+    let tz = date.timezone();
+    let mut local = date.naive_local().date().and_hms(hour, 0, 0);
+
+    loop {
+        if let Some(time) = tz.from_local_datetime(&local).earliest() {
+            return time;
+        }
+
+        local += Duration::hours(1);
+    }
+}
+
+// only safe for dates early enough in the month to exist in every month
+pub fn add_months(date: DateTime<Tz>, months: i32) -> DateTime<Tz> {
+    let total = date.year() * 12 + date.month0() as i32 + months;
+
+    date.with_day(1)
+        .unwrap()
+        .with_year(total.div_euclid(12))
+        .unwrap()
+        .with_month(total.rem_euclid(12) as u32 + 1)
+        .unwrap()
+        .with_day(date.day())
+        .unwrap()
+}
+
+// The top of the current hour. This works back from the time itself rather than building a local
+// time, which is ambiguous for the hour repeated when the clocks go back.
+pub fn top_of_hour(now: DateTime<Tz>) -> DateTime<Tz> {
+    now - Duration::seconds((now.minute() * 60 + now.second()) as i64)
+        - Duration::nanoseconds(now.nanosecond() as i64)
+}
+
+// the top of the next hour
+pub fn next_hour(now: DateTime<Tz>) -> DateTime<Tz> {
+    top_of_hour(now) + Duration::hours(1)
+}
+
+// the top of the next minute
+pub fn next_minute(now: DateTime<Tz>) -> DateTime<Tz> {
+    now - Duration::seconds(now.second() as i64) - Duration::nanoseconds(now.nanosecond() as i64)
+        + Duration::minutes(1)
+}
+
+// how many days ahead (or back) we'll look for the next occurrence; enough for any month
+const MAX_DAYS: i64 = 62;
+
+const WEEKDAYS: &[(Weekday, &str)] = &[
+    (Weekday::Mon, "monday"),
+    (Weekday::Tue, "tuesday"),
+    (Weekday::Wed, "wednesday"),
+    (Weekday::Thu, "thursday"),
+    (Weekday::Fri, "friday"),
+    (Weekday::Sat, "saturday"),
+    (Weekday::Sun, "sunday"),
+];
+
+const ORDINALS: &[&str] = &["first", "second", "third", "fourth", "fifth"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Days {
+    Every,
+    Weekdays,
+    Weekends,
+    Weekly(Weekday),
+    // every other week, counting from a fixed Monday so it doesn't drift across restarts
+    EveryOther(Weekday),
+    // a day of the month, only up to the 28th so every month has one
+    Monthly(u32),
+}
+
+// A schedule written the way people say it: "every other friday", "first of the month",
+// "weekdays at 7am". Anything without a time runs at 9am; anything that's only a time runs daily.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Recurrence {
+    pub days: Days,
+    pub hour: u32,
+}
+
+impl Recurrence {
+    pub fn parse(phrase: &str) -> Option<Recurrence> {
+        let phrase = phrase.trim().to_lowercase();
+
+        // nothing at all isn't every day at 9am
+        if phrase.is_empty() {
+            return None;
+        }
+
+        let (days, hour) = match phrase.rsplit_once(" at ") {
+            Some((days, time)) => (days.trim(), parse_hour(time.trim())?),
+            None => match parse_hour(&phrase) {
+                Some(hour) => ("day", hour),
+                None => (phrase.as_str(), 9),
+            },
+        };
+
+        Some(Recurrence {
+            days: parse_days(days)?,
+            hour,
+        })
+    }
+
+    // Whether the hour of the given time is one this runs in. This goes by `at_hour`, so a
+    // schedule in the hour the clocks skip runs in the hour after, and one in the hour they
+    // repeat only runs the first time around.
+    pub fn matches(&self, time: DateTime<Tz>) -> bool {
+        self.runs_on(time) && top_of_hour(time) == at_hour(time, self.hour)
+    }
+
+    // the first time this runs after now, if there's one in the next couple of months
+    pub fn next(&self, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        (0..=MAX_DAYS)
+            .map(|days| at_hour(now + Duration::days(days), self.hour))
+            .find(|time| *time > now && self.runs_on(*time))
+    }
+
+    // the last time this ran before now, if there's one in the last couple of months
+    pub fn previous(&self, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        (0..=MAX_DAYS)
+            .map(|days| at_hour(now - Duration::days(days), self.hour))
+            .find(|time| *time < now && self.runs_on(*time))
+    }
+
+    fn runs_on(&self, date: DateTime<Tz>) -> bool {
+        let weekday = date.weekday();
+
+        match self.days {
+            Days::Every => true,
+            Days::Weekdays => weekday.num_days_from_monday() < 5,
+            Days::Weekends => weekday.num_days_from_monday() >= 5,
+            Days::Weekly(day) => weekday == day,
+            Days::EveryOther(day) => {
+                // the first day of the common era was a Monday
+                weekday == day && (date.num_days_from_ce() - 1) / 7 % 2 == 0
+            }
+            Days::Monthly(day) => date.day() == day,
+        }
+    }
+}
+
+// written so it parses back into the same thing
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days = match self.days {
+            Days::Every => "every day".to_string(),
+            Days::Weekdays => "weekdays".to_string(),
+            Days::Weekends => "weekends".to_string(),
+            Days::Weekly(day) => format!("every {}", weekday_name(day)),
+            Days::EveryOther(day) => format!("every other {}", weekday_name(day)),
+            Days::Monthly(day) => format!("the {} of the month", ordinal(day)),
+        };
+
+        write!(f, "{} at {}", days, format_hour(self.hour))
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = anyhow::Error;
+
+    fn from_str(phrase: &str) -> anyhow::Result<Recurrence> {
+        Recurrence::parse(phrase).ok_or_else(|| anyhow!("I don't know when \"{}\" is.", phrase))
+    }
+}
+
+// For fixed schedules, like `scheduler::spawn("statements", scheduler::every("first of the
+// month at 9am")?, ...)`. If nothing turns up, it looks again once the window's gone by.
+pub fn every(phrase: &str) -> anyhow::Result<impl Fn(DateTime<Tz>) -> DateTime<Tz>> {
+    let recurrence: Recurrence = phrase.parse()?;
+    Ok(move |now| {
+        recurrence
+            .next(now)
+            .unwrap_or_else(|| now + Duration::days(MAX_DAYS))
+    })
+}
+
+fn parse_days(days: &str) -> Option<Days> {
+    let days = days.strip_prefix("every ").unwrap_or(days);
+    let days = days.strip_prefix("the ").unwrap_or(days);
+
+    match days {
+        "" | "day" | "daily" | "every day" => return Some(Days::Every),
+        "weekday" | "weekdays" => return Some(Days::Weekdays),
+        "weekend" | "weekends" => return Some(Days::Weekends),
+        "month" | "monthly" => return Some(Days::Monthly(1)),
+        _ => (),
+    }
+
+    if let Some(day) = days.strip_prefix("other ") {
+        return parse_weekday(day).map(Days::EveryOther);
+    }
+
+    if let Some(day) = parse_weekday(days) {
+        return Some(Days::Weekly(day));
+    }
+
+    // "first of the month", "15th of the month", or "month on the 15th"
+    let day = days
+        .strip_suffix(" of the month")
+        .or_else(|| days.strip_suffix(" of every month"))
+        .or_else(|| days.strip_prefix("month on the "))?;
+
+    let day = match ORDINALS.iter().position(|o| *o == day) {
+        Some(i) => i as u32 + 1,
+        None => day
+            .trim_end_matches(|c: char| c.is_alphabetic())
+            .parse()
+            .ok()?,
+    };
+
+    if (1..=28).contains(&day) {
+        Some(Days::Monthly(day))
+    } else {
+        None
+    }
+}
+
+// "friday", "fridays", or "fri"
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    let day = day.strip_suffix('s').unwrap_or(day);
+
+    WEEKDAYS
+        .iter()
+        .find(|(_, name)| *name == day || name.get(..3) == Some(day))
+        .map(|(weekday, _)| *weekday)
+}
+
+// "7", "7am", "7 pm", "19", "noon", or "midnight"
+pub fn parse_hour(hour: &str) -> Option<u32> {
+    let hour = hour.replace(' ', "");
+
+    match hour.as_str() {
+        "noon" => return Some(12),
+        "midnight" => return Some(0),
+        _ => (),
+    }
+
+    let (number, pm) = if let Some(h) = hour.strip_suffix("am") {
+        (h, Some(false))
+    } else if let Some(h) = hour.strip_suffix("pm") {
+        (h, Some(true))
+    } else {
+        (hour.as_str(), None)
+    };
+
+    match (number.parse::<u32>().ok()?, pm) {
+        // there's no such thing as 0am on a clock
+        (0, Some(_)) => None,
+        (12, Some(false)) => Some(0),
+        (12, Some(true)) => Some(12),
+        (h, Some(true)) if h < 12 => Some(h + 12),
+        (h, Some(false)) if h < 12 => Some(h),
+        (h, None) if h < 24 => Some(h),
+        _ => None,
+    }
+}
+
+pub fn format_hour(hour: u32) -> String {
+    match hour {
+        0 => "12am".to_string(),
+        12 => "12pm".to_string(),
+        h if h > 12 => format!("{}pm", h - 12),
+        h => format!("{}am", h),
+    }
+}
+
+fn weekday_name(day: Weekday) -> &'static str {
+    WEEKDAYS
+        .iter()
+        .find(|(weekday, _)| *weekday == day)
+        .map(|(_, name)| *name)
+        .unwrap()
+}
+
+fn ordinal(day: u32) -> String {
+    let suffix = match (day % 10, day % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+
+    format!("{}{}", day, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // in 2024, the clocks went forward on March 10th and back on November 3rd
+    fn pacific(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Tz> {
+        Pacific.ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    #[test]
+    fn at_hour_skips_the_missing_hour() {
+        let morning = pacific(2024, 3, 10, 9, 30);
+
+        assert_eq!(at_hour(morning, 2), pacific(2024, 3, 10, 3, 0));
+        assert_eq!(at_hour(morning, 7), pacific(2024, 3, 10, 7, 0));
+    }
+
+    #[test]
+    fn at_hour_takes_the_first_repeated_hour() {
+        let morning = pacific(2024, 11, 3, 9, 30);
+        let first = Pacific
+            .from_local_datetime(&morning.naive_local().date().and_hms(1, 0, 0))
+            .earliest()
+            .unwrap();
+
+        assert_eq!(at_hour(morning, 1), first);
+    }
+
+    #[test]
+    fn next_hour_gets_through_the_repeated_hour() {
+        let first = Pacific
+            .from_local_datetime(
+                &pacific(2024, 11, 3, 0, 0)
+                    .naive_local()
+                    .date()
+                    .and_hms(1, 30, 0),
+            )
+            .earliest()
+            .unwrap();
+
+        // 1:30 the first time, then 1:00 and 2:00 the second time (an hour apart each)
+        let second = next_hour(first);
+        assert_eq!(second - first, Duration::minutes(30));
+        assert_eq!(second.hour(), 1);
+
+        let third = next_hour(second + Duration::minutes(1));
+        assert_eq!(third - second, Duration::hours(1));
+        assert_eq!(third.hour(), 2);
+    }
+
+    #[test]
+    fn next_hour_across_the_missing_hour() {
+        let before = pacific(2024, 3, 10, 1, 45);
+
+        assert_eq!(next_hour(before), pacific(2024, 3, 10, 3, 0));
+    }
+
+    #[test]
+    fn parses_hours() {
+        for (hour, parsed) in [
+            ("midnight", Some(0)),
+            ("12am", Some(0)),
+            ("0", Some(0)),
+            ("1am", Some(1)),
+            ("7", Some(7)),
+            ("7 am", Some(7)),
+            ("11am", Some(11)),
+            ("noon", Some(12)),
+            ("12pm", Some(12)),
+            ("12", Some(12)),
+            ("1pm", Some(13)),
+            ("7 pm", Some(19)),
+            ("11pm", Some(23)),
+            ("23", Some(23)),
+            ("24", None),
+            ("13pm", None),
+            ("13am", None),
+            ("0am", None),
+            ("0pm", None),
+            ("-1", None),
+            ("7:30pm", None),
+            ("pm", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_hour(hour), parsed, "{:?}", hour);
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let parse = |phrase| Recurrence::parse(phrase).unwrap();
+
+        assert_eq!(parse("weekdays at 7am").days, Days::Weekdays);
+        assert_eq!(parse("weekdays at 7am").hour, 7);
+        assert_eq!(
+            parse("every other friday").days,
+            Days::EveryOther(Weekday::Fri)
+        );
+        assert_eq!(parse("every other friday").hour, 9);
+        assert_eq!(parse("Fridays at 5 PM").days, Days::Weekly(Weekday::Fri));
+        assert_eq!(parse("Fridays at 5 PM").hour, 17);
+        assert_eq!(parse("first of the month at noon").days, Days::Monthly(1));
+        assert_eq!(parse("the 15th of the month").days, Days::Monthly(15));
+        assert_eq!(parse("month on the 3rd").days, Days::Monthly(3));
+        assert_eq!(parse("7pm").days, Days::Every);
+        assert_eq!(parse("7pm").hour, 19);
+        assert_eq!(parse("midnight").hour, 0);
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_schedule() {
+        assert!(Recurrence::parse("whenever").is_none());
+        assert!(Recurrence::parse("").is_none());
+        assert!(Recurrence::parse("the 31st of the month").is_none());
+        assert!(Recurrence::parse("fridays at 13pm").is_none());
+        assert!(Recurrence::parse("fridays at 25").is_none());
+        assert!("someday".parse::<Recurrence>().is_err());
+        assert!(every("someday").is_err());
+    }
+
+    #[test]
+    fn displays_what_it_parses() {
+        for phrase in [
+            "every day at 7am",
+            "weekdays at 12pm",
+            "every friday at 5pm",
+            "every other monday at 12am",
+            "the 15th of the month at 9am",
+        ] {
+            assert_eq!(Recurrence::parse(phrase).unwrap().to_string(), phrase);
+        }
+    }
+
+    #[test]
+    fn next_and_previous() {
+        let fridays = Recurrence::parse("fridays at 9am").unwrap();
+        let wednesday = pacific(2024, 5, 15, 12, 0);
+
+        assert_eq!(fridays.next(wednesday), Some(pacific(2024, 5, 17, 9, 0)));
+        assert_eq!(
+            fridays.previous(wednesday),
+            Some(pacific(2024, 5, 10, 9, 0))
+        );
+
+        // right on time is neither next nor previous
+        let friday = pacific(2024, 5, 17, 9, 0);
+        assert_eq!(fridays.next(friday), Some(pacific(2024, 5, 24, 9, 0)));
+        assert_eq!(fridays.previous(friday), Some(pacific(2024, 5, 10, 9, 0)));
+    }
+
+    #[test]
+    fn runs_in_the_hour_after_a_skipped_one() {
+        let early = Recurrence::parse("every day at 2am").unwrap();
+
+        assert_eq!(
+            early.next(pacific(2024, 3, 9, 12, 0)),
+            Some(pacific(2024, 3, 10, 3, 0))
+        );
+        assert!(early.matches(pacific(2024, 3, 10, 3, 15)));
+        assert!(!early.matches(pacific(2024, 3, 10, 4, 15)));
+        assert!(early.matches(pacific(2024, 3, 11, 2, 15)));
+        assert!(!early.matches(pacific(2024, 3, 11, 3, 15)));
+    }
+
+    #[test]
+    fn runs_once_in_a_repeated_hour() {
+        let early = Recurrence::parse("every day at 1am").unwrap();
+        let first = at_hour(pacific(2024, 11, 3, 9, 0), 1);
+        let second = first + Duration::hours(1);
+
+        assert_eq!(second.hour(), 1);
+        assert!(early.matches(first));
+        assert!(!early.matches(second));
+        assert_eq!(early.next(first), Some(pacific(2024, 11, 4, 1, 0)));
+        assert_eq!(early.previous(second), Some(first));
+    }
+}
@@ -0,0 +1,272 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail};
+use rusqlite::Connection;
+use tokio::sync::oneshot;
+
+use crate::config;
+
+// how long a write waits on another connection's write before giving up with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// opens the database shared by all of the bots, for things like logs and reports
+pub fn open_shared() -> anyhow::Result<Connection> {
+    open("bots")
+}
+
+// how long something can sit in a media cache before cleaning up takes it
+const MEDIA_DAYS: u64 = 1;
+
+// Each bot keeps everything it has in a directory of its own, under BOTS_DATA_DIR (or the usual
+// config directory): its database, and a directory for each of these that it uses. Only the
+// store is kept per bot; the rest are shared, under "bots".
+#[derive(Clone, Copy)]
+pub enum Area {
+    // what the Matrix SDK keeps between runs (sync state and encryption keys)
+    Store,
+    // files being worked on, which can go whenever nothing's using them
+    Media,
+    // downloads kept around in case they're needed again, which look after their own size
+    Cache,
+}
+
+const AREAS: [Area; 3] = [Area::Store, Area::Media, Area::Cache];
+
+impl Area {
+    fn name(self) -> &'static str {
+        match self {
+            Area::Store => "store",
+            Area::Media => "media",
+            Area::Cache => "cache",
+        }
+    }
+}
+
+pub fn root() -> PathBuf {
+    match env::var("BOTS_DATA_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => dirs::config_dir().expect("no config directory found"),
+    }
+}
+
+// everything with a directory: each bot, the help bot, and what's shared between them
+fn bot_names() -> Vec<String> {
+    let mut names: Vec<String> = config::BOTS
+        .iter()
+        .map(|bot| format!("{}bot", bot))
+        .collect();
+    names.push("helpbot".to_string());
+    names.push("bots".to_string());
+
+    names
+}
+
+// where a bot's database lives
+pub fn path(bot_name: &str) -> PathBuf {
+    root().join(bot_name).join("database")
+}
+
+// One of a bot's directories, created if it isn't there yet. The Matrix SDK used to keep its
+// store right in the bot's directory, so that's moved over the first time through, rather than
+// logging in as a new device without any of the old keys.
+pub fn dir(bot_name: &str, area: Area) -> anyhow::Result<PathBuf> {
+    let bot_dir = root().join(bot_name);
+    let dir = bot_dir.join(area.name());
+
+    if let (Area::Store, false) = (area, dir.exists()) {
+        fs::create_dir_all(&dir)?;
+
+        for name in ["matrix-sdk-state", "matrix-sdk-crypto"] {
+            if bot_dir.join(name).exists() {
+                println!("moving {}'s {} into {:?}", bot_name, name, dir);
+                fs::rename(bot_dir.join(name), dir.join(name))?;
+            }
+        }
+    }
+
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
+
+// How much each bot has on disk, in its database and each of its directories, for the bots that
+// have anything at all.
+pub fn usage() -> Vec<(String, Vec<(&'static str, u64)>)> {
+    bot_names()
+        .into_iter()
+        .filter(|bot_name| root().join(bot_name).exists())
+        .map(|bot_name| {
+            let bot_dir = root().join(&bot_name);
+
+            // the write-ahead log and its index are the database too
+            let database = ["database", "database-wal", "database-shm"]
+                .iter()
+                .map(|name| size(&bot_dir.join(name)))
+                .sum();
+
+            let mut areas = vec![("database", database)];
+
+            for area in AREAS {
+                let dir = bot_dir.join(area.name());
+
+                if dir.exists() {
+                    areas.push((area.name(), size(&dir)));
+                }
+            }
+
+            (bot_name, areas)
+        })
+        .collect()
+}
+
+// Empties the media directories (of one bot, or all of them) of anything that's been there a
+// while, returning how many bytes that freed up. Stores are left alone; they're still needed. So
+// are caches, which are kept under their own size limit as they fill up.
+pub fn clean(bot_name: Option<&str>) -> anyhow::Result<u64> {
+    let bot_names = match bot_name {
+        Some(bot_name) if bot_names().iter().any(|name| name == bot_name) => {
+            vec![bot_name.to_string()]
+        }
+        Some(bot_name) => bail!("There's no bot called {}.", bot_name),
+        None => bot_names(),
+    };
+
+    let cutoff = SystemTime::now() - Duration::from_secs(MEDIA_DAYS * 24 * 60 * 60);
+    let mut freed = 0;
+
+    for bot_name in bot_names {
+        let media = root().join(bot_name).join(Area::Media.name());
+
+        if !media.exists() {
+            continue;
+        }
+
+        for entry in fs::read_dir(media)? {
+            let path = entry?.path();
+
+            if fs::metadata(&path)?.modified()? > cutoff {
+                continue;
+            }
+
+            let bytes = size(&path);
+
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+
+            freed += bytes;
+        }
+    }
+
+    Ok(freed)
+}
+
+// everything in a file or directory, or nothing if it isn't there
+fn size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+// like "1.5 GB"
+pub fn pretty_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+
+    for unit in ["bytes", "KB", "MB", "GB"] {
+        if size < 1024.0 {
+            return match unit {
+                "bytes" => format!("{} bytes", bytes),
+                unit => format!("{:.1} {}", size, unit),
+            };
+        }
+
+        size /= 1024.0;
+    }
+
+    format!("{:.1} TB", size)
+}
+
+// Opens (creating the directory if needed) the database for a single bot. Everything runs in WAL
+// mode, so readers never wait on a writer, and writers wait on each other for a bit rather than
+// failing right away.
+pub fn open(bot_name: &str) -> anyhow::Result<Connection> {
+    let db_file = path(bot_name);
+
+    if let Some(dir) = db_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let conn = Connection::open(db_file)?;
+
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+    Ok(conn)
+}
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+// A bot's database, living on a thread of its own so queries never block whatever task is waiting
+// on them. Jobs run one at a time, in the order they were sent, so a job that reads and then writes
+// can't be interleaved with another one.
+#[derive(Clone)]
+pub struct Db {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Db {
+    pub fn open(bot_name: &str) -> anyhow::Result<Db> {
+        let mut conn = open(bot_name)?;
+        let (jobs, rx) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name(format!("{} database", bot_name))
+            .spawn(move || {
+                for job in rx {
+                    job(&mut conn);
+                }
+            })?;
+
+        Ok(Db { jobs })
+    }
+
+    // runs the closure on the database thread, and waits (asynchronously) for what it returns
+    pub async fn call<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.jobs
+            .send(Box::new(move |conn| {
+                // nobody's waiting anymore if this fails, so there's no one to tell
+                let _ = tx.send(f(conn));
+            }))
+            .map_err(|_| anyhow!("the database thread has stopped"))?;
+
+        rx.await?
+    }
+}
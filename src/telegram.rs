@@ -0,0 +1,104 @@
+use std::env;
+
+use anyhow::bail;
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+
+// what's in front of a chat ID in a recipient's address, like "telegram:123456789"
+const PREFIX: &str = "telegram:";
+
+// the biggest photo the Bot API takes as a photo; anything bigger goes as a file
+const MAX_PHOTO_SIZE: usize = 10 * 1024 * 1024;
+
+// Photos can be mirrored to Telegram chats with a bot from @BotFather, whose token is
+// TELEGRAM_BOT_TOKEN. Each chat is a recipient of its own, with "telegram:" and the chat's ID for
+// an address.
+fn token() -> Option<String> {
+    env::var("TELEGRAM_BOT_TOKEN").ok()
+}
+
+pub fn enabled() -> bool {
+    token().is_some()
+}
+
+// the chat an address stands for, if it's a Telegram one
+pub fn chat(address: &str) -> Option<&str> {
+    address
+        .strip_prefix(PREFIX)
+        .filter(|chat| !chat.is_empty() && !chat.contains(char::is_whitespace))
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    description: Option<String>,
+}
+
+// sends a photo or video to a chat, as whatever kind of message Telegram shows it best as
+pub async fn send(
+    chat: &str,
+    data: &[u8],
+    mime_type: &str,
+    caption: Option<&str>,
+) -> anyhow::Result<()> {
+    let token = match token() {
+        Some(token) => token,
+        None => bail!("TELEGRAM_BOT_TOKEN environmental variable not set"),
+    };
+
+    let (method, field) = match mime_type {
+        "image/jpeg" | "image/png" if data.len() <= MAX_PHOTO_SIZE => ("sendPhoto", "photo"),
+        "image/gif" => ("sendAnimation", "animation"),
+        mime_type if mime_type.starts_with("video/") => ("sendVideo", "video"),
+        _ => ("sendDocument", "document"),
+    };
+
+    let file_name = match mime_type {
+        "image/png" => "photo.png",
+        "image/gif" => "animation.gif",
+        mime_type if mime_type.starts_with("video/") => "video.mp4",
+        _ => "photo.jpg",
+    };
+
+    let mut form = Form::new().text("chat_id", chat.to_string()).part(
+        field,
+        Part::bytes(data.to_vec())
+            .file_name(file_name)
+            .mime_str(mime_type)?,
+    );
+
+    if let Some(caption) = caption {
+        form = form.text("caption", caption.to_string());
+    }
+
+    let url = format!("https://api.telegram.org/bot{}/{}", token, method);
+    let response: Response = reqwest::Client::new()
+        .post(url)
+        .multipart(form)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if !response.ok {
+        bail!(
+            "Telegram said no: {}",
+            response.description.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_chats() {
+        assert_eq!(chat("telegram:-100123456"), Some("-100123456"));
+        assert_eq!(chat("telegram:@family"), Some("@family"));
+        assert_eq!(chat("telegram:"), None);
+        assert_eq!(chat("grandma@example.com"), None);
+    }
+}
@@ -0,0 +1,165 @@
+// Stand-ins for the outside world, so a bot's whole pipeline can be run in a test: an SMTP server
+// that keeps whatever it's sent, and a homeserver that only serves media.
+
+use std::collections::HashMap;
+use std::net::TcpListener as StdTcpListener;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Extension, Path};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use bytes::Bytes;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::image;
+use crate::mail;
+use crate::matrix;
+
+// an email as the sink got it
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+// Takes anything sent to it over plain SMTP, from anyone, and keeps it.
+pub struct SmtpSink {
+    port: u16,
+    received: Arc<Mutex<Vec<Email>>>,
+}
+
+impl SmtpSink {
+    pub async fn start() -> anyhow::Result<SmtpSink> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let received = Arc::new(Mutex::new(vec![]));
+
+        tokio::spawn({
+            let received = received.clone();
+
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let received = received.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = smtp_session(stream, received).await {
+                            println!("SMTP sink session failed: {}", e);
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(SmtpSink { port, received })
+    }
+
+    // a connection to the sink, without TLS or logging in, sending as `from`
+    pub fn mailer(&self, from: &str) -> mail::Smtp {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(self.port)
+            .build();
+
+        mail::Smtp::new(transport, from)
+    }
+
+    pub fn received(&self) -> Vec<Email> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+// just enough SMTP to get a message from lettre
+async fn smtp_session(stream: TcpStream, received: Arc<Mutex<Vec<Email>>>) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut to = vec![];
+
+    writer.write_all(b"220 localhost ESMTP\r\n").await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let command = line.to_ascii_uppercase();
+
+        if command.starts_with("RCPT TO:") {
+            to.push(line[8..].trim().trim_matches(['<', '>']).to_string());
+        } else if command.starts_with("DATA") {
+            writer
+                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                .await?;
+
+            let mut data = vec![];
+
+            while let Some(line) = lines.next_line().await? {
+                if line == "." {
+                    break;
+                }
+
+                // a dot at the start of a line comes doubled
+                data.push(match line.strip_prefix('.') {
+                    Some(rest) if rest.starts_with('.') => rest.to_string(),
+                    _ => line,
+                });
+            }
+
+            received.lock().unwrap().push(Email {
+                to: std::mem::take(&mut to),
+                data: data.join("\r\n"),
+            });
+        } else if command.starts_with("RSET") {
+            to.clear();
+        } else if command.starts_with("QUIT") {
+            writer.write_all(b"221 Bye\r\n").await?;
+            return Ok(());
+        }
+
+        // everything else is fine by us
+        writer.write_all(b"250 OK\r\n").await?;
+    }
+
+    Ok(())
+}
+
+// Serves media by ID at the same path a homeserver does, with its type sniffed from what it is.
+pub struct MediaServer {
+    url: String,
+}
+
+impl MediaServer {
+    pub fn start(media: HashMap<String, Bytes>) -> anyhow::Result<MediaServer> {
+        let listener = StdTcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+
+        let app = Router::new()
+            .route("/_matrix/media/r0/download/:server/:id", get(on_download))
+            .layer(Extension(Arc::new(media)));
+
+        let server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
+
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                println!("Could not run media server! {}", e);
+            }
+        });
+
+        Ok(MediaServer { url })
+    }
+
+    pub fn homeserver(&self) -> matrix::Homeserver {
+        matrix::Homeserver::new(&self.url)
+    }
+}
+
+async fn on_download(
+    Extension(media): Extension<Arc<HashMap<String, Bytes>>>,
+    Path((_, id)): Path<(String, String)>,
+) -> Response {
+    match media.get(&id) {
+        Some(data) => {
+            let mime_type = image::sniff_mime_type(data).unwrap_or("application/octet-stream");
+            ([(header::CONTENT_TYPE, mime_type)], data.clone()).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
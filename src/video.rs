@@ -0,0 +1,241 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use bytes::Bytes;
+
+use crate::storage;
+use crate::storage::Area;
+
+// most mail servers won't take much more than this
+const DEFAULT_MAX_SIZE: usize = 20 * 1024 * 1024;
+
+// the biggest video we'll email, from VIDEO_MAX_SIZE (in bytes)
+pub fn max_size() -> usize {
+    env::var("VIDEO_MAX_SIZE")
+        .map(|size| size.parse().expect("not an integer"))
+        .unwrap_or(DEFAULT_MAX_SIZE)
+}
+
+// passes small videos through untouched, and re-encodes anything bigger to a 720p MP4 with ffmpeg
+pub fn shrink(video: &Bytes) -> anyhow::Result<Bytes> {
+    if video.len() <= max_size() {
+        return Ok(video.clone());
+    }
+
+    println!("transcoding {} byte video", video.len());
+
+    let shrunk = ffmpeg(
+        video,
+        "mp4",
+        &[
+            "-vf",
+            "scale=-2:'min(720,ih)'",
+            "-c:v",
+            "libx264",
+            "-preset",
+            "fast",
+            "-crf",
+            "28",
+            "-c:a",
+            "aac",
+            "-b:a",
+            "96k",
+            "-movflags",
+            "+faststart",
+        ],
+    )?;
+
+    if shrunk.len() > max_size() {
+        bail!("That video is too big to email, even after shrinking it.");
+    }
+
+    println!("shrunk video to {} bytes", shrunk.len());
+
+    Ok(shrunk)
+}
+
+// Copies the streams into a new file without any of the metadata, which is where phones keep the
+// location. There's no picking out only the location, so everything goes.
+pub fn scrub(video: &Bytes, mime_type: &str) -> anyhow::Result<Bytes> {
+    let extension = match mime_type {
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        _ => "mp4",
+    };
+
+    ffmpeg(video, extension, &["-map_metadata", "-1", "-c", "copy"])
+}
+
+// ISO media brands a video can start with
+const VIDEO_BRANDS: &[&[u8]] = &[b"mp41", b"mp42", b"isom", b"iso2", b"avc1", b"qt  "];
+
+// brands for a HEIF with an image sequence in it, rather than only stills
+const SEQUENCE_BRANDS: &[&[u8]] = &[b"msf1", b"hevc", b"avcs"];
+
+// The moving part of a live or motion photo, as a video and its type, if it has one. Motion photos
+// (from Android phones) have a whole MP4 tacked onto the end of the still, which comes off as it
+// is. A HEIF image sequence is a movie already, so it's made into an MP4 a mail client can play.
+pub fn motion(photo: &Bytes) -> anyhow::Result<Option<(Bytes, &'static str)>> {
+    if let Some((start, mime_type)) = embedded_video(photo) {
+        return Ok(Some((photo.slice(start..), mime_type)));
+    }
+
+    if !image_sequence(photo) {
+        return Ok(None);
+    }
+
+    println!("converting {} byte image sequence", photo.len());
+
+    let video = ffmpeg(
+        photo,
+        "mp4",
+        &[
+            "-map",
+            "0:v:0",
+            "-c:v",
+            "libx264",
+            "-pix_fmt",
+            "yuv420p",
+            "-preset",
+            "fast",
+            "-crf",
+            "28",
+            "-an",
+            "-movflags",
+            "+faststart",
+        ],
+    )?;
+
+    Ok(Some((video, "video/mp4")))
+}
+
+// Where a video stuck on the end of a still starts, found by the file type box it starts with,
+// along with its type. The still's own box (if it has one) is right at the start, so that's left
+// out.
+fn embedded_video(photo: &[u8]) -> Option<(usize, &'static str)> {
+    let mut at = 8;
+
+    while let Some(found) = photo.get(at..)?.windows(4).position(|w| w == b"ftyp") {
+        let ftyp = at + found;
+        let start = ftyp - 4;
+        let size = u32::from_be_bytes(photo[start..ftyp].try_into().ok()?) as usize;
+        let brand = photo.get(ftyp + 4..ftyp + 8)?;
+
+        if (8..=256).contains(&size) && VIDEO_BRANDS.contains(&brand) {
+            let mime_type = match brand {
+                b"qt  " => "video/quicktime",
+                _ => "video/mp4",
+            };
+
+            return Some((start, mime_type));
+        }
+
+        at = ftyp + 4;
+    }
+
+    None
+}
+
+// whether a HEIF says (in its major or compatible brands) that it has an image sequence
+fn image_sequence(photo: &[u8]) -> bool {
+    if photo.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+
+    let size = u32::from_be_bytes([photo[0], photo[1], photo[2], photo[3]]) as usize;
+
+    let brands = match photo.get(8..size.min(photo.len())) {
+        Some(brands) => brands,
+        None => return false,
+    };
+
+    // the major brand, a minor version, and then the compatible brands
+    brands
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .any(|(_, brand)| SEQUENCE_BRANDS.contains(&brand))
+}
+
+// runs the video through ffmpeg with the given output options, writing whatever type the
+// extension says
+fn ffmpeg(video: &Bytes, extension: &str, args: &[&str]) -> anyhow::Result<Bytes> {
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let media = storage::dir("bots", Area::Media)?;
+    let input = media.join(format!("{}-in", stamp));
+    let output = media.join(format!("{}-out.{}", stamp, extension));
+
+    fs::write(&input, video)?;
+
+    let status = Command::new("ffmpeg")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(&input)
+        .args(args)
+        .arg(&output)
+        .status();
+
+    let result = match status {
+        Ok(status) if status.success() => fs::read(&output).map(Bytes::from),
+        Ok(status) => Err(std::io::Error::other(format!(
+            "ffmpeg exited with {}",
+            status
+        ))),
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&output);
+
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file type box, with the given major brand and nothing compatible
+    fn ftyp(brand: &[u8]) -> Vec<u8> {
+        let mut ftyp = 16u32.to_be_bytes().to_vec();
+        ftyp.extend_from_slice(b"ftyp");
+        ftyp.extend_from_slice(brand);
+        ftyp.extend_from_slice(&[0, 0, 0, 0]);
+        ftyp
+    }
+
+    #[test]
+    fn finds_embedded_videos() {
+        let mut photo = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        photo.extend_from_slice(b"not a ftyp box at all");
+        let start = photo.len();
+        photo.extend(ftyp(b"mp42"));
+        photo.extend_from_slice(b"moov");
+
+        assert_eq!(embedded_video(&photo), Some((start, "video/mp4")));
+
+        // a HEIC's own box doesn't count
+        let mut heic = ftyp(b"heic");
+        assert_eq!(embedded_video(&heic), None);
+
+        let start = heic.len();
+        heic.extend(ftyp(b"qt  "));
+        assert_eq!(embedded_video(&heic), Some((start, "video/quicktime")));
+    }
+
+    #[test]
+    fn spots_image_sequences() {
+        assert!(!image_sequence(&ftyp(b"heic")));
+        assert!(image_sequence(&ftyp(b"msf1")));
+
+        // or it's only compatible with one
+        let mut compatible = 24u32.to_be_bytes().to_vec();
+        compatible.extend_from_slice(b"ftypheic\0\0\0\0mif1msf1");
+        assert!(image_sequence(&compatible));
+
+        assert!(!image_sequence(b"\xFF\xD8\xFF\xE1"));
+    }
+}
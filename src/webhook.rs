@@ -1,17 +1,148 @@
 use anyhow::{bail, Result};
-use serde::Serialize;
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::config;
+use crate::storage;
+
+const HOME_ASSISTANT: &str = "http://ha.kulak.us";
+
+// how many failures in a row before we stop trying
+const FAILURE_THRESHOLD: usize = 3;
+
+// how long to wait on Home Assistant before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// history can be a lot of states, so it gets longer
+const HISTORY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how often to check whether Home Assistant is back
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// what "webhook test" sends, since the real thing would be coming from someone
+const SAMPLE_MESSAGE: &str = "This is only a test.";
+
+// how many echoes can pile up before a slow listener starts missing them
+const ECHO_BUFFER: usize = 32;
+
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+static ECHOES: Lazy<broadcast::Sender<String>> = Lazy::new(|| broadcast::channel(ECHO_BUFFER).0);
 
 #[derive(Serialize)]
 struct Body<'a> {
     what: &'a str,
 }
 
-async fn webook(id: &str, message: &str) -> Result<()> {
-    let url = format!("http://ha.kulak.us/api/webhook/{}", id);
+async fn webook(name: &str, id: &str, message: &str) -> Result<()> {
+    if test_mode() {
+        let echo = dry_run(name, message)?;
+        let _ = ECHOES.send(echo);
+        return Ok(());
+    }
+
+    if TRIPPED.load(Ordering::SeqCst) {
+        record(name, Duration::ZERO, "tripped");
+        bail!("Home Assistant seems down");
+    }
+
+    let started = Instant::now();
+    let result = call(id, message).await;
+
+    record(
+        name,
+        started.elapsed(),
+        if result.is_ok() { "ok" } else { "failed" },
+    );
+
+    match result {
+        Ok(_) => {
+            FAILURES.store(0, Ordering::SeqCst);
+            Ok(())
+        }
+        Err(e) => {
+            let failures = FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+
+            if failures >= FAILURE_THRESHOLD && !TRIPPED.swap(true, Ordering::SeqCst) {
+                println!("{} webhook failures in a row, tripping breaker", failures);
+                tokio::spawn(probe());
+            }
+
+            Err(e)
+        }
+    }
+}
+
+// waits for Home Assistant to answer anything at all, then resets the breaker
+async fn probe() {
+    let client = reqwest::Client::new();
+
+    loop {
+        tokio::time::sleep(PROBE_INTERVAL).await;
+
+        let url = format!("{}/api/", HOME_ASSISTANT);
+
+        if client.get(url).timeout(TIMEOUT).send().await.is_ok() {
+            println!("Home Assistant is back, resetting breaker");
+            FAILURES.store(0, Ordering::SeqCst);
+            TRIPPED.store(false, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+// With WEBHOOK_TEST_MODE=true, nothing goes to Home Assistant. Every call is logged as a "test"
+// instead, and echoed to anyone listening (the home bot, which posts them to WEBHOOK_ADMIN_ROOM).
+fn test_mode() -> bool {
+    env::var("WEBHOOK_TEST_MODE")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+// everything that would have been sent, without sending it
+pub fn echoes() -> broadcast::Receiver<String> {
+    ECHOES.subscribe()
+}
+
+fn dry_run(name: &str, message: &str) -> Result<String> {
+    let body = serde_json::to_string(&Body { what: message })?;
+
+    println!("not calling the {} webhook with {}", name, body);
+    record(name, Duration::ZERO, "test");
+
+    Ok(format!("The {} webhook would have gotten {}", name, body))
+}
+
+// Everything about calling a webhook but the call itself, with a sample message, so config changes
+// can be checked without anything going off in the house.
+pub fn test(name: &str) -> Result<String> {
+    let name = name.to_lowercase();
+
+    if !registry().contains_key(&name) {
+        bail!("I don't know the {} webhook.", name);
+    }
+
+    dry_run(&name, SAMPLE_MESSAGE)
+}
+
+async fn call(id: &str, message: &str) -> Result<()> {
+    let url = format!("{}/api/webhook/{}", HOME_ASSISTANT, id);
     let body = Body { what: message };
 
-    let response = reqwest::Client::new().post(url).json(&body).send().await?;
+    let response = reqwest::Client::new()
+        .post(url)
+        .timeout(TIMEOUT)
+        .json(&body)
+        .send()
+        .await?;
 
     if !response.status().is_success() {
         bail!(
@@ -23,12 +154,103 @@ async fn webook(id: &str, message: &str) -> Result<()> {
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct State {
+    state: String,
+}
+
+// The current state of a Home Assistant entity, like "61.5" for a humidity sensor. This needs a
+// long-lived access token in HA_TOKEN.
+pub async fn state(entity_id: &str) -> Result<String> {
+    let token = env::var("HA_TOKEN").expect("HA_TOKEN environmental variable not set");
+    let url = format!("{}/api/states/{}", HOME_ASSISTANT, entity_id);
+
+    let response = reqwest::Client::new()
+        .get(url)
+        .timeout(TIMEOUT)
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("Home Assistant doesn't know {}.", entity_id);
+    }
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from Home Assistant: {}",
+            response.status()
+        );
+    }
+
+    Ok(response.json::<State>().await?.state)
+}
+
+#[derive(Deserialize)]
+struct Change {
+    state: String,
+    last_changed: String,
+}
+
+// Every state an entity was in between two times, oldest first, starting with the one it was
+// already in. Like state(), this needs HA_TOKEN.
+pub async fn state_history(
+    entity_id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, String)>> {
+    let token = env::var("HA_TOKEN").expect("HA_TOKEN environmental variable not set");
+    let url = format!(
+        "{}/api/history/period/{}",
+        HOME_ASSISTANT,
+        start.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+
+    let end = end.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let response = reqwest::Client::new()
+        .get(url)
+        .query(&[
+            ("filter_entity_id", entity_id),
+            ("end_time", &end),
+            ("minimal_response", ""),
+            ("no_attributes", ""),
+        ])
+        .timeout(HISTORY_TIMEOUT)
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from Home Assistant: {}",
+            response.status()
+        );
+    }
+
+    // one list per entity, and there's only the one
+    let mut entities = response.json::<Vec<Vec<Change>>>().await?;
+
+    let changes = match entities.pop() {
+        Some(changes) => changes,
+        None => bail!("Home Assistant has no history for {}.", entity_id),
+    };
+
+    Ok(changes
+        .into_iter()
+        .filter_map(|change| {
+            let date = DateTime::parse_from_rfc3339(&change.last_changed).ok()?;
+            Some((date.with_timezone(&Utc), change.state))
+        })
+        .collect())
+}
+
 pub async fn play_video(url: &str) -> Result<()> {
     let id = env::var("PLAY_VIDEO").expect("PLAY_VIDEO environmental variable not set");
 
     println!("playing video at {}", url);
 
-    webook(&id, url).await?;
+    webook("play_video", &id, url).await?;
     Ok(())
 }
 
@@ -37,7 +259,7 @@ pub async fn broadcast(message: &str) -> Result<()> {
 
     println!("broadcasting {}", message);
 
-    webook(&id, message).await?;
+    webook("broadcast", &id, message).await?;
     Ok(())
 }
 
@@ -46,6 +268,177 @@ pub async fn notify(message: &str) -> Result<()> {
 
     println!("notifying {}", message);
 
-    webook(&id, message).await?;
+    webook("notify", &id, message).await?;
+    Ok(())
+}
+
+// Tells Home Assistant someone's taken care of an appliance (like emptying the washer), so it can
+// clear whatever reminder it has up. Nothing happens without APPLIANCE_CLEARED.
+pub async fn clear_appliance(appliance: &str) -> Result<()> {
+    let id = match env::var("APPLIANCE_CLEARED") {
+        Ok(id) => id,
+        Err(_) => return Ok(()),
+    };
+
+    println!("clearing {}", appliance);
+
+    webook("appliance_cleared", &id, appliance).await?;
+    Ok(())
+}
+
+// Every webhook we know about, by name: the built-in ones, plus anything in WEBHOOKS (a JSON map
+// of name to Home Assistant webhook ID).
+pub fn registry() -> HashMap<String, String> {
+    let mut webhooks: HashMap<String, String> = config::load("WEBHOOKS");
+
+    for (name, var) in [
+        ("broadcast", "BROADCAST"),
+        ("notify", "NOTIFY"),
+        ("play_video", "PLAY_VIDEO"),
+        ("appliance_cleared", "APPLIANCE_CLEARED"),
+    ] {
+        if let Ok(id) = env::var(var) {
+            webhooks.entry(name.to_string()).or_insert(id);
+        }
+    }
+
+    webhooks
+}
+
+pub async fn trigger(name: &str, message: &str) -> Result<()> {
+    let name = name.to_lowercase();
+
+    let id = match registry().remove(&name) {
+        Some(id) => id,
+        None => bail!("I don't know the {} webhook.", name),
+    };
+
+    println!("triggering {} with {}", name, message);
+
+    webook(&name, &id, message).await?;
     Ok(())
 }
+
+// One webhook call, as it went: "ok", "failed", "tripped" (not even tried, because the breaker
+// was open), or "test" (not even tried, on purpose).
+pub struct Call {
+    pub date: String,
+    pub name: String,
+    pub latency: Duration,
+    pub status: String,
+}
+
+fn open_log() -> Result<Connection> {
+    let conn = storage::open_shared()?;
+
+    conn.execute(
+        "
+        CREATE TABLE IF NOT EXISTS webhook_log (
+            id INTEGER PRIMARY KEY,
+            date TEXT NOT NULL,
+            name TEXT NOT NULL,
+            latency_ms INTEGER NOT NULL,
+            status TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(conn)
+}
+
+// a webhook should still go through even if we can't write down that it did
+fn record(name: &str, latency: Duration, status: &str) {
+    let result = open_log().and_then(|conn| {
+        conn.execute(
+            "
+            INSERT INTO webhook_log
+                (date, name, latency_ms, status)
+            VALUES
+                (?1, ?2, ?3, ?4)",
+            params![
+                chrono::Utc::now().to_rfc3339(),
+                name,
+                latency.as_millis() as i64,
+                status
+            ],
+        )?;
+
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        println!("could not log the {} webhook: {}", name, e);
+    }
+}
+
+// the most recent calls, newest first
+pub fn history(limit: usize) -> Result<Vec<Call>> {
+    let conn = open_log()?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT date, name, latency_ms, status
+        FROM webhook_log
+        ORDER BY id DESC
+        LIMIT ?1",
+    )?;
+
+    let calls = stmt
+        .query_map(params![limit as i64], |row| {
+            Ok(Call {
+                date: row.get(0)?,
+                name: row.get(1)?,
+                latency: Duration::from_millis(row.get::<_, i64>(2)? as u64),
+                status: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Call>>>()?;
+
+    Ok(calls)
+}
+
+// Every call ever made, in the Prometheus text format: how many there were and how long they
+// took altogether, by webhook and status.
+pub fn metrics() -> Result<String> {
+    let conn = open_log()?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT name, status, COUNT(*), SUM(latency_ms)
+        FROM webhook_log
+        GROUP BY name, status
+        ORDER BY name, status",
+    )?;
+
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(String, String, i64, i64)>>>()?;
+
+    let mut calls = vec![
+        "# HELP webhook_calls_total Webhook calls, by webhook and status.".to_string(),
+        "# TYPE webhook_calls_total counter".to_string(),
+    ];
+
+    let mut seconds = vec![
+        "# HELP webhook_seconds_total Time spent waiting on webhooks, by webhook and status."
+            .to_string(),
+        "# TYPE webhook_seconds_total counter".to_string(),
+    ];
+
+    for (name, status, count, latency_ms) in rows {
+        let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        let labels = format!("name=\"{}\",status=\"{}\"", name, status);
+        calls.push(format!("webhook_calls_total{{{}}} {}", labels, count));
+        seconds.push(format!(
+            "webhook_seconds_total{{{}}} {:.3}",
+            labels,
+            latency_ms as f64 / 1000.0
+        ));
+    }
+
+    calls.append(&mut seconds);
+
+    Ok(calls.join("\n") + "\n")
+}
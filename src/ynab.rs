@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use matrix_sdk::ruma::UserId;
+use serde::Serialize;
+
+use crate::config;
+use crate::matrix;
+use crate::scheduler;
+
+#[derive(Serialize)]
+struct Body<'a> {
+    transaction: Transaction<'a>,
+}
+
+#[derive(Serialize)]
+struct Transaction<'a> {
+    account_id: &'a str,
+    date: &'a str,
+    amount: i64,
+    payee_name: &'a str,
+    memo: Option<&'a str>,
+    import_id: String,
+    cleared: &'a str,
+}
+
+// Exporting is only turned on when YNAB_TOKEN, YNAB_BUDGET, and YNAB_ACCOUNTS (a JSON map of
+// Matrix ID to YNAB account ID) are all set.
+pub fn enabled() -> bool {
+    env::var("YNAB_TOKEN").is_ok()
+        && env::var("YNAB_BUDGET").is_ok()
+        && env::var("YNAB_ACCOUNTS").is_ok()
+}
+
+// pushes one ledger transaction to the account of each side that's mapped to YNAB
+pub async fn push(
+    id: i64,
+    sender: Option<&str>,
+    receiver: &str,
+    amount: i64,
+    date: &str,
+    memo: Option<&str>,
+) -> Result<()> {
+    let accounts: HashMap<String, String> = config::load("YNAB_ACCOUNTS");
+
+    let date = scheduler::timezone()
+        .timestamp_millis(DateTime::<Utc>::from_str(date)?.timestamp_millis())
+        .format("%Y-%m-%d")
+        .to_string();
+
+    if let Some(account) = accounts.get(receiver) {
+        let payee = sender
+            .map(payee_name)
+            .unwrap_or_else(|| "Family Bank".to_string());
+        post(
+            account,
+            &date,
+            amount,
+            &payee,
+            memo,
+            format!("bots:{}:in", id),
+        )
+        .await?;
+    }
+
+    if let Some(account) = sender.and_then(|s| accounts.get(s)) {
+        let payee = payee_name(receiver);
+        post(
+            account,
+            &date,
+            -amount,
+            &payee,
+            memo,
+            format!("bots:{}:out", id),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn post(
+    account_id: &str,
+    date: &str,
+    amount: i64,
+    payee_name: &str,
+    memo: Option<&str>,
+    import_id: String,
+) -> Result<()> {
+    let token = env::var("YNAB_TOKEN").expect("YNAB_TOKEN environmental variable not set");
+    let budget = env::var("YNAB_BUDGET").expect("YNAB_BUDGET environmental variable not set");
+
+    let url = format!(
+        "https://api.youneedabudget.com/v1/budgets/{}/transactions",
+        budget
+    );
+
+    let body = Body {
+        transaction: Transaction {
+            account_id,
+            date,
+            // YNAB works in thousandths of a dollar
+            amount: amount * 10,
+            payee_name,
+            memo,
+            import_id,
+            cleared: "cleared",
+        },
+    };
+
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "unexpected response status from YNAB: {}",
+            response.status()
+        );
+    }
+
+    Ok(())
+}
+
+fn payee_name(user_id: &str) -> String {
+    match UserId::try_from(user_id) {
+        Ok(user_id) => matrix::pretty_user_id(&user_id),
+        Err(_) => user_id.to_string(),
+    }
+}